@@ -13,24 +13,37 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::env;
+use std::fs;
 use std::net::SocketAddr;
-use std::path::Path;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
+use std::time::Duration;
 
 use common::prelude::*;
 use common::state::State;
-use common::config::{Config, HttpConfig};
-
-use scripts::{Blueprint, Repository, JobContext};
+use common::config::{Config, HttpConfig, JobsConfig, SiemConfig};
+use common::config::{ScriptsConfig, SigningConfig, WorkersConfig};
+
+use events::JobEventKind;
+use scripts::{Blueprint, Repository, JobContext, JobLogs, HeartbeatMonitor};
+use scripts::{ArtifactUploader, Dispatcher, ScheduleMonitor, StopSignal};
+use scripts::{sweep_orphaned_workdirs, Interpreters, ORPHANED_MIN_AGE};
+use metrics::Metrics;
 use processor::{Processor, ProcessorApi};
+use utils;
 use web::WebApp;
 
 
 struct InnerApp {
     locked: bool,
-    scripts_blueprint: Blueprint,
+    scripts_blueprint: Arc<Mutex<Blueprint>>,
     processor: Processor<Repository>,
+    heartbeats: HeartbeatMonitor,
+    schedules: ScheduleMonitor,
+    metrics: Arc<Metrics>,
+    stop: Arc<StopSignal>,
     http: Option<WebApp<ProcessorApi<Repository>>>,
 }
 
@@ -38,32 +51,67 @@ impl InnerApp {
     fn new() -> Result<Self> {
         let state = Arc::new(State::new());
         let blueprint = Blueprint::new(state.clone());
+        let metrics = Arc::new(Metrics::new());
+        let stop = Arc::new(StopSignal::new());
 
         let processor = Processor::new(
             0,
             Arc::new(blueprint.repository()),
-            JobContext::default(),
+            JobContext {
+                metrics: metrics.clone(),
+                stop: stop.clone(),
+                events: blueprint.repository().events(),
+                journal: blueprint.repository().journal(),
+                dead_letters: blueprint.repository().dead_letters(),
+                .. JobContext::default()
+            },
             state.clone(),
         )?;
 
+        let heartbeats = HeartbeatMonitor::new(
+            Arc::new(blueprint.repository()), processor.api(),
+        );
+        let schedules = ScheduleMonitor::new(
+            Arc::new(blueprint.repository()), processor.api(),
+        );
+
         Ok(InnerApp {
             locked: false,
-            scripts_blueprint: blueprint,
+            scripts_blueprint: Arc::new(Mutex::new(blueprint)),
             http: None,
             processor,
+            heartbeats,
+            schedules,
+            metrics,
+            stop,
         })
     }
 
-    fn restart_http_server(&mut self, config: &HttpConfig) -> Result<()> {
+    fn restart_http_server(
+        &mut self,
+        config: &HttpConfig,
+        siem: &SiemConfig,
+        signing: &SigningConfig,
+    ) -> Result<()> {
         // Stop the server if it's already running
         if let Some(http) = self.http.take() {
             http.stop();
         }
 
+        // The admin API can reload the scripts from the disk
+        let blueprint = self.scripts_blueprint.clone();
+        let reload = Arc::new(move || -> Result<()> {
+            blueprint.lock()?.reload()
+        });
+
         let http = WebApp::new(
-            Arc::new(self.scripts_blueprint.repository()),
+            Arc::new(self.scripts_blueprint.lock()?.repository()),
             config,
+            siem,
+            signing,
             self.processor.api(),
+            self.metrics.clone(),
+            reload,
         )?;
 
         // Lock the server if it was locked before
@@ -76,29 +124,139 @@ impl InnerApp {
         Ok(())
     }
 
-    fn set_scripts_path<P: AsRef<Path>>(
-        &mut self, path: P, recursive: bool,
+    fn set_scripts(
+        &mut self, config: &ScriptsConfig, jobs: &JobsConfig,
     ) -> Result<()> {
-        self.scripts_blueprint.clear();
-        self.scripts_blueprint.collect_path(path, recursive)?;
+        let interpreters = Interpreters::from_config(config)?;
+        {
+            let mut blueprint = self.scripts_blueprint.lock()?;
+            blueprint.clear();
+            blueprint.set_interpreters(interpreters);
+            blueprint.set_artifacts_collected(
+                jobs.logs.path.is_some()
+                    || jobs.artifacts_upload.endpoint.is_some(),
+            );
+            blueprint.collect_path(&config.path, config.recursive)?;
+        }
         self.processor.api().cleanup()?;
 
         Ok(())
     }
 
-    fn set_job_environment(&self, env: HashMap<String, String>) -> Result<()> {
+    fn set_job_context(
+        &self,
+        env: HashMap<String, String>,
+        jobs: &JobsConfig,
+        workers: &WorkersConfig,
+    ) -> Result<()> {
+        let repository = self.scripts_blueprint.lock()?.repository();
+        let timeout = jobs.timeout.as_u64();
+
+        // Jobs would fail to start if their directories can't be created
+        let temp_dir = jobs.temp_dir.as_ref().map(PathBuf::from);
+        if let Some(ref dir) = temp_dir {
+            if let Some(ref size) = jobs.temp_dir_tmpfs {
+                fs::create_dir_all(dir)?;
+                utils::mount_tmpfs(&dir.canonicalize()?, size.as_u64())?;
+            }
+            utils::check_temp_base(dir)?;
+        } else if jobs.temp_dir_tmpfs.is_some() {
+            return Err(ErrorKind::InvalidInput(
+                "jobs.temp-dir-tmpfs requires jobs.temp-dir to be set".into(),
+            ).into());
+        }
+
         self.processor.api().update_context(JobContext {
             environment: env,
+            body_storage: jobs.body_storage.clone(),
+            // A zero timeout disables it
+            timeout: if timeout > 0 {
+                Some(Duration::from_secs(timeout))
+            } else {
+                None
+            },
+            logs: jobs.logs.path.as_ref().map(|path| {
+                JobLogs::new(path.as_str(), jobs.logs.retention.as_u64())
+            }),
+            uploader: ArtifactUploader::from_config(&jobs.artifacts_upload)?,
+            limits: jobs.limits.clone(),
+            cgroup: jobs.cgroup.clone(),
+            sandbox: jobs.sandbox,
+            workdirs: jobs.workdirs.as_ref().map(PathBuf::from),
+            temp_dir,
+            pre_wrapper: jobs.pre_wrapper.as_ref().map(PathBuf::from),
+            post_wrapper: jobs.post_wrapper.as_ref().map(PathBuf::from),
+            container_runtime: jobs.container_runtime.clone(),
+            keep_workdir_on_failure: jobs.keep_workdir_on_failure,
+            kill_grace_period: Duration::from_secs(
+                jobs.kill_grace_period.as_u64(),
+            ),
+            stop: self.stop.clone(),
+            dispatcher: Dispatcher::from_config(workers)?.map(Arc::new),
+            metrics: self.metrics.clone(),
+            events: repository.events(),
+            journal: repository.journal(),
+            dead_letters: repository.dead_letters(),
             .. JobContext::default()
         })?;
         Ok(())
     }
 
+    fn sweep_orphaned_workdirs(&self, jobs: &JobsConfig) -> Result<()> {
+        let base = jobs.temp_dir
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(env::temp_dir);
+        let min_age = Duration::from_secs(ORPHANED_MIN_AGE);
+        sweep_orphaned_workdirs(&base, min_age)?;
+
+        // Hooks can store their bodies in the tmpfs even if it's not the
+        // default backend
+        let tmpfs = Path::new(&jobs.body_storage.tmpfs_path);
+        if tmpfs.is_dir() && tmpfs != base {
+            sweep_orphaned_workdirs(tmpfs, min_age)?;
+        }
+        Ok(())
+    }
+
+    fn set_queue_file(&self, path: Option<&String>) -> Result<()> {
+        let repository = self.scripts_blueprint.lock()?.repository();
+        let journal = repository.journal();
+        let processor = self.processor.api();
+
+        // Queue again the jobs which didn't end before Fisher stopped
+        for entry in journal.open(path.map(Path::new))? {
+            let job_id = entry.job_id.clone();
+            if let Some((job, priority)) = repository.restore_job(entry) {
+                repository.events().publish(job.event(JobEventKind::Queued));
+                processor.queue(job, priority)?;
+            } else {
+                // The hook of the job doesn't exist anymore
+                journal.forget(&job_id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_dead_letters(&self, dir: Option<&String>) -> Result<()> {
+        let repository = self.scripts_blueprint.lock()?.repository();
+        repository.dead_letters().set_dir(dir.map(Path::new))
+    }
+
     fn set_threads_count(&self, count: u16) -> Result<()> {
         self.processor.api().set_threads_count(count)?;
         Ok(())
     }
 
+    fn tls_changed(&self, config: &HttpConfig) -> Result<bool> {
+        if let Some(ref http) = self.http {
+            http.tls_changed(config)
+        } else {
+            Ok(false)
+        }
+    }
+
     fn http_addr(&self) -> Option<&SocketAddr> {
         if let Some(ref http) = self.http {
             Some(http.addr())
@@ -129,17 +287,23 @@ impl InnerApp {
         Ok(())
     }
 
-    fn stop(mut self) -> Result<()> {
-        if let Some(ref http) = self.http {
-            http.lock();
-        }
-
-        self.processor.stop()?;
-
+    fn stop(
+        mut self,
+        grace_period: Duration,
+        kill_grace_period: Duration,
+    ) -> Result<()> {
+        // Stop the HTTP server first, so the requests in flight are answered
+        // and their jobs queued before the processor stops. Locking it
+        // rejects the webhooks received in the meantime
         if let Some(http) = self.http.take() {
+            http.lock();
             http.stop();
         }
 
+        self.heartbeats.stop();
+        self.schedules.stop();
+        self.processor.shutdown(grace_period, kill_grace_period)?;
+
         Ok(())
     }
 }
@@ -153,12 +317,17 @@ pub struct Fisher {
 impl Fisher {
     pub fn new(config: Config) -> Result<Self> {
         let mut inner = InnerApp::new()?;
-        inner.set_scripts_path(
-            &config.scripts.path, config.scripts.recursive,
+        inner.set_scripts(&config.scripts, &config.jobs)?;
+        inner.set_job_context(
+            config.env.clone(), &config.jobs, &config.workers,
         )?;
-        inner.set_job_environment(config.env.clone())?;
+        inner.sweep_orphaned_workdirs(&config.jobs)?;
         inner.set_threads_count(config.jobs.threads)?;
-        inner.restart_http_server(&config.http)?;
+        inner.set_queue_file(config.jobs.queue_file.as_ref())?;
+        inner.set_dead_letters(config.jobs.dead_letters.as_ref())?;
+        inner.restart_http_server(
+            &config.http, &config.siem, &config.signing,
+        )?;
 
         Ok(Fisher {
             config,
@@ -180,14 +349,45 @@ impl Fisher {
     }
 
     fn reload_inner(&mut self, new_config: Config) -> Result<()> {
-        // Restart the HTTP server if its configuration changed
-        if self.config.http != new_config.http {
-            self.inner.restart_http_server(&new_config.http)?;
+        // Restart the HTTP server if its configuration changed, or if the
+        // TLS certificate was renewed
+        if self.config.http != new_config.http
+            || self.config.siem != new_config.siem
+            || self.config.signing != new_config.signing
+            || self.inner.tls_changed(&new_config.http)?
+        {
+            self.inner.restart_http_server(
+                &new_config.http, &new_config.siem, &new_config.signing,
+            )?;
         }
 
         // Update the job context if the environment is different
-        if self.config.env != new_config.env {
-            self.inner.set_job_environment(new_config.env.clone())?;
+        if self.config.env != new_config.env
+            || self.config.jobs.body_storage != new_config.jobs.body_storage
+            || self.config.jobs.timeout != new_config.jobs.timeout
+            || self.config.jobs.logs != new_config.jobs.logs
+            || self.config.jobs.artifacts_upload
+                != new_config.jobs.artifacts_upload
+            || self.config.jobs.limits != new_config.jobs.limits
+            || self.config.jobs.cgroup != new_config.jobs.cgroup
+            || self.config.jobs.sandbox != new_config.jobs.sandbox
+            || self.config.jobs.workdirs != new_config.jobs.workdirs
+            || self.config.jobs.temp_dir != new_config.jobs.temp_dir
+            || self.config.jobs.temp_dir_tmpfs
+                != new_config.jobs.temp_dir_tmpfs
+            || self.config.jobs.pre_wrapper != new_config.jobs.pre_wrapper
+            || self.config.jobs.post_wrapper != new_config.jobs.post_wrapper
+            || self.config.jobs.container_runtime
+                != new_config.jobs.container_runtime
+            || self.config.jobs.kill_grace_period
+                != new_config.jobs.kill_grace_period
+            || self.config.jobs.keep_workdir_on_failure
+                != new_config.jobs.keep_workdir_on_failure
+            || self.config.workers != new_config.workers
+        {
+            self.inner.set_job_context(
+                new_config.env.clone(), &new_config.jobs, &new_config.workers,
+            )?;
         }
 
         // Update the threads count if it's different
@@ -196,10 +396,19 @@ impl Fisher {
         }
 
         // Reload hooks, changing the script path
-        self.inner.set_scripts_path(
-            &new_config.scripts.path,
-            new_config.scripts.recursive,
-        )?;
+        self.inner.set_scripts(&new_config.scripts, &new_config.jobs)?;
+
+        // Start persisting the jobs to the new file
+        if self.config.jobs.queue_file != new_config.jobs.queue_file {
+            self.inner.set_queue_file(new_config.jobs.queue_file.as_ref())?;
+        }
+
+        // Start saving the failed jobs to the new directory
+        if self.config.jobs.dead_letters != new_config.jobs.dead_letters {
+            self.inner.set_dead_letters(
+                new_config.jobs.dead_letters.as_ref(),
+            )?;
+        }
 
         self.config = new_config;
 
@@ -207,6 +416,12 @@ impl Fisher {
     }
 
     pub fn stop(self) -> Result<()> {
-        self.inner.stop()
+        let grace_period = Duration::from_secs(
+            self.config.jobs.shutdown_grace_period.as_u64(),
+        );
+        let kill_grace_period = Duration::from_secs(
+            self.config.jobs.kill_grace_period.as_u64(),
+        );
+        self.inner.stop(grace_period, kill_grace_period)
     }
 }
@@ -20,11 +20,10 @@ extern crate toml;
 
 use std::fs;
 use std::io::Read;
-use std::path::Path;
 use std::time::{Instant, Duration};
 
 use fisher::*;
-use libc::{SIGUSR1, SIGINT, SIGTERM};
+use libc::{SIGCHLD, SIGUSR1, SIGINT, SIGTERM};
 use signal::trap::Trap;
 
 
@@ -50,19 +49,33 @@ fn usage(exit_code: i32, error_msg: &str) -> ! {
 }
 
 
-fn parse_cli() -> String {
+struct Cli {
+    config_path: String,
+    threads: Option<u16>,
+}
+
+
+fn parse_cli() -> Cli {
     // Parse the CLI args
     let mut only_args = false;
     let mut flag_help = false;
     let mut flag_version = false;
     let mut config_path = None;
+    let mut threads = None;
 
-    for arg in ::std::env::args().skip(1) {
+    let mut args = ::std::env::args().skip(1);
+    while let Some(arg) = args.next() {
         if !only_args && arg.chars().next() == Some('-') {
             match arg.as_str() {
                 "--" => only_args = true,
                 "-h" | "--help" => flag_help = true,
                 "--version" => flag_version = true,
+                "--threads" => {
+                    match args.next().and_then(|c| c.parse::<u16>().ok()) {
+                        Some(count) if count > 0 => threads = Some(count),
+                        _ => usage(1, "--threads needs a positive number"),
+                    }
+                }
                 _ => usage(1, &format!("invalid flag: {}", arg)),
             }
         } else if config_path.is_none() {
@@ -81,39 +94,57 @@ fn parse_cli() -> String {
         println!();
 
         println!("OPTIONS");
-        println!("  -h | --help   Show this message");
-        println!("  --version     Show the Fisher version");
+        println!("  -h | --help       Show this message");
+        println!("  --version         Show the Fisher version");
+        println!("  --threads <num>   Override the number of jobs run in \
+                  parallel");
 
         ::std::process::exit(0);
     } else if flag_version {
         show_version();
         ::std::process::exit(0);
     } else if let Some(path) = config_path {
-        path
+        Cli {
+            config_path: path,
+            threads,
+        }
     } else {
         usage(1, "too few arguments");
     }
 }
 
 
-fn read_config<P: AsRef<Path>>(path: P) -> Result<Config> {
+fn read_config(cli: &Cli) -> Result<Config> {
     // Read the configuration from a file
-    let mut file = fs::File::open(path)?;
+    let mut file = fs::File::open(&cli.config_path)?;
     let mut buffer = String::new();
     file.read_to_string(&mut buffer)?;
 
-    Ok(toml::from_str(&buffer).map_err(|e| {
+    let mut config: Config = toml::from_str(&buffer).map_err(|e| {
         Error::new(ErrorKind::GenericError(Box::new(e)).into())
-    })?)
+    })?;
+
+    // The CLI flags take precedence over the configuration file
+    if let Some(threads) = cli.threads {
+        config.jobs.threads = threads;
+    }
+
+    Ok(config)
 }
 
 
 fn app() -> Result<()> {
-    let signal_trap = Trap::trap(&[SIGINT, SIGTERM, SIGUSR1]);
+    // When running as PID 1 the orphaned processes are reparented to Fisher,
+    // which has to reap them
+    let mut signals = vec![SIGINT, SIGTERM, SIGUSR1];
+    if running_as_init() {
+        signals.push(SIGCHLD);
+    }
+    let signal_trap = Trap::trap(&signals);
 
-    let config_path = parse_cli();
+    let cli = parse_cli();
 
-    let mut app = Fisher::new(read_config(&config_path)?)?;
+    let mut app = Fisher::new(read_config(&cli)?)?;
     println!("HTTP server listening on {}", app.web_address().unwrap());
 
     // Wait for signals
@@ -121,12 +152,15 @@ fn app() -> Result<()> {
         let deadline = Instant::now() + Duration::from_secs(60);
         match signal_trap.wait(deadline) {
             Some(SIGINT) | Some(SIGTERM) => break,
+            Some(SIGCHLD) => {
+                reap_orphans();
+            }
             Some(SIGUSR1) => {
                 println!("Reloading configuration and scripts...");
 
                 // Don't crash if the reload fails, just show errors
                 // No changes are applied if the reload fails
-                match read_config(&config_path) {
+                match read_config(&cli) {
                     Ok(new_config) => {
                         if let Err(err) = app.reload(new_config) {
                             err.pretty_print()
@@ -61,6 +61,15 @@ pub struct Config {
     /// Configuration for running jobs.
     #[serde(default)]
     pub jobs: JobsConfig,
+    /// Configuration for forwarding security events to a SIEM.
+    #[serde(default)]
+    pub siem: SiemConfig,
+    /// Configuration for signing outgoing deliveries.
+    #[serde(default)]
+    pub signing: SigningConfig,
+    /// Configuration for running the jobs on remote workers.
+    #[serde(default)]
+    pub workers: WorkersConfig,
     /// Extra environment variables.
     #[serde(default)]
     pub env: HashMap<String, String>,
@@ -73,29 +82,265 @@ pub struct HttpConfig {
     /// The number of proxies Fisher is behind.
     #[serde(rename="behind-proxies", default="default_behind_proxies")]
     pub behind_proxies: u8,
+    /// The address ranges of the trusted proxies.
+    #[serde(rename="trusted-proxies", default)]
+    pub trusted_proxies: Vec<utils::Cidr>,
     /// The socket address to bind.
     #[serde(default="default_bind")]
     pub bind: SocketAddr,
     /// The rate limit for bad requests
     #[serde(rename="rate-limit", default)]
     pub rate_limit: RateLimitConfig,
+    /// The rate limit applied to every request
+    #[serde(rename="request-limit", default)]
+    pub request_limit: Option<RequestLimitConfig>,
     /// Enable or disable the health endpoint
     #[serde(rename="health-endpoint", default="default_health_endpoint")]
     pub health_endpoint: bool,
+    /// Enable or disable the metrics endpoint
+    #[serde(rename="metrics-endpoint", default)]
+    pub metrics_endpoint: bool,
+    /// The tokens allowed to use the admin endpoints.
+    #[serde(rename="admin-tokens", default)]
+    pub admin_tokens: Option<utils::Secrets>,
+    /// The tokens required to use the health and metrics endpoints.
+    #[serde(rename="monitoring-tokens", default)]
+    pub monitoring_tokens: Option<utils::Secrets>,
+    /// The tokens allowed to dispatch jobs to this instance.
+    #[serde(rename="worker-tokens", default)]
+    pub worker_tokens: Option<utils::Secrets>,
+    /// TLS termination for the HTTP server.
+    #[serde(default)]
+    pub tls: TlsConfig,
+    /// Cross-origin requests allowed to the hooks.
+    #[serde(default)]
+    pub cors: CorsConfig,
+    /// Unix socket to listen on, in addition to the TCP address.
+    #[serde(rename="unix-socket", default)]
+    pub unix_socket: Option<UnixSocketConfig>,
+    /// Additional addresses to listen on.
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    /// How long to wait for pending requests when stopping.
+    #[serde(rename="drain-timeout", default="default_drain_timeout")]
+    pub drain_timeout: utils::TimeString,
+    /// The maximum size of the body of the requests.
+    #[serde(rename="max-body-size", default="default_max_body_size")]
+    pub max_body_size: utils::SizeString,
+    /// The format of the access log, if enabled.
+    #[serde(rename="access-log", default)]
+    pub access_log: Option<AccessLogFormat>,
+    /// The file the access log is appended to, instead of the standard
+    /// output.
+    #[serde(rename="access-log-path", default)]
+    pub access_log_path: Option<String>,
+    /// The size above which request bodies are stored on disk.
+    #[serde(rename="spool-threshold", default="default_spool_threshold")]
+    pub spool_threshold: utils::SizeString,
+    /// How many delivery IDs are remembered to detect redeliveries.
+    #[serde(rename="replay-cache", default="default_replay_cache")]
+    pub replay_cache: usize,
+    /// How many requests can be handled at the same time.
+    #[serde(rename="max-connections", default="default_max_connections")]
+    pub max_connections: usize,
+    /// How long clients have to send the body of the requests.
+    #[serde(rename="read-timeout", default="default_read_timeout")]
+    pub read_timeout: utils::TimeString,
+    /// How many jobs can wait in the queue before rejecting new webhooks.
+    #[serde(rename="max-queued-jobs", default)]
+    pub max_queued_jobs: Option<usize>,
+    /// What to do with new webhooks when the queue is full.
+    #[serde(rename="queue-overflow", default="default_queue_overflow")]
+    pub queue_overflow: QueueOverflow,
+    /// Whether connections are kept open between requests.
+    #[serde(rename="keep-alive", default="default_keep_alive")]
+    pub keep_alive: bool,
+    /// The path all the routes are served under.
+    #[serde(rename="base-path", default="default_base_path")]
+    pub base_path: String,
+}
+
+impl HttpConfig {
+    /// Return the prefix to add to all the routes, without the trailing
+    /// slash.
+    pub fn route_prefix(&self) -> &str {
+        self.base_path.trim_right_matches('/')
+    }
 }
 
 default_fn!(default_behind_proxies: u8 = 0);
 default_fn!(default_bind: SocketAddr = "127.0.0.1:8000".parse().unwrap());
 default_fn!(default_health_endpoint: bool = true);
+default_fn!(default_drain_timeout: utils::TimeString = 10.into());
+default_fn!(default_max_body_size: utils::SizeString =
+    (25 * 1024 * 1024).into());
+default_fn!(default_spool_threshold: utils::SizeString =
+    (1024 * 1024).into());
+default_fn!(default_replay_cache: usize = 1000);
+default_fn!(default_base_path: String = "/".into());
+default_fn!(default_max_connections: usize = 64);
+default_fn!(default_read_timeout: utils::TimeString = 30.into());
+default_fn!(default_queue_overflow: QueueOverflow = QueueOverflow::Reject);
+default_fn!(default_keep_alive: bool = true);
 
 default!(HttpConfig {
     behind_proxies: default_behind_proxies(),
+    trusted_proxies: Vec::new(),
     bind: default_bind(),
     rate_limit: RateLimitConfig::default(),
+    request_limit: None,
     health_endpoint: default_health_endpoint(),
+    metrics_endpoint: false,
+    admin_tokens: None,
+    monitoring_tokens: None,
+    worker_tokens: None,
+    tls: TlsConfig::default(),
+    cors: CorsConfig::default(),
+    unix_socket: None,
+    listeners: Vec::new(),
+    drain_timeout: default_drain_timeout(),
+    max_body_size: default_max_body_size(),
+    access_log: None,
+    access_log_path: None,
+    spool_threshold: default_spool_threshold(),
+    replay_cache: default_replay_cache(),
+    max_connections: default_max_connections(),
+    read_timeout: default_read_timeout(),
+    max_queued_jobs: None,
+    queue_overflow: default_queue_overflow(),
+    keep_alive: default_keep_alive(),
+    base_path: default_base_path(),
 });
 
 
+/// What happens to new webhooks when the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum QueueOverflow {
+    /// Reject the webhook, asking the sender to retry later.
+    #[serde(rename="reject")]
+    Reject,
+    /// Drop the oldest job in the queue to make room for the new one.
+    #[serde(rename="drop-oldest")]
+    DropOldest,
+    /// Wait for room in the queue before answering the webhook.
+    #[serde(rename="block")]
+    Block,
+}
+
+
+/// The format of the access log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum AccessLogFormat {
+    /// One human-readable line per request.
+    #[serde(rename="plain")]
+    Plain,
+    /// One JSON object per request.
+    #[serde(rename="json")]
+    Json,
+}
+
+
+/// Configuration for an additional address to listen on.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub struct ListenerConfig {
+    /// The socket address to bind.
+    pub bind: SocketAddr,
+    /// The endpoints available on this address.
+    #[serde(default="default_endpoints")]
+    pub endpoints: Vec<Endpoint>,
+}
+
+default_fn!(default_endpoints: Vec<Endpoint> =
+    vec![
+        Endpoint::Hooks, Endpoint::Health, Endpoint::Metrics, Endpoint::Admin,
+        Endpoint::Worker,
+    ]);
+
+
+/// An endpoint of the HTTP API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Endpoint {
+    /// The endpoint receiving webhooks.
+    #[serde(rename="hooks")]
+    Hooks,
+    /// The endpoint used to monitor the instance.
+    #[serde(rename="health")]
+    Health,
+    /// The endpoint exporting metrics to Prometheus.
+    #[serde(rename="metrics")]
+    Metrics,
+    /// The endpoints used to manage the instance.
+    #[serde(rename="admin")]
+    Admin,
+    /// The endpoint receiving jobs from other instances.
+    #[serde(rename="worker")]
+    Worker,
+}
+
+
+/// Configuration for TLS termination.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub struct TlsConfig {
+    /// Path to the PEM certificate chain.
+    #[serde(default)]
+    pub certificate: Option<String>,
+    /// Path to the PEM private key.
+    #[serde(rename="private-key", default)]
+    pub private_key: Option<String>,
+}
+
+default!(TlsConfig {
+    certificate: None,
+    private_key: None,
+});
+
+
+/// Configuration for cross-origin requests to the hooks.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub struct CorsConfig {
+    /// The origins allowed to call the hooks, or `*` for any origin.
+    #[serde(rename="allowed-origins", default)]
+    pub allowed_origins: Vec<String>,
+    /// How long browsers can cache the preflight responses.
+    #[serde(rename="max-age", default="default_cors_max_age")]
+    pub max_age: utils::TimeString,
+}
+
+default_fn!(default_cors_max_age: utils::TimeString = 3600.into());
+
+default!(CorsConfig {
+    allowed_origins: Vec::new(),
+    max_age: default_cors_max_age(),
+});
+
+
+/// Configuration for listening on an Unix socket.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub struct UnixSocketConfig {
+    /// Path of the socket.
+    pub path: String,
+    /// Permissions of the socket, as an octal string.
+    #[serde(default)]
+    pub mode: Option<String>,
+}
+
+impl UnixSocketConfig {
+    /// Parse the permissions of the socket.
+    pub fn mode(&self) -> Result<Option<u32>> {
+        if let Some(ref mode) = self.mode {
+            match u32::from_str_radix(mode, 8) {
+                Ok(parsed) if parsed <= 0o777 => Ok(Some(parsed)),
+                _ => Err(ErrorKind::InvalidInput(
+                    format!("invalid socket permissions: {}", mode),
+                ).into()),
+            }
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+
 /// Configuration for rate limiting.
 #[derive(Debug, PartialEq, Eq)]
 pub struct RateLimitConfig {
@@ -170,18 +415,321 @@ impl<'de> Deserialize<'de> for RateLimitConfig {
 }
 
 
+/// Configuration for limiting the requests of every client.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub struct RequestLimitConfig {
+    /// The sustained rate of allowed requests.
+    pub rate: RateLimitConfig,
+    /// The number of requests allowed in a burst.
+    #[serde(default)]
+    pub burst: Option<u64>,
+}
+
+impl RequestLimitConfig {
+    /// Return the size of the bursts, which defaults to the allowed requests.
+    pub fn burst(&self) -> u64 {
+        self.burst.unwrap_or(self.rate.allowed)
+    }
+}
+
+
 /// Configuration for running jobs.
 #[derive(Debug, PartialEq, Eq, Deserialize)]
 pub struct JobsConfig {
     /// The number of execution threads to use.
     #[serde(default = "default_threads")]
     pub threads: u16,
+    /// After how long jobs are killed, if their hook doesn't set a timeout.
+    #[serde(default = "default_jobs_timeout")]
+    pub timeout: utils::TimeString,
+    /// The file the queued jobs are persisted to, if any.
+    #[serde(rename="queue-file", default)]
+    pub queue_file: Option<String>,
+    /// The directory the jobs which failed after all their retries are saved
+    /// to, if any.
+    #[serde(rename="dead-letters", default)]
+    pub dead_letters: Option<String>,
+    /// Where to store the request bodies.
+    #[serde(rename="body-storage", default)]
+    pub body_storage: BodyStorageConfig,
+    /// Where to save the output of the jobs.
+    #[serde(default)]
+    pub logs: JobLogsConfig,
+    /// Where to upload the artifacts of the jobs.
+    #[serde(rename="artifacts-upload", default)]
+    pub artifacts_upload: ArtifactsUploadConfig,
+    /// The resource limits of the jobs, if their hook doesn't set them.
+    #[serde(default)]
+    pub limits: ResourceLimits,
+    /// The cgroup the jobs are run in.
+    #[serde(default)]
+    pub cgroup: JobCgroupConfig,
+    /// Whether the jobs run in a sandbox, if their hook doesn't say so.
+    #[serde(default)]
+    pub sandbox: bool,
+    /// Where the persistent working directories of the hooks are created.
+    #[serde(default)]
+    pub workdirs: Option<String>,
+    /// Where the temporary directories of the jobs are created, if not in
+    /// the system one.
+    #[serde(rename="temp-dir", default)]
+    pub temp_dir: Option<String>,
+    /// The size of the tmpfs mounted on the temporary directory, if any.
+    #[serde(rename="temp-dir-tmpfs", default)]
+    pub temp_dir_tmpfs: Option<utils::SizeString>,
+    /// The script run before every job, if any.
+    #[serde(rename="pre-wrapper", default)]
+    pub pre_wrapper: Option<String>,
+    /// The script run after every job, if any.
+    #[serde(rename="post-wrapper", default)]
+    pub post_wrapper: Option<String>,
+    /// The CLI of the container runtime running the hooks with an image.
+    #[serde(rename="container-runtime", default="default_container_runtime")]
+    pub container_runtime: String,
+    /// Whether the working directory of failed jobs is kept, if their hook
+    /// doesn't say so.
+    #[serde(rename="keep-workdir-on-failure", default)]
+    pub keep_workdir_on_failure: bool,
+    /// How long to wait for the running jobs when stopping.
+    #[serde(rename="shutdown-grace-period",
+            default="default_shutdown_grace_period")]
+    pub shutdown_grace_period: utils::TimeString,
+    /// How long the jobs being stopped can take to exit before being killed.
+    #[serde(rename="kill-grace-period", default="default_kill_grace_period")]
+    pub kill_grace_period: utils::TimeString,
 }
 
 default_fn!(default_threads: u16 = 1);
+default_fn!(default_jobs_timeout: utils::TimeString = 0.into());
+default_fn!(default_shutdown_grace_period: utils::TimeString = 60.into());
+default_fn!(default_container_runtime: String = "docker".into());
+default_fn!(default_kill_grace_period: utils::TimeString = 5.into());
 
 default!(JobsConfig {
     threads: default_threads(),
+    timeout: default_jobs_timeout(),
+    queue_file: None,
+    dead_letters: None,
+    body_storage: BodyStorageConfig::default(),
+    logs: JobLogsConfig::default(),
+    artifacts_upload: ArtifactsUploadConfig::default(),
+    limits: ResourceLimits::default(),
+    cgroup: JobCgroupConfig::default(),
+    sandbox: false,
+    workdirs: None,
+    temp_dir: None,
+    temp_dir_tmpfs: None,
+    pre_wrapper: None,
+    post_wrapper: None,
+    container_runtime: default_container_runtime(),
+    keep_workdir_on_failure: false,
+    shutdown_grace_period: default_shutdown_grace_period(),
+    kill_grace_period: default_kill_grace_period(),
+});
+
+
+/// Configuration for saving the output of the jobs.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub struct JobLogsConfig {
+    /// The directory the logs are saved in, if they're saved.
+    #[serde(default)]
+    pub path: Option<String>,
+    /// How long the logs are kept.
+    #[serde(default="default_logs_retention")]
+    pub retention: utils::TimeString,
+}
+
+default_fn!(default_logs_retention: utils::TimeString =
+    (7 * 24 * 60 * 60).into());
+
+default!(JobLogsConfig {
+    path: None,
+    retention: default_logs_retention(),
+});
+
+
+/// Configuration for uploading the artifacts of the jobs to an S3-compatible
+/// object store.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ArtifactsUploadConfig {
+    /// The URL of the object store, if the artifacts are uploaded.
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// The bucket the artifacts are uploaded to.
+    #[serde(default)]
+    pub bucket: Option<String>,
+    /// The region the requests are signed for.
+    #[serde(default="default_artifacts_region")]
+    pub region: String,
+    /// The credentials used to sign the requests.
+    #[serde(rename="access-key", default)]
+    pub access_key: Option<String>,
+    #[serde(rename="secret-key", default)]
+    pub secret_key: Option<String>,
+    /// The prefix of the keys of the uploaded artifacts.
+    #[serde(default)]
+    pub prefix: String,
+}
+
+default_fn!(default_artifacts_region: String = "us-east-1".into());
+
+default!(ArtifactsUploadConfig {
+    endpoint: None,
+    bucket: None,
+    region: default_artifacts_region(),
+    access_key: None,
+    secret_key: None,
+    prefix: String::new(),
+});
+
+
+/// Resource limits applied to the processes of the jobs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResourceLimits {
+    /// How much CPU time the processes can use.
+    #[serde(rename="cpu-time", default)]
+    pub cpu_time: Option<utils::TimeString>,
+    /// The maximum size of the virtual memory of the processes.
+    #[serde(rename="address-space", default)]
+    pub address_space: Option<utils::SizeString>,
+    /// How many files the processes can keep open.
+    #[serde(rename="open-files", default)]
+    pub open_files: Option<u64>,
+    /// The maximum size of the core dumps.
+    #[serde(rename="core-size", default)]
+    pub core_size: Option<utils::SizeString>,
+}
+
+impl ResourceLimits {
+    /// Return these limits, taking the ones not set from the fallback.
+    pub fn or(&self, fallback: &ResourceLimits) -> ResourceLimits {
+        ResourceLimits {
+            cpu_time: self.cpu_time.clone()
+                .or_else(|| fallback.cpu_time.clone()),
+            address_space: self.address_space.clone()
+                .or_else(|| fallback.address_space.clone()),
+            open_files: self.open_files.or(fallback.open_files),
+            core_size: self.core_size.clone()
+                .or_else(|| fallback.core_size.clone()),
+        }
+    }
+}
+
+
+/// Configuration for running each job in its own cgroup.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobCgroupConfig {
+    /// The cgroup the ones of the jobs are created in, if they're created.
+    #[serde(default)]
+    pub path: Option<String>,
+    /// The maximum memory usage of each job.
+    #[serde(rename="memory-max", default)]
+    pub memory_max: Option<utils::SizeString>,
+    /// The share of a CPU each job can use, as a percentage.
+    #[serde(rename="cpu-percent", default)]
+    pub cpu_percent: Option<u32>,
+}
+
+
+/// The backend used to store request bodies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum BodyStorageKind {
+    /// Store the body in the job's working directory.
+    #[serde(rename="disk")]
+    Disk,
+    /// Store the body in a memory-backed filesystem.
+    #[serde(rename="tmpfs")]
+    Tmpfs,
+    /// Upload the body to an object store.
+    #[serde(rename="object-store")]
+    ObjectStore,
+}
+
+
+/// Configuration for storing request bodies.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct BodyStorageConfig {
+    /// The backend used by hooks without a preference.
+    #[serde(default="default_body_storage_backend")]
+    pub backend: BodyStorageKind,
+    /// The directory bodies are stored in by the tmpfs backend.
+    #[serde(rename="tmpfs-path", default="default_tmpfs_path")]
+    pub tmpfs_path: String,
+    /// The base URL bodies are uploaded to by the object store backend.
+    #[serde(rename="object-store-url", default)]
+    pub object_store_url: Option<String>,
+}
+
+default_fn!(default_body_storage_backend: BodyStorageKind =
+    BodyStorageKind::Disk);
+default_fn!(default_tmpfs_path: String = "/dev/shm".into());
+
+default!(BodyStorageConfig {
+    backend: default_body_storage_backend(),
+    tmpfs_path: default_tmpfs_path(),
+    object_store_url: None,
+});
+
+
+/// Configuration for forwarding security events to a SIEM.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub struct SiemConfig {
+    /// The endpoint security events are sent to, if enabled.
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// The number of invalid requests to an hook considered a burst.
+    #[serde(rename="burst-threshold", default="default_burst_threshold")]
+    pub burst_threshold: u64,
+    /// The interval of time to consider when detecting bursts.
+    #[serde(rename="burst-interval", default="default_burst_interval")]
+    pub burst_interval: utils::TimeString,
+}
+
+default_fn!(default_burst_threshold: u64 = 10);
+default_fn!(default_burst_interval: utils::TimeString = 60.into());
+
+default!(SiemConfig {
+    endpoint: None,
+    burst_threshold: default_burst_threshold(),
+    burst_interval: default_burst_interval(),
+});
+
+
+/// Configuration for signing outgoing deliveries.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct SigningConfig {
+    /// The secret key used to sign deliveries, if enabled.
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// The header containing the signature.
+    #[serde(default="default_signing_header")]
+    pub header: String,
+}
+
+default_fn!(default_signing_header: String = "X-Fisher-Signature".into());
+
+default!(SigningConfig {
+    secret: None,
+    header: default_signing_header(),
+});
+
+
+/// Configuration for running the jobs on remote workers.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct WorkersConfig {
+    /// The URLs of the workers, if the jobs are dispatched to them.
+    #[serde(default)]
+    pub urls: Vec<String>,
+    /// The token used to authenticate to the workers.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+default!(WorkersConfig {
+    urls: Vec::new(),
+    token: None,
 });
 
 
@@ -194,12 +742,23 @@ pub struct ScriptsConfig {
     /// Search subdirectories or not.
     #[serde(default = "default_recursive")]
     pub recursive: bool,
+    /// Run the scripts without the executable bit through an interpreter.
+    #[serde(
+        rename="run-non-executable", default="default_run_non_executable"
+    )]
+    pub run_non_executable: bool,
+    /// The interpreters of the non-executable scripts, by extension.
+    #[serde(default)]
+    pub interpreters: HashMap<String, String>,
 }
 
 default_fn!(default_path: String = ".".into());
 default_fn!(default_recursive: bool = false);
+default_fn!(default_run_non_executable: bool = false);
 
 default!(ScriptsConfig {
     path: default_path(),
     recursive: default_recursive(),
+    run_non_executable: default_run_non_executable(),
+    interpreters: HashMap::new(),
 });
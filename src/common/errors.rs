@@ -48,6 +48,14 @@ pub enum ErrorKind {
     /// provided as the first parameter.
     ProviderNotFound(String),
 
+    /// A provider with the same name is already registered. The provider name
+    /// is provided as the first parameter.
+    ProviderAlreadyRegistered(String),
+
+    /// The same route is declared by more than one hook. The route and the
+    /// names of the hooks are provided as the parameters.
+    DuplicateRoute(String, String, String),
+
     /// The input you provided was invalid. A more detailed error message is
     /// available in the first parameter.
     InvalidInput(String),
@@ -56,6 +64,14 @@ pub enum ErrorKind {
     /// available in the first parameter.
     InvalidTimeString(String),
 
+    /// The size string you provided was invalid. The provided size string is
+    /// available in the first parameter.
+    InvalidSizeString(String),
+
+    /// The cron schedule you provided was invalid. The provided schedule is
+    /// available in the first parameter.
+    InvalidSchedule(String),
+
     /// The rate limits configuration you provided was invalid. The provided
     /// configuration string is available in the first parameter.
     InvalidRateLimitsConfig(String),
@@ -75,6 +91,37 @@ pub enum ErrorKind {
     /// The hex string has the wrong length.
     InvalidHexLength,
 
+    /// The character is not valid base64. The character is available in the
+    /// first parameter.
+    InvalidBase64Char(char),
+
+    /// The base64 string has the wrong length.
+    InvalidBase64Length,
+
+    /// A remote server replied with an unexpected HTTP status code. The
+    /// status code is available in the first parameter.
+    UnexpectedStatusCode(u16),
+
+    /// TLS couldn't be set up for the HTTP server. A more detailed error
+    /// message is available in the first parameter.
+    TlsError(String),
+
+    /// The body of the request is bigger than the allowed size. The maximum
+    /// size, in bytes, is available in the first parameter.
+    BodyTooLarge(u64),
+
+    /// The body of the request wasn't received in time. The timeout, in
+    /// seconds, is available in the first parameter.
+    BodyTimeout(u64),
+
+    /// The hook didn't exit before its timeout, and it was killed. The
+    /// timeout, in seconds, is available in the first parameter.
+    HookTimedOut(u64),
+
+    /// The cgroup of a job couldn't be removed, since its processes didn't
+    /// exit. The path of the cgroup is available in the first parameter.
+    CgroupNotRemoved(String),
+
     /// An internal communication channel is broken.
     BrokenChannel,
 
@@ -116,6 +163,17 @@ impl fmt::Display for ErrorKind {
                     format!("Provider {} not found", provider)
                 }
 
+                ErrorKind::ProviderAlreadyRegistered(ref provider) => {
+                    format!("Provider {} is already registered", provider)
+                }
+
+                ErrorKind::DuplicateRoute(ref route, ref first, ref second) => {
+                    format!(
+                        "Route /{} is used by both {} and {}",
+                        route, first, second,
+                    )
+                }
+
                 ErrorKind::InvalidInput(ref error) => {
                     format!("invalid input: {}", error)
                 }
@@ -124,6 +182,14 @@ impl fmt::Display for ErrorKind {
                     format!("invalid time string: {}", time_string)
                 }
 
+                ErrorKind::InvalidSizeString(ref size_string) => {
+                    format!("invalid size string: {}", size_string)
+                }
+
+                ErrorKind::InvalidSchedule(ref schedule) => {
+                    format!("invalid schedule: {}", schedule)
+                }
+
                 ErrorKind::InvalidRateLimitsConfig(ref config) => {
                     format!("invalid rate limits config: {}", config)
                 }
@@ -140,6 +206,38 @@ impl fmt::Display for ErrorKind {
                     "invalid length of the hex".into()
                 }
 
+                ErrorKind::InvalidBase64Char(chr) => {
+                    format!("{} is not valid base64", chr)
+                }
+
+                ErrorKind::InvalidBase64Length => {
+                    "invalid length of the base64".into()
+                }
+
+                ErrorKind::UnexpectedStatusCode(code) => {
+                    format!("unexpected HTTP status code: {}", code)
+                }
+
+                ErrorKind::TlsError(ref error) => {
+                    format!("TLS error: {}", error)
+                }
+
+                ErrorKind::BodyTooLarge(max) => {
+                    format!("the body is bigger than {} bytes", max)
+                }
+
+                ErrorKind::BodyTimeout(timeout) => {
+                    format!("the body wasn't received in {} seconds", timeout)
+                }
+
+                ErrorKind::HookTimedOut(timeout) => {
+                    format!("the hook didn't exit in {} seconds", timeout)
+                }
+
+                ErrorKind::CgroupNotRemoved(ref path) => {
+                    format!("the cgroup {} couldn't be removed", path)
+                }
+
                 ErrorKind::BrokenChannel => {
                     "an internal communication channel crashed".into()
                 }
@@ -292,6 +390,13 @@ impl Error {
     }
 }
 
+
+/// Show a nicely-formatted warning to the user, for problems which don't
+/// stop Fisher from working. The function uses ANSI formatting codes.
+pub(crate) fn print_warning(message: &str) {
+    println!("{} {}", Colour::Yellow.bold().paint("Warning:"), message);
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.kind)
@@ -302,8 +407,14 @@ impl StdError for Error {
     fn description(&self) -> &str {
         match self.kind {
             ErrorKind::ProviderNotFound(..) => "provider not found",
+            ErrorKind::ProviderAlreadyRegistered(..) => {
+                "provider already registered"
+            }
+            ErrorKind::DuplicateRoute(..) => "duplicate route",
             ErrorKind::InvalidInput(..) => "invalid input",
             ErrorKind::InvalidTimeString(..) => "invalid time string",
+            ErrorKind::InvalidSizeString(..) => "invalid size string",
+            ErrorKind::InvalidSchedule(..) => "invalid schedule",
             ErrorKind::InvalidRateLimitsConfig(..) => {
                 "invalid rate limits config"
             }
@@ -311,6 +422,14 @@ impl StdError for Error {
             ErrorKind::WrongRequestKind => "wrong request kind",
             ErrorKind::InvalidHexChar(..) => "invalid character in hex",
             ErrorKind::InvalidHexLength => "invalid length of the hex",
+            ErrorKind::InvalidBase64Char(..) => "invalid character in base64",
+            ErrorKind::InvalidBase64Length => "invalid length of the base64",
+            ErrorKind::UnexpectedStatusCode(..) => "unexpected status code",
+            ErrorKind::TlsError(..) => "TLS error",
+            ErrorKind::BodyTooLarge(..) => "body too large",
+            ErrorKind::BodyTimeout(..) => "body timeout",
+            ErrorKind::HookTimedOut(..) => "hook timed out",
+            ErrorKind::CgroupNotRemoved(..) => "cgroup not removed",
             ErrorKind::BrokenChannel => {
                 "internal communication channel crashed"
             }
@@ -29,3 +29,33 @@ pub struct HealthDetails {
     /// The total number of threads running, either waiting or working.
     pub max_threads: u16,
 }
+
+
+/// This struct contains some information about the loaded hooks.
+
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct HooksHealth {
+    /// The number of hooks currently loaded.
+    pub hooks_loaded: usize,
+
+    /// When the hooks were last loaded from the disk, as a UNIX timestamp.
+    pub last_reload: u64,
+
+    /// When the last failed job ended, as a UNIX timestamp.
+    pub last_failure: Option<u64>,
+}
+
+
+/// A job waiting in the processor's queue.
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct QueuedJob {
+    /// The name of the hook the job belongs to.
+    pub hook: String,
+
+    /// The priority of the job.
+    pub priority: isize,
+
+    /// If the job is delayed by the hook's rate limit.
+    pub delayed: bool,
+}
@@ -18,9 +18,10 @@
 use std::hash::Hash;
 use std::sync::Arc;
 use std::fmt::Debug;
+use std::time::Duration;
 
 use super::prelude::*;
-use super::structs::HealthDetails;
+use super::structs::{HealthDetails, QueuedJob};
 
 
 /// This trait represents a script that can be run by Fisher.
@@ -32,9 +33,9 @@ pub trait ScriptTrait {
     /// the same between calls to the same script.
     fn id(&self) -> Self::Id;
 
-    /// This method returns if multiple instances of the script can be safely
-    /// run in parallel.
-    fn can_be_parallel(&self) -> bool;
+    /// This method returns how many instances of the script can be safely
+    /// run in parallel, or `None` if there is no limit.
+    fn max_concurrency(&self) -> Option<usize>;
 }
 
 
@@ -82,11 +83,39 @@ pub trait JobTrait<S: ScriptTrait> {
     /// Execute the job and return the output of it.
     fn execute(&self, ctx: &Self::Context) -> Result<Self::Output>;
 
+    /// Check if a newer job replaced this one while it was waiting, in which
+    /// case it's dropped without being executed. By default jobs are never
+    /// replaced.
+    fn superseded(&self, _ctx: &Self::Context) -> bool {
+        false
+    }
+
+    /// Notify the job it was dropped from the queue without being executed.
+    fn dropped(&self, _ctx: &Self::Context) {}
+
+    /// Ask the jobs running with the context to stop, because the processor
+    /// is shutting down. By default the running jobs are left alone.
+    fn stop_running(_ctx: &Self::Context)
+    where
+        Self: Sized,
+    {
+    }
+
     /// Get the ID of the underlying script.
     fn script_id(&self) -> S::Id;
 
     /// Get the name of the underlying script.
     fn script_name(&self) -> &str;
+
+    /// Return the job to run again and how long to wait before running it,
+    /// if the output requires the job to be retried. By default jobs are
+    /// never retried.
+    fn retry(&self, _output: &Self::Output) -> Option<(Self, Duration)>
+    where
+        Self: Sized,
+    {
+        None
+    }
 }
 
 
@@ -95,12 +124,32 @@ pub trait ProcessorApiTrait<S: ScriptsRepositoryTrait>: Send {
     /// Queue a new job into the processor.
     fn queue(&self, job: S::Job, priority: isize) -> Result<()>;
 
+    /// Queue a new job into the processor, which will be run only after the
+    /// delay expired.
+    fn queue_delayed(
+        &self,
+        job: S::Job,
+        priority: isize,
+        delay: Duration,
+    ) -> Result<()>;
+
     /// Get some insights about the health of the processor.
     fn health_details(&self) -> Result<HealthDetails>;
 
+    /// Get the list of the jobs waiting to be processed, from the next one
+    /// to run.
+    fn queued_jobs(&self) -> Result<Vec<QueuedJob>>;
+
+    /// Drop the oldest job waiting to be processed, returning whether there
+    /// was one.
+    fn drop_oldest(&self) -> Result<bool>;
+
     /// Execute periodic cleanup tasks on the processor.
     fn cleanup(&self) -> Result<()>;
 
+    /// Change the maximum number of jobs run in parallel.
+    fn set_threads_count(&self, count: u16) -> Result<()>;
+
     /// Lock the processor, preventing new jobs to be run.
     fn lock(&self) -> Result<()>;
 
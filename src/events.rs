@@ -0,0 +1,359 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Live events about the lifecycle of the jobs, streamed to the clients of
+//! the admin API and used to track the state of the jobs.
+
+use std::collections::{HashMap, VecDeque};
+use std::result::Result as StdResult;
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json;
+
+
+/// How many events can wait to be sent to a subscriber. Subscribers falling
+/// behind are disconnected.
+const SUBSCRIPTION_BUFFER: usize = 256;
+
+/// How many jobs are tracked to answer the status requests.
+const TRACKED_JOBS: usize = 1000;
+
+
+/// What happened to a job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum JobEventKind {
+    #[serde(rename="queued")]
+    Queued,
+    #[serde(rename="started")]
+    Started,
+    #[serde(rename="finished")]
+    Finished,
+    #[serde(rename="failed")]
+    Failed,
+    #[serde(rename="coalesced")]
+    Coalesced,
+    #[serde(rename="dropped")]
+    Dropped,
+}
+
+impl JobEventKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            JobEventKind::Queued => "queued",
+            JobEventKind::Started => "started",
+            JobEventKind::Finished => "finished",
+            JobEventKind::Failed => "failed",
+            JobEventKind::Coalesced => "coalesced",
+            JobEventKind::Dropped => "dropped",
+        }
+    }
+}
+
+
+/// An event in the lifecycle of a job.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct JobEvent {
+    pub event: JobEventKind,
+    pub hook: String,
+    /// The unique identifier of the job.
+    pub job: String,
+    /// The identifier of the request which started the job.
+    pub request_id: Option<String>,
+    /// When the event happened, as a UNIX timestamp.
+    pub timestamp: u64,
+}
+
+impl JobEvent {
+    pub fn new(
+        event: JobEventKind,
+        hook: &str,
+        job: &str,
+        request_id: Option<&str>,
+    ) -> Self {
+        JobEvent {
+            event,
+            hook: hook.to_string(),
+            job: job.to_string(),
+            request_id: request_id.map(|id| id.to_string()),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0),
+        }
+    }
+
+    /// Format the event as a Server-Sent Event.
+    pub fn to_sse(&self) -> String {
+        format!(
+            "event: {}\ndata: {}\n\n",
+            self.event.name(),
+            serde_json::to_string(self).unwrap(),
+        )
+    }
+}
+
+
+/// The state of a job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum JobState {
+    #[serde(rename="queued")]
+    Queued,
+    #[serde(rename="running")]
+    Running,
+    #[serde(rename="succeeded")]
+    Succeeded,
+    #[serde(rename="failed")]
+    Failed,
+    #[serde(rename="coalesced")]
+    Coalesced,
+    #[serde(rename="dropped")]
+    Dropped,
+}
+
+
+/// The current state of a job, with the time of its changes as UNIX
+/// timestamps.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct JobStatus {
+    pub job: String,
+    pub hook: String,
+    pub state: JobState,
+    pub queued_at: Option<u64>,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+}
+
+
+/// The status of the most recent jobs.
+#[derive(Debug, Default)]
+struct Tracker {
+    jobs: HashMap<String, JobStatus>,
+    // The oldest job is forgotten first
+    order: VecDeque<String>,
+}
+
+impl Tracker {
+    fn record(&mut self, event: &JobEvent) {
+        if !self.jobs.contains_key(&event.job) {
+            if self.order.len() >= TRACKED_JOBS {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.jobs.remove(&oldest);
+                }
+            }
+            self.order.push_back(event.job.clone());
+        }
+
+        let status = self.jobs.entry(event.job.clone()).or_insert_with(|| {
+            JobStatus {
+                job: event.job.clone(),
+                hook: event.hook.clone(),
+                state: JobState::Queued,
+                queued_at: None,
+                started_at: None,
+                finished_at: None,
+            }
+        });
+
+        match event.event {
+            JobEventKind::Queued => {
+                status.state = JobState::Queued;
+                status.queued_at = Some(event.timestamp);
+            }
+            JobEventKind::Started => {
+                status.state = JobState::Running;
+                status.started_at = Some(event.timestamp);
+            }
+            JobEventKind::Finished => {
+                status.state = JobState::Succeeded;
+                status.finished_at = Some(event.timestamp);
+            }
+            JobEventKind::Failed => {
+                status.state = JobState::Failed;
+                status.finished_at = Some(event.timestamp);
+            }
+            JobEventKind::Coalesced => {
+                status.state = JobState::Coalesced;
+                status.finished_at = Some(event.timestamp);
+            }
+            JobEventKind::Dropped => {
+                status.state = JobState::Dropped;
+                status.finished_at = Some(event.timestamp);
+            }
+        }
+    }
+}
+
+
+/// Broadcast the events of the jobs to all the subscribers, keeping track of
+/// the state of the most recent jobs.
+#[derive(Debug, Default)]
+pub struct JobEvents {
+    subscribers: Mutex<Vec<mpsc::SyncSender<JobEvent>>>,
+    tracker: Mutex<Tracker>,
+}
+
+impl JobEvents {
+    pub fn new() -> Self {
+        JobEvents::default()
+    }
+
+    /// Start receiving the events published from now on.
+    pub fn subscribe(&self) -> Subscription {
+        let (send, recv) = mpsc::sync_channel(SUBSCRIPTION_BUFFER);
+        self.subscribers.lock().unwrap().push(send);
+
+        Subscription { events: recv }
+    }
+
+    /// Send the event to all the subscribers, forgetting the ones which went
+    /// away or are too slow to receive it.
+    pub fn publish(&self, event: JobEvent) {
+        self.tracker.lock().unwrap().record(&event);
+
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.try_send(event.clone()).is_ok());
+    }
+
+    /// Return the status of a recent job.
+    pub fn status(&self, job: &str) -> Option<JobStatus> {
+        self.tracker.lock().unwrap().jobs.get(job).cloned()
+    }
+}
+
+
+/// The events received by a subscriber.
+#[derive(Debug)]
+pub struct Subscription {
+    events: mpsc::Receiver<JobEvent>,
+}
+
+impl Subscription {
+    /// Wait for the next event. `Ok(None)` is returned if no event arrived
+    /// before the timeout, and an error if the subscriber was disconnected.
+    pub fn next(&self, timeout: Duration) -> StdResult<Option<JobEvent>, ()> {
+        match self.events.recv_timeout(timeout) {
+            Ok(event) => Ok(Some(event)),
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{JobEvent, JobEventKind, JobEvents, JobState};
+    use super::{SUBSCRIPTION_BUFFER, TRACKED_JOBS};
+
+
+    fn event(kind: JobEventKind) -> JobEvent {
+        JobEvent::new(kind, "example.sh", "job-id", Some("request-id"))
+    }
+
+
+    #[test]
+    fn test_publish() {
+        let timeout = Duration::from_millis(10);
+        let events = JobEvents::new();
+
+        // Events published before subscribing are not received
+        events.publish(event(JobEventKind::Queued));
+        let first = events.subscribe();
+        let second = events.subscribe();
+        assert_eq!(first.next(timeout), Ok(None));
+
+        events.publish(event(JobEventKind::Started));
+        for subscription in &[&first, &second] {
+            let received = subscription.next(timeout).unwrap().unwrap();
+            assert_eq!(received.event, JobEventKind::Started);
+            assert_eq!(received.hook, "example.sh");
+            assert_eq!(received.job, "job-id");
+        }
+
+        // Subscribers which went away are forgotten
+        drop(second);
+        events.publish(event(JobEventKind::Finished));
+        assert_eq!(events.subscribers.lock().unwrap().len(), 1);
+
+        // Subscribers falling behind are disconnected
+        for _ in 0..SUBSCRIPTION_BUFFER {
+            events.publish(event(JobEventKind::Failed));
+        }
+        assert!(events.subscribers.lock().unwrap().is_empty());
+        for _ in 0..SUBSCRIPTION_BUFFER {
+            assert!(first.next(timeout).unwrap().is_some());
+        }
+        assert_eq!(first.next(timeout), Err(()));
+    }
+
+
+    #[test]
+    fn test_status() {
+        let events = JobEvents::new();
+        assert!(events.status("job-id").is_none());
+
+        for &(kind, state) in &[
+            (JobEventKind::Queued, JobState::Queued),
+            (JobEventKind::Started, JobState::Running),
+            (JobEventKind::Failed, JobState::Failed),
+            (JobEventKind::Coalesced, JobState::Coalesced),
+            (JobEventKind::Dropped, JobState::Dropped),
+        ] {
+            let mut event = event(kind);
+            event.timestamp = 1500000000;
+            events.publish(event);
+
+            let status = events.status("job-id").unwrap();
+            assert_eq!(status.state, state);
+            assert_eq!(status.hook, "example.sh");
+        }
+
+        let status = events.status("job-id").unwrap();
+        assert_eq!(status.queued_at, Some(1500000000));
+        assert_eq!(status.started_at, Some(1500000000));
+        assert_eq!(status.finished_at, Some(1500000000));
+
+        // Only the most recent jobs are remembered
+        for i in 0..TRACKED_JOBS {
+            let id = format!("job-{}", i);
+            events.publish(
+                JobEvent::new(JobEventKind::Queued, "example.sh", &id, None),
+            );
+        }
+        assert!(events.status("job-id").is_none());
+        assert!(events.status("job-0").is_some());
+        let last = format!("job-{}", TRACKED_JOBS - 1);
+        assert_eq!(events.status(&last).unwrap().state, JobState::Queued);
+    }
+
+
+    #[test]
+    fn test_to_sse() {
+        let mut event = event(JobEventKind::Failed);
+        event.timestamp = 1500000000;
+
+        assert_eq!(event.to_sse(), concat!(
+            "event: failed\n",
+            "data: {\"event\":\"failed\",\"hook\":\"example.sh\",",
+            "\"job\":\"job-id\",\"request_id\":\"request-id\",",
+            "\"timestamp\":1500000000}\n\n",
+        ));
+    }
+}
@@ -18,14 +18,17 @@
 #![cfg_attr(feature = "clippy", plugin(clippy))]
 
 extern crate ansi_term;
+extern crate flate2;
 #[cfg(test)]
 extern crate hyper;
 #[macro_use]
 extern crate lazy_static;
+extern crate libc;
 extern crate nix;
+#[cfg(feature = "tls")]
+extern crate openssl;
 extern crate rand;
 extern crate regex;
-#[cfg(feature = "provider-github")]
 extern crate ring;
 extern crate serde;
 #[macro_use]
@@ -33,16 +36,23 @@ extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
 extern crate tiny_http;
+extern crate toml;
+#[cfg(feature = "provider-pubsub")]
+extern crate untrusted;
 extern crate url;
 extern crate users;
+extern crate xml;
 
 #[macro_use]
 mod utils;
 mod app;
+mod events;
 mod processor;
 mod providers;
 mod requests;
 mod scripts;
+mod metrics;
+mod siem;
 mod web;
 pub mod common;
 
@@ -50,3 +60,8 @@ pub mod common;
 pub use app::Fisher;
 pub use common::config::Config;
 pub use common::errors::*;
+pub use providers::{payload_env, register_provider, xml_payload_env,
+                    PayloadField, ProviderResponse, ProviderTrait};
+pub use requests::{Request, RequestType};
+pub use utils::{reap_orphans, running_as_init};
+pub use web::{WebRequest, XmlElement};
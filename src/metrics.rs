@@ -0,0 +1,333 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Metrics about the instance, exported in the Prometheus text format.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use common::structs::HealthDetails;
+use scripts::ResourceUsage;
+
+
+/// The upper bounds of the job duration histogram buckets, in seconds.
+const DURATION_BUCKETS: &[f64] = &[
+    0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0,
+];
+
+
+#[derive(Debug, Default)]
+struct HookMetrics {
+    received: u64,
+    accepted: u64,
+    rejected: u64,
+    failed: u64,
+    dropped: u64,
+    // Each bucket counts the jobs shorter than its bound
+    duration_buckets: Vec<u64>,
+    duration_count: u64,
+    duration_sum: f64,
+    // Only jobs run in their own cgroup report the resources they used
+    cpu_usec: u64,
+    memory_peak: u64,
+}
+
+
+/// Counters about the requests and the jobs of every hook.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    hooks: Mutex<BTreeMap<String, HookMetrics>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    fn update<F: FnOnce(&mut HookMetrics)>(&self, hook: &str, f: F) {
+        let mut hooks = self.hooks.lock().unwrap();
+        f(hooks.entry(hook.to_string()).or_insert_with(HookMetrics::default));
+    }
+
+    /// Record a request received for the hook.
+    pub fn request_received(&self, hook: &str) {
+        self.update(hook, |m| m.received += 1);
+    }
+
+    /// Record a request the hook accepted.
+    pub fn request_accepted(&self, hook: &str) {
+        self.update(hook, |m| m.accepted += 1);
+    }
+
+    /// Record a request the hook rejected, either because it was invalid or
+    /// because it was over the rate limit.
+    pub fn request_rejected(&self, hook: &str) {
+        self.update(hook, |m| m.rejected += 1);
+    }
+
+    /// Record the end of a job of the hook.
+    pub fn job_finished(&self, hook: &str, duration: Duration, success: bool) {
+        let secs = duration.as_secs() as f64
+            + f64::from(duration.subsec_nanos()) / 1_000_000_000.0;
+
+        self.update(hook, |m| {
+            if m.duration_buckets.is_empty() {
+                m.duration_buckets = vec![0; DURATION_BUCKETS.len()];
+            }
+            for (count, bound) in
+                m.duration_buckets.iter_mut().zip(DURATION_BUCKETS.iter())
+            {
+                if secs <= *bound {
+                    *count += 1;
+                }
+            }
+            m.duration_count += 1;
+            m.duration_sum += secs;
+
+            if !success {
+                m.failed += 1;
+            }
+        });
+    }
+
+    /// Record a job of the hook dropped from the queue to make room for
+    /// newer ones.
+    pub fn job_dropped(&self, hook: &str) {
+        self.update(hook, |m| m.dropped += 1);
+    }
+
+    /// Record the resources used by a job of the hook.
+    pub fn job_resources(&self, hook: &str, usage: &ResourceUsage) {
+        self.update(hook, |m| {
+            if let Some(cpu_usec) = usage.cpu_usec {
+                m.cpu_usec += cpu_usec;
+            }
+            if let Some(peak) = usage.memory_peak {
+                m.memory_peak = m.memory_peak.max(peak);
+            }
+        });
+    }
+
+    /// Render all the metrics in the Prometheus text format.
+    pub fn render(&self, health: &HealthDetails) -> String {
+        let hooks = self.hooks.lock().unwrap();
+        let mut out = String::new();
+
+        gauge(
+            &mut out, "fisher_jobs_queued",
+            "Jobs waiting to be processed.", health.queued_jobs as u64,
+        );
+        gauge(
+            &mut out, "fisher_jobs_running",
+            "Jobs currently being processed.", u64::from(health.busy_threads),
+        );
+        gauge(
+            &mut out, "fisher_threads",
+            "Threads available to process jobs.",
+            u64::from(health.max_threads),
+        );
+
+        counter(
+            &mut out, &hooks, "fisher_requests_received_total",
+            "Requests received by each hook.", |m| m.received,
+        );
+        counter(
+            &mut out, &hooks, "fisher_requests_accepted_total",
+            "Requests accepted by each hook.", |m| m.accepted,
+        );
+        counter(
+            &mut out, &hooks, "fisher_requests_rejected_total",
+            "Requests rejected by each hook.", |m| m.rejected,
+        );
+        counter(
+            &mut out, &hooks, "fisher_jobs_failed_total",
+            "Jobs of each hook which failed.", |m| m.failed,
+        );
+        counter(
+            &mut out, &hooks, "fisher_jobs_dropped_total",
+            "Jobs of each hook dropped from a full queue.", |m| m.dropped,
+        );
+
+        let name = "fisher_job_cpu_seconds_total";
+        header(&mut out, name, "counter", "CPU time used by the jobs.");
+        for (hook, m) in hooks.iter() {
+            let _ = writeln!(
+                out, "{}{{hook=\"{}\"}} {}",
+                name, escape(hook), m.cpu_usec as f64 / 1_000_000.0,
+            );
+        }
+
+        let name = "fisher_job_memory_peak_bytes";
+        header(
+            &mut out, name, "gauge", "Highest memory usage of a single job.",
+        );
+        for (hook, m) in hooks.iter() {
+            let _ = writeln!(
+                out, "{}{{hook=\"{}\"}} {}", name, escape(hook), m.memory_peak,
+            );
+        }
+
+        let name = "fisher_job_duration_seconds";
+        header(&mut out, name, "histogram", "How long the jobs took to run.");
+        for (hook, m) in hooks.iter() {
+            if m.duration_count == 0 {
+                continue;
+            }
+
+            let hook = escape(hook);
+            for (count, bound) in
+                m.duration_buckets.iter().zip(DURATION_BUCKETS.iter())
+            {
+                let _ = writeln!(
+                    out, "{}_bucket{{hook=\"{}\",le=\"{}\"}} {}",
+                    name, hook, bound, count,
+                );
+            }
+            let _ = writeln!(
+                out, "{}_bucket{{hook=\"{}\",le=\"+Inf\"}} {}",
+                name, hook, m.duration_count,
+            );
+            let _ = writeln!(
+                out, "{}_sum{{hook=\"{}\"}} {}", name, hook, m.duration_sum,
+            );
+            let _ = writeln!(
+                out, "{}_count{{hook=\"{}\"}} {}",
+                name, hook, m.duration_count,
+            );
+        }
+
+        out
+    }
+}
+
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, "gauge", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+
+fn counter<F: Fn(&HookMetrics) -> u64>(
+    out: &mut String,
+    hooks: &BTreeMap<String, HookMetrics>,
+    name: &str,
+    help: &str,
+    value: F,
+) {
+    header(out, name, "counter", help);
+    for (hook, m) in hooks.iter() {
+        let _ = writeln!(
+            out, "{}{{hook=\"{}\"}} {}", name, escape(hook), value(m),
+        );
+    }
+}
+
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use common::structs::HealthDetails;
+    use scripts::ResourceUsage;
+
+    use super::{escape, Metrics};
+
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.request_received("a.sh");
+        metrics.request_received("a.sh");
+        metrics.request_accepted("a.sh");
+        metrics.request_received("b.sh");
+        metrics.request_rejected("b.sh");
+        metrics.job_finished("a.sh", Duration::from_millis(300), true);
+        metrics.job_finished("a.sh", Duration::from_secs(2), false);
+        metrics.job_dropped("b.sh");
+        metrics.job_resources("a.sh", &ResourceUsage {
+            cpu_usec: Some(1_500_000),
+            memory_peak: Some(2048),
+        });
+        metrics.job_resources("a.sh", &ResourceUsage {
+            cpu_usec: Some(500_000),
+            memory_peak: Some(1024),
+        });
+        metrics.job_resources("b.sh", &ResourceUsage::default());
+
+        let out = metrics.render(&HealthDetails {
+            queued_jobs: 1,
+            busy_threads: 2,
+            max_threads: 3,
+        });
+        let lines = out.lines().collect::<Vec<_>>();
+
+        for expected in &[
+            "# TYPE fisher_jobs_queued gauge",
+            "fisher_jobs_queued 1",
+            "fisher_jobs_running 2",
+            "fisher_threads 3",
+            "# TYPE fisher_requests_received_total counter",
+            "fisher_requests_received_total{hook=\"a.sh\"} 2",
+            "fisher_requests_received_total{hook=\"b.sh\"} 1",
+            "fisher_requests_accepted_total{hook=\"a.sh\"} 1",
+            "fisher_requests_accepted_total{hook=\"b.sh\"} 0",
+            "fisher_requests_rejected_total{hook=\"b.sh\"} 1",
+            "fisher_jobs_failed_total{hook=\"a.sh\"} 1",
+            "fisher_jobs_dropped_total{hook=\"a.sh\"} 0",
+            "fisher_jobs_dropped_total{hook=\"b.sh\"} 1",
+            "fisher_job_cpu_seconds_total{hook=\"a.sh\"} 2",
+            "fisher_job_cpu_seconds_total{hook=\"b.sh\"} 0",
+            "fisher_job_memory_peak_bytes{hook=\"a.sh\"} 2048",
+            "fisher_job_memory_peak_bytes{hook=\"b.sh\"} 0",
+            "# TYPE fisher_job_duration_seconds histogram",
+            "fisher_job_duration_seconds_bucket{hook=\"a.sh\",le=\"0.1\"} 0",
+            "fisher_job_duration_seconds_bucket{hook=\"a.sh\",le=\"0.5\"} 1",
+            "fisher_job_duration_seconds_bucket{hook=\"a.sh\",le=\"5\"} 2",
+            "fisher_job_duration_seconds_bucket{hook=\"a.sh\",le=\"+Inf\"} 2",
+            "fisher_job_duration_seconds_sum{hook=\"a.sh\"} 2.3",
+            "fisher_job_duration_seconds_count{hook=\"a.sh\"} 2",
+        ] {
+            assert!(lines.contains(expected), "missing line: {}", expected);
+        }
+
+        // Hooks without jobs don't have an histogram
+        assert!(
+            !out.contains("fisher_job_duration_seconds_count{hook=\"b.sh\"")
+        );
+    }
+
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a.sh"), "a.sh");
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
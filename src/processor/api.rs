@@ -14,10 +14,11 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
 
 use common::prelude::*;
 use common::state::State;
-use common::structs::HealthDetails;
+use common::structs::{HealthDetails, QueuedJob};
 
 use processor::scheduler::{Scheduler, SchedulerInput};
 #[cfg(test)]
@@ -72,6 +73,24 @@ impl<S: ScriptsRepositoryTrait> Processor<S> {
         Ok(())
     }
 
+    /// Shut this processor down: no new jobs are started, and the running
+    /// ones are waited for up to the grace period. After that they're asked
+    /// to stop, and killed if they don't within the kill grace period. The
+    /// jobs which didn't complete are reported.
+    pub fn shutdown(
+        self,
+        grace_period: Duration,
+        kill_grace_period: Duration,
+    ) -> Result<()> {
+        let deadline = Instant::now() + grace_period;
+        self.input.send(
+            SchedulerInput::Shutdown(deadline, kill_grace_period),
+        )?;
+        self.wait.recv()?;
+
+        Ok(())
+    }
+
     /// Get a struct allowing you to control the processor.
     pub fn api(&self) -> ProcessorApi<S> {
         ProcessorApi {
@@ -100,11 +119,6 @@ impl<S: ScriptsRepositoryTrait> ProcessorApi<S> {
         self.input.send(SchedulerInput::UpdateContext(ctx))?;
         Ok(())
     }
-
-    pub fn set_threads_count(&self, count: u16) -> Result<()> {
-        self.input.send(SchedulerInput::SetThreadsCount(count))?;
-        Ok(())
-    }
 }
 
 impl<S: ScriptsRepositoryTrait> ProcessorApiTrait<S> for ProcessorApi<S> {
@@ -113,17 +127,45 @@ impl<S: ScriptsRepositoryTrait> ProcessorApiTrait<S> for ProcessorApi<S> {
         Ok(())
     }
 
+    fn queue_delayed(
+        &self,
+        job: Job<S>,
+        priority: isize,
+        delay: Duration,
+    ) -> Result<()> {
+        let run_at = Instant::now() + delay;
+        self.input.send(SchedulerInput::DelayedJob(job, priority, run_at))?;
+        Ok(())
+    }
+
     fn health_details(&self) -> Result<HealthDetails> {
         let (res_send, res_recv) = mpsc::channel();
         self.input.send(SchedulerInput::HealthStatus(res_send))?;
         Ok(res_recv.recv()?)
     }
 
+    fn queued_jobs(&self) -> Result<Vec<QueuedJob>> {
+        let (res_send, res_recv) = mpsc::channel();
+        self.input.send(SchedulerInput::QueuedJobs(res_send))?;
+        Ok(res_recv.recv()?)
+    }
+
+    fn drop_oldest(&self) -> Result<bool> {
+        let (res_send, res_recv) = mpsc::channel();
+        self.input.send(SchedulerInput::DropOldest(res_send))?;
+        Ok(res_recv.recv()?)
+    }
+
     fn cleanup(&self) -> Result<()> {
         self.input.send(SchedulerInput::Cleanup)?;
         Ok(())
     }
 
+    fn set_threads_count(&self, count: u16) -> Result<()> {
+        self.input.send(SchedulerInput::SetThreadsCount(count))?;
+        Ok(())
+    }
+
     fn lock(&self) -> Result<()> {
         self.input.send(SchedulerInput::Lock)?;
         Ok(())
@@ -27,3 +27,4 @@ mod types;
 mod test_utils;
 
 pub use processor::api::{Processor, ProcessorApi};
+pub use processor::scheduler::STATUS_EVENTS_PRIORITY;
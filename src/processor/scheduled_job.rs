@@ -14,6 +14,7 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::cmp::Ordering;
+use std::time::Instant;
 
 use common::prelude::*;
 use common::serial::Serial;
@@ -26,6 +27,9 @@ pub struct ScheduledJob<S: ScriptsRepositoryTrait> {
     job: Job<S>,
     priority: isize,
     serial: Serial,
+    /// When the job should run compared to the jobs of other hooks with the
+    /// same priority.
+    round: u64,
 }
 
 impl<S: ScriptsRepositoryTrait> ScheduledJob<S> {
@@ -34,6 +38,7 @@ impl<S: ScriptsRepositoryTrait> ScheduledJob<S> {
             job: job,
             priority: priority,
             serial: serial,
+            round: 0,
         }
     }
 
@@ -50,6 +55,14 @@ impl<S: ScriptsRepositoryTrait> ScheduledJob<S> {
         result
     }
 
+    pub fn superseded(&self, ctx: &JobContext<S>) -> bool {
+        self.job.superseded(ctx)
+    }
+
+    pub fn dropped(&self, ctx: &JobContext<S>) {
+        self.job.dropped(ctx)
+    }
+
     pub fn hook_id(&self) -> ScriptId<S> {
         self.job.script_id()
     }
@@ -57,17 +70,40 @@ impl<S: ScriptsRepositoryTrait> ScheduledJob<S> {
     pub fn hook_name(&self) -> &str {
         self.job.script_name()
     }
+
+    pub fn priority(&self) -> isize {
+        self.priority
+    }
+
+    pub fn serial(&self) -> Serial {
+        self.serial
+    }
+
+    pub fn round(&self) -> u64 {
+        self.round
+    }
+
+    pub fn set_round(&mut self, round: u64) {
+        self.round = round;
+    }
+
+    /// Return the job to run again if it has to be retried, with when it
+    /// should be run.
+    pub fn retry(&self, output: &JobOutput<S>) -> Option<(Job<S>, Instant)> {
+        self.job
+            .retry(output)
+            .map(|(job, delay)| (job, Instant::now() + delay))
+    }
 }
 
 impl<S: ScriptsRepositoryTrait> Ord for ScheduledJob<S> {
     fn cmp(&self, other: &ScheduledJob<S>) -> Ordering {
-        let priority_ord = self.priority.cmp(&other.priority);
-
-        if priority_ord == Ordering::Equal {
-            self.serial.cmp(&other.serial).reverse()
-        } else {
-            priority_ord
-        }
+        // Jobs in earlier rounds are run first, and jobs in the same round
+        // in the order they were queued
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| self.round.cmp(&other.round).reverse())
+            .then_with(|| self.serial.cmp(&other.serial).reverse())
     }
 }
 
@@ -13,21 +13,29 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::cmp;
 use std::collections::{BinaryHeap, HashMap, HashSet};
-use std::time::Instant;
+use std::iter;
+use std::time::{Duration, Instant};
 use std::sync::{mpsc, Arc, RwLock};
 
 use common::prelude::*;
+use common::errors::print_warning;
 use common::state::{State, UniqueId};
 use common::serial::Serial;
-use common::structs::HealthDetails;
+use common::structs::{HealthDetails, QueuedJob};
 
 use super::thread::{ProcessResult, Thread, ThreadCompleter};
 use super::scheduled_job::ScheduledJob;
 use super::types::{Job, JobContext, JobOutput, ScriptId};
 
 
-const STATUS_EVENTS_PRIORITY: isize = 1000;
+/// The priority status hooks are queued with.
+pub const STATUS_EVENTS_PRIORITY: isize = 1000;
+
+/// How long the jobs which were killed while shutting down have to finish,
+/// in seconds, before they're considered unfinished.
+const KILLED_JOBS_MARGIN: u64 = 1;
 
 
 #[cfg(test)]
@@ -52,7 +60,10 @@ impl<S: ScriptsRepositoryTrait> DebugDetails<S> {
 
 pub enum SchedulerInput<S: ScriptsRepositoryTrait> {
     Job(Job<S>, isize),
+    DelayedJob(Job<S>, isize, Instant),
     HealthStatus(mpsc::Sender<HealthDetails>),
+    QueuedJobs(mpsc::Sender<Vec<QueuedJob>>),
+    DropOldest(mpsc::Sender<bool>),
     ProcessOutput(JobOutput<S>),
 
     Cleanup,
@@ -66,6 +77,10 @@ pub enum SchedulerInput<S: ScriptsRepositoryTrait> {
     SetThreadsCount(u16),
 
     StopSignal,
+    /// Stop running new jobs, and wait until the deadline for the running
+    /// ones to end. The ones still running are then asked to stop, and they
+    /// have the grace period to do so before being killed.
+    Shutdown(Instant, Duration),
     JobEnded(ScriptId<S>, ThreadCompleter),
 }
 
@@ -79,9 +94,18 @@ pub struct Scheduler<S: ScriptsRepositoryTrait + 'static> {
 
     locked: bool,
     should_stop: bool,
+    shutdown_at: Option<Instant>,
+    /// How long the jobs asked to stop can take, if they weren't asked yet.
+    kill_grace_period: Option<Duration>,
     queue: BinaryHeap<ScheduledJob<S>>,
+    delayed: Vec<(Instant, ScheduledJob<S>)>,
     waiting: HashMap<ScriptId<S>, BinaryHeap<ScheduledJob<S>>>,
+    concurrency: HashMap<ScriptId<S>, usize>,
     threads: HashMap<UniqueId, Thread<S>>,
+    /// The round of the last job queued for each hook.
+    rounds: HashMap<ScriptId<S>, u64>,
+    /// The round of the last job which started running.
+    current_round: u64,
 
     input_send: mpsc::Sender<SchedulerInput<S>>,
     input_recv: mpsc::Receiver<SchedulerInput<S>>,
@@ -98,11 +122,14 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
     ) -> Self {
         let (input_send, input_recv) = mpsc::channel();
 
-        // Populate the waiting HashMap with non-parallel hooks
+        // Populate the waiting HashMap with the hooks with a concurrency
+        // limit
         let mut waiting = HashMap::new();
+        let mut concurrency = HashMap::new();
         for hook in hooks.iter() {
-            if !hook.can_be_parallel() {
+            if let Some(limit) = hook.max_concurrency() {
                 waiting.insert(hook.id(), BinaryHeap::new());
+                concurrency.insert(hook.id(), limit);
             }
         }
 
@@ -114,9 +141,15 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
 
             locked: false,
             should_stop: false,
+            shutdown_at: None,
+            kill_grace_period: None,
             queue: BinaryHeap::new(),
+            delayed: Vec::new(),
             waiting: waiting,
+            concurrency: concurrency,
             threads: HashMap::with_capacity(max_threads as usize),
+            rounds: HashMap::new(),
+            current_round: 0,
 
             input_send: input_send,
             input_recv: input_recv,
@@ -136,7 +169,42 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
 
         let mut serial = Serial::zero();
         let mut to_schedule = Vec::new();
-        while let Ok(input) = self.input_recv.recv() {
+        loop {
+            // Stop the running jobs once the grace period ends, and stop
+            // waiting for them once they had the time to be killed
+            if let Some(deadline) = self.shutdown_at {
+                if Instant::now() >= deadline {
+                    match self.kill_grace_period.take() {
+                        Some(grace_period) => self.stop_running(grace_period),
+                        None => break,
+                    }
+                }
+            }
+
+            self.queue_delayed_jobs();
+
+            // Wake up when the next delayed job should be run, or when the
+            // grace period ends
+            let input = if let Some(run_at) = self.next_wakeup() {
+                let now = Instant::now();
+                let timeout = if run_at > now {
+                    run_at - now
+                } else {
+                    Duration::from_secs(0)
+                };
+
+                match self.input_recv.recv_timeout(timeout) {
+                    Ok(input) => input,
+                    Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            } else {
+                match self.input_recv.recv() {
+                    Ok(input) => input,
+                    Err(..) => break,
+                }
+            };
+
             // Check if the periodic cleanup should be done now
             if self.last_cleanup.elapsed().as_secs() > 30 {
                 self.cleanup_threads();
@@ -147,12 +215,19 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
 
             match input {
                 SchedulerInput::Job(job, priority) => {
-                    self.queue_job(
+                    self.enqueue(
                         ScheduledJob::new(job, priority, serial.incr()),
                     );
                     self.run_jobs();
                 }
 
+                SchedulerInput::DelayedJob(job, priority, run_at) => {
+                    self.delayed.push((
+                        run_at,
+                        ScheduledJob::new(job, priority, serial.incr()),
+                    ));
+                }
+
                 SchedulerInput::HealthStatus(return_to) => {
                     // Count the busy threads
                     let busy_threads = self.threads
@@ -160,7 +235,7 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
                         .filter(|thread| thread.busy())
                         .count();
 
-                    let mut queued_jobs = self.queue.len();
+                    let mut queued_jobs = self.queue.len() + self.delayed.len();
                     for waiting in self.waiting.values() {
                         queued_jobs += waiting.len();
                     }
@@ -172,6 +247,19 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
                     })?;
                 }
 
+                SchedulerInput::QueuedJobs(return_to) => {
+                    return_to.send(self.queued_jobs())?;
+                }
+
+                SchedulerInput::DropOldest(return_to) => {
+                    let dropped = self.drop_oldest();
+                    if let Some(ref job) = dropped {
+                        let ctx = self.jobs_context.read().unwrap().clone();
+                        job.dropped(&ctx);
+                    }
+                    return_to.send(dropped.is_some())?;
+                }
+
                 SchedulerInput::ProcessOutput(output) => {
                     if let Some(jobs) = self.hooks.jobs_after_output(output) {
                         for job in jobs {
@@ -185,7 +273,7 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
 
                     // This is a separated step due to mutable borrows
                     for job in to_schedule.drain(..) {
-                        self.queue_job(job);
+                        self.enqueue(job);
                     }
 
                     self.run_jobs();
@@ -267,12 +355,66 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
                         break;
                     }
                 }
+
+                SchedulerInput::Shutdown(deadline, kill_grace_period) => {
+                    self.should_stop = true;
+                    self.shutdown_at = Some(deadline);
+                    self.kill_grace_period = Some(kill_grace_period);
+                    self.cleanup_threads();
+
+                    if self.threads.is_empty() {
+                        break;
+                    }
+                }
             }
         }
 
+        if self.shutdown_at.is_some() {
+            self.report_unfinished();
+        }
+
         Ok(())
     }
 
+    /// Ask the running jobs to stop, waiting for them at most for the grace
+    /// period and the time needed to kill them.
+    fn stop_running(&mut self, grace_period: Duration) {
+        let running = self.threads
+            .values()
+            .filter(|thread| thread.busy())
+            .count();
+        if running > 0 {
+            print_warning(
+                &format!("stopping {} jobs still running", running),
+            );
+        }
+
+        let ctx = self.jobs_context.read().unwrap().clone();
+        <Job<S> as JobTrait<S::Script>>::stop_running(&ctx);
+        self.shutdown_at = Some(
+            Instant::now() + grace_period
+                + Duration::from_secs(KILLED_JOBS_MARGIN),
+        );
+    }
+
+    /// Tell about the jobs which didn't run before shutting down.
+    fn report_unfinished(&self) {
+        let running = self.threads
+            .values()
+            .filter(|thread| thread.busy())
+            .count();
+        let queued = self.queued_jobs().len();
+
+        if running > 0 || queued > 0 {
+            print_warning(&format!(
+                "{} jobs were still running and {} jobs were queued while \
+                 shutting down",
+                running,
+                queued,
+            ));
+        }
+    }
+
     #[inline]
     fn spawn_thread(&mut self) {
         let ctx_lock = self.jobs_context.clone();
@@ -283,15 +425,28 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
                 completer.manual_mode();
 
                 let ctx = ctx_lock.read().unwrap().clone();
-                let result = job.execute(&ctx);
+
+                // Jobs replaced by newer ones don't need to run at all
+                let result = if job.superseded(&ctx) {
+                    None
+                } else {
+                    Some(job.execute(&ctx))
+                };
 
                 match result {
-                    Ok(output) => {
+                    Some(Ok(output)) => {
+                        // Failed jobs can ask to be run again later
+                        if let Some((retry, run_at)) = job.retry(&output) {
+                            input.send(SchedulerInput::DelayedJob(
+                                retry, job.priority(), run_at,
+                            ))?;
+                        }
                         input.send(SchedulerInput::ProcessOutput(output))?;
                     }
-                    Err(error) => {
+                    Some(Err(error)) => {
                         error.pretty_print();
                     }
+                    None => {}
                 }
 
                 input.send(SchedulerInput::JobEnded(job.hook_id(), completer))?;
@@ -356,23 +511,118 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
         }
         for hook_id in &to_remove {
             let _ = self.waiting.remove(&hook_id);
+            let _ = self.concurrency.remove(&hook_id);
         }
 
-        // Add new hooks
+        // Add new hooks, and update the limits of the existing ones
         for hook in self.hooks.iter() {
-            if hook.can_be_parallel() {
-                continue;
-            }
+            let limit = match hook.max_concurrency() {
+                Some(limit) => limit,
+                None => continue,
+            };
+            self.concurrency.insert(hook.id(), limit);
+
             if self.waiting.contains_key(&hook.id()) {
                 continue;
             }
 
             self.waiting.insert(hook.id(), BinaryHeap::new());
         }
+
+        // Hooks without jobs in later rounds start from the current one
+        let current_round = self.current_round;
+        self.rounds.retain(|_, round| *round > current_round);
+    }
+
+    fn queued_jobs(&self) -> Vec<QueuedJob> {
+        let details = |job: &ScheduledJob<S>, delayed| QueuedJob {
+            hook: job.hook_name().to_string(),
+            priority: job.priority(),
+            delayed,
+        };
+
+        let mut jobs = self.queue.iter()
+            .chain(self.waiting.values().flat_map(|waiting| waiting.iter()))
+            .map(|job| details(job, false))
+            .collect::<Vec<_>>();
+
+        // Jobs with an higher priority are run first, and delayed jobs are
+        // run only after the others
+        jobs.sort_by(|a, b| b.priority.cmp(&a.priority));
+        let mut delayed = self.delayed.iter().collect::<Vec<_>>();
+        delayed.sort_by_key(|&&(run_at, _)| run_at);
+        for &&(_, ref job) in &delayed {
+            jobs.push(details(job, true));
+        }
+
+        jobs
+    }
+
+    /// Remove the job waiting to be run since the longest time, if any.
+    /// Delayed jobs are not considered, since they're not ready to run yet.
+    fn drop_oldest(&mut self) -> Option<ScheduledJob<S>> {
+        let mut oldest = None;
+        for job in self.queue
+            .iter()
+            .chain(self.waiting.values().flat_map(|waiting| waiting.iter()))
+        {
+            if oldest.map(|serial| job.serial() < serial).unwrap_or(true) {
+                oldest = Some(job.serial());
+            }
+        }
+
+        let oldest = match oldest {
+            Some(serial) => serial,
+            None => return None,
+        };
+
+        let heaps = iter::once(&mut self.queue)
+            .chain(self.waiting.values_mut());
+        for heap in heaps {
+            if !heap.iter().any(|job| job.serial() == oldest) {
+                continue;
+            }
+
+            let (mut dropped, rest): (Vec<_>, Vec<_>) = heap
+                .drain()
+                .partition(|job| job.serial() == oldest);
+            heap.extend(rest);
+            return dropped.pop();
+        }
+
+        None
+    }
+
+    fn next_wakeup(&self) -> Option<Instant> {
+        self.delayed
+            .iter()
+            .map(|&(run_at, _)| run_at)
+            .chain(self.shutdown_at)
+            .min()
+    }
+
+    fn queue_delayed_jobs(&mut self) {
+        if self.delayed.is_empty() {
+            return;
+        }
+
+        let now = Instant::now();
+        let (ready, delayed) = self.delayed
+            .drain(..)
+            .partition::<Vec<_>, _>(|&(run_at, _)| run_at <= now);
+        self.delayed = delayed;
+
+        if !ready.is_empty() {
+            for (_, job) in ready {
+                self.enqueue(job);
+            }
+            self.run_jobs();
+        }
     }
 
     fn run_jobs(&mut self) {
-        if self.locked {
+        // No new jobs are started while shutting down
+        if self.locked || self.shutdown_at.is_some() {
             return;
         }
 
@@ -381,11 +631,17 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
         // all of them are processed
         'main: loop {
             if let Some(mut job) = self.get_job() {
+                let round = job.round();
+
                 // Try to run the job in a thread
                 for thread in self.threads.values_mut() {
                     match thread.process(job) {
                         ProcessResult::Rejected(j) => job = j,
-                        ProcessResult::Executing => continue 'main,
+                        ProcessResult::Executing => {
+                            self.current_round =
+                                cmp::max(self.current_round, round);
+                            continue 'main;
+                        }
                     }
                 }
                 self.queue_job(job);
@@ -394,12 +650,25 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
         }
     }
 
+    /// Queue a new job after the ones of its hook already queued. Jobs with
+    /// the same priority are run round-robin across the hooks, so a hook with
+    /// a lot of queued jobs can't starve the other ones.
+    fn enqueue(&mut self, mut job: ScheduledJob<S>) {
+        let hook_id = job.hook_id();
+        let last = self.rounds.get(&hook_id).cloned().unwrap_or(0);
+        let round = cmp::max(self.current_round, last) + 1;
+
+        self.rounds.insert(hook_id, round);
+        job.set_round(round);
+        self.queue_job(job);
+    }
+
     fn queue_job(&mut self, job: ScheduledJob<S>) {
         let hook_id = job.hook_id();
 
-        // Put the job in waiting if it can't be parallel and
-        // it's already running
-        if self.is_running(hook_id) {
+        // Put the job in waiting if too many jobs of its hook are already
+        // running
+        if self.at_capacity(hook_id) {
             if let Some(waiting) = self.waiting.get_mut(&hook_id) {
                 waiting.push(job);
                 return;
@@ -414,9 +683,9 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
             if let Some(job) = self.queue.pop() {
                 let hook_id = job.hook_id();
 
-                // Put the job in waiting if it can't be parallel and
-                // it's already running
-                if self.is_running(hook_id) {
+                // Put the job in waiting if too many jobs of its hook are
+                // already running
+                if self.at_capacity(hook_id) {
                     if let Some(waiting) = self.waiting.get_mut(&hook_id) {
                         waiting.push(job);
                         continue;
@@ -430,14 +699,17 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
         }
     }
 
-    fn is_running(&self, hook: ScriptId<S>) -> bool {
-        for thread in self.threads.values() {
-            if thread.currently_running() == Some(hook) {
-                return true;
-            }
-        }
-
-        return false;
+    fn at_capacity(&self, hook: ScriptId<S>) -> bool {
+        let limit = match self.concurrency.get(&hook) {
+            Some(limit) => *limit,
+            None => return false,
+        };
+
+        let running = self.threads
+            .values()
+            .filter(|thread| thread.currently_running() == Some(hook))
+            .count();
+        running >= limit
     }
 }
 
@@ -446,6 +718,7 @@ impl<S: ScriptsRepositoryTrait> Scheduler<S> {
 mod tests {
     use std::collections::VecDeque;
     use std::sync::{mpsc, Arc, Mutex};
+    use std::time::Duration;
 
     use common::prelude::*;
     use common::state::State;
@@ -505,6 +778,199 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_processor_shutdown() {
+        test_wrapper(|| {
+            let repo = Repository::<&'static str>::new();
+
+            let (run_send, run_recv) = mpsc::channel();
+            repo.add_script("slow", true, move |name| {
+                ::std::thread::sleep(Duration::from_millis(200));
+                run_send.send(name)?;
+                Ok(())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                1,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+
+            // The first job is running, while the second one is queued
+            processor.api().queue(repo.job("slow", "first").unwrap(), 0)?;
+            processor.api().queue(repo.job("slow", "second").unwrap(), 0)?;
+
+            processor.shutdown(
+                Duration::from_secs(10), Duration::from_secs(10),
+            )?;
+
+            // The running job completed, but the queued one didn't start
+            assert_eq!(run_recv.try_recv(), Ok("first"));
+            assert!(run_recv.try_recv().is_err());
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_processor_shutdown_grace_period() {
+        test_wrapper(|| {
+            let repo = Repository::<()>::new();
+
+            let (run_send, run_recv) = mpsc::channel();
+            repo.add_script("slow", true, move |_| {
+                ::std::thread::sleep(Duration::from_secs(2));
+                run_send.send(())?;
+                Ok(())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                1,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+
+            processor.api().queue(repo.job("slow", ()).unwrap(), 0)?;
+
+            // The shutdown doesn't wait for the job past the grace periods,
+            // even if it doesn't stop when asked to
+            processor.shutdown(
+                Duration::from_millis(50), Duration::from_millis(50),
+            )?;
+            assert!(run_recv.try_recv().is_err());
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_delayed_jobs() {
+        test_wrapper(|| {
+            let repo = Repository::<()>::new();
+
+            let (run_send, run_recv) = mpsc::channel();
+            repo.add_script("delayed", true, move |_| {
+                run_send.send(())?;
+                Ok(())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                1,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+
+            processor.api().queue_delayed(
+                repo.job("delayed", ()).unwrap(),
+                0,
+                Duration::from_millis(200),
+            )?;
+
+            // The job is counted as queued, but it's not run immediately
+            assert_eq!(processor.api().health_details()?.queued_jobs, 1);
+            assert!(run_recv.recv_timeout(Duration::from_millis(50)).is_err());
+
+            // The job is run after the delay
+            assert!(run_recv.recv_timeout(Duration::from_secs(5)).is_ok());
+
+            processor.stop()?;
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_drop_oldest() {
+        test_wrapper(|| {
+            let repo = Repository::<&'static str>::new();
+
+            let (run_send, run_recv) = mpsc::channel();
+            repo.add_script("job", true, move |name| {
+                run_send.send(name)?;
+                Ok(())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                1,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+
+            // The oldest job is dropped even if it has an higher priority
+            api.lock()?;
+            api.queue(repo.job("job", "first").unwrap(), 10)?;
+            api.queue(repo.job("job", "second").unwrap(), 0)?;
+            assert!(api.drop_oldest()?);
+            assert_eq!(api.health_details()?.queued_jobs, 1);
+            api.unlock()?;
+
+            let timeout = Duration::from_secs(5);
+            assert_eq!(run_recv.recv_timeout(timeout), Ok("second"));
+            assert!(run_recv.recv_timeout(Duration::from_millis(50)).is_err());
+
+            // Nothing is dropped if the queue is empty
+            assert!(!api.drop_oldest()?);
+
+            processor.stop()?;
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_queued_jobs() {
+        test_wrapper(|| {
+            let repo = Repository::<()>::new();
+            repo.add_script("low", true, |_| Ok(()));
+            repo.add_script("high", true, |_| Ok(()));
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                1,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+
+            // Prevent jobs from being run
+            api.lock()?;
+
+            api.queue_delayed(
+                repo.job("low", ()).unwrap(), 0, Duration::from_secs(60),
+            )?;
+            api.queue(repo.job("low", ()).unwrap(), 0)?;
+            api.queue(repo.job("high", ()).unwrap(), 10)?;
+
+            let queued = api.queued_jobs()?;
+            let summary = queued
+                .iter()
+                .map(|job| (job.hook.as_str(), job.priority, job.delayed))
+                .collect::<Vec<_>>();
+            assert_eq!(summary, vec![
+                ("high", 10, false),
+                ("low", 0, false),
+                ("low", 0, true),
+            ]);
+
+            api.unlock()?;
+            processor.stop()?;
+            Ok(())
+        });
+    }
+
+
     fn run_multiple_append(threads: u16, prioritized: bool) -> Result<String> {
         let repo = Repository::<char>::new();
 
@@ -574,6 +1040,54 @@ mod tests {
         assert_eq!(output.len(), 10);
     }
 
+
+    #[test]
+    fn test_fair_scheduling() {
+        test_wrapper(|| {
+            let repo = Repository::<char>::new();
+
+            let (append_send, append_recv) = mpsc::channel();
+            for name in &["chatty", "quiet"] {
+                let append_send = append_send.clone();
+                repo.add_script(name, true, move |arg| {
+                    append_send.send(arg)?;
+                    Ok(())
+                });
+            }
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                1,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+
+            // Prevent jobs from being run
+            api.lock()?;
+
+            for chr in &['a', 'b', 'c', 'd', 'e'] {
+                api.queue(repo.job("chatty", *chr).unwrap(), 0)?;
+            }
+            api.queue(repo.job("quiet", 'x').unwrap(), 0)?;
+            api.queue(repo.job("quiet", 'y').unwrap(), 0)?;
+            api.queue(repo.job("quiet", 'Z').unwrap(), 10)?;
+
+            api.unlock()?;
+            processor.stop()?;
+
+            // Jobs of the two hooks alternate, after the higher priority one
+            let mut output = String::new();
+            while let Ok(part) = append_recv.try_recv() {
+                output.push(part);
+            }
+            assert_eq!(output, "Zaxbycde");
+
+            Ok(())
+        });
+    }
+
     #[test]
     fn test_non_parallel_processing() {
         test_wrapper(|| {
@@ -635,6 +1149,65 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_limited_concurrency() {
+        test_wrapper(|| {
+            let repo = Repository::<Arc<Mutex<mpsc::Receiver<()>>>>::new();
+
+            repo.add_limited_script("wait", Some(2), |recv| {
+                recv.lock()?.recv()?;
+                Ok(())
+            });
+
+            let repo = Arc::new(repo);
+            let processor = Processor::new(
+                3,
+                repo.clone(),
+                (),
+                Arc::new(State::new()),
+            )?;
+            let api = processor.api();
+
+            // Queue six jobs
+            let mut waiters = VecDeque::new();
+            for _ in 0..6 {
+                let (unlock_send, unlock_recv) = mpsc::channel();
+
+                api.queue(
+                    repo.job("wait", Arc::new(Mutex::new(unlock_recv)))
+                        .unwrap(),
+                    0,
+                )?;
+                waiters.push_back(unlock_send);
+            }
+
+            while waiters.len() >= 2 {
+                // Wait for the next job to start
+                let mut status;
+                loop {
+                    status = api.health_details()?;
+                    if status.queued_jobs == waiters.len() - 2 {
+                        break;
+                    }
+                }
+
+                // Only two jobs should be running, even if there is a free
+                // thread
+                assert_eq!(status.busy_threads, 2);
+                assert_eq!(status.max_threads, 3);
+
+                waiters.pop_front().unwrap().send(())?;
+            }
+            for waiting in waiters.drain(..) {
+                waiting.send(())?;
+            }
+
+            processor.stop()?;
+
+            Ok(())
+        });
+    }
+
     #[test]
     fn test_health_details() {
         test_wrapper(|| {
@@ -24,7 +24,7 @@ use common::prelude::*;
 pub struct Script<I: Send + Sync + Debug + Clone> {
     id: usize,
     name: String,
-    can_be_parallel: bool,
+    max_concurrency: Option<usize>,
     func: Arc<Mutex<Box<Fn(I) -> Result<()> + Send>>>,
 }
 
@@ -35,8 +35,8 @@ impl<I: Send + Sync + Debug + Clone> ScriptTrait for Script<I> {
         self.id
     }
 
-    fn can_be_parallel(&self) -> bool {
-        self.can_be_parallel
+    fn max_concurrency(&self) -> Option<usize> {
+        self.max_concurrency
     }
 }
 
@@ -44,10 +44,10 @@ impl<I: Send + Sync + Debug + Clone> Debug for Script<I> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "Script {{ id: {}, name: {}, can_be_parallel: {} }}",
+            "Script {{ id: {}, name: {}, max_concurrency: {:?} }}",
             self.id,
             self.name,
-            self.can_be_parallel,
+            self.max_concurrency,
         )
     }
 }
@@ -97,6 +97,16 @@ impl<I: Send + Sync + Debug + Clone> Repository<I> {
         name: &str,
         parallel: bool,
         func: F,
+    ) {
+        let limit = if parallel { None } else { Some(1) };
+        self.add_limited_script(name, limit, func);
+    }
+
+    pub fn add_limited_script<F: Fn(I) -> Result<()> + 'static + Send>(
+        &self,
+        name: &str,
+        max_concurrency: Option<usize>,
+        func: F,
     ) {
         self.ids
             .write()
@@ -107,7 +117,7 @@ impl<I: Send + Sync + Debug + Clone> Repository<I> {
             Arc::new(Script {
                 id: self.last_id.fetch_add(1, Ordering::SeqCst),
                 name: name.to_string(),
-                can_be_parallel: parallel,
+                max_concurrency,
                 func: Arc::new(Mutex::new(Box::new(func))),
             }),
         );
@@ -138,7 +148,9 @@ impl<I: Send + Sync + Debug + Clone> Repository<I> {
         self.scripts.write().unwrap().clear();
 
         for script in scripts.drain(..) {
-            self.add_script(&script.name, script.can_be_parallel, |_| Ok(()));
+            self.add_limited_script(
+                &script.name, script.max_concurrency, |_| Ok(()),
+            );
         }
     }
 }
@@ -0,0 +1,245 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use serde_json;
+use ring;
+
+use providers::prelude::*;
+use utils;
+use common::prelude::*;
+
+
+#[derive(Debug, Deserialize)]
+struct DropboxPayload {
+    list_folder: Option<DropboxListFolder>,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct DropboxListFolder {
+    #[serde(default)]
+    accounts: Vec<String>,
+}
+
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DropboxProvider {
+    secret: utils::Secrets,
+}
+
+impl ProviderTrait for DropboxProvider {
+    fn new(config: &str) -> Result<Self> {
+        Ok(serde_json::from_str(config)?)
+    }
+
+    fn validate(&self, request: &Request) -> RequestType {
+        let req;
+        if let Request::Web(ref inner) = *request {
+            req = inner;
+        } else {
+            return RequestType::Invalid;
+        }
+
+        // Dropbox verifies the endpoint by asking it to echo a challenge
+        if req.params.contains_key("challenge") && req.body.is_empty() {
+            return RequestType::Handshake;
+        }
+
+        // Notifications are signed with the app secret
+        let signature = match req.headers.get("X-Dropbox-Signature") {
+            Some(signature) => signature,
+            None => return RequestType::Invalid,
+        };
+        let body = req.body_bytes();
+        if !self.secret.any(|s| verify_signature(s, body, signature)) {
+            return RequestType::Invalid;
+        }
+
+        // Check if the JSON in the body is valid
+        if serde_json::from_str::<DropboxPayload>(&req.body).is_err() {
+            return RequestType::Invalid;
+        }
+
+        RequestType::ExecuteHook
+    }
+
+    fn env(&self, request: &Request) -> HashMap<String, String> {
+        let mut res = HashMap::new();
+
+        let req;
+        if let Request::Web(ref inner) = *request {
+            req = inner;
+        } else {
+            return res;
+        }
+
+        let payload: DropboxPayload = match serde_json::from_str(&req.body) {
+            Ok(payload) => payload,
+            Err(..) => return res,
+        };
+
+        let accounts = payload.list_folder.map(|l| l.accounts);
+        res.insert(
+            "ACCOUNTS".to_string(),
+            accounts.unwrap_or_default().join(" "),
+        );
+
+        res
+    }
+
+    fn response(
+        &self,
+        request: &Request,
+        kind: RequestType,
+    ) -> Option<ProviderResponse> {
+        if kind != RequestType::Handshake {
+            return None;
+        }
+
+        if let Request::Web(ref req) = *request {
+            req.params
+                .get("challenge")
+                .map(|challenge| ProviderResponse::text(challenge.as_str()))
+        } else {
+            None
+        }
+    }
+
+    fn trigger_status_hooks(&self, request: &Request) -> bool {
+        // Challenges are never executed, but be safe anyway
+        if let Request::Web(ref req) = *request {
+            !req.params.contains_key("challenge")
+        } else {
+            true
+        }
+    }
+}
+
+
+fn verify_signature(secret: &str, payload: &[u8], signature: &str) -> bool {
+    // Convert the signature from hex
+    let signature = if let Ok(converted) = utils::from_hex(signature) {
+        converted
+    } else {
+        // This is not hex
+        return false;
+    };
+
+    let key = ring::hmac::VerificationKey::new(
+        &ring::digest::SHA256, secret.as_bytes(),
+    );
+    ring::hmac::verify(&key, payload, &signature).is_ok()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use utils::testing::*;
+    use requests::RequestType;
+    use providers::{ProviderResponse, ProviderTrait};
+    use web::WebRequest;
+
+    use super::DropboxProvider;
+
+
+    static BODY: &str = concat!(
+        r#"{"list_folder": {"accounts": ["#,
+        r#""dbid:AAH4f99T0taONIb-OurWxbNQ6ywGRopQngc", "dbid:AAA1234"]}, "#,
+        r#""delta": {"users": [12345678, 23456789]}}"#,
+    );
+
+    static SIGNATURE: &str = concat!(
+        "fbbc7f836bca519b87af14d31d747a53",
+        "68d1f9490567ec4ff34ede3bb0f9f5eb",
+    );
+
+
+    fn notification(signature: &str) -> WebRequest {
+        let mut req = dummy_web_request();
+        req.body = BODY.into();
+        req.headers.insert("X-Dropbox-Signature".into(), signature.into());
+        req
+    }
+
+
+    #[test]
+    fn test_new() {
+        assert!(DropboxProvider::new(r#"{"secret": "abcde"}"#).is_ok());
+
+        for wrong in &[r#"{}"#, r#"{"secret": 12345}"#] {
+            assert!(DropboxProvider::new(wrong).is_err(), wrong.to_string());
+        }
+    }
+
+
+    #[test]
+    fn test_challenge() {
+        let provider = DropboxProvider::new(r#"{"secret": "secret"}"#).unwrap();
+
+        let mut req = dummy_web_request();
+        req.params.insert("challenge".into(), "abcdef".into());
+        let req = req.into();
+
+        assert_eq!(provider.validate(&req), RequestType::Handshake);
+        assert_eq!(
+            provider.response(&req, RequestType::Handshake),
+            Some(ProviderResponse::text("abcdef"))
+        );
+        assert_eq!(provider.response(&req, RequestType::ExecuteHook), None);
+        assert!(!provider.trigger_status_hooks(&req));
+    }
+
+
+    #[test]
+    fn test_validate() {
+        let provider = DropboxProvider::new(r#"{"secret": "secret"}"#).unwrap();
+
+        // Notifications with the right signature are executed
+        assert_eq!(
+            provider.validate(&notification(SIGNATURE).into()),
+            RequestType::ExecuteHook
+        );
+
+        // Notifications with the wrong signature are rejected
+        assert_eq!(
+            provider.validate(&notification("12345").into()),
+            RequestType::Invalid
+        );
+        assert_eq!(
+            provider.validate(&notification("not hex").into()),
+            RequestType::Invalid
+        );
+
+        // Notifications without a signature are rejected
+        assert_eq!(
+            provider.validate(&dummy_web_request().into()),
+            RequestType::Invalid
+        );
+    }
+
+
+    #[test]
+    fn test_env() {
+        let provider = DropboxProvider::new(r#"{"secret": "secret"}"#).unwrap();
+        let env = provider.env(&notification(SIGNATURE).into());
+
+        assert_eq!(env.len(), 1);
+        assert_eq!(
+            env["ACCOUNTS"],
+            "dbid:AAH4f99T0taONIb-OurWxbNQ6ywGRopQngc dbid:AAA1234"
+        );
+    }
+}
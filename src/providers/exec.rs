@@ -0,0 +1,291 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::env;
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json;
+
+use providers::prelude::*;
+use web::WebRequest;
+use utils;
+use common::prelude::*;
+
+
+fn default_timeout() -> utils::TimeString {
+    10.into()
+}
+
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExecProvider {
+    validator: String,
+    #[serde(default = "default_timeout")]
+    timeout: utils::TimeString,
+}
+
+impl ExecProvider {
+    fn run_validator(
+        &self, req: &WebRequest,
+    ) -> Option<HashMap<String, String>> {
+        let mut command = Command::new(&self.validator);
+
+        // The validator only gets the details of the request
+        command.env_clear();
+        if let Some(path) = env::var_os("PATH") {
+            command.env("PATH", path);
+        }
+        command.env("FISHER_REQUEST_ID", &req.id);
+        command.env("FISHER_REQUEST_IP", req.source.to_string());
+        command.env("FISHER_REQUEST_BODY_ENCODING", req.body_encoding());
+        for (key, value) in &req.headers {
+            let key = utils::env_name(key);
+            command.env(format!("FISHER_REQUEST_HEADER_{}", key), value);
+        }
+        for (key, value) in &req.params {
+            let key = utils::env_name(key);
+            command.env(format!("FISHER_REQUEST_PARAM_{}", key), value);
+        }
+
+        command.stdin(Stdio::piped());
+        command.stdout(Stdio::piped());
+        command.stderr(Stdio::null());
+
+        let mut child = match utils::spawn_tracked(&mut command) {
+            Ok(child) => child,
+            Err(..) => return None,
+        };
+
+        // Both pipes are handled in the background, so a validator not
+        // reading its stdin or writing a lot of output can't block this
+        if let Some(mut stdin) = child.stdin.take() {
+            let body = req.body_bytes().to_vec();
+            thread::spawn(move || {
+                let _ = stdin.write_all(&body);
+            });
+        }
+        let reader = child.stdout.take().map(|mut stdout| {
+            thread::spawn(move || {
+                let mut output = Vec::new();
+                let _ = stdout.read_to_end(&mut output);
+                output
+            })
+        });
+
+        // Wait for the validator, killing it if it takes too long
+        let timeout = Duration::from_secs(self.timeout.as_u64());
+        let start = Instant::now();
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break Some(status),
+                Ok(None) => {}
+                Err(..) => break None,
+            }
+
+            if start.elapsed() >= timeout {
+                let _ = child.kill();
+                let _ = child.wait();
+                break None;
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        };
+        utils::release_tracked(&child);
+        let status = match status {
+            Some(status) => status,
+            None => return None,
+        };
+
+        let output = match reader.map(|r| r.join()) {
+            Some(Ok(output)) => output,
+            _ => Vec::new(),
+        };
+
+        if status.success() {
+            Some(parse_output(&String::from_utf8_lossy(&output)))
+        } else {
+            None
+        }
+    }
+}
+
+impl ProviderTrait for ExecProvider {
+    fn new(config: &str) -> Result<Self> {
+        Ok(serde_json::from_str(config)?)
+    }
+
+    fn validate(&self, request: &Request) -> RequestType {
+        self.validate_with_env(request).0
+    }
+
+    fn validate_with_env(
+        &self,
+        request: &Request,
+    ) -> (RequestType, Option<HashMap<String, String>>) {
+        let req;
+        if let Request::Web(ref inner) = *request {
+            req = inner;
+        } else {
+            return (RequestType::Invalid, None);
+        }
+
+        // The env returned by the validator is carried by the job, so the
+        // validator doesn't have to run again
+        match self.run_validator(req) {
+            Some(env) => (RequestType::ExecuteHook, Some(env)),
+            None => (RequestType::Invalid, None),
+        }
+    }
+
+    fn env(&self, request: &Request) -> HashMap<String, String> {
+        // Only the jobs which didn't keep the env of the validation, like
+        // the ones restored from the journal, need to run the validator
+        match *request {
+            Request::Web(ref req) => {
+                self.run_validator(req).unwrap_or_default()
+            }
+            _ => HashMap::new(),
+        }
+    }
+}
+
+
+/// Parse the `KEY=VALUE` lines returned by the validator.
+fn parse_output(output: &str) -> HashMap<String, String> {
+    let mut env = HashMap::new();
+
+    for line in output.lines() {
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().unwrap_or("").trim();
+        if let Some(value) = parts.next() {
+            if !key.is_empty() {
+                env.insert(utils::env_name(key), value.to_string());
+            }
+        }
+    }
+
+    env
+}
+
+
+#[cfg(test)]
+mod tests {
+    use requests::RequestType;
+    use providers::ProviderTrait;
+    use scripts::test_utils::*;
+
+    use super::{parse_output, ExecProvider};
+
+
+    fn provider(env: &TestEnv, name: &str, extra: &str) -> ExecProvider {
+        let path = env.scripts_dir().join(name);
+        ExecProvider::new(&format!(
+            r#"{{"validator": "{}"{}}}"#, path.to_str().unwrap(), extra,
+        )).unwrap()
+    }
+
+
+    #[test]
+    fn test_new() {
+        for right in &[
+            r#"{"validator": "/bin/true"}"#,
+            r#"{"validator": "/bin/true", "timeout": "1m"}"#,
+        ] {
+            assert!(ExecProvider::new(right).is_ok(), right.to_string());
+        }
+
+        for wrong in &[
+            r#"{}"#,
+            r#"{"validator": 12345}"#,
+            r#"{"validator": "/bin/true", "timeout": "invalid"}"#,
+        ] {
+            assert!(ExecProvider::new(wrong).is_err(), wrong.to_string());
+        }
+    }
+
+
+    #[test]
+    fn test_validate_and_env() {
+        test_wrapper(|env| {
+            env.create_script("validator.sh", &[
+                r#"#!/bin/bash"#,
+                r#"[[ "${FISHER_REQUEST_HEADER_X_TOKEN}" == "a" ]] || exit 1"#,
+                r#"body="$(cat)""#,
+                r#"echo "body-length=${#body}""#,
+                r#"echo "this line is ignored""#,
+                r#"echo "USER=${FISHER_REQUEST_PARAM_USER}""#,
+            ])?;
+            let provider = provider(env, "validator.sh", "");
+
+            let mut req = dummy_web_request();
+            req.body = "hello".into();
+            req.params.insert("user".into(), "pietro".into());
+            let invalid = req.clone().into();
+            req.headers.insert("X-Token".into(), "a".into());
+            let valid = req.into();
+
+            assert_eq!(provider.validate(&invalid), RequestType::Invalid);
+            assert_eq!(provider.validate(&valid), RequestType::ExecuteHook);
+            assert_eq!(
+                provider.validate_with_env(&invalid),
+                (RequestType::Invalid, None)
+            );
+
+            // The env is collected when the request is validated
+            let (result, env) = provider.validate_with_env(&valid);
+            assert_eq!(result, RequestType::ExecuteHook);
+            let env = env.unwrap();
+            assert_eq!(env.len(), 2);
+            assert_eq!(env["BODY_LENGTH"], "5");
+            assert_eq!(env["USER"], "pietro");
+
+            // Jobs without it run the validator again
+            assert_eq!(provider.env(&valid), env);
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_validate_timeout() {
+        test_wrapper(|env| {
+            env.create_script("slow.sh", &[r#"#!/bin/bash"#, r#"sleep 10"#])?;
+            let provider = provider(env, "slow.sh", r#", "timeout": "1""#);
+
+            assert_eq!(
+                provider.validate(&dummy_web_request().into()),
+                RequestType::Invalid
+            );
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_parse_output() {
+        let env = parse_output("A=1\nb-c=2=3\n\nnothing\n=4\nD=\n");
+
+        assert_eq!(env.len(), 3);
+        assert_eq!(env["A"], "1");
+        assert_eq!(env["B_C"], "2=3");
+        assert_eq!(env["D"], "");
+    }
+}
@@ -31,6 +31,15 @@ lazy_static! {
         "push", "repository", "release", "status", "team", "team_add", "watch",
     ];
 
+    static ref GITHUB_FIELDS: Vec<PayloadField> = vec![
+        PayloadField::new("REF", &["/ref", "/pull_request/head/ref"]),
+        PayloadField::new("SHA", &[
+            "/after", "/pull_request/head/sha", "/deployment/sha", "/sha",
+        ]),
+        PayloadField::new("REPO", &["/repository/full_name"]),
+        PayloadField::new("SENDER", &["/sender/login"]),
+    ];
+
     static ref GITHUB_HEADERS: Vec<&'static str> = vec![
         "X-GitHub-Event",
         "X-Hub-Signature",
@@ -40,9 +49,11 @@ lazy_static! {
 
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct GitHubProvider {
-    secret: Option<String>,
+    secret: Option<utils::Secrets>,
     events: Option<Vec<String>>,
+    branches: Option<Vec<String>>,
 }
 
 impl ProviderTrait for GitHubProvider {
@@ -52,7 +63,12 @@ impl ProviderTrait for GitHubProvider {
         if let Some(ref events) = inst.events {
             // Check if the events exists
             for event in events {
-                if !GITHUB_EVENTS.contains(&event.as_ref()) {
+                let exists = if utils::is_glob(event) {
+                    GITHUB_EVENTS.iter().any(|e| utils::glob_matches(event, e))
+                } else {
+                    GITHUB_EVENTS.contains(&event.as_ref())
+                };
+                if !exists {
                     // Return an error if the event doesn't exist
                     return Err(
                         ErrorKind::InvalidInput(
@@ -82,10 +98,11 @@ impl ProviderTrait for GitHubProvider {
         }
 
         // Check the signature only if a secret key was provided
-        if let Some(ref secret) = self.secret {
-            // Check if the signature is valid
+        if let Some(ref secrets) = self.secret {
+            // Check if the signature is valid for any of the secrets
             let signature = &req.headers["X-Hub-Signature"];
-            if !verify_signature(secret, &req.body, signature) {
+            let body = req.body_bytes();
+            if !secrets.any(|s| verify_signature(s, body, signature)) {
                 return RequestType::Invalid;
             }
         }
@@ -98,21 +115,34 @@ impl ProviderTrait for GitHubProvider {
 
         // Check if the event should be accepted
         if let Some(ref events) = self.events {
-            if !(events.contains(event) || *event == "ping") {
+            let allowed = events.iter().any(|e| utils::glob_matches(e, event));
+            if !(allowed || *event == "ping") {
                 return RequestType::Invalid;
             }
         }
 
         // Check if the JSON in the body is valid
-        if serde_json::from_str::<serde_json::Value>(&req.body).is_err() {
-            return RequestType::Invalid;
-        }
+        let payload = match serde_json::from_str(&req.body) {
+            Ok(payload) => payload,
+            Err(..) => return RequestType::Invalid,
+        };
 
         // The "ping" event is a ping (doh!)
         if event == "ping" {
             return RequestType::Ping;
         }
 
+        // Check if the branch should be accepted, rejecting events which
+        // aren't about a branch
+        if let Some(ref branches) = self.branches {
+            let allowed = payload_branch(&payload).map(|branch| {
+                branches.iter().any(|b| utils::glob_matches(b, branch))
+            });
+            if allowed != Some(true) {
+                return RequestType::Invalid;
+            }
+        }
+
         // Process the hook in the other cases
         RequestType::ExecuteHook
     }
@@ -134,12 +164,50 @@ impl ProviderTrait for GitHubProvider {
             req.headers["X-GitHub-Delivery"].clone(),
         );
 
+        // The branch can't be extracted with the payload fields
+        if let Ok(payload) = serde_json::from_str(&req.body) {
+            res.insert(
+                "BRANCH".to_string(),
+                payload_branch(&payload).unwrap_or("").to_string(),
+            );
+        }
+
         res
     }
+
+    fn payload_fields(&self) -> &[PayloadField] {
+        &GITHUB_FIELDS
+    }
+
+    fn delivery_id(&self, request: &Request) -> Option<String> {
+        if let Request::Web(ref req) = *request {
+            req.headers.get("X-GitHub-Delivery").cloned()
+        } else {
+            None
+        }
+    }
 }
 
 
-fn verify_signature(secret: &str, payload: &str, raw_signature: &str) -> bool {
+/// Return the branch an event is about, if any.
+fn payload_branch(payload: &serde_json::Value) -> Option<&str> {
+    // Pushes contain the full ref, while branch creations and deletions
+    // contain just the name
+    if let Some(reference) = payload["ref"].as_str() {
+        if reference.starts_with("refs/heads/") {
+            return Some(&reference[11..]);
+        } else if payload["ref_type"].as_str() == Some("branch") {
+            return Some(reference);
+        }
+        return None;
+    }
+
+    // Pull requests are about their base branch
+    payload["pull_request"]["base"]["ref"].as_str()
+}
+
+
+fn verify_signature(secret: &str, payload: &[u8], raw_signature: &str) -> bool {
     // The signature must have a =
     if !raw_signature.contains('=') {
         return false;
@@ -174,15 +242,15 @@ fn verify_signature(secret: &str, payload: &str, raw_signature: &str) -> bool {
 
     // Verify the HMAC signature
     let key = ring::hmac::VerificationKey::new(digest, secret.as_bytes());
-    ring::hmac::verify(&key, payload.as_bytes(), &signature).is_ok()
+    ring::hmac::verify(&key, payload, &signature).is_ok()
 }
 
 
 #[cfg(test)]
 mod tests {
     use utils::testing::*;
-    use requests::RequestType;
-    use providers::ProviderTrait;
+    use requests::{Request, RequestType};
+    use providers::{Provider, ProviderTrait};
 
     use super::{verify_signature, GitHubProvider, GITHUB_EVENTS};
 
@@ -195,6 +263,9 @@ mod tests {
             r#"{"secret": "abcde"}"#,
             r#"{"events": ["push", "fork"]}"#,
             r#"{"secret": "abcde", "events": ["push", "fork"]}"#,
+            r#"{"secret": ["abcde", "fghij"]}"#,
+            r#"{"events": ["pull_request*"], "branches": ["master"]}"#,
+            r#"{"branches": ["master", "release/*"]}"#,
         ] {
             assert!(GitHubProvider::new(right).is_ok(), right.to_string());
         }
@@ -204,12 +275,16 @@ mod tests {
             // Wrong types
             r#"{"secret": 12345}"#,
             r#"{"secret": true}"#,
+            r#"{"secret": []}"#,
             r#"{"events": 12345}"#,
             r#"{"events": true}"#,
             r#"{"events": {}}"#,
             r#"{"events": [12345]}"#,
             r#"{"events": [true]}"#,
             r#"{"events": ["invalid_event"]}"#,
+            r#"{"events": ["invalid_*"]}"#,
+            r#"{"branches": "master"}"#,
+            r#"{"branches": [12345]}"#,
         ] {
             assert!(GitHubProvider::new(wrong).is_err(), wrong.to_string());
         }
@@ -249,6 +324,82 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_validate_filters() {
+        fn request(event: &str, body: &str) -> Request {
+            let mut request = dummy_web_request();
+            request.headers.insert("X-GitHub-Event".into(), event.into());
+            request.headers.insert("X-GitHub-Delivery".into(), "1".into());
+            request.headers.insert("X-Hub-Signature".into(), "".into());
+            request.body = body.into();
+            request.into()
+        }
+
+        let provider = GitHubProvider::new(
+            r#"{"events": ["push", "pull_request*"],
+                "branches": ["master", "release/*"]}"#,
+        ).unwrap();
+
+        let pr_master = r#"{"pull_request": {"base": {"ref": "master"}}}"#;
+        let pr_dev = r#"{"pull_request": {"base": {"ref": "dev"}}}"#;
+
+        for &(event, body, expected) in &[
+            ("push", r#"{"ref": "refs/heads/master"}"#, true),
+            ("push", r#"{"ref": "refs/heads/release/1.0"}"#, true),
+            ("push", r#"{"ref": "refs/heads/feature"}"#, false),
+            ("push", r#"{"ref": "refs/tags/master"}"#, false),
+            ("pull_request", pr_master, true),
+            ("pull_request", pr_dev, false),
+            ("pull_request_review", pr_master, true),
+            ("create", r#"{"ref": "master", "ref_type": "branch"}"#, false),
+            ("issues", r#"{}"#, false),
+        ] {
+            let expected = if expected {
+                RequestType::ExecuteHook
+            } else {
+                RequestType::Invalid
+            };
+            assert_eq!(
+                provider.validate(&request(event, body)),
+                expected,
+                "{} {}", event, body
+            );
+        }
+
+        // Pings are always accepted
+        assert_eq!(
+            provider.validate(&request("ping", "{}")),
+            RequestType::Ping
+        );
+    }
+
+
+    #[test]
+    fn test_validate_multiple_secrets() {
+        fn request() -> Request {
+            let mut request = dummy_web_request();
+            request.headers.insert("X-GitHub-Event".into(), "push".into());
+            request.headers.insert("X-GitHub-Delivery".into(), "1".into());
+            request.headers.insert(
+                "X-Hub-Signature".into(),
+                "sha1=5d61605c3feea9799210ddcb71307d4ba264225f".into(),
+            );
+            request.body = "{}".into();
+            request.into()
+        }
+
+        // The request is signed with "secret"
+        for &(config, expected) in &[
+            (r#"{"secret": ["secret", "new"]}"#, RequestType::ExecuteHook),
+            (r#"{"secret": ["old", "secret"]}"#, RequestType::ExecuteHook),
+            (r#"{"secret": ["old", "new"]}"#, RequestType::Invalid),
+        ] {
+            let provider = GitHubProvider::new(config).unwrap();
+            assert_eq!(provider.validate(&request()), expected, "{}", config);
+        }
+    }
+
+
     #[test]
     fn test_env() {
         let provider = GitHubProvider::new("{}").unwrap();
@@ -271,6 +422,52 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_delivery_id() {
+        let provider = GitHubProvider::new("{}").unwrap();
+
+        let mut request = dummy_web_request();
+        assert_eq!(provider.delivery_id(&request.clone().into()), None);
+
+        request
+            .headers
+            .insert("X-GitHub-Delivery".to_string(), "12345".to_string());
+        assert_eq!(
+            provider.delivery_id(&request.into()),
+            Some("12345".to_string())
+        );
+    }
+
+
+    #[test]
+    fn test_env_payload() {
+        let provider = Provider::new("GitHub", "{}").unwrap();
+
+        let mut request = dummy_web_request();
+        request
+            .headers
+            .insert("X-GitHub-Event".to_string(), "push".to_string());
+        request
+            .headers
+            .insert("X-GitHub-Delivery".to_string(), "12345".to_string());
+        request.body = r#"{
+            "ref": "refs/heads/master",
+            "after": "0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c",
+            "repository": {"full_name": "pietroalbini/fisher"},
+            "sender": {"login": "pietroalbini"}
+        }"#.into();
+
+        let env = provider.env(&request.into());
+        assert_eq!(env.len(), 7);
+        assert_eq!(env["EVENT"], "push");
+        assert_eq!(env["REF"], "refs/heads/master");
+        assert_eq!(env["BRANCH"], "master");
+        assert_eq!(env["SHA"], "0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c");
+        assert_eq!(env["REPO"], "pietroalbini/fisher");
+        assert_eq!(env["SENDER"], "pietroalbini");
+    }
+
+
     #[test]
     fn test_verify_signature() {
         // Check if the function allows invalid signatures
@@ -282,7 +479,7 @@ mod tests {
             "sha1=e75efc0f29bf50c23f99b30b86f7c78fdaf5f11d",
         ] {
             assert!(
-                !verify_signature("secret", "payload", signature),
+                !verify_signature("secret", b"payload", signature),
                 signature.to_string()
             );
         }
@@ -290,7 +487,7 @@ mod tests {
         // This is known to be right
         assert!(verify_signature(
             "secret",
-            "payload",
+            b"payload",
             "sha1=f75efc0f29bf50c23f99b30b86f7c78fdaf5f11d"
         ));
     }
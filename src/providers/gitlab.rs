@@ -16,6 +16,7 @@
 use serde_json;
 
 use providers::prelude::*;
+use utils;
 use common::prelude::*;
 
 
@@ -25,6 +26,16 @@ lazy_static! {
         "Build", "Pipeline", "Confidential Issue",
     ];
 
+    static ref GITLAB_FIELDS: Vec<PayloadField> = vec![
+        PayloadField::new("REF", &["/ref", "/object_attributes/ref"]),
+        PayloadField::new("SHA", &[
+            "/checkout_sha", "/object_attributes/last_commit/id",
+            "/object_attributes/sha", "/sha",
+        ]),
+        PayloadField::new("REPO", &["/project/path_with_namespace"]),
+        PayloadField::new("USER", &["/user_username", "/user/username"]),
+    ];
+
     static ref GITLAB_HEADERS: Vec<&'static str> = vec![
         "X-Gitlab-Event",
     ];
@@ -32,9 +43,11 @@ lazy_static! {
 
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct GitLabProvider {
-    secret: Option<String>,
+    secret: Option<utils::Secrets>,
     events: Option<Vec<String>>,
+    branches: Option<Vec<String>>,
 }
 
 impl ProviderTrait for GitLabProvider {
@@ -45,7 +58,12 @@ impl ProviderTrait for GitLabProvider {
         if let Some(ref events) = inst.events {
             // Check if the events exists
             for event in events {
-                if !GITLAB_EVENTS.contains(&event.as_ref()) {
+                let exists = if utils::is_glob(event) {
+                    GITLAB_EVENTS.iter().any(|e| utils::glob_matches(event, e))
+                } else {
+                    GITLAB_EVENTS.contains(&event.as_ref())
+                };
+                if !exists {
                     // Return an error if the event doesn't exist
                     return Err(
                         ErrorKind::InvalidInput(
@@ -75,11 +93,11 @@ impl ProviderTrait for GitLabProvider {
         }
 
         // Check if the secret token is correct
-        if let Some(ref secret) = self.secret {
+        if let Some(ref secrets) = self.secret {
             // The header with the token must be present
             if let Some(token) = req.headers.get("X-Gitlab-Token") {
-                // The token must match
-                if token != secret {
+                // The token must match one of the secrets
+                if !secrets.contains(token) {
                     return RequestType::Invalid;
                 }
             } else {
@@ -92,14 +110,26 @@ impl ProviderTrait for GitLabProvider {
         // Check if the event should be accepted
         if let Some(ref events) = self.events {
             // The event is whitelisted
-            if !events.contains(&event.to_string()) {
+            if !events.iter().any(|e| utils::glob_matches(e, event)) {
                 return RequestType::Invalid;
             }
         }
 
         // Check if the JSON body is valid
-        if !serde_json::from_str::<serde_json::Value>(&req.body).is_ok() {
-            return RequestType::Invalid;
+        let payload = match serde_json::from_str(&req.body) {
+            Ok(payload) => payload,
+            Err(..) => return RequestType::Invalid,
+        };
+
+        // Check if the branch should be accepted, rejecting events which
+        // aren't about a branch
+        if let Some(ref branches) = self.branches {
+            let allowed = payload_branch(&payload).map(|branch| {
+                branches.iter().any(|b| utils::glob_matches(b, branch))
+            });
+            if allowed != Some(true) {
+                return RequestType::Invalid;
+            }
         }
 
         RequestType::ExecuteHook
@@ -120,8 +150,56 @@ impl ProviderTrait for GitLabProvider {
         let mut res = HashMap::new();
         res.insert("EVENT".to_string(), event_header.to_string());
 
+        // The branch can't be extracted with the payload fields
+        if let Ok(payload) = serde_json::from_str(&req.body) {
+            res.insert(
+                "BRANCH".to_string(),
+                payload_branch(&payload).unwrap_or("").to_string(),
+            );
+        }
+
         res
     }
+
+    fn payload_fields(&self) -> &[PayloadField] {
+        &GITLAB_FIELDS
+    }
+
+    fn delivery_id(&self, request: &Request) -> Option<String> {
+        // Only recent GitLab versions send an unique ID with each delivery
+        if let Request::Web(ref req) = *request {
+            req.headers.get("X-Gitlab-Event-UUID").cloned()
+        } else {
+            None
+        }
+    }
+}
+
+
+/// Return the branch an event is about, if any.
+fn payload_branch(payload: &serde_json::Value) -> Option<&str> {
+    // Pushes contain the full ref
+    if let Some(reference) = payload["ref"].as_str() {
+        if reference.starts_with("refs/heads/") {
+            return Some(&reference[11..]);
+        } else if reference.starts_with("refs/") {
+            return None;
+        }
+    }
+
+    let attrs = &payload["object_attributes"];
+
+    // Merge requests are about their target branch
+    if let Some(branch) = attrs["target_branch"].as_str() {
+        return Some(branch);
+    }
+
+    // Pipelines and builds contain just the name of the ref
+    let is_tag = attrs["tag"].as_bool().or_else(|| payload["tag"].as_bool());
+    if is_tag == Some(true) {
+        return None;
+    }
+    attrs["ref"].as_str().or_else(|| payload["ref"].as_str())
 }
 
 
@@ -144,7 +222,7 @@ mod tests {
     use utils::testing::*;
     use requests::{Request, RequestType};
     use web::WebRequest;
-    use providers::ProviderTrait;
+    use providers::{Provider, ProviderTrait};
 
     use super::{normalize_event_name, GitLabProvider, GITLAB_EVENTS};
 
@@ -168,6 +246,8 @@ mod tests {
             r#"{"secret": "abcde"}"#,
             r#"{"events": ["Push", "Issue"]}"#,
             r#"{"secret": "abcde", "events": ["Push", "Issue"]}"#,
+            r#"{"events": ["* Push"], "branches": ["master"]}"#,
+            r#"{"branches": ["master", "release/*"]}"#,
         ] {
             assert!(GitLabProvider::new(right).is_ok(), right.to_string());
         }
@@ -182,6 +262,9 @@ mod tests {
             r#"{"events": [12345]}"#,
             r#"{"events": [true]}"#,
             r#"{"events": ["invalid_event"]}"#,
+            r#"{"events": ["Invalid*"]}"#,
+            r#"{"branches": "master"}"#,
+            r#"{"branches": [12345]}"#,
         ] {
             assert!(GitLabProvider::new(wrong).is_err(), wrong.to_string());
         }
@@ -264,6 +347,20 @@ mod tests {
         req.headers
             .insert("X-Gitlab-Token".to_string(), "abcde".to_string());
         assert_eq!(provider.validate(&req.into()), RequestType::ExecuteHook);
+
+        // Check a request while rotating the secret
+        let config = r#"{"secret": ["12345", "abcde"]}"#;
+        let provider = GitLabProvider::new(config).unwrap();
+        for &(token, expected) in &[
+            ("12345", RequestType::ExecuteHook),
+            ("abcde", RequestType::ExecuteHook),
+            ("fghij", RequestType::Invalid),
+        ] {
+            let mut req = base_request();
+            req.headers
+                .insert("X-Gitlab-Token".to_string(), token.to_string());
+            assert_eq!(provider.validate(&req.into()), expected);
+        }
     }
 
 
@@ -291,6 +388,17 @@ mod tests {
             RequestType::Invalid
         );
 
+        // With a glob in the list of allowed events
+        let provider = GitLabProvider::new(r#"{"events": ["*Push"]}"#).unwrap();
+        assert_eq!(
+            provider.validate(&with_event("Tag Push Hook")),
+            RequestType::ExecuteHook
+        );
+        assert_eq!(
+            provider.validate(&with_event("Issue Hook")),
+            RequestType::Invalid
+        );
+
         // Without a list of allowed events
         let provider = GitLabProvider::new("{}").unwrap();
         assert_eq!(
@@ -308,20 +416,97 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_validate_branches() {
+        let config = r#"{"branches": ["master", "release/*"]}"#;
+        let provider = GitLabProvider::new(config).unwrap();
+
+        fn with_body(event: &str, body: &str) -> Request {
+            let mut base = base_request();
+            base.body = body.to_string();
+            base.headers
+                .insert("X-Gitlab-Event".to_string(), event.to_string());
+
+            Request::Web(base)
+        }
+
+        let mr_master = r#"{"object_attributes": {"target_branch": "master"}}"#;
+        let mr_dev = r#"{"object_attributes": {"target_branch": "dev"}}"#;
+        let pipeline = r#"{"object_attributes": {"ref": "master"}}"#;
+        let pipeline_tag =
+            r#"{"object_attributes": {"ref": "v1", "tag": true}}"#;
+
+        for &(event, body, expected) in &[
+            ("Push Hook", r#"{"ref": "refs/heads/master"}"#, true),
+            ("Push Hook", r#"{"ref": "refs/heads/release/1.0"}"#, true),
+            ("Push Hook", r#"{"ref": "refs/heads/feature"}"#, false),
+            ("Tag Push Hook", r#"{"ref": "refs/tags/master"}"#, false),
+            ("Merge Request Hook", mr_master, true),
+            ("Merge Request Hook", mr_dev, false),
+            ("Pipeline Hook", pipeline, true),
+            ("Pipeline Hook", pipeline_tag, false),
+            ("Build Hook", r#"{"ref": "master", "tag": false}"#, true),
+            ("Issue Hook", r#"{}"#, false),
+        ] {
+            let expected = if expected {
+                RequestType::ExecuteHook
+            } else {
+                RequestType::Invalid
+            };
+            assert_eq!(
+                provider.validate(&with_body(event, body)),
+                expected,
+                "{} {}", event, body
+            );
+        }
+    }
+
+
     #[test]
     fn test_env() {
         let mut expected = HashMap::new();
         expected.insert("EVENT".to_string(), "Push".to_string());
+        for key in &["BRANCH", "REF", "SHA", "REPO", "USER"] {
+            expected.insert(key.to_string(), String::new());
+        }
 
         let mut req = base_request();
         req.headers
             .insert("X-Gitlab-Event".to_string(), "Push Hook".to_string());
 
-        let provider = GitLabProvider::new("{}").unwrap();
+        let provider = Provider::new("GitLab", "{}").unwrap();
         assert_eq!(provider.env(&req.into()), expected);
     }
 
 
+    #[test]
+    fn test_env_payload() {
+        let mut req = base_request();
+        req.headers.insert(
+            "X-Gitlab-Event".to_string(),
+            "Merge Request Hook".to_string(),
+        );
+        req.body = r#"{
+            "user": {"username": "pietroalbini"},
+            "project": {"path_with_namespace": "pietroalbini/fisher"},
+            "object_attributes": {
+                "target_branch": "master",
+                "last_commit": {"id": "da1560886d4f094c3e6c9ef40349f7d3"}
+            }
+        }"#.to_string();
+
+        let provider = Provider::new("GitLab", "{}").unwrap();
+        let env = provider.env(&req.into());
+
+        assert_eq!(env["EVENT"], "Merge Request");
+        assert_eq!(env["BRANCH"], "master");
+        assert_eq!(env["REF"], "");
+        assert_eq!(env["SHA"], "da1560886d4f094c3e6c9ef40349f7d3");
+        assert_eq!(env["REPO"], "pietroalbini/fisher");
+        assert_eq!(env["USER"], "pietroalbini");
+    }
+
+
     #[test]
     fn test_normalize_event_name() {
         assert_eq!(normalize_event_name("Push"), "Push");
@@ -0,0 +1,361 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use serde_json;
+
+use providers::prelude::*;
+use utils;
+use common::prelude::*;
+
+
+lazy_static! {
+    static ref HARBOR_EVENTS: Vec<&'static str> = vec![
+        "PUSH_ARTIFACT", "PULL_ARTIFACT", "DELETE_ARTIFACT",
+        "SCANNING_COMPLETED", "SCANNING_FAILED", "SCANNING_STOPPED",
+        "QUOTA_EXCEED", "QUOTA_WARNING", "REPLICATION", "TAG_RETENTION",
+    ];
+}
+
+
+#[derive(Debug, Deserialize)]
+struct HarborPayload {
+    #[serde(rename = "type")]
+    kind: String,
+    operator: Option<String>,
+    event_data: HarborEventData,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct HarborEventData {
+    #[serde(default)]
+    resources: Vec<HarborResource>,
+    repository: Option<HarborRepository>,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct HarborResource {
+    digest: Option<String>,
+    tag: Option<String>,
+    resource_url: Option<String>,
+    scan_overview: Option<HashMap<String, HarborScanReport>>,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct HarborRepository {
+    repo_full_name: Option<String>,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct HarborScanReport {
+    scan_status: Option<String>,
+    severity: Option<String>,
+    summary: Option<HarborScanSummary>,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct HarborScanSummary {
+    total: Option<u64>,
+    fixable: Option<u64>,
+}
+
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HarborProvider {
+    secret: Option<utils::Secrets>,
+    events: Option<Vec<String>>,
+}
+
+impl ProviderTrait for HarborProvider {
+    fn new(config: &str) -> Result<Self> {
+        let inst: HarborProvider = serde_json::from_str(config)?;
+
+        if let Some(ref events) = inst.events {
+            // Check if the events exists
+            for event in events {
+                if !HARBOR_EVENTS.contains(&event.as_ref()) {
+                    // Return an error if the event doesn't exist
+                    return Err(
+                        ErrorKind::InvalidInput(
+                            format!(r#""{}" is not a Harbor event"#, event),
+                        ).into(),
+                    );
+                }
+            }
+        }
+
+        Ok(inst)
+    }
+
+    fn validate(&self, request: &Request) -> RequestType {
+        let req;
+        if let Request::Web(ref inner) = *request {
+            req = inner;
+        } else {
+            return RequestType::Invalid;
+        }
+
+        // Harbor sends the configured auth header as-is
+        if let Some(ref secrets) = self.secret {
+            if let Some(header) = req.headers.get("Authorization") {
+                if !secrets.contains(header) {
+                    return RequestType::Invalid;
+                }
+            } else {
+                return RequestType::Invalid;
+            }
+        }
+
+        // The body must be a valid Harbor payload
+        let payload: HarborPayload = match serde_json::from_str(&req.body) {
+            Ok(payload) => payload,
+            Err(..) => return RequestType::Invalid,
+        };
+
+        // Check if the event is valid
+        if !HARBOR_EVENTS.contains(&payload.kind.as_ref()) {
+            return RequestType::Invalid;
+        }
+
+        // Check if the event should be accepted
+        if let Some(ref events) = self.events {
+            if !events.contains(&payload.kind) {
+                return RequestType::Invalid;
+            }
+        }
+
+        RequestType::ExecuteHook
+    }
+
+    fn env(&self, request: &Request) -> HashMap<String, String> {
+        let mut res = HashMap::new();
+
+        let req;
+        if let Request::Web(ref inner) = *request {
+            req = inner;
+        } else {
+            return res;
+        }
+
+        let payload: HarborPayload = match serde_json::from_str(&req.body) {
+            Ok(payload) => payload,
+            Err(..) => return res,
+        };
+
+        macro_rules! set {
+            ($key:expr, $value:expr) => {
+                res.insert($key.to_string(), $value.unwrap_or_default());
+            };
+        }
+
+        set!("EVENT", Some(payload.kind));
+        set!("OPERATOR", payload.operator);
+        set!(
+            "REPOSITORY",
+            payload.event_data.repository.and_then(|r| r.repo_full_name)
+        );
+
+        // Only the first resource is exposed, since Harbor sends a separate
+        // webhook for each artifact
+        let resource = payload.event_data.resources.into_iter().next();
+        let (tag, digest, url, scan) = match resource {
+            Some(res) => (
+                res.tag,
+                res.digest,
+                res.resource_url,
+                res.scan_overview.and_then(|o| o.into_iter().next()),
+            ),
+            None => (None, None, None, None),
+        };
+
+        set!("TAG", tag);
+        set!("DIGEST", digest);
+        set!("RESOURCE_URL", url);
+
+        // Expose a summary of the vulnerability scan, if present
+        if let Some((_, report)) = scan {
+            let summary = report.summary;
+            set!("SCAN_STATUS", report.scan_status);
+            set!("SCAN_SEVERITY", report.severity);
+            set!(
+                "SCAN_TOTAL",
+                summary.as_ref().and_then(|s| s.total).map(|t| t.to_string())
+            );
+            set!(
+                "SCAN_FIXABLE",
+                summary.as_ref().and_then(|s| s.fixable).map(|f| f.to_string())
+            );
+        }
+
+        res
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use utils::testing::*;
+    use requests::RequestType;
+    use providers::ProviderTrait;
+    use web::WebRequest;
+
+    use super::{HarborProvider, HARBOR_EVENTS};
+
+
+    fn request(kind: &str) -> WebRequest {
+        let mut req = dummy_web_request();
+        req.body = format!(r#"{{
+            "type": "{}",
+            "occur_at": 1586922308,
+            "operator": "admin",
+            "event_data": {{
+                "resources": [{{
+                    "digest": "sha256:abcdef",
+                    "tag": "latest",
+                    "resource_url": "harbor.example.com/library/app:latest",
+                    "scan_overview": {{
+                        "application/vnd.scanner.adapter.vuln.report": {{
+                            "scan_status": "Success",
+                            "severity": "High",
+                            "summary": {{"total": 12, "fixable": 5}}
+                        }}
+                    }}
+                }}],
+                "repository": {{
+                    "name": "app",
+                    "namespace": "library",
+                    "repo_full_name": "library/app"
+                }}
+            }}
+        }}"#, kind);
+
+        req
+    }
+
+
+    #[test]
+    fn test_new() {
+        for right in &[
+            r#"{}"#,
+            r#"{"secret": "Bearer abcde"}"#,
+            r#"{"events": ["PUSH_ARTIFACT", "SCANNING_COMPLETED"]}"#,
+            r#"{"secret": "abcde", "events": ["PUSH_ARTIFACT"]}"#,
+        ] {
+            assert!(HarborProvider::new(right).is_ok(), right.to_string());
+        }
+
+        for wrong in &[
+            r#"{"secret": 12345}"#,
+            r#"{"secret": true}"#,
+            r#"{"events": 12345}"#,
+            r#"{"events": [true]}"#,
+            r#"{"events": ["invalid_event"]}"#,
+            r#"{"events": ["push_artifact"]}"#,
+        ] {
+            assert!(HarborProvider::new(wrong).is_err(), wrong.to_string());
+        }
+    }
+
+
+    #[test]
+    fn test_validate_basic() {
+        let provider = HarborProvider::new("{}").unwrap();
+
+        for event in HARBOR_EVENTS.iter() {
+            assert_eq!(
+                provider.validate(&request(event).into()),
+                RequestType::ExecuteHook
+            );
+        }
+
+        // Unknown events are rejected
+        assert_eq!(
+            provider.validate(&request("SOMETHING_ELSE").into()),
+            RequestType::Invalid
+        );
+
+        // Invalid payloads are rejected
+        assert_eq!(
+            provider.validate(&dummy_web_request().into()),
+            RequestType::Invalid
+        );
+    }
+
+
+    #[test]
+    fn test_validate_secret() {
+        let provider =
+            HarborProvider::new(r#"{"secret": "Bearer abcde"}"#).unwrap();
+
+        // Without the header
+        assert_eq!(
+            provider.validate(&request("PUSH_ARTIFACT").into()),
+            RequestType::Invalid
+        );
+
+        // With the wrong header
+        let mut req = request("PUSH_ARTIFACT");
+        req.headers.insert("Authorization".into(), "Bearer 12345".into());
+        assert_eq!(provider.validate(&req.into()), RequestType::Invalid);
+
+        // With the right header
+        let mut req = request("PUSH_ARTIFACT");
+        req.headers.insert("Authorization".into(), "Bearer abcde".into());
+        assert_eq!(provider.validate(&req.into()), RequestType::ExecuteHook);
+    }
+
+
+    #[test]
+    fn test_validate_events() {
+        let provider =
+            HarborProvider::new(r#"{"events": ["PUSH_ARTIFACT"]}"#).unwrap();
+
+        assert_eq!(
+            provider.validate(&request("PUSH_ARTIFACT").into()),
+            RequestType::ExecuteHook
+        );
+        assert_eq!(
+            provider.validate(&request("PULL_ARTIFACT").into()),
+            RequestType::Invalid
+        );
+    }
+
+
+    #[test]
+    fn test_env() {
+        let provider = HarborProvider::new("{}").unwrap();
+        let env = provider.env(&request("SCANNING_COMPLETED").into());
+
+        assert_eq!(env.len(), 10);
+        assert_eq!(env["EVENT"], "SCANNING_COMPLETED");
+        assert_eq!(env["OPERATOR"], "admin");
+        assert_eq!(env["REPOSITORY"], "library/app");
+        assert_eq!(env["TAG"], "latest");
+        assert_eq!(env["DIGEST"], "sha256:abcdef");
+        assert_eq!(
+            env["RESOURCE_URL"],
+            "harbor.example.com/library/app:latest"
+        );
+        assert_eq!(env["SCAN_STATUS"], "Success");
+        assert_eq!(env["SCAN_SEVERITY"], "High");
+        assert_eq!(env["SCAN_TOTAL"], "12");
+        assert_eq!(env["SCAN_FIXABLE"], "5");
+    }
+}
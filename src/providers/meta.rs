@@ -0,0 +1,335 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use serde_json;
+use ring;
+
+use providers::prelude::*;
+use web::WebRequest;
+use utils;
+use common::prelude::*;
+
+
+#[derive(Debug, Deserialize)]
+struct MetaPayload {
+    object: String,
+    #[serde(default)]
+    entry: Vec<MetaEntry>,
+}
+
+impl MetaPayload {
+    fn fields(&self) -> Vec<&str> {
+        let mut fields = Vec::new();
+        for entry in &self.entry {
+            for change in &entry.changes {
+                if !fields.contains(&change.field.as_str()) {
+                    fields.push(change.field.as_str());
+                }
+            }
+        }
+
+        fields
+    }
+}
+
+
+#[derive(Debug, Deserialize)]
+struct MetaEntry {
+    #[serde(default)]
+    changes: Vec<MetaChange>,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct MetaChange {
+    field: String,
+}
+
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetaProvider {
+    secret: utils::Secrets,
+    #[serde(rename = "verify-token")]
+    verify_token: Option<String>,
+    objects: Option<Vec<String>>,
+    fields: Option<Vec<String>>,
+}
+
+impl MetaProvider {
+    fn is_handshake(&self, req: &WebRequest) -> bool {
+        // Handshakes are accepted only if a verify token is configured
+        let token = match self.verify_token {
+            Some(ref token) => token,
+            None => return false,
+        };
+
+        req.params.get("hub.mode").map(|m| m.as_str()) == Some("subscribe")
+            && req.params.get("hub.verify_token") == Some(token)
+            && req.params.contains_key("hub.challenge")
+    }
+}
+
+impl ProviderTrait for MetaProvider {
+    fn new(config: &str) -> Result<Self> {
+        Ok(serde_json::from_str(config)?)
+    }
+
+    fn validate(&self, request: &Request) -> RequestType {
+        let req;
+        if let Request::Web(ref inner) = *request {
+            req = inner;
+        } else {
+            return RequestType::Invalid;
+        }
+
+        // Meta verifies the endpoint by asking it to echo a challenge
+        if self.is_handshake(req) {
+            return RequestType::Handshake;
+        }
+
+        // Deliveries are signed with the app secret
+        let signature = match req.headers.get("X-Hub-Signature-256") {
+            Some(signature) => signature,
+            None => return RequestType::Invalid,
+        };
+        let body = req.body_bytes();
+        if !self.secret.any(|s| verify_signature(s, body, signature)) {
+            return RequestType::Invalid;
+        }
+
+        let payload: MetaPayload = match serde_json::from_str(&req.body) {
+            Ok(payload) => payload,
+            Err(..) => return RequestType::Invalid,
+        };
+
+        // Check if the object should be accepted
+        if let Some(ref objects) = self.objects {
+            if !objects.contains(&payload.object) {
+                return RequestType::Invalid;
+            }
+        }
+
+        // Check if at least one of the changed fields should be accepted
+        if let Some(ref fields) = self.fields {
+            let changed = payload.fields();
+            if !fields.iter().any(|f| changed.contains(&f.as_str())) {
+                return RequestType::Invalid;
+            }
+        }
+
+        RequestType::ExecuteHook
+    }
+
+    fn env(&self, request: &Request) -> HashMap<String, String> {
+        let mut res = HashMap::new();
+
+        let req;
+        if let Request::Web(ref inner) = *request {
+            req = inner;
+        } else {
+            return res;
+        }
+
+        let payload: MetaPayload = match serde_json::from_str(&req.body) {
+            Ok(payload) => payload,
+            Err(..) => return res,
+        };
+
+        res.insert("FIELDS".to_string(), payload.fields().join(" "));
+        res.insert("OBJECT".to_string(), payload.object);
+
+        res
+    }
+
+    fn response(
+        &self,
+        request: &Request,
+        kind: RequestType,
+    ) -> Option<ProviderResponse> {
+        if kind != RequestType::Handshake {
+            return None;
+        }
+
+        if let Request::Web(ref req) = *request {
+            req.params
+                .get("hub.challenge")
+                .map(|challenge| ProviderResponse::text(challenge.as_str()))
+        } else {
+            None
+        }
+    }
+}
+
+
+fn verify_signature(secret: &str, payload: &[u8], raw_signature: &str) -> bool {
+    // The signature must start with the algorithm
+    if !raw_signature.starts_with("sha256=") {
+        return false;
+    }
+
+    // Convert the signature from hex
+    let signature = match utils::from_hex(&raw_signature[7..]) {
+        Ok(converted) => converted,
+        // This is not hex
+        Err(..) => return false,
+    };
+
+    let key = ring::hmac::VerificationKey::new(
+        &ring::digest::SHA256, secret.as_bytes(),
+    );
+    ring::hmac::verify(&key, payload, &signature).is_ok()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use utils::testing::*;
+    use requests::RequestType;
+    use providers::{ProviderResponse, ProviderTrait};
+    use web::WebRequest;
+
+    use super::MetaProvider;
+
+
+    static BODY: &str = concat!(
+        r#"{"object": "page", "entry": [{"id": "1234", "time": 1500000000, "#,
+        r#""changes": [{"field": "feed", "value": {}}, "#,
+        r#"{"field": "mention", "value": {}}]}]}"#,
+    );
+
+    static SIGNATURE: &str = concat!(
+        "sha256=afcce43aa691a13dd67f397c5ee406a5",
+        "7863354b367765f5bbe20a36c73d4320",
+    );
+
+
+    fn delivery(signature: &str) -> WebRequest {
+        let mut req = dummy_web_request();
+        req.body = BODY.into();
+        req.headers.insert("X-Hub-Signature-256".into(), signature.into());
+        req
+    }
+
+
+    fn handshake(token: &str) -> WebRequest {
+        let mut req = dummy_web_request();
+        req.params.insert("hub.mode".into(), "subscribe".into());
+        req.params.insert("hub.verify_token".into(), token.into());
+        req.params.insert("hub.challenge".into(), "1158201444".into());
+        req
+    }
+
+
+    #[test]
+    fn test_new() {
+        for right in &[
+            r#"{"secret": "abcde"}"#,
+            r#"{"secret": "abcde", "verify-token": "12345"}"#,
+            r#"{"secret": "abcde", "objects": ["page"], "fields": ["feed"]}"#,
+        ] {
+            assert!(MetaProvider::new(right).is_ok(), right.to_string());
+        }
+
+        for wrong in &[
+            r#"{}"#,
+            r#"{"secret": 12345}"#,
+            r#"{"secret": "abcde", "objects": "page"}"#,
+            r#"{"secret": "abcde", "fields": [true]}"#,
+        ] {
+            assert!(MetaProvider::new(wrong).is_err(), wrong.to_string());
+        }
+    }
+
+
+    #[test]
+    fn test_handshake() {
+        let provider = MetaProvider::new(
+            r#"{"secret": "secret", "verify-token": "token"}"#
+        ).unwrap();
+
+        let req = handshake("token").into();
+        assert_eq!(provider.validate(&req), RequestType::Handshake);
+        assert_eq!(
+            provider.response(&req, RequestType::Handshake),
+            Some(ProviderResponse::text("1158201444"))
+        );
+
+        // The verify token must match
+        assert_eq!(
+            provider.validate(&handshake("wrong").into()),
+            RequestType::Invalid
+        );
+
+        // Handshakes are rejected without a verify token
+        let provider = MetaProvider::new(r#"{"secret": "secret"}"#).unwrap();
+        assert_eq!(
+            provider.validate(&handshake("token").into()),
+            RequestType::Invalid
+        );
+    }
+
+
+    #[test]
+    fn test_validate_signature() {
+        let provider = MetaProvider::new(r#"{"secret": "secret"}"#).unwrap();
+
+        assert_eq!(
+            provider.validate(&delivery(SIGNATURE).into()),
+            RequestType::ExecuteHook
+        );
+
+        for wrong in &["", "sha256=12345", "sha1=12345", "sha256=not hex"] {
+            assert_eq!(
+                provider.validate(&delivery(wrong).into()),
+                RequestType::Invalid
+            );
+        }
+
+        assert_eq!(
+            provider.validate(&dummy_web_request().into()),
+            RequestType::Invalid
+        );
+    }
+
+
+    #[test]
+    fn test_validate_filters() {
+        for &(config, result) in &[
+            (r#""objects": ["page"]"#, RequestType::ExecuteHook),
+            (r#""objects": ["user"]"#, RequestType::Invalid),
+            (r#""fields": ["feed"]"#, RequestType::ExecuteHook),
+            (r#""fields": ["email", "mention"]"#, RequestType::ExecuteHook),
+            (r#""fields": ["email"]"#, RequestType::Invalid),
+        ] {
+            let provider = MetaProvider::new(
+                &format!(r#"{{"secret": "secret", {}}}"#, config),
+            ).unwrap();
+
+            assert_eq!(provider.validate(&delivery(SIGNATURE).into()), result);
+        }
+    }
+
+
+    #[test]
+    fn test_env() {
+        let provider = MetaProvider::new(r#"{"secret": "secret"}"#).unwrap();
+        let env = provider.env(&delivery(SIGNATURE).into());
+
+        assert_eq!(env.len(), 2);
+        assert_eq!(env["OBJECT"], "page");
+        assert_eq!(env["FIELDS"], "feed mention");
+    }
+}
@@ -13,12 +13,26 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+mod payload;
+mod response;
 mod status;
 mod standalone;
 #[cfg(feature = "provider-github")]
 mod github;
 #[cfg(feature = "provider-gitlab")]
 mod gitlab;
+#[cfg(feature = "provider-harbor")]
+mod harbor;
+#[cfg(feature = "provider-dropbox")]
+mod dropbox;
+#[cfg(feature = "provider-exec")]
+mod exec;
+#[cfg(feature = "provider-meta")]
+mod meta;
+#[cfg(feature = "provider-netlify")]
+mod netlify;
+#[cfg(feature = "provider-pubsub")]
+mod pubsub;
 #[cfg(test)]
 pub mod testing;
 
@@ -29,23 +43,93 @@ pub mod prelude {
     pub use std::path::PathBuf;
 
     pub use providers::ProviderTrait;
+    pub use providers::payload::PayloadField;
+    pub use providers::response::ProviderResponse;
     pub use requests::{Request, RequestType};
     pub use common::prelude::*;
 }
 
 
 pub use self::status::{StatusEvent, StatusEventKind, StatusProvider};
+pub use self::status::HeartbeatMissed;
+pub use self::payload::{payload_env, xml_payload_env, PayloadField};
+pub use self::response::ProviderResponse;
 
 
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::RwLock;
 
 use requests::{Request, RequestType};
 use common::prelude::*;
+use self::payload::request_payload_env;
 
 
-/// This trait should be implemented by every Fisher provider
-/// The objects implementing this trait must also implement Clone and Debug
+/// A provider registered at runtime with
+/// [`register_provider`](fn.register_provider.html).
+type CustomProvider = Box<ProviderTrait + Send + Sync>;
+
+/// A function creating a custom provider from its configuration string.
+type ProviderFactory = fn(&str) -> Result<CustomProvider>;
+
+
+lazy_static! {
+    static ref CUSTOM_PROVIDERS: RwLock<HashMap<String, ProviderFactory>> =
+        RwLock::new(HashMap::new());
+}
+
+
+fn custom_factory<P: ProviderTrait + Send + Sync + 'static>(
+    config: &str,
+) -> Result<CustomProvider> {
+    Ok(Box::new(P::new(config)?))
+}
+
+
+/// Names used by configuration comments which aren't providers.
+static RESERVED_NAMES: &[&str] = &[
+    "RequireAll", "RateLimit", "IpFilter", "Route", "Concurrency",
+    "Coalesce", "Schedule", "Env",
+];
+
+
+/// Register a custom provider, allowing hooks to use it with the
+/// `## Fisher-Name: {config}` comment. The name must contain only ASCII
+/// letters, and it must not clash with the name of another provider.
+///
+/// Providers should be registered before starting Fisher: hooks using a
+/// provider which isn't registered yet will fail to load.
+pub fn register_provider<P: ProviderTrait + Send + Sync + 'static>(
+    name: &str,
+) -> Result<()> {
+    // The name must be matchable by the configuration comment
+    let valid = !name.is_empty() && name.chars().all(|c| match c {
+        'a'...'z' | 'A'...'Z' => true,
+        _ => false,
+    });
+    if !valid {
+        return Err(ErrorKind::InvalidInput(
+            format!("invalid provider name: {}", name),
+        ).into());
+    }
+
+    // Some names are reserved for other configuration comments
+    let mut providers = CUSTOM_PROVIDERS.write()?;
+    let reserved =
+        Provider::is_builtin(name) || RESERVED_NAMES.contains(&name);
+    if reserved || providers.contains_key(name) {
+        return Err(ErrorKind::ProviderAlreadyRegistered(name.into()).into());
+    }
+    providers.insert(name.to_string(), custom_factory::<P>);
+
+    Ok(())
+}
+
+
+/// This trait should be implemented by every Fisher provider, including the
+/// custom ones registered with
+/// [`register_provider`](fn.register_provider.html)
+/// The objects implementing this trait must also implement Debug
 pub trait ProviderTrait: ::std::fmt::Debug {
     /// This method should create a new instance of the provider, from a
     /// given configuration string
@@ -57,10 +141,29 @@ pub trait ProviderTrait: ::std::fmt::Debug {
     /// type if the request is valid
     fn validate(&self, &Request) -> RequestType;
 
+    /// This method should validate an incoming request like `validate`, also
+    /// returning the environment variables of the job if computing them is
+    /// part of the validation. Those variables are then used instead of the
+    /// ones returned by `env`. You're not required to implement this method
+    fn validate_with_env(
+        &self,
+        req: &Request,
+    ) -> (RequestType, Option<HashMap<String, String>>) {
+        (self.validate(req), None)
+    }
+
     /// This method should provide the environment variables of the provided
     /// request. Those variables will be passed to the process
     fn env(&self, &Request) -> HashMap<String, String>;
 
+    /// This method should return the fields of the JSON or XML payload
+    /// exported as environment variables, along with the ones returned by
+    /// `env` (which win if the names clash). You're not required to
+    /// implement this method
+    fn payload_fields(&self) -> &[PayloadField] {
+        &[]
+    }
+
     /// This method should prepare the directory in which the hook will be run.
     /// This means, if you want to add extra files in there you should use
     /// this. You're not required to implement this method
@@ -68,6 +171,34 @@ pub trait ProviderTrait: ::std::fmt::Debug {
         Ok(())
     }
 
+    /// This method should return the HTTP response sent back for a valid
+    /// request, if the service expects a specific one (for example the echo
+    /// of a challenge during an handshake). If it returns None the generic
+    /// response is sent instead
+    fn response(
+        &self,
+        _req: &Request,
+        _kind: RequestType,
+    ) -> Option<ProviderResponse> {
+        None
+    }
+
+    /// This method should return the unique identifier the service assigned
+    /// to the delivery, if any. Deliveries with an identifier already seen
+    /// recently are considered redeliveries and are not executed again
+    fn delivery_id(&self, _req: &Request) -> Option<String> {
+        None
+    }
+
+    /// This method should return the priority of the job started by the
+    /// request, if the service is allowed to choose it. Since this allows
+    /// requests to jump ahead of the queue, only providers authenticating
+    /// the requests should return it. If it returns None the priority of the
+    /// hook is used instead
+    fn priority(&self, _req: &Request) -> Option<isize> {
+        None
+    }
+
     /// This method tells the scheduler if the hook should trigger status hooks
     /// after the request is processed. By default this returns true, change it
     /// only if you really know what you're doing
@@ -86,6 +217,7 @@ macro_rules! ProviderEnum {
                 #[cfg($cfg)]
                 $name($provider),
             )*
+            Custom(String, CustomProvider),
         }
 
         impl Provider {
@@ -102,9 +234,28 @@ macro_rules! ProviderEnum {
                             }
                         },
                     )*
-                    _ => Err(
-                        ErrorKind::ProviderNotFound(name.to_string()).into()
-                    ),
+                    _ => {
+                        let factory = CUSTOM_PROVIDERS.read()?
+                            .get(name)
+                            .cloned();
+                        if let Some(factory) = factory {
+                            Ok(Provider::Custom(name.into(), factory(config)?))
+                        } else {
+                            Err(ErrorKind::ProviderNotFound(
+                                name.to_string()
+                            ).into())
+                        }
+                    },
+                }
+            }
+
+            fn is_builtin(name: &str) -> bool {
+                match name {
+                    $(
+                        #[cfg($cfg)]
+                        stringify!($name) => true,
+                    )*
+                    _ => false,
                 }
             }
 
@@ -116,20 +267,67 @@ macro_rules! ProviderEnum {
                             (prov as &ProviderTrait).validate(req)
                         },
                     )*
+                    Provider::Custom(_, ref prov) => prov.validate(req),
                 }
             }
 
+            pub fn validate_with_env(
+                &self,
+                req: &Request,
+            ) -> (RequestType, Option<HashMap<String, String>>) {
+                let (result, env) = match *self {
+                    $(
+                        #[cfg($cfg)]
+                        Provider::$name(ref prov) => {
+                            (prov as &ProviderTrait).validate_with_env(req)
+                        },
+                    )*
+                    Provider::Custom(_, ref prov) => {
+                        prov.validate_with_env(req)
+                    },
+                };
+
+                (result, env.map(|env| self.with_payload_env(req, env)))
+            }
+
             pub fn env(&self, req: &Request) -> HashMap<String, String> {
-                match *self {
+                let env = match *self {
                     $(
                         #[cfg($cfg)]
                         Provider::$name(ref prov) => {
                             (prov as &ProviderTrait).env(req)
                         },
                     )*
+                    Provider::Custom(_, ref prov) => prov.env(req),
+                };
+
+                self.with_payload_env(req, env)
+            }
+
+            pub fn payload_fields(&self) -> &[PayloadField] {
+                match *self {
+                    $(
+                        #[cfg($cfg)]
+                        Provider::$name(ref prov) => {
+                            (prov as &ProviderTrait).payload_fields()
+                        },
+                    )*
+                    Provider::Custom(_, ref prov) => prov.payload_fields(),
                 }
             }
 
+            /// Add the payload fields exported by the provider to its
+            /// environment variables.
+            fn with_payload_env(
+                &self,
+                req: &Request,
+                env: HashMap<String, String>,
+            ) -> HashMap<String, String> {
+                let mut res = request_payload_env(req, self.payload_fields());
+                res.extend(env);
+                res
+            }
+
             pub fn prepare_directory(&self, req: &Request, path: &PathBuf)
                                     -> Result<()> {
                 match *self {
@@ -140,6 +338,51 @@ macro_rules! ProviderEnum {
                                 .prepare_directory(req, path)
                         },
                     )*
+                    Provider::Custom(_, ref prov) => {
+                        prov.prepare_directory(req, path)
+                    },
+                }
+            }
+
+            pub fn response(
+                &self,
+                req: &Request,
+                kind: RequestType,
+            ) -> Option<ProviderResponse> {
+                match *self {
+                    $(
+                        #[cfg($cfg)]
+                        Provider::$name(ref prov) => {
+                            (prov as &ProviderTrait).response(req, kind)
+                        }
+                    )*
+                    Provider::Custom(_, ref prov) => {
+                        prov.response(req, kind)
+                    }
+                }
+            }
+
+            pub fn delivery_id(&self, req: &Request) -> Option<String> {
+                match *self {
+                    $(
+                        #[cfg($cfg)]
+                        Provider::$name(ref prov) => {
+                            (prov as &ProviderTrait).delivery_id(req)
+                        }
+                    )*
+                    Provider::Custom(_, ref prov) => prov.delivery_id(req),
+                }
+            }
+
+            pub fn priority(&self, req: &Request) -> Option<isize> {
+                match *self {
+                    $(
+                        #[cfg($cfg)]
+                        Provider::$name(ref prov) => {
+                            (prov as &ProviderTrait).priority(req)
+                        }
+                    )*
+                    Provider::Custom(_, ref prov) => prov.priority(req),
                 }
             }
 
@@ -151,6 +394,9 @@ macro_rules! ProviderEnum {
                             (prov as &ProviderTrait).trigger_status_hooks(req)
                         }
                     )*
+                    Provider::Custom(_, ref prov) => {
+                        prov.trigger_status_hooks(req)
+                    }
                 }
             }
 
@@ -160,6 +406,7 @@ macro_rules! ProviderEnum {
                         #[cfg($cfg)]
                         Provider::$name(..) => stringify!($name),
                     )*
+                    Provider::Custom(ref name, _) => name,
                 }
             }
         }
@@ -172,5 +419,99 @@ ProviderEnum! {
     any(test, not(test)) | Status => self::status::StatusProvider,
     feature="provider-github" | GitHub => self::github::GitHubProvider,
     feature="provider-gitlab" | GitLab => self::gitlab::GitLabProvider,
+    feature="provider-harbor" | Harbor => self::harbor::HarborProvider,
+    feature="provider-dropbox" | Dropbox => self::dropbox::DropboxProvider,
+    feature="provider-exec" | Exec => self::exec::ExecProvider,
+    feature="provider-meta" | Meta => self::meta::MetaProvider,
+    feature="provider-netlify" | Netlify => self::netlify::NetlifyProvider,
+    feature="provider-pubsub" | PubSub => self::pubsub::PubSubProvider,
     test | Testing => self::testing::TestingProvider
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use common::prelude::*;
+    use requests::{Request, RequestType};
+    use utils::testing::*;
+
+    use super::{register_provider, PayloadField, Provider, ProviderTrait};
+
+
+    #[derive(Debug)]
+    struct CustomProvider {
+        fields: Vec<PayloadField>,
+    }
+
+    impl ProviderTrait for CustomProvider {
+        fn new(config: &str) -> Result<Self> {
+            if config == "fail" {
+                Err(ErrorKind::InvalidInput("failed".into()).into())
+            } else {
+                Ok(CustomProvider {
+                    fields: vec![
+                        PayloadField::new("NAME", &["/name"]),
+                        PayloadField::new("CUSTOM", &["/custom"]),
+                    ],
+                })
+            }
+        }
+
+        fn validate(&self, _req: &Request) -> RequestType {
+            RequestType::Ping
+        }
+
+        fn env(&self, _req: &Request) -> HashMap<String, String> {
+            let mut env = HashMap::new();
+            env.insert("CUSTOM".into(), "yes".into());
+            env
+        }
+
+        fn payload_fields(&self) -> &[PayloadField] {
+            &self.fields
+        }
+    }
+
+
+    #[test]
+    fn test_register_provider() {
+        register_provider::<CustomProvider>("CustomTesting").unwrap();
+
+        // Names can't be registered twice, or clash with builtin providers
+        let names = &[
+            "CustomTesting", "Standalone", "Status", "RequireAll", "RateLimit",
+            "IpFilter",
+        ];
+        for name in names {
+            assert!(register_provider::<CustomProvider>(name).is_err());
+        }
+
+        // Names must be usable in configuration comments
+        for name in &["", "Custom-Testing", "Custom1"] {
+            assert!(register_provider::<CustomProvider>(name).is_err());
+        }
+
+        // The registered provider can be used by hooks
+        let provider = Provider::new("CustomTesting", "{}").unwrap();
+        let req = dummy_web_request().into();
+        assert_eq!(provider.name(), "CustomTesting");
+        assert_eq!(provider.validate(&req), RequestType::Ping);
+        assert_eq!(provider.env(&req)["CUSTOM"], "yes");
+
+        // The payload fields are exported, but the provider's variables win
+        let mut req = dummy_web_request();
+        req.body = r#"{"name": "fisher", "custom": "no"}"#.into();
+        let env = provider.env(&req.into());
+        assert_eq!(env.len(), 2);
+        assert_eq!(env["NAME"], "fisher");
+        assert_eq!(env["CUSTOM"], "yes");
+
+        // Configuration errors are returned
+        assert!(Provider::new("CustomTesting", "fail").is_err());
+
+        // Unknown providers are still rejected
+        assert!(Provider::new("CustomMissing", "{}").is_err());
+    }
+}
@@ -0,0 +1,325 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use serde::de::DeserializeOwned;
+use serde_json;
+use ring;
+
+use providers::prelude::*;
+use utils;
+use common::prelude::*;
+
+
+lazy_static! {
+    static ref NETLIFY_STATES: Vec<&'static str> = vec![
+        "new", "enqueued", "building", "uploading", "uploaded", "preparing",
+        "prepared", "processing", "processed", "ready", "error", "retrying",
+    ];
+}
+
+
+#[derive(Debug, Deserialize)]
+struct NetlifyPayload {
+    id: String,
+    name: Option<String>,
+    state: String,
+    branch: Option<String>,
+    deploy_url: Option<String>,
+    deploy_ssl_url: Option<String>,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct TokenHeader {
+    alg: String,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct TokenClaims {
+    iss: String,
+    sha256: String,
+}
+
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetlifyProvider {
+    secret: utils::Secrets,
+    states: Option<Vec<String>>,
+}
+
+impl ProviderTrait for NetlifyProvider {
+    fn new(config: &str) -> Result<Self> {
+        let inst: NetlifyProvider = serde_json::from_str(config)?;
+
+        if let Some(ref states) = inst.states {
+            // Check if the states exists
+            for state in states {
+                if !NETLIFY_STATES.contains(&state.as_ref()) {
+                    // Return an error if the state doesn't exist
+                    return Err(
+                        ErrorKind::InvalidInput(format!(
+                            r#""{}" is not a Netlify deploy state"#, state,
+                        )).into(),
+                    );
+                }
+            }
+        }
+
+        Ok(inst)
+    }
+
+    fn validate(&self, request: &Request) -> RequestType {
+        let req;
+        if let Request::Web(ref inner) = *request {
+            req = inner;
+        } else {
+            return RequestType::Invalid;
+        }
+
+        // Deliveries are signed with a JWS containing the body's hash
+        let token = match req.headers.get("X-Webhook-Signature") {
+            Some(token) => token,
+            None => return RequestType::Invalid,
+        };
+        let body = req.body_bytes();
+        if !self.secret.any(|s| verify_token(s, body, token)) {
+            return RequestType::Invalid;
+        }
+
+        let payload: NetlifyPayload = match serde_json::from_str(&req.body) {
+            Ok(payload) => payload,
+            Err(..) => return RequestType::Invalid,
+        };
+
+        // Check if the deploy state should be accepted
+        if let Some(ref states) = self.states {
+            if !states.contains(&payload.state) {
+                return RequestType::Invalid;
+            }
+        }
+
+        RequestType::ExecuteHook
+    }
+
+    fn env(&self, request: &Request) -> HashMap<String, String> {
+        let mut res = HashMap::new();
+
+        let req;
+        if let Request::Web(ref inner) = *request {
+            req = inner;
+        } else {
+            return res;
+        }
+
+        let payload: NetlifyPayload = match serde_json::from_str(&req.body) {
+            Ok(payload) => payload,
+            Err(..) => return res,
+        };
+
+        // Prefer the HTTPS version of the deploy URL
+        let deploy_url = payload.deploy_ssl_url.or(payload.deploy_url);
+
+        res.insert("DEPLOY_ID".to_string(), payload.id);
+        res.insert("STATE".to_string(), payload.state);
+        res.insert("SITE_NAME".to_string(), payload.name.unwrap_or_default());
+        res.insert("BRANCH".to_string(), payload.branch.unwrap_or_default());
+        res.insert("DEPLOY_URL".to_string(), deploy_url.unwrap_or_default());
+
+        res
+    }
+}
+
+
+fn decode_json<T: DeserializeOwned>(input: &str) -> Option<T> {
+    match utils::from_base64(input) {
+        Ok(decoded) => serde_json::from_slice(&decoded).ok(),
+        Err(..) => None,
+    }
+}
+
+
+fn verify_token(secret: &str, body: &[u8], token: &str) -> bool {
+    let parts = token.split('.').collect::<Vec<_>>();
+    if parts.len() != 3 {
+        return false;
+    }
+
+    // Decode all the parts of the token
+    let header: TokenHeader = match decode_json(parts[0]) {
+        Some(header) => header,
+        None => return false,
+    };
+    let claims: TokenClaims = match decode_json(parts[1]) {
+        Some(claims) => claims,
+        None => return false,
+    };
+    let signature = match utils::from_base64(parts[2]) {
+        Ok(signature) => signature,
+        Err(..) => return false,
+    };
+
+    // Netlify signs its tokens only with HS256
+    if header.alg != "HS256" {
+        return false;
+    }
+
+    let key = ring::hmac::VerificationKey::new(
+        &ring::digest::SHA256, secret.as_bytes(),
+    );
+    let signed = format!("{}.{}", parts[0], parts[1]);
+    if ring::hmac::verify(&key, signed.as_bytes(), &signature).is_err() {
+        return false;
+    }
+
+    // The token must be issued by Netlify for this exact body
+    let digest = ring::digest::digest(&ring::digest::SHA256, body);
+    claims.iss == "netlify"
+        && claims.sha256.to_lowercase() == utils::to_hex(digest.as_ref())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use utils::testing::*;
+    use requests::RequestType;
+    use providers::ProviderTrait;
+    use web::WebRequest;
+
+    use super::NetlifyProvider;
+
+
+    static BODY: &str = concat!(
+        r#"{"id": "5a2b3c", "site_id": "abc123", "name": "fisher-docs", "#,
+        r#""state": "ready", "branch": "master", "#,
+        r#""deploy_url": "http://5a2b3c--fisher-docs.netlify.com", "#,
+        r#""deploy_ssl_url": "https://5a2b3c--fisher-docs.netlify.com"}"#,
+    );
+
+    static TOKEN: &str = concat!(
+        "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.",
+        "eyJpc3MiOiJuZXRsaWZ5Iiwic2hhMjU2IjoiYWY5MmU2NTZiZjE2ODFiOTNkMjRlN2Q1",
+        "YmVkYjMzM2NjZDFhNjRkMDU5ZTM0ZDdiMWRmM2ExMWRiYjg1NjFjYiJ9.",
+        "xSjXduRdLiuVJeZutNjjle3CUzk8x6-Rd7SpfOM9aDc",
+    );
+
+    static TOKEN_OTHER_BODY: &str = concat!(
+        "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.",
+        "eyJpc3MiOiJuZXRsaWZ5Iiwic2hhMjU2IjoiZDkyOThhMTBkMWIwNzM1ODM3ZGM0YmQ4",
+        "NWRhYzY0MWIwZjNjZWYyN2E0N2U1ZDUzYTU0ZjJmM2Y1YjJmY2ZmYSJ9.",
+        "bbTAHt98Yb_onnOJ8lMfDMLUySKgN7N_HWUQRoRWtVo",
+    );
+
+    static TOKEN_OTHER_ISSUER: &str = concat!(
+        "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.",
+        "eyJpc3MiOiJzb21lb25lIiwic2hhMjU2IjoiYWY5MmU2NTZiZjE2ODFiOTNkMjRlN2Q1",
+        "YmVkYjMzM2NjZDFhNjRkMDU5ZTM0ZDdiMWRmM2ExMWRiYjg1NjFjYiJ9.",
+        "iLArU4Z8Ih2vipEW-Mfgl22vwCM1qtYn5jGPVakeWoE",
+    );
+
+
+    fn notification(token: &str) -> WebRequest {
+        let mut req = dummy_web_request();
+        req.body = BODY.into();
+        req.headers.insert("X-Webhook-Signature".into(), token.into());
+        req
+    }
+
+
+    #[test]
+    fn test_new() {
+        for right in &[
+            r#"{"secret": "abcde"}"#,
+            r#"{"secret": "abcde", "states": ["ready", "error"]}"#,
+        ] {
+            assert!(NetlifyProvider::new(right).is_ok(), right.to_string());
+        }
+
+        for wrong in &[
+            r#"{}"#,
+            r#"{"secret": 12345}"#,
+            r#"{"secret": "abcde", "states": "ready"}"#,
+            r#"{"secret": "abcde", "states": ["invalid"]}"#,
+        ] {
+            assert!(NetlifyProvider::new(wrong).is_err(), wrong.to_string());
+        }
+    }
+
+
+    #[test]
+    fn test_validate_signature() {
+        let provider = NetlifyProvider::new(r#"{"secret": "secret"}"#).unwrap();
+
+        assert_eq!(
+            provider.validate(&notification(TOKEN).into()),
+            RequestType::ExecuteHook
+        );
+
+        // Tokens for another body or from another issuer are rejected
+        for wrong in &[TOKEN_OTHER_BODY, TOKEN_OTHER_ISSUER, "", "a.b.c"] {
+            assert_eq!(
+                provider.validate(&notification(wrong).into()),
+                RequestType::Invalid
+            );
+        }
+
+        // Tokens signed with another secret are rejected
+        let provider = NetlifyProvider::new(r#"{"secret": "wrong"}"#).unwrap();
+        assert_eq!(
+            provider.validate(&notification(TOKEN).into()),
+            RequestType::Invalid
+        );
+
+        // Requests without a token are rejected
+        assert_eq!(
+            provider.validate(&dummy_web_request().into()),
+            RequestType::Invalid
+        );
+    }
+
+
+    #[test]
+    fn test_validate_states() {
+        for &(config, result) in &[
+            (r#""states": ["ready"]"#, RequestType::ExecuteHook),
+            (r#""states": ["error", "ready"]"#, RequestType::ExecuteHook),
+            (r#""states": ["error"]"#, RequestType::Invalid),
+        ] {
+            let provider = NetlifyProvider::new(
+                &format!(r#"{{"secret": "secret", {}}}"#, config),
+            ).unwrap();
+
+            assert_eq!(provider.validate(&notification(TOKEN).into()), result);
+        }
+    }
+
+
+    #[test]
+    fn test_env() {
+        let provider = NetlifyProvider::new(r#"{"secret": "secret"}"#).unwrap();
+        let env = provider.env(&notification(TOKEN).into());
+
+        assert_eq!(env.len(), 5);
+        assert_eq!(env["DEPLOY_ID"], "5a2b3c");
+        assert_eq!(env["STATE"], "ready");
+        assert_eq!(env["SITE_NAME"], "fisher-docs");
+        assert_eq!(env["BRANCH"], "master");
+        assert_eq!(
+            env["DEPLOY_URL"],
+            "https://5a2b3c--fisher-docs.netlify.com"
+        );
+    }
+}
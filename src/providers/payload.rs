@@ -0,0 +1,242 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+
+use serde_json::{self, Value};
+
+use common::prelude::*;
+use requests::Request;
+use utils;
+use web::XmlElement;
+
+
+/// A field of a JSON payload exported as an environment variable.
+///
+/// The value is taken from the first JSON pointer which resolves to a
+/// string, a number or a boolean. If none of them resolve the variable is
+/// still exported, but empty. With XML payloads the pointers are paths of
+/// elements or attributes, as accepted by
+/// [`XmlElement::select`](struct.XmlElement.html#method.select).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PayloadField {
+    pub name: String,
+    pub pointers: Vec<String>,
+}
+
+impl PayloadField {
+    /// Create a new field, exported with the provided name.
+    pub fn new(name: &str, pointers: &[&str]) -> Self {
+        PayloadField {
+            name: name.to_string(),
+            pointers: pointers.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    /// Check if the name of the field can be used in an environment
+    /// variable.
+    pub(crate) fn validate(&self) -> Result<()> {
+        if utils::is_env_name(&self.name) {
+            Ok(())
+        } else {
+            Err(ErrorKind::InvalidInput(format!(
+                "invalid payload field name: {}",
+                self.name
+            )).into())
+        }
+    }
+}
+
+
+/// Extract the provided fields from the JSON or XML body of a request.
+/// Nothing is exported if the body can't be parsed.
+pub(crate) fn request_payload_env(
+    req: &Request,
+    fields: &[PayloadField],
+) -> HashMap<String, String> {
+    if fields.is_empty() {
+        return HashMap::new();
+    }
+
+    if let Request::Web(ref req) = *req {
+        if let Ok(Some(payload)) = req.xml() {
+            return xml_payload_env(&payload, fields);
+        } else if let Ok(payload) = serde_json::from_str(&req.body) {
+            return payload_env(&payload, fields);
+        }
+    }
+
+    HashMap::new()
+}
+
+
+/// Extract the provided fields from a JSON payload.
+pub fn payload_env(
+    payload: &Value,
+    fields: &[PayloadField],
+) -> HashMap<String, String> {
+    let mut res = HashMap::new();
+
+    for field in fields {
+        let value = field
+            .pointers
+            .iter()
+            .filter_map(|pointer| payload.pointer(pointer))
+            .filter_map(scalar_to_string)
+            .next();
+
+        res.insert(field.name.clone(), value.unwrap_or_default());
+    }
+
+    res
+}
+
+
+/// Extract the provided fields from an XML payload.
+pub fn xml_payload_env(
+    payload: &XmlElement,
+    fields: &[PayloadField],
+) -> HashMap<String, String> {
+    let mut res = HashMap::new();
+
+    for field in fields {
+        let value = field
+            .pointers
+            .iter()
+            .filter_map(|pointer| payload.select(pointer))
+            .next();
+
+        res.insert(field.name.clone(), value.unwrap_or_default());
+    }
+
+    res
+}
+
+
+fn scalar_to_string(value: &Value) -> Option<String> {
+    match *value {
+        Value::String(ref string) => Some(string.clone()),
+        Value::Number(ref number) => Some(number.to_string()),
+        Value::Bool(boolean) => Some(boolean.to_string()),
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use utils::testing::dummy_web_request;
+
+    use super::{payload_env, request_payload_env, xml_payload_env,
+                PayloadField};
+
+
+    fn fields() -> Vec<PayloadField> {
+        vec![
+            PayloadField::new("REPO", &["/repository/full_name"]),
+            PayloadField::new("SHA", &["/after", "/pull_request/head/sha"]),
+            PayloadField::new("NUMBER", &["/number"]),
+            PayloadField::new("MISSING", &["/missing", "/repository"]),
+        ]
+    }
+
+
+    fn xml_fields() -> Vec<PayloadField> {
+        vec![
+            PayloadField::new("REPO", &["/push/repository/@full_name"]),
+            PayloadField::new(
+                "SHA",
+                &["/push/after", "/push/pull_request/head/sha"],
+            ),
+            PayloadField::new("NUMBER", &["/push/number"]),
+            PayloadField::new("MISSING", &["/push/missing"]),
+        ]
+    }
+
+
+    #[test]
+    fn test_payload_env() {
+        let payload = serde_json::from_str(r#"{
+            "repository": {"full_name": "a/b"},
+            "pull_request": {"head": {"sha": "1234"}},
+            "number": 42
+        }"#).unwrap();
+
+        let env = payload_env(&payload, &fields());
+        assert_eq!(env.len(), 4);
+        assert_eq!(env["REPO"], "a/b");
+        assert_eq!(env["SHA"], "1234");
+        assert_eq!(env["NUMBER"], "42");
+        assert_eq!(env["MISSING"], "");
+    }
+
+
+    #[test]
+    fn test_xml_payload_env() {
+        let mut req = dummy_web_request();
+        req.headers.insert("Content-Type".into(), "application/xml".into());
+        req.body = r#"<push>
+            <repository full_name="a/b" />
+            <pull_request><head><sha>1234</sha></head></pull_request>
+            <number>42</number>
+        </push>"#.into();
+        let payload = req.xml().unwrap().unwrap();
+
+        let env = xml_payload_env(&payload, &xml_fields());
+        assert_eq!(env.len(), 4);
+        assert_eq!(env["REPO"], "a/b");
+        assert_eq!(env["SHA"], "1234");
+        assert_eq!(env["NUMBER"], "42");
+        assert_eq!(env["MISSING"], "");
+    }
+
+
+    #[test]
+    fn test_request_payload_env() {
+        // JSON bodies
+        let mut req = dummy_web_request();
+        req.body = r#"{"repository": {"full_name": "a/b"}}"#.into();
+        let env = request_payload_env(&req.clone().into(), &fields());
+        assert_eq!(env.len(), 4);
+        assert_eq!(env["REPO"], "a/b");
+
+        // Nothing is exported without fields
+        assert!(request_payload_env(&req.into(), &[]).is_empty());
+
+        // XML bodies
+        let mut req = dummy_web_request();
+        req.headers.insert("Content-Type".into(), "application/xml".into());
+        req.body = r#"<push><number>42</number></push>"#.into();
+        let env = request_payload_env(&req.into(), &xml_fields());
+        assert_eq!(env.len(), 4);
+        assert_eq!(env["NUMBER"], "42");
+
+        // Bodies which can't be parsed
+        let mut req = dummy_web_request();
+        req.body = "not a payload".into();
+        assert!(request_payload_env(&req.into(), &fields()).is_empty());
+    }
+
+
+    #[test]
+    fn test_payload_field_validate() {
+        assert!(PayloadField::new("REPO_2", &[]).validate().is_ok());
+        assert!(PayloadField::new("", &[]).validate().is_err());
+        assert!(PayloadField::new("A-B", &[]).validate().is_err());
+    }
+}
@@ -0,0 +1,665 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::de::DeserializeOwned;
+use serde_json;
+use ring;
+use untrusted;
+
+use providers::prelude::*;
+use utils;
+use common::prelude::*;
+
+
+lazy_static! {
+    static ref GOOGLE_ISSUERS: Vec<&'static str> = vec![
+        "accounts.google.com",
+        "https://accounts.google.com",
+    ];
+}
+
+
+/// The name of the file containing the decoded message data.
+static DATA_FILE_NAME: &'static str = "pubsub_data";
+
+
+#[derive(Debug, Deserialize)]
+struct PushPayload {
+    message: PushMessage,
+    subscription: String,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct PushMessage {
+    #[serde(default)]
+    attributes: HashMap<String, String>,
+    #[serde(default)]
+    data: String,
+    #[serde(rename = "messageId")]
+    message_id: String,
+    #[serde(rename = "publishTime", default)]
+    publish_time: String,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct TokenHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct TokenClaims {
+    iss: String,
+    aud: String,
+    exp: u64,
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kid: Option<String>,
+    kty: String,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+
+/// The keys loaded from the jwks file, along with the modification time and
+/// the size the file had.
+#[derive(Debug, Default)]
+struct LoadedKeys {
+    keys: Vec<Jwk>,
+    version: Option<(SystemTime, u64)>,
+}
+
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PubSubProvider {
+    jwks: String,
+    audience: String,
+    #[serde(rename = "service-account")]
+    service_account: Option<String>,
+    /// The keys loaded from the jwks file.
+    #[serde(skip)]
+    keys: RwLock<LoadedKeys>,
+}
+
+impl PubSubProvider {
+    fn load_keys(&self) -> Result<LoadedKeys> {
+        // The version is checked first, so changes made while the file is
+        // being read are picked up later
+        let version = jwks_version(&self.jwks);
+
+        let mut content = String::new();
+        File::open(&self.jwks)?.read_to_string(&mut content)?;
+        let jwks: Jwks = serde_json::from_str(&content)?;
+
+        Ok(LoadedKeys {
+            keys: jwks.keys,
+            version: version,
+        })
+    }
+
+    /// Reload the keys if the jwks file changed since they were loaded. The
+    /// current keys are kept if the file can't be loaded, and loading it is
+    /// tried again with the next request.
+    fn refresh_keys(&self) {
+        let version = jwks_version(&self.jwks);
+        if version.is_none() || version == self.keys.read().unwrap().version {
+            return;
+        }
+
+        if let Ok(loaded) = self.load_keys() {
+            *self.keys.write().unwrap() = loaded;
+        }
+    }
+
+    fn verify_token(&self, token: &str) -> bool {
+        let parts = token.split('.').collect::<Vec<_>>();
+        if parts.len() != 3 {
+            return false;
+        }
+
+        // Decode all the parts of the token
+        let header: TokenHeader = match decode_json(parts[0]) {
+            Some(header) => header,
+            None => return false,
+        };
+        let claims: TokenClaims = match decode_json(parts[1]) {
+            Some(claims) => claims,
+            None => return false,
+        };
+        let signature = match utils::from_base64(parts[2]) {
+            Ok(signature) => signature,
+            Err(..) => return false,
+        };
+
+        // Google signs its tokens only with RS256
+        if header.alg != "RS256" {
+            return false;
+        }
+
+        // Google rotates its keys, so the file might have been updated
+        self.refresh_keys();
+
+        let signed = format!("{}.{}", parts[0], parts[1]);
+        let valid = self.keys.read().unwrap().keys.iter()
+            .filter(|key| key.kty == "RSA")
+            .filter(|key| header.kid.is_none() || key.kid == header.kid)
+            .any(|key| verify_signature(key, signed.as_bytes(), &signature));
+        if !valid {
+            return false;
+        }
+
+        // The token must be issued by Google
+        if !GOOGLE_ISSUERS.contains(&claims.iss.as_str()) {
+            return false;
+        }
+
+        // The token must not be expired
+        let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(now) => now.as_secs(),
+            Err(..) => return false,
+        };
+        if claims.exp <= now {
+            return false;
+        }
+
+        // Anyone can get a token signed by Google for their own audience
+        if claims.aud != self.audience {
+            return false;
+        }
+
+        // Check the service account only if one was provided
+        if let Some(ref account) = self.service_account {
+            if claims.email.as_ref() != Some(account) || !claims.email_verified
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+impl ProviderTrait for PubSubProvider {
+    fn new(config: &str) -> Result<Self> {
+        let mut inst: PubSubProvider = serde_json::from_str(config)?;
+
+        // The keys are loaded right away, so missing or invalid files are
+        // reported when the hooks are loaded
+        let keys = inst.load_keys()?;
+        inst.keys = RwLock::new(keys);
+
+        Ok(inst)
+    }
+
+    fn validate(&self, request: &Request) -> RequestType {
+        let req;
+        if let Request::Web(ref inner) = *request {
+            req = inner;
+        } else {
+            return RequestType::Invalid;
+        }
+
+        // Pub/Sub sends an OIDC token signed by Google
+        let token = match req.headers.get("Authorization") {
+            Some(header) if header.starts_with("Bearer ") => &header[7..],
+            _ => return RequestType::Invalid,
+        };
+        if !self.verify_token(token) {
+            return RequestType::Invalid;
+        }
+
+        // The body must be a push message with valid data
+        let payload: PushPayload = match serde_json::from_str(&req.body) {
+            Ok(payload) => payload,
+            Err(..) => return RequestType::Invalid,
+        };
+        if utils::from_base64(&payload.message.data).is_err() {
+            return RequestType::Invalid;
+        }
+
+        RequestType::ExecuteHook
+    }
+
+    fn env(&self, request: &Request) -> HashMap<String, String> {
+        let mut res = HashMap::new();
+
+        let payload = match parse_payload(request) {
+            Some(payload) => payload,
+            None => return res,
+        };
+
+        res.insert("SUBSCRIPTION".to_string(), payload.subscription);
+        res.insert("MESSAGE_ID".to_string(), payload.message.message_id);
+        res.insert("PUBLISH_TIME".to_string(), payload.message.publish_time);
+
+        // Expose each message attribute as its own variable
+        for (key, value) in payload.message.attributes {
+            res.insert(format!("ATTRIBUTE_{}", utils::env_name(&key)), value);
+        }
+
+        res
+    }
+
+    fn delivery_id(&self, request: &Request) -> Option<String> {
+        parse_payload(request).map(|payload| payload.message.message_id)
+    }
+
+    fn prepare_directory(&self, req: &Request, path: &PathBuf) -> Result<()> {
+        let payload = match parse_payload(req) {
+            Some(payload) => payload,
+            None => return Ok(()),
+        };
+
+        // Hand the decoded message data to the hook
+        let data = utils::from_base64(&payload.message.data)?;
+        File::create(path.join(DATA_FILE_NAME))?.write_all(&data)?;
+
+        Ok(())
+    }
+}
+
+
+fn parse_payload(request: &Request) -> Option<PushPayload> {
+    if let Request::Web(ref req) = *request {
+        serde_json::from_str(&req.body).ok()
+    } else {
+        None
+    }
+}
+
+
+fn jwks_version(path: &str) -> Option<(SystemTime, u64)> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(..) => return None,
+    };
+    metadata.modified().ok().map(|modified| (modified, metadata.len()))
+}
+
+
+fn decode_json<T: DeserializeOwned>(input: &str) -> Option<T> {
+    match utils::from_base64(input) {
+        Ok(decoded) => serde_json::from_slice(&decoded).ok(),
+        Err(..) => None,
+    }
+}
+
+
+fn verify_signature(key: &Jwk, message: &[u8], signature: &[u8]) -> bool {
+    let (n, e) = match (&key.n, &key.e) {
+        (&Some(ref n), &Some(ref e)) => (n, e),
+        _ => return false,
+    };
+    let (n, e) = match (utils::from_base64(n), utils::from_base64(e)) {
+        (Ok(n), Ok(e)) => (n, e),
+        _ => return false,
+    };
+    if n.is_empty() || e.is_empty() {
+        return false;
+    }
+
+    // ring expects the public key as a DER-encoded RSAPublicKey
+    let mut integers = der_integer(&n);
+    integers.extend(der_integer(&e));
+    let public_key = der_tagged(0x30, &integers);
+
+    ring::signature::verify(
+        &ring::signature::RSA_PKCS1_2048_8192_SHA256,
+        untrusted::Input::from(&public_key),
+        untrusted::Input::from(message),
+        untrusted::Input::from(signature),
+    ).is_ok()
+}
+
+
+fn der_integer(bytes: &[u8]) -> Vec<u8> {
+    // Strip the leading zeroes, keeping at least one byte
+    let mut start = 0;
+    while start < bytes.len() - 1 && bytes[start] == 0 {
+        start += 1;
+    }
+
+    // Integers are signed, so positive ones can't have the high bit set
+    let mut content = Vec::with_capacity(bytes.len() - start + 1);
+    if bytes[start] & 0x80 != 0 {
+        content.push(0);
+    }
+    content.extend_from_slice(&bytes[start..]);
+
+    der_tagged(0x02, &content)
+}
+
+
+fn der_tagged(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut result = vec![tag];
+
+    let len = content.len();
+    if len < 0x80 {
+        result.push(len as u8);
+    } else {
+        let len_bytes = (0..8)
+            .rev()
+            .map(|i| (len >> (i * 8)) as u8)
+            .skip_while(|byte| *byte == 0)
+            .collect::<Vec<_>>();
+        result.push(0x80 | len_bytes.len() as u8);
+        result.extend(len_bytes);
+    }
+
+    result.extend_from_slice(content);
+    result
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+
+    use utils;
+    use utils::testing::*;
+    use requests::RequestType;
+    use providers::ProviderTrait;
+    use web::WebRequest;
+
+    use super::{der_tagged, PubSubProvider, DATA_FILE_NAME};
+
+
+    static AUDIENCE: &str = "https://example.com/hook";
+
+    static MODULUS: &[&str] = &[
+        "yfECXtuvJpTzNG2TLCyTPnJhDMMliHrC62mlFQPMEyjaHc2r8qrxo8Ln",
+        "nMC9w1e0xH2zOhkGeYfrml58Z-N9QAVT9d0-rnFRDdM14XJOQrIdMx8r",
+        "ISGRdNq1X4ZYepMqmsKJl-ZdJYKNXclxFoG_y3HWIR_opKIPKtH6EElw",
+        "YkIJtYkBFCH7_s_4X2IL-Tat0R338pBfE12WYuQ_af8rnAhBpJ8d9ggN",
+        "qJ3xjMeUK7F-K0cL4hFibd2amn69gSifqEtb9UjbpS4Lb2me9b-nA7YT",
+        "wbzPgsFPLTGvqCO6pWKGdDikWbb31lLZCrQ6pT5Xu5r4cUb752ak0wDw",
+        "7xWIvw",
+    ];
+
+    static VALID_TOKEN: &[&str] = &[
+        "eyJhbGciOiJSUzI1NiIsImtpZCI6InRlc3Qta2V5IiwidHlwIjoiSldU",
+        "In0.eyJpc3MiOiJodHRwczovL2FjY291bnRzLmdvb2dsZS5jb20iLCJh",
+        "dWQiOiJodHRwczovL2V4YW1wbGUuY29tL2hvb2siLCJlbWFpbCI6InB1",
+        "c2hAZXhhbXBsZS5pYW0uZ3NlcnZpY2VhY2NvdW50LmNvbSIsImVtYWls",
+        "X3ZlcmlmaWVkIjp0cnVlLCJpYXQiOjE1MDAwMDAwMDAsImV4cCI6NDEw",
+        "MjQ0NDgwMH0.fyV3DDcnMPGUlXm_XJLjPF--6an3pyggsXQhYMj690gG",
+        "1nN7ms8cVboyvYBeJFY4F5YPJ9EHul_ip469vPgg9npxDex7LKySa7Se",
+        "DX-_afqJLr6S-MXCP_taE8ecYNDUSbJ3vuMeyQPswUY8h2tj1BKS2ePi",
+        "1oUmdwzhjRXicNCsy2x5VTV9_6wZbLP4GC6GH3FojmxIp4M8uBtAocRs",
+        "lZtG3tGG3-4FWtsTcXSAor7O-DbBoaRnBR38PXUD8w3pU5OWV0YmVR1k",
+        "kRmyaUMa3vXEI-2FOF2vDBY7HIg8Aw4EKHsx3gGBKiiGOFQdmJzj3l5k",
+        "hWeAefSaF4cBMtdTOQ",
+    ];
+
+    static EXPIRED_TOKEN: &[&str] = &[
+        "eyJhbGciOiJSUzI1NiIsImtpZCI6InRlc3Qta2V5IiwidHlwIjoiSldU",
+        "In0.eyJpc3MiOiJodHRwczovL2FjY291bnRzLmdvb2dsZS5jb20iLCJh",
+        "dWQiOiJodHRwczovL2V4YW1wbGUuY29tL2hvb2siLCJlbWFpbCI6InB1",
+        "c2hAZXhhbXBsZS5pYW0uZ3NlcnZpY2VhY2NvdW50LmNvbSIsImVtYWls",
+        "X3ZlcmlmaWVkIjp0cnVlLCJpYXQiOjE1MDAwMDAwMDAsImV4cCI6MTUw",
+        "MDAwMzYwMH0.JO1CY4r8BDmobFBdXP549KsyLhqS5WsIuFw1SRXYJVfY",
+        "LeiWpYYOM6JiCrZtm6lMuz9kHBx9gRV_l6vgxFxGbGkEqWFUha37C_03",
+        "bAesluwrltb5Jl_yzLldAsJqE6nfqDvjR1New5w3GDTvjnSn_YQMW5cw",
+        "d4h5UYdj0zvKsdl7E3gsmyk0Ch1TdEfEVmFRYpNMTHkAE2PFlQETbESg",
+        "2fGz_K7-PwNwhE4fWlPDiNV8JpfEmCoC7FaOoWI83NF7q7Ouzbx9BJyb",
+        "W4EcdLE3S4II9sk4YRULrwMuPIC9_CTpmoO-22OYo5QveGmv954J6PNs",
+        "yIxqk-PlTPQfaI6JaQ",
+    ];
+
+
+    fn create_jwks() -> PathBuf {
+        let dir = utils::create_temp_dir().unwrap();
+        let path = dir.join("jwks.json");
+
+        let content = json!({
+            "keys": [{
+                "kty": "RSA",
+                "alg": "RS256",
+                "use": "sig",
+                "kid": "test-key",
+                "n": MODULUS.concat(),
+                "e": "AQAB",
+            }],
+        });
+        write!(File::create(&path).unwrap(), "{}", content).unwrap();
+
+        path
+    }
+
+
+    fn provider(
+        jwks: &PathBuf,
+        audience: &str,
+        extra: &str,
+    ) -> PubSubProvider {
+        PubSubProvider::new(&format!(
+            r#"{{"jwks": "{}", "audience": "{}"{}}}"#,
+            jwks.to_str().unwrap(), audience, extra,
+        )).unwrap()
+    }
+
+
+    fn request(token: &str) -> WebRequest {
+        let mut req = dummy_web_request();
+        req.headers.insert(
+            "Authorization".into(), format!("Bearer {}", token),
+        );
+        req.body = r#"{
+            "message": {
+                "attributes": {"event-type": "deploy", "region": "eu"},
+                "data": "aGVsbG8gd29ybGQ=",
+                "messageId": "136969346945",
+                "publishTime": "2017-09-01T10:00:00Z"
+            },
+            "subscription": "projects/myproject/subscriptions/fisher"
+        }"#.into();
+
+        req
+    }
+
+
+    #[test]
+    fn test_new() {
+        let jwks = create_jwks();
+
+        let path = jwks.to_str().unwrap();
+        let right = format!(r#"{{"jwks": "{}", "audience": "a"}}"#, path);
+        assert!(PubSubProvider::new(&right).is_ok());
+
+        // The audience is required
+        let wrong = format!(r#"{{"jwks": "{}"}}"#, path);
+        assert!(PubSubProvider::new(&wrong).is_err());
+
+        for wrong in &[
+            r#"{}"#,
+            r#"{"jwks": 12345, "audience": "a"}"#,
+            r#"{"jwks": "/this/does/not/exist.json", "audience": "a"}"#,
+        ] {
+            assert!(PubSubProvider::new(wrong).is_err(), wrong.to_string());
+        }
+
+        fs::remove_dir_all(jwks.parent().unwrap()).unwrap();
+    }
+
+
+    #[test]
+    fn test_validate() {
+        let jwks = create_jwks();
+        let valid = VALID_TOKEN.concat();
+        let expired = EXPIRED_TOKEN.concat();
+
+        let prov = provider(&jwks, AUDIENCE, "");
+        assert_eq!(
+            prov.validate(&request(&valid).into()),
+            RequestType::ExecuteHook
+        );
+
+        // Expired tokens are rejected
+        assert_eq!(
+            prov.validate(&request(&expired).into()),
+            RequestType::Invalid
+        );
+
+        // Tampered tokens are rejected
+        let mut tampered = valid.clone().into_bytes();
+        let pos = tampered.len() - 20;
+        tampered[pos] = if tampered[pos] == b'A' { b'B' } else { b'A' };
+        let tampered = String::from_utf8(tampered).unwrap();
+        assert_eq!(
+            prov.validate(&request(&tampered).into()),
+            RequestType::Invalid
+        );
+
+        // Requests without the token are rejected
+        let mut req = request(&valid);
+        req.headers.remove("Authorization");
+        assert_eq!(prov.validate(&req.into()), RequestType::Invalid);
+
+        // Invalid payloads are rejected
+        let mut req = request(&valid);
+        req.body = "{}".into();
+        assert_eq!(prov.validate(&req.into()), RequestType::Invalid);
+
+        fs::remove_dir_all(jwks.parent().unwrap()).unwrap();
+    }
+
+
+    #[test]
+    fn test_reload_keys() {
+        let jwks = create_jwks();
+        let valid = VALID_TOKEN.concat();
+        let prov = provider(&jwks, AUDIENCE, "");
+
+        let mut original = String::new();
+        File::open(&jwks).unwrap().read_to_string(&mut original).unwrap();
+
+        // The keys are reloaded when the file changes
+        write!(File::create(&jwks).unwrap(), r#"{{"keys": []}}"#).unwrap();
+        assert_eq!(
+            prov.validate(&request(&valid).into()),
+            RequestType::Invalid
+        );
+
+        // Files which can't be loaded are ignored until they're fixed
+        write!(File::create(&jwks).unwrap(), "not json").unwrap();
+        assert_eq!(
+            prov.validate(&request(&valid).into()),
+            RequestType::Invalid
+        );
+        write!(File::create(&jwks).unwrap(), "{}", original).unwrap();
+        assert_eq!(
+            prov.validate(&request(&valid).into()),
+            RequestType::ExecuteHook
+        );
+
+        fs::remove_dir_all(jwks.parent().unwrap()).unwrap();
+    }
+
+
+    #[test]
+    fn test_validate_claims() {
+        let jwks = create_jwks();
+        let valid = VALID_TOKEN.concat();
+
+        for &(audience, extra, result) in &[
+            (AUDIENCE, "", RequestType::ExecuteHook),
+            ("https://example.com/other", "", RequestType::Invalid),
+            (AUDIENCE,
+             r#", "service-account": "push@example.iam.gserviceaccount.com""#,
+             RequestType::ExecuteHook),
+            (AUDIENCE,
+             r#", "service-account": "other@example.iam.gserviceaccount.com""#,
+             RequestType::Invalid),
+        ] {
+            let prov = provider(&jwks, audience, extra);
+            assert_eq!(prov.validate(&request(&valid).into()), result);
+        }
+
+        fs::remove_dir_all(jwks.parent().unwrap()).unwrap();
+    }
+
+
+    #[test]
+    fn test_env() {
+        let jwks = create_jwks();
+        let prov = provider(&jwks, AUDIENCE, "");
+
+        let env = prov.env(&request("").into());
+        assert_eq!(env.len(), 5);
+        assert_eq!(
+            env["SUBSCRIPTION"],
+            "projects/myproject/subscriptions/fisher"
+        );
+        assert_eq!(env["MESSAGE_ID"], "136969346945");
+        assert_eq!(env["PUBLISH_TIME"], "2017-09-01T10:00:00Z");
+        assert_eq!(env["ATTRIBUTE_EVENT_TYPE"], "deploy");
+        assert_eq!(env["ATTRIBUTE_REGION"], "eu");
+
+        fs::remove_dir_all(jwks.parent().unwrap()).unwrap();
+    }
+
+
+    #[test]
+    fn test_prepare_directory() {
+        let jwks = create_jwks();
+        let prov = provider(&jwks, AUDIENCE, "");
+
+        let dir = utils::create_temp_dir().unwrap();
+        prov.prepare_directory(&request("").into(), &dir).unwrap();
+
+        let mut data = String::new();
+        File::open(dir.join(DATA_FILE_NAME))
+            .unwrap()
+            .read_to_string(&mut data)
+            .unwrap();
+        assert_eq!(data, "hello world");
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(jwks.parent().unwrap()).unwrap();
+    }
+
+
+    #[test]
+    fn test_der_tagged() {
+        assert_eq!(der_tagged(0x02, &[1]), vec![0x02, 0x01, 0x01]);
+
+        let long = der_tagged(0x30, &[0; 300]);
+        assert_eq!(&long[..4], &[0x30, 0x82, 0x01, 0x2c]);
+        assert_eq!(long.len(), 304);
+    }
+}
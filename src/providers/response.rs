@@ -0,0 +1,72 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use serde_json::{self, Value};
+
+
+/// A custom HTTP response a provider sends back to the service which made
+/// the request, instead of the generic one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProviderResponse {
+    pub status: u16,
+    pub content_type: String,
+    pub body: String,
+}
+
+impl ProviderResponse {
+    /// Create a new plain text response.
+    pub fn text<S: Into<String>>(body: S) -> Self {
+        ProviderResponse {
+            status: 200,
+            content_type: "text/plain".into(),
+            body: body.into(),
+        }
+    }
+
+    /// Create a new JSON response.
+    pub fn json(body: &Value) -> Self {
+        ProviderResponse {
+            status: 200,
+            content_type: "application/json".into(),
+            body: serde_json::to_string(body).unwrap(),
+        }
+    }
+
+    /// Change the status code of the response.
+    pub fn with_status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::ProviderResponse;
+
+
+    #[test]
+    fn test_provider_response() {
+        let text = ProviderResponse::text("hello");
+        assert_eq!(text.status, 200);
+        assert_eq!(text.content_type, "text/plain");
+        assert_eq!(text.body, "hello");
+
+        let json = ProviderResponse::json(&json!({"type": 1})).with_status(202);
+        assert_eq!(json.status, 202);
+        assert_eq!(json.content_type, "application/json");
+        assert_eq!(json.body, r#"{"type":1}"#);
+    }
+}
@@ -18,15 +18,20 @@ use std::net::IpAddr;
 use serde_json;
 
 use providers::prelude::*;
+use utils;
 
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct StandaloneProvider {
-    secret: Option<String>,
+    secret: Option<utils::Secrets>,
     from: Option<Vec<IpAddr>>,
 
     param_name: Option<String>,
     header_name: Option<String>,
+
+    #[serde(default)]
+    fields: Vec<PayloadField>,
 }
 
 impl StandaloneProvider {
@@ -48,7 +53,10 @@ impl StandaloneProvider {
 impl ProviderTrait for StandaloneProvider {
     fn new(config: &str) -> Result<Self> {
         // Check if it's possible to create a new instance and return it
-        let inst = serde_json::from_str(config)?;
+        let inst: StandaloneProvider = serde_json::from_str(config)?;
+        for field in &inst.fields {
+            field.validate()?;
+        }
         Ok(inst)
     }
 
@@ -61,7 +69,7 @@ impl ProviderTrait for StandaloneProvider {
         }
 
         // Check if the secret code is valid
-        if let Some(ref correct_secret) = self.secret {
+        if let Some(ref secrets) = self.secret {
             let secret = if let Some(found) = req.params.get(&self.param_name()) {
                 // Secret in the request parameters
                 found
@@ -73,8 +81,8 @@ impl ProviderTrait for StandaloneProvider {
                 return RequestType::Invalid;
             };
 
-            // Abort if the secret doesn't match
-            if secret != correct_secret {
+            // Abort if the secret doesn't match any of the allowed ones
+            if !secrets.contains(secret) {
                 return RequestType::Invalid;
             }
         }
@@ -92,6 +100,24 @@ impl ProviderTrait for StandaloneProvider {
     fn env(&self, _req: &Request) -> HashMap<String, String> {
         HashMap::new()
     }
+
+    fn payload_fields(&self) -> &[PayloadField] {
+        &self.fields
+    }
+
+    fn priority(&self, request: &Request) -> Option<isize> {
+        // Only requests authenticated with the secret are trusted
+        if self.secret.is_none() {
+            return None;
+        }
+
+        if let Request::Web(ref req) = *request {
+            req.header("X-Fisher-Priority")
+                .and_then(|priority| priority.trim().parse().ok())
+        } else {
+            None
+        }
+    }
 }
 
 
@@ -100,8 +126,8 @@ mod tests {
     use std::collections::HashMap;
 
     use utils::testing::*;
-    use requests::RequestType;
-    use providers::ProviderTrait;
+    use requests::{Request, RequestType};
+    use providers::{Provider, ProviderTrait};
 
     use super::StandaloneProvider;
 
@@ -117,6 +143,8 @@ mod tests {
             r#"{"secret": "abcde", "param_name": "a", "header_name": "b"}"#,
             r#"{"from": ["127.0.0.1", "192.168.1.1", "10.0.0.2"]}"#,
             r#"{"from": ["127.0.0.1"], "secret": "abcde"}"#,
+            r#"{"secret": ["a", "b"]}"#,
+            r#"{"fields": [{"name": "REPO", "pointers": ["/repo"]}]}"#,
         ];
         for one in &right {
             assert!(StandaloneProvider::new(one).is_ok(), "Should be valid: {}", one);
@@ -125,10 +153,14 @@ mod tests {
         let wrong = vec![
             r#"{"secret": 123}"#,
             r#"{"secret": true}"#,
-            r#"{"secret": ["a", "b"]}"#,
+            r#"{"secret": []}"#,
+            r#"{"secret": [123]}"#,
             r#"{"secret": {"a": "b"}}"#,
             r#"{"from": "127.0.0.1"}"#,
             r#"{"from": ["256.0.0.1"]}"#,
+            r#"{"fields": [{"name": "A-B", "pointers": ["/repo"]}]}"#,
+            r#"{"fields": [{"name": "REPO"}]}"#,
+            r#"{"fields": [{"name": "A", "pointers": [], "b": 1}]}"#,
         ];
         for one in &wrong {
             assert!(StandaloneProvider::new(one).is_err(), "Should be invalid: {}", one);
@@ -186,6 +218,27 @@ mod tests {
         assert_eq!(p.validate(&req.into()), RequestType::ExecuteHook);
     }
 
+    #[test]
+    fn test_priority() {
+        let mut req = dummy_web_request();
+        req.params.insert("secret".into(), "abcde".into());
+        req.headers.insert("x-fisher-priority".into(), "100".into());
+        let req: Request = req.into();
+
+        // Only trusted requests can choose the priority
+        let p = StandaloneProvider::new(r#"{"secret": "abcde"}"#).unwrap();
+        assert_eq!(p.validate(&req), RequestType::ExecuteHook);
+        assert_eq!(p.priority(&req), Some(100));
+        let p = StandaloneProvider::new(r#"{}"#).unwrap();
+        assert_eq!(p.priority(&req), None);
+
+        // Invalid priorities are ignored
+        let p = StandaloneProvider::new(r#"{"secret": "abcde"}"#).unwrap();
+        let mut req = dummy_web_request();
+        req.headers.insert("X-Fisher-Priority".into(), "high".into());
+        assert_eq!(p.priority(&req.into()), None);
+    }
+
     #[test]
     fn test_validate_from() {
         let config = r#"{"from": ["192.168.1.1", "10.0.0.1"]}"#;
@@ -210,4 +263,20 @@ mod tests {
         assert!(p.env(&dummy_web_request().into()) == HashMap::new());
     }
 
+    #[test]
+    fn test_env_payload() {
+        let p = Provider::new("Standalone", r#"{"fields": [
+            {"name": "REPO", "pointers": ["/repository/name"]},
+            {"name": "SHA", "pointers": ["/after", "/sha"]}
+        ]}"#).unwrap();
+
+        let mut req = dummy_web_request();
+        req.body = r#"{"repository": {"name": "fisher"}, "sha": "1"}"#.into();
+
+        let env = p.env(&req.into());
+        assert_eq!(env.len(), 2);
+        assert_eq!(env["REPO"], "fisher");
+        assert_eq!(env["SHA"], "1");
+    }
+
 }
@@ -16,7 +16,8 @@
 use std::fs;
 use std::io::Write;
 use std::slice::Iter as SliceIter;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
 
 use serde_json;
 
@@ -24,10 +25,25 @@ use providers::prelude::*;
 use scripts::JobOutput;
 
 
+#[derive(Debug, Clone)]
+pub struct HeartbeatMissed {
+    pub hook_name: String,
+    /// The expected interval between deliveries, in seconds.
+    pub interval: u64,
+    /// The UNIX timestamp of the last delivery, if any was received.
+    pub last_seen: Option<u64>,
+}
+
+
 #[derive(Debug, Clone)]
 pub enum StatusEvent {
     JobCompleted(JobOutput),
     JobFailed(JobOutput),
+    /// A job failed after all its retries, and its request was saved.
+    JobDeadLettered(JobOutput),
+    /// A job refused to process its request.
+    JobRejected(JobOutput),
+    HeartbeatMissed(HeartbeatMissed),
 }
 
 impl StatusEvent {
@@ -36,6 +52,13 @@ impl StatusEvent {
         match *self {
             StatusEvent::JobCompleted(..) => StatusEventKind::JobCompleted,
             StatusEvent::JobFailed(..) => StatusEventKind::JobFailed,
+            StatusEvent::JobDeadLettered(..) => {
+                StatusEventKind::JobDeadLettered
+            }
+            StatusEvent::JobRejected(..) => StatusEventKind::JobRejected,
+            StatusEvent::HeartbeatMissed(..) => {
+                StatusEventKind::HeartbeatMissed
+            }
         }
     }
 
@@ -43,7 +66,10 @@ impl StatusEvent {
     pub fn hook_name(&self) -> &String {
         match *self {
             StatusEvent::JobCompleted(ref output) |
-            StatusEvent::JobFailed(ref output) => &output.script_name,
+            StatusEvent::JobFailed(ref output) |
+            StatusEvent::JobDeadLettered(ref output) |
+            StatusEvent::JobRejected(ref output) => &output.script_name,
+            StatusEvent::HeartbeatMissed(ref missed) => &missed.hook_name,
         }
     }
 
@@ -51,9 +77,45 @@ impl StatusEvent {
     pub fn source_ip(&self) -> IpAddr {
         match *self {
             StatusEvent::JobCompleted(ref output) |
-            StatusEvent::JobFailed(ref output) => output.request_ip,
+            StatusEvent::JobFailed(ref output) |
+            StatusEvent::JobDeadLettered(ref output) |
+            StatusEvent::JobRejected(ref output) => output.request_ip,
+            // Missed heartbeats are detected by Fisher itself
+            StatusEvent::HeartbeatMissed(..) => {
+                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))
+            }
         }
     }
+
+    /// Return the output of the job the event is about, if any.
+    #[inline]
+    pub fn job_output(&self) -> Option<&JobOutput> {
+        match *self {
+            StatusEvent::JobCompleted(ref output) |
+            StatusEvent::JobFailed(ref output) |
+            StatusEvent::JobDeadLettered(ref output) |
+            StatusEvent::JobRejected(ref output) => Some(output),
+            StatusEvent::HeartbeatMissed(..) => None,
+        }
+    }
+
+    /// Return the environment variables pointing to the files with the
+    /// output of the job, saved in the provided directory.
+    pub fn output_env(&self, dir: &Path) -> Vec<(String, String)> {
+        if self.job_output().is_none() {
+            return Vec::new();
+        }
+
+        vec![
+            ("FISHER_STATUS_STDOUT".into(), path_env(dir, "stdout")),
+            ("FISHER_STATUS_STDERR".into(), path_env(dir, "stderr")),
+        ]
+    }
+}
+
+
+fn path_env(dir: &Path, name: &str) -> String {
+    dir.join(name).to_string_lossy().into_owned()
 }
 
 
@@ -61,6 +123,9 @@ impl StatusEvent {
 pub enum StatusEventKind {
     #[serde(rename = "job_completed")] JobCompleted,
     #[serde(rename = "job_failed")] JobFailed,
+    #[serde(rename = "job_dead_lettered")] JobDeadLettered,
+    #[serde(rename = "job_rejected")] JobRejected,
+    #[serde(rename = "heartbeat_missed")] HeartbeatMissed,
 }
 
 impl StatusEventKind {
@@ -68,12 +133,16 @@ impl StatusEventKind {
         match *self {
             StatusEventKind::JobCompleted => "job_completed",
             StatusEventKind::JobFailed => "job_failed",
+            StatusEventKind::JobDeadLettered => "job_dead_lettered",
+            StatusEventKind::JobRejected => "job_rejected",
+            StatusEventKind::HeartbeatMissed => "heartbeat_missed",
         }
     }
 }
 
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct StatusProvider {
     events: Vec<StatusEventKind>,
     hooks: Option<Vec<String>>,
@@ -143,8 +212,11 @@ impl ProviderTrait for StatusProvider {
                 env.insert("SUCCESS".into(), "1".into());
                 env.insert("EXIT_CODE".into(), "0".into());
                 env.insert("SIGNAL".into(), String::new());
+                env.insert("TIMED_OUT".into(), "0".into());
             }
-            StatusEvent::JobFailed(ref output) => {
+            StatusEvent::JobFailed(ref output) |
+            StatusEvent::JobDeadLettered(ref output) |
+            StatusEvent::JobRejected(ref output) => {
                 env.insert("SUCCESS".into(), "0".into());
                 env.insert(
                     "EXIT_CODE".into(),
@@ -162,6 +234,48 @@ impl ProviderTrait for StatusProvider {
                         String::new()
                     },
                 );
+                env.insert(
+                    "TIMED_OUT".into(),
+                    if output.timed_out { "1" } else { "0" }.into(),
+                );
+            }
+            StatusEvent::HeartbeatMissed(ref missed) => {
+                env.insert("SUCCESS".into(), "0".into());
+                env.insert("EXIT_CODE".into(), String::new());
+                env.insert("SIGNAL".into(), String::new());
+                env.insert("TIMED_OUT".into(), "0".into());
+                env.insert(
+                    "HEARTBEAT_INTERVAL".into(),
+                    format!("{}", missed.interval),
+                );
+                env.insert(
+                    "LAST_SEEN".into(),
+                    if let Some(last_seen) = missed.last_seen {
+                        format!("{}", last_seen)
+                    } else {
+                        String::new()
+                    },
+                );
+            }
+        }
+
+        // Point to the uploaded artifacts, one URL per line
+        if let Some(output) = req.job_output() {
+            if !output.artifact_urls.is_empty() {
+                env.insert(
+                    "ARTIFACT_URLS".into(),
+                    output.artifact_urls.join("\n"),
+                );
+            }
+        }
+
+        // Point to the saved request, so it can be inspected or requeued
+        if let StatusEvent::JobDeadLettered(ref output) = *req {
+            if let Some(ref path) = output.dead_letter {
+                env.insert(
+                    "DEAD_LETTER".into(),
+                    path.to_string_lossy().into_owned(),
+                );
             }
         }
 
@@ -181,15 +295,10 @@ impl ProviderTrait for StatusProvider {
             }};
         }
 
-        match *req {
-            StatusEvent::JobCompleted(ref output) => {
-                new_file!(path, "stdout", output.stdout);
-                new_file!(path, "stderr", output.stderr);
-            }
-            StatusEvent::JobFailed(ref output) => {
-                new_file!(path, "stdout", output.stdout);
-                new_file!(path, "stderr", output.stderr);
-            }
+        // There is no output for missed heartbeats
+        if let Some(output) = req.job_output() {
+            new_file!(path, "stdout", output.stdout);
+            new_file!(path, "stderr", output.stderr);
         }
 
         Ok(())
@@ -212,7 +321,7 @@ mod tests {
     use requests::RequestType;
     use providers::ProviderTrait;
 
-    use super::{StatusEvent, StatusProvider};
+    use super::{HeartbeatMissed, StatusEvent, StatusProvider};
 
 
     #[test]
@@ -243,6 +352,7 @@ mod tests {
             r#"{"events": []}"#,
             r#"{"events": ["job_completed"]}"#,
             r#"{"events": ["job_completed", "job_failed"]}"#,
+            r#"{"events": ["heartbeat_missed"]}"#,
             r#"{"events": [], "hooks": []}"#,
             r#"{"events": [], "hooks": ["abc"]}"#,
         ] {
@@ -317,26 +427,107 @@ mod tests {
         // Try with a job_completed event
         let event = StatusEvent::JobCompleted(dummy_job_output());
         let env = provider.env(&event.into());
-        assert_eq!(env.len(), 5);
+        assert_eq!(env.len(), 6);
         assert_eq!(env.get("EVENT").unwrap(), &"job_completed".to_string());
         assert_eq!(env.get("HOOK_NAME").unwrap(), &"test".to_string());
         assert_eq!(env.get("SUCCESS").unwrap(), &"1".to_string());
         assert_eq!(env.get("EXIT_CODE").unwrap(), &"0".to_string());
         assert_eq!(env.get("SIGNAL").unwrap(), &"".to_string());
+        assert_eq!(env.get("TIMED_OUT").unwrap(), &"0".to_string());
 
         // Try with a job_failed event
         let mut output = dummy_job_output();
         output.success = false;
         output.exit_code = None;
         output.signal = Some(9);
+        output.timed_out = true;
 
         let env = provider.env(&StatusEvent::JobFailed(output).into());
-        assert_eq!(env.len(), 5);
+        assert_eq!(env.len(), 6);
         assert_eq!(env.get("EVENT").unwrap(), &"job_failed".to_string());
         assert_eq!(env.get("HOOK_NAME").unwrap(), &"test".to_string());
         assert_eq!(env.get("SUCCESS").unwrap(), &"0".to_string());
         assert_eq!(env.get("EXIT_CODE").unwrap(), &"".to_string());
         assert_eq!(env.get("SIGNAL").unwrap(), &"9".to_string());
+        assert_eq!(env.get("TIMED_OUT").unwrap(), &"1".to_string());
+    }
+
+
+    #[test]
+    fn test_env_job_dead_lettered() {
+        let provider = StatusProvider::new(
+            r#"{"events": ["job_dead_lettered"]}"#,
+        ).unwrap();
+
+        let mut output = dummy_job_output();
+        output.success = false;
+        output.exit_code = Some(1);
+        output.dead_letter = Some("/var/lib/fisher/dead/abcd.json".into());
+
+        let event = StatusEvent::JobDeadLettered(output).into();
+        assert_eq!(provider.validate(&event), RequestType::ExecuteHook);
+
+        let env = provider.env(&event);
+        assert_eq!(env.len(), 7);
+        assert_eq!(env.get("EVENT").unwrap(), "job_dead_lettered");
+        assert_eq!(env.get("SUCCESS").unwrap(), "0");
+        assert_eq!(env.get("EXIT_CODE").unwrap(), "1");
+        assert_eq!(
+            env.get("DEAD_LETTER").unwrap(), "/var/lib/fisher/dead/abcd.json",
+        );
+    }
+
+
+    #[test]
+    fn test_env_artifact_urls() {
+        let provider = StatusProvider::new(
+            r#"{"events": ["job_completed"]}"#,
+        ).unwrap();
+
+        let mut output = dummy_job_output();
+        output.artifact_urls = vec![
+            "http://s3.local/builds/job/1/app.tar.gz".into(),
+            "http://s3.local/builds/job/1/report.html".into(),
+        ];
+
+        let env = provider.env(&StatusEvent::JobCompleted(output).into());
+        assert_eq!(env.len(), 7);
+        assert_eq!(
+            env.get("ARTIFACT_URLS").unwrap(),
+            "http://s3.local/builds/job/1/app.tar.gz\n\
+             http://s3.local/builds/job/1/report.html",
+        );
+    }
+
+
+    #[test]
+    fn test_env_heartbeat_missed() {
+        let provider = StatusProvider::new(
+            r#"{"events": ["heartbeat_missed"], "hooks": ["test"]}"#,
+        ).unwrap();
+
+        let event = StatusEvent::HeartbeatMissed(HeartbeatMissed {
+            hook_name: "test".into(),
+            interval: 600,
+            last_seen: Some(1500000000),
+        }).into();
+        assert_eq!(provider.validate(&event), RequestType::ExecuteHook);
+
+        let env = provider.env(&event);
+        assert_eq!(env.len(), 8);
+        assert_eq!(env["EVENT"], "heartbeat_missed");
+        assert_eq!(env["HOOK_NAME"], "test");
+        assert_eq!(env["SUCCESS"], "0");
+        assert_eq!(env["HEARTBEAT_INTERVAL"], "600");
+        assert_eq!(env["LAST_SEEN"], "1500000000");
+
+        // Hooks which never received a delivery don't have a last seen time
+        let event = StatusEvent::HeartbeatMissed(HeartbeatMissed {
+            hook_name: "test".into(),
+            interval: 600,
+            last_seen: None,
+        });
+        assert_eq!(provider.env(&event.into())["LAST_SEEN"], "");
     }
 
     #[test]
@@ -362,11 +553,25 @@ mod tests {
 
         let event = StatusEvent::JobCompleted(dummy_job_output());
         let tempdir = utils::create_temp_dir().unwrap();
-        provider.prepare_directory(&event.into(), &tempdir).unwrap();
+        provider.prepare_directory(&event.clone().into(), &tempdir).unwrap();
 
         assert_eq!(read!(tempdir, "stdout"), "hello world".to_string());
         assert_eq!(read!(tempdir, "stderr"), "something happened".to_string());
 
+        // The files are exposed to the status hooks
+        let env = event.output_env(&tempdir);
+        assert_eq!(env[0].0, "FISHER_STATUS_STDOUT");
+        assert_eq!(env[0].1, tempdir.join("stdout").to_str().unwrap());
+        assert_eq!(env[1].0, "FISHER_STATUS_STDERR");
+        assert_eq!(env[1].1, tempdir.join("stderr").to_str().unwrap());
+
+        let missed = StatusEvent::HeartbeatMissed(HeartbeatMissed {
+            hook_name: "test".into(),
+            interval: 60,
+            last_seen: None,
+        });
+        assert!(missed.output_env(&tempdir).is_empty());
+
         fs::remove_dir_all(&tempdir).unwrap();
     }
 }
@@ -69,6 +69,10 @@ impl ProviderTrait for TestingProvider {
                 "ping" => {
                     return RequestType::Ping;
                 }
+                // "handshake" will return RequestType::Handshake
+                "handshake" => {
+                    return RequestType::Handshake;
+                }
                 _ => {}
             }
         }
@@ -94,6 +98,43 @@ impl ProviderTrait for TestingProvider {
         res
     }
 
+    fn delivery_id(&self, request: &Request) -> Option<String> {
+        if let &Request::Web(ref inner) = request {
+            inner.params.get("delivery").cloned()
+        } else {
+            None
+        }
+    }
+
+    fn priority(&self, request: &Request) -> Option<isize> {
+        if let &Request::Web(ref inner) = request {
+            inner.params.get("priority").and_then(|p| p.parse().ok())
+        } else {
+            None
+        }
+    }
+
+    fn response(
+        &self,
+        request: &Request,
+        kind: RequestType,
+    ) -> Option<ProviderResponse> {
+        if let &Request::Web(ref inner) = request {
+            // Echo the challenge of handshakes
+            if kind == RequestType::Handshake {
+                let challenge = inner.params.get("challenge");
+                return challenge.map(|c| ProviderResponse::text(c.as_str()));
+            }
+
+            // Allow to override the response of the other requests
+            if let Some(body) = inner.params.get("response") {
+                return Some(ProviderResponse::text(body.as_str()));
+            }
+        }
+
+        None
+    }
+
     fn prepare_directory(&self, _req: &Request, path: &PathBuf) -> Result<()> {
         // Create a test file
         let mut dest = path.clone();
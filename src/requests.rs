@@ -13,15 +13,19 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::borrow::Cow;
+
 use common::prelude::*;
 use web::WebRequest;
 use providers::StatusEvent;
+use scripts::ScheduledRun;
 
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum RequestType {
     ExecuteHook,
     Ping,
+    Handshake,
     Invalid,
 }
 
@@ -30,6 +34,7 @@ pub enum RequestType {
 pub enum Request {
     Web(WebRequest),
     Status(StatusEvent),
+    Scheduled(ScheduledRun),
 }
 
 impl Request {
@@ -41,6 +46,17 @@ impl Request {
         }
     }
 
+    /// Return the request with its body in memory, loading it from the disk
+    /// if it was spooled when the request was received.
+    pub fn with_body(&self) -> Result<Cow<Request>> {
+        if let Request::Web(ref req) = *self {
+            if let Some(loaded) = req.load_spooled()? {
+                return Ok(Cow::Owned(Request::Web(loaded)));
+            }
+        }
+        Ok(Cow::Borrowed(self))
+    }
+
     pub fn status(&self) -> Result<&StatusEvent> {
         if let Request::Status(ref req) = *self {
             Ok(req)
@@ -63,3 +79,10 @@ impl From<StatusEvent> for Request {
         Request::Status(from)
     }
 }
+
+
+impl From<ScheduledRun> for Request {
+    fn from(from: ScheduledRun) -> Request {
+        Request::Scheduled(from)
+    }
+}
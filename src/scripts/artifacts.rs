@@ -0,0 +1,180 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Collection of the artifacts of the jobs.
+//!
+//! Hooks can declare glob patterns of the files they produce: once the job
+//! ends, the matching files in its working directory are copied elsewhere,
+//! keeping their relative paths, before the working directory is removed.
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::result::Result as StdResult;
+
+use serde::de::{Error as DeError, Deserialize, Deserializer};
+
+use common::prelude::*;
+use utils;
+
+
+/// A glob pattern of the artifacts of a hook, relative to the working
+/// directory of the job.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArtifactPattern(String);
+
+impl ArtifactPattern {
+    pub fn matches(&self, path: &str) -> bool {
+        utils::glob_matches(&self.0, path)
+    }
+}
+
+impl<'de> Deserialize<'de> for ArtifactPattern {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> StdResult<ArtifactPattern, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        // Patterns can't escape the working directory
+        let valid = !pattern.is_empty() && Path::new(&pattern)
+            .components()
+            .all(|component| match component {
+                Component::Normal(..) | Component::CurDir => true,
+                _ => false,
+            });
+        if !valid {
+            return Err(D::Error::custom(
+                format!("invalid artifact pattern: {}", pattern),
+            ));
+        }
+        Ok(ArtifactPattern(pattern.trim_left_matches("./").to_string()))
+    }
+}
+
+
+/// Copy the files in the working directory matching any of the patterns to
+/// the destination, returning the paths of the copies.
+pub fn collect(
+    patterns: &[ArtifactPattern],
+    workdir: &Path,
+    dest: &Path,
+) -> Result<Vec<PathBuf>> {
+    let mut collected = Vec::new();
+
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        for entry in fs::read_dir(workdir.join(&relative))? {
+            let entry = entry?;
+            let path = relative.join(entry.file_name());
+
+            // Symlinks are never followed, since they could point to files
+            // outside of the working directory
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                pending.push(path);
+                continue;
+            } else if !file_type.is_file() {
+                continue;
+            }
+
+            let name = path.to_string_lossy().into_owned();
+            if !patterns.iter().any(|pattern| pattern.matches(&name)) {
+                continue;
+            }
+
+            let copy = dest.join(&path);
+            if let Some(parent) = copy.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(entry.path(), &copy)?;
+
+            // The artifacts are only readable by the user running Fisher,
+            // like the logs they're kept with
+            let mut permissions = fs::metadata(&copy)?.permissions();
+            let mode = permissions.mode() & 0o700;
+            permissions.set_mode(mode);
+            fs::set_permissions(&copy, permissions)?;
+
+            collected.push(copy);
+        }
+    }
+
+    collected.sort();
+    Ok(collected)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    use serde_json;
+
+    use utils;
+
+    use super::{collect, ArtifactPattern};
+
+
+    #[test]
+    fn test_artifact_pattern() {
+        for pattern in &["*.tar.gz", "build/*", "./report.html"] {
+            let json = serde_json::to_string(pattern).unwrap();
+            assert!(serde_json::from_str::<ArtifactPattern>(&json).is_ok());
+        }
+
+        let pattern: ArtifactPattern =
+            serde_json::from_str(r#""./report.html""#).unwrap();
+        assert!(pattern.matches("report.html"));
+
+        for pattern in &["", "/etc/*", "../*", "build/../../*"] {
+            let json = serde_json::to_string(pattern).unwrap();
+            assert!(serde_json::from_str::<ArtifactPattern>(&json).is_err());
+        }
+    }
+
+
+    #[test]
+    fn test_collect() {
+        let workdir = utils::create_temp_dir().unwrap();
+        let dest = utils::create_temp_dir().unwrap();
+
+        fs::create_dir_all(workdir.join("build").join("docs")).unwrap();
+        File::create(workdir.join("build").join("app.tar.gz")).unwrap();
+        File::create(workdir.join("build").join("docs").join("index.html"))
+            .unwrap();
+        File::create(workdir.join("request_body")).unwrap();
+        symlink("/etc/passwd", workdir.join("build").join("passwd")).unwrap();
+
+        let patterns = vec![
+            ArtifactPattern("build/*".into()),
+            ArtifactPattern("*.log".into()),
+        ];
+        let collected = collect(&patterns, &workdir, &dest).unwrap();
+        assert_eq!(collected, vec![
+            dest.join("build").join("app.tar.gz"),
+            dest.join("build").join("docs").join("index.html"),
+        ]);
+
+        let mode = fs::metadata(&collected[0]).unwrap().permissions().mode();
+        assert_eq!(mode & 0o077, 0);
+
+        // The files not matching any pattern and the symlinks are ignored
+        assert!(!dest.join("request_body").exists());
+        assert!(!dest.join("build").join("passwd").exists());
+
+        fs::remove_dir_all(&workdir).unwrap();
+        fs::remove_dir_all(&dest).unwrap();
+    }
+}
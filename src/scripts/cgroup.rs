@@ -0,0 +1,274 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! cgroup v2 integration for the jobs.
+//!
+//! Every execution of a job runs in its own cgroup, created inside the
+//! configured parent before the script is started and removed after it
+//! ends. The parent must be delegated to the user running Fisher, with the
+//! `memory` and `cpu` controllers enabled in its `cgroup.subtree_control`.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use libc;
+
+use common::prelude::*;
+use common::config::JobCgroupConfig;
+
+
+/// The period of the CPU bandwidth limit, in microseconds.
+const CPU_PERIOD: u64 = 100_000;
+
+/// How many times removing a cgroup is attempted, while its processes are
+/// being killed.
+const REMOVE_ATTEMPTS: u32 = 50;
+
+/// How long to wait between the attempts of removing a cgroup, in
+/// milliseconds.
+const REMOVE_INTERVAL: u64 = 20;
+
+
+/// The resources used by the processes of a job.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    /// The CPU time used, in microseconds.
+    pub cpu_usec: Option<u64>,
+    /// The highest memory usage, in bytes.
+    pub memory_peak: Option<u64>,
+}
+
+
+/// The cgroup of an execution of a job, removed when dropped.
+#[derive(Debug)]
+pub struct JobCgroup {
+    path: PathBuf,
+    procs: File,
+}
+
+impl JobCgroup {
+    /// Create the cgroup with the provided name, if cgroups are enabled.
+    pub fn create(
+        config: &JobCgroupConfig, name: &str,
+    ) -> Result<Option<Self>> {
+        let path = if let Some(ref parent) = config.path {
+            Path::new(parent).join(name)
+        } else {
+            return Ok(None);
+        };
+
+        fs::create_dir(&path)?;
+
+        // The cgroup is removed if it can't be configured
+        let procs = match OpenOptions::new()
+            .write(true)
+            .open(path.join("cgroup.procs"))
+        {
+            Ok(file) => file,
+            Err(error) => {
+                let _ = fs::remove_dir(&path);
+                return Err(error.into());
+            }
+        };
+        let cgroup = JobCgroup { path, procs };
+
+        if let Some(ref max) = config.memory_max {
+            cgroup.write("memory.max", &max.as_u64().to_string())?;
+        }
+        if let Some(percent) = config.cpu_percent {
+            let quota = u64::from(percent) * CPU_PERIOD / 100;
+            cgroup.write("cpu.max", &format!("{} {}", quota, CPU_PERIOD))?;
+        }
+
+        Ok(Some(cgroup))
+    }
+
+    /// Return the descriptor the script uses to join the cgroup.
+    pub fn procs_fd(&self) -> RawFd {
+        self.procs.as_raw_fd()
+    }
+
+    /// Return the resources used by the processes in the cgroup so far.
+    pub fn usage(&self) -> ResourceUsage {
+        let cpu_usec = self.read("cpu.stat").and_then(|stat| {
+            stat.lines()
+                .filter_map(|line| {
+                    let mut parts = line.split_whitespace();
+                    match (parts.next(), parts.next()) {
+                        (Some("usage_usec"), Some(value)) => value.parse().ok(),
+                        _ => None,
+                    }
+                })
+                .next()
+        });
+
+        // Older kernels don't track the peak memory usage
+        let memory_peak = self.read("memory.peak")
+            .and_then(|peak| peak.trim().parse().ok());
+
+        ResourceUsage {
+            cpu_usec,
+            memory_peak,
+        }
+    }
+
+    fn write(&self, file: &str, content: &str) -> Result<()> {
+        // The files of cgroups are created by the kernel, and they're
+        // missing if their controller is not enabled
+        OpenOptions::new()
+            .write(true)
+            .open(self.path.join(file))?
+            .write_all(content.as_bytes())?;
+        Ok(())
+    }
+
+    fn read(&self, file: &str) -> Option<String> {
+        let mut content = String::new();
+        match File::open(self.path.join(file)) {
+            Ok(mut f) => f.read_to_string(&mut content).ok().map(|_| content),
+            Err(..) => None,
+        }
+    }
+}
+
+impl Drop for JobCgroup {
+    fn drop(&mut self) {
+        // Processes left behind by the script prevent removing the cgroup,
+        // and killing them is not instantaneous
+        let _ = self.write("cgroup.kill", "1");
+
+        for _ in 0..REMOVE_ATTEMPTS {
+            match fs::remove_dir(&self.path) {
+                Ok(()) => return,
+                Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
+                    return;
+                }
+                Err(..) => {
+                    thread::sleep(Duration::from_millis(REMOVE_INTERVAL));
+                }
+            }
+        }
+
+        let path = self.path.to_string_lossy().into_owned();
+        let error: Error = ErrorKind::CgroupNotRemoved(path).into();
+        error.pretty_print();
+    }
+}
+
+
+/// Move the current process into the cgroup. This is called in the child
+/// process right before the script is executed, so it only uses the raw
+/// system call.
+pub fn join(procs_fd: RawFd) -> io::Result<()> {
+    // Writing 0 moves the process doing the write
+    let content = b"0";
+    let result = unsafe {
+        libc::write(
+            procs_fd, content.as_ptr() as *const libc::c_void, content.len(),
+        )
+    };
+
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::path::Path;
+
+    use common::config::JobCgroupConfig;
+    use utils;
+
+    use super::JobCgroup;
+
+
+    fn fake_cgroup(parent: &Path, name: &str) -> JobCgroup {
+        // A plain directory with the same files behaves like a cgroup
+        let path = parent.join(name);
+        fs::create_dir(&path).unwrap();
+
+        JobCgroup {
+            procs: File::create(path.join("cgroup.procs")).unwrap(),
+            path,
+        }
+    }
+
+
+    #[test]
+    fn test_create() {
+        // Nothing is created if cgroups are disabled
+        let config = JobCgroupConfig::default();
+        assert!(JobCgroup::create(&config, "job").unwrap().is_none());
+
+        // Directories which are not cgroups are not left behind
+        let parent = utils::create_temp_dir().unwrap();
+        let config = JobCgroupConfig {
+            path: Some(parent.to_str().unwrap().into()),
+            memory_max: Some(1024.into()),
+            cpu_percent: Some(50),
+        };
+        assert!(JobCgroup::create(&config, "job").is_err());
+        assert!(!parent.join("job").exists());
+
+        fs::remove_dir_all(&parent).unwrap();
+    }
+
+
+    #[test]
+    fn test_usage_and_cleanup() {
+        let parent = utils::create_temp_dir().unwrap();
+        let cgroup = fake_cgroup(&parent, "job");
+        let path = parent.join("job");
+
+        cgroup.write("cgroup.procs", "0").unwrap();
+        let mut procs = String::new();
+        File::open(path.join("cgroup.procs")).unwrap()
+            .read_to_string(&mut procs).unwrap();
+        assert_eq!(procs, "0");
+
+        // Files missing from the cgroup are not created
+        assert!(cgroup.write("cgroup.kill", "1").is_err());
+        assert!(!path.join("cgroup.kill").exists());
+
+        File::create(path.join("cpu.stat")).unwrap()
+            .write_all(b"usage_usec 1500\nuser_usec 1000\n").unwrap();
+        assert_eq!(cgroup.usage().cpu_usec, Some(1500));
+        assert_eq!(cgroup.usage().memory_peak, None);
+
+        File::create(path.join("memory.peak")).unwrap()
+            .write_all(b"4096\n").unwrap();
+        assert_eq!(cgroup.usage().memory_peak, Some(4096));
+
+        // The cgroup is removed once its processes are gone, and the kernel
+        // removes its files
+        for file in &["cgroup.procs", "cpu.stat", "memory.peak"] {
+            fs::remove_file(path.join(file)).unwrap();
+        }
+        drop(cgroup);
+        assert!(!path.exists());
+
+        fs::remove_dir_all(&parent).unwrap();
+    }
+}
@@ -0,0 +1,201 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Per-hook coalescing of pending jobs.
+//!
+//! Jobs of hooks with coalescing enabled wait for the window before being
+//! run. Every job accepted in the meantime with the same key supersedes the
+//! pending one, which is then dropped when its turn comes: only the last job
+//! of a burst is executed.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde_json;
+
+use common::prelude::*;
+use utils;
+
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CoalesceConfig {
+    window: String,
+    key: Option<String>,
+}
+
+
+#[derive(Debug, Default)]
+struct Pending {
+    /// The most recent pending job for each key.
+    latest: HashMap<String, String>,
+    /// The pending jobs replaced by a newer one.
+    superseded: HashSet<String>,
+}
+
+
+#[derive(Debug)]
+pub struct HookCoalesce {
+    window: Duration,
+    key: Option<String>,
+    pending: Mutex<Pending>,
+}
+
+impl HookCoalesce {
+    /// Parse the content of the `Fisher-Coalesce` configuration comment.
+    pub fn new(config: &str) -> Result<Self> {
+        let config: CoalesceConfig = serde_json::from_str(config)?;
+
+        let window = utils::parse_time(&config.window)? as u64;
+        if window == 0 {
+            return Err(
+                ErrorKind::InvalidTimeString(config.window.clone()).into(),
+            );
+        }
+
+        Ok(HookCoalesce {
+            window: Duration::from_secs(window),
+            key: config.key,
+            pending: Mutex::new(Pending::default()),
+        })
+    }
+
+    /// Coalesce all the pending jobs, running a job only after no newer one
+    /// was accepted for the whole window.
+    pub fn debounce(window: Duration) -> Self {
+        HookCoalesce {
+            window,
+            key: None,
+            pending: Mutex::new(Pending::default()),
+        }
+    }
+
+    /// Return how long jobs wait for newer ones before being run.
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Return the key of a job, taken from the environment its provider
+    /// exposes. Without a configured key all the jobs share the same one.
+    pub fn key(&self, env: &HashMap<String, String>) -> String {
+        match self.key {
+            Some(ref key) => env.get(key).cloned().unwrap_or_default(),
+            None => String::new(),
+        }
+    }
+
+    /// Record a new pending job, superseding the one with the same key.
+    pub fn push(&self, key: String, job_id: &str) {
+        let mut pending = self.pending.lock().unwrap();
+
+        if let Some(previous) = pending.latest.insert(key, job_id.into()) {
+            pending.superseded.insert(previous);
+        }
+    }
+
+    /// Mark the job as started, returning `false` if it was superseded and
+    /// it shouldn't be run. Jobs accepted from now on won't supersede it.
+    pub fn start(&self, job_id: &str) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+
+        if pending.superseded.remove(job_id) {
+            return false;
+        }
+
+        let key = pending
+            .latest
+            .iter()
+            .find(|&(_, latest)| latest == job_id)
+            .map(|(key, _)| key.clone());
+        if let Some(key) = key {
+            pending.latest.remove(&key);
+        }
+
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use super::HookCoalesce;
+
+
+    #[test]
+    fn test_parse() {
+        let coalesce = HookCoalesce::new(r#"{"window": "30s"}"#).unwrap();
+        assert_eq!(coalesce.window(), Duration::from_secs(30));
+        assert_eq!(coalesce.key, None);
+
+        let coalesce = HookCoalesce::new(
+            r#"{"window": "1m", "key": "BRANCH"}"#,
+        ).unwrap();
+        assert_eq!(coalesce.window(), Duration::from_secs(60));
+        assert_eq!(coalesce.key, Some("BRANCH".into()));
+
+        for invalid in &[
+            r#"{"window": "0"}"#,
+            r#"{"window": "soon"}"#,
+            r#"{"window": "1m", "unknown": true}"#,
+            r#"{"key": "BRANCH"}"#,
+        ] {
+            assert!(HookCoalesce::new(invalid).is_err());
+        }
+    }
+
+
+    #[test]
+    fn test_key() {
+        let mut env = HashMap::new();
+        env.insert("BRANCH".to_string(), "master".to_string());
+
+        let all = HookCoalesce::new(r#"{"window": "1m"}"#).unwrap();
+        assert_eq!(all.key(&env), "");
+
+        let branch = HookCoalesce::new(
+            r#"{"window": "1m", "key": "BRANCH"}"#,
+        ).unwrap();
+        assert_eq!(branch.key(&env), "master");
+        assert_eq!(branch.key(&HashMap::new()), "");
+    }
+
+
+    #[test]
+    fn test_coalescing() {
+        let coalesce = HookCoalesce::new(r#"{"window": "1m"}"#).unwrap();
+
+        // Only the last job with the same key is run
+        coalesce.push("master".into(), "a");
+        coalesce.push("master".into(), "b");
+        coalesce.push("develop".into(), "c");
+        coalesce.push("master".into(), "d");
+        assert!(!coalesce.start("a"));
+        assert!(!coalesce.start("b"));
+        assert!(coalesce.start("c"));
+        assert!(coalesce.start("d"));
+
+        // Started jobs are not superseded by newer ones
+        coalesce.push("master".into(), "e");
+        assert!(coalesce.start("e"));
+        assert!(coalesce.start("d"));
+
+        // Jobs never pushed are always started
+        assert!(coalesce.start("f"));
+    }
+}
@@ -20,9 +20,11 @@ use std::os::unix::fs::PermissionsExt;
 use std::sync::Arc;
 
 use common::prelude::*;
+use common::errors::print_warning;
 use common::state::State;
 
 use scripts::Script;
+use scripts::interpreter::Interpreters;
 
 
 pub(in scripts) struct Collector {
@@ -30,6 +32,7 @@ pub(in scripts) struct Collector {
     state: Arc<State>,
     base: PathBuf,
     recursive: bool,
+    interpreters: Arc<Interpreters>,
 }
 
 impl Collector {
@@ -37,6 +40,7 @@ impl Collector {
         base: P,
         state: Arc<State>,
         recursive: bool,
+        interpreters: Arc<Interpreters>,
     ) -> Result<Self> {
         let mut dirs = VecDeque::new();
         dirs.push_front(read_dir(&base)?);
@@ -46,6 +50,7 @@ impl Collector {
             state: state,
             base: base.as_ref().to_path_buf(),
             recursive: recursive,
+            interpreters: interpreters,
         })
     }
 
@@ -57,9 +62,9 @@ impl Collector {
             return Ok(None);
         }
 
-        // Check if the file is executable and readable
+        // Check if the file is readable
         let mode = e.metadata()?.permissions().mode();
-        if !((mode & 0o111) != 0 && (mode & 0o444) != 0) {
+        if (mode & 0o444) == 0 {
             // Skip files with wrong permissions
             return Ok(None);
         }
@@ -68,13 +73,47 @@ impl Collector {
         let name = match e.strip_prefix(&self.base) {
             Ok(stripped) => stripped,
             Err(_) => &e,
-        }.to_str()
-            .unwrap()
-            .to_string();
+        }.to_string_lossy()
+            .into_owned();
+
+        // Files without the executable bit are only scripts if there is an
+        // interpreter for them
+        let mut interpreter = None;
+        if (mode & 0o111) == 0 {
+            let found = match self.interpreters.find(&e) {
+                Ok(Some(found)) => found,
+                _ => return Ok(None),
+            };
+
+            if !self.interpreters.enabled() {
+                print_warning(&format!(
+                    "skipped the script {}, since it's not executable \
+                     (enable scripts.run-non-executable to run it with {})",
+                    name,
+                    found.program(),
+                ));
+                return Ok(None);
+            }
+            interpreter = Some(found);
+        }
 
-        let exec = canonicalize(&e)?.to_str().unwrap().into();
+        // Scripts are executed by path, so it must be represented exactly
+        let exec = match canonicalize(&e)?.to_str() {
+            Some(exec) => exec.to_string(),
+            None => {
+                return Err(ErrorKind::InvalidInput(format!(
+                    "the path of the script {} is not valid UTF-8",
+                    e.display(),
+                )).into());
+            }
+        };
+
+        let mut script = Script::load(name, exec, &self.state)?;
+        if let Some(interpreter) = interpreter {
+            script.set_interpreter(interpreter);
+        }
 
-        Ok(Some(Arc::new(Script::load(name, exec, &self.state)?)))
+        Ok(Some(Arc::new(script)))
     }
 }
 
@@ -122,10 +161,13 @@ impl Iterator for Collector {
 
 #[cfg(test)]
 mod tests {
-    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
     use std::fs;
+    use std::sync::Arc;
 
+    use common::config::ScriptsConfig;
     use common::prelude::*;
+    use scripts::interpreter::Interpreters;
     use scripts::test_utils::*;
 
     use super::Collector;
@@ -135,10 +177,22 @@ mod tests {
         env: &TestEnv,
         recurse: bool,
         expected: &[&str],
+    ) -> Result<()> {
+        assert_collected_with(env, recurse, Interpreters::default(), expected)
+    }
+
+
+    fn assert_collected_with(
+        env: &TestEnv,
+        recurse: bool,
+        interpreters: Interpreters,
+        expected: &[&str],
     ) -> Result<()> {
         let mut found = 0;
 
-        let c = Collector::new(&env.scripts_dir(), env.state(), recurse)?;
+        let c = Collector::new(
+            &env.scripts_dir(), env.state(), recurse, Arc::new(interpreters),
+        )?;
         for script in c {
             found += 1;
 
@@ -185,6 +239,54 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_scripts_collection_of_non_executable_scripts() {
+        test_wrapper(|env| {
+            env.create_script("executable.sh", &[])?;
+            env.create_script("shebang.sh", &["#!/bin/bash", "echo"])?;
+            env.create_script("mapped.py", &["print()"])?;
+            env.create_script("readme.txt", &["Hello world"])?;
+            for name in &["shebang.sh", "mapped.py", "readme.txt"] {
+                let path = env.scripts_dir().join(name);
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o644))?;
+            }
+
+            let mut config = ScriptsConfig::default();
+            config.interpreters.insert("py".into(), "python3".into());
+
+            // Non-executable scripts are skipped by default
+            let interpreters = Interpreters::from_config(&config)?;
+            assert_collected_with(
+                &env, false, interpreters, &["executable.sh"],
+            )?;
+
+            config.run_non_executable = true;
+            let interpreters = Interpreters::from_config(&config)?;
+            assert_collected_with(&env, false, interpreters, &[
+                "executable.sh", "shebang.sh", "mapped.py",
+            ])?;
+
+            // The interpreter is stored in the collected scripts
+            let interpreters = Arc::new(Interpreters::from_config(&config)?);
+            let c = Collector::new(
+                &env.scripts_dir(), env.state(), false, interpreters,
+            )?;
+            for script in c {
+                let script = script?;
+                let program = script.interpreter().map(|i| i.program());
+                match script.name() {
+                    "executable.sh" => assert_eq!(program, None),
+                    "shebang.sh" => assert_eq!(program, Some("/bin/bash")),
+                    "mapped.py" => assert_eq!(program, Some("python3")),
+                    name => panic!("Unexpected script collected: {}", name),
+                }
+            }
+
+            Ok(())
+        });
+    }
+
+
     #[test]
     fn test_scripts_collection_with_invalid_scripts_fails() {
         test_wrapper(|env| {
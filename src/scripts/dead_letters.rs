@@ -0,0 +1,225 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Jobs which failed after all their retries.
+//!
+//! When a directory is configured, the request which started every failed
+//! job is saved in it as `<job id>.json`, with its body, its headers and the
+//! outcome of the last attempt. The files are left there until an operator
+//! requeues the job through the admin API or removes them.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json;
+
+use common::prelude::*;
+use scripts::jobs::{Job, JobOutput};
+use scripts::journal::JournalEntry;
+
+
+/// A job which failed after all its retries.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub entry: JournalEntry,
+    /// How many times the job was executed.
+    pub attempts: u32,
+    /// The UNIX timestamp of when the job failed for the last time.
+    pub failed_at: u64,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub timed_out: bool,
+}
+
+
+/// The directory the failed jobs are saved to.
+#[derive(Debug)]
+pub struct DeadLetters {
+    dir: Mutex<Option<PathBuf>>,
+}
+
+impl DeadLetters {
+    pub fn new() -> Self {
+        DeadLetters {
+            dir: Mutex::new(None),
+        }
+    }
+
+    /// Start saving the failed jobs in the directory, or stop saving them if
+    /// no directory is provided.
+    pub fn set_dir(&self, dir: Option<&Path>) -> Result<()> {
+        if let Some(dir) = dir {
+            fs::create_dir_all(dir)?;
+        }
+
+        *self.dir.lock()? = dir.map(|dir| dir.to_path_buf());
+        Ok(())
+    }
+
+    /// Save the job, if it was started by the HTTP server, returning the
+    /// path of its file. The output is missing if the job couldn't start.
+    pub fn save(
+        &self,
+        job: &Job,
+        priority: isize,
+        output: Option<&JobOutput>,
+    ) -> Result<Option<PathBuf>> {
+        let dir = match *self.dir.lock()? {
+            Some(ref dir) => dir.clone(),
+            None => return Ok(None),
+        };
+        let entry = match job.journal_entry(priority)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        let letter = DeadLetter {
+            entry,
+            attempts: job.attempt(),
+            failed_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or(0),
+            exit_code: output.and_then(|output| output.exit_code),
+            signal: output.and_then(|output| output.signal),
+            timed_out: output.map(|output| output.timed_out).unwrap_or(false),
+        };
+
+        // The file is written somewhere else first, so operators never see
+        // half-written ones
+        let path = dir.join(format!("{}.json", job.id()));
+        let mut temp_path = OsString::from(path.as_os_str());
+        temp_path.push(".tmp");
+        {
+            // Request bodies might contain secrets
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&temp_path)?;
+            file.write_all(&serde_json::to_vec(&letter)?)?;
+            file.sync_data()?;
+        }
+        fs::rename(&temp_path, &path)?;
+
+        Ok(Some(path))
+    }
+
+    /// Return the saved job with the ID, if any.
+    pub fn get(&self, job_id: &str) -> Result<Option<DeadLetter>> {
+        let path = match self.path(job_id)? {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        let mut content = String::new();
+        match File::open(&path) {
+            Ok(mut file) => file.read_to_string(&mut content)?,
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
+                return Ok(None);
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        Ok(Some(serde_json::from_str(&content)?))
+    }
+
+    /// Remove the saved job with the ID, once it's been requeued.
+    pub fn remove(&self, job_id: &str) -> Result<()> {
+        if let Some(path) = self.path(job_id)? {
+            fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+
+    fn path(&self, job_id: &str) -> Result<Option<PathBuf>> {
+        // IDs are hex strings, so nothing outside the directory is read
+        if job_id.is_empty() || !job_id.chars().all(|c| c.is_digit(16)) {
+            return Ok(None);
+        }
+
+        Ok(self.dir
+            .lock()?
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.json", job_id))))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Arc;
+
+    use requests::Request;
+    use scripts::Job;
+    use scripts::test_utils::*;
+    use utils::testing::dummy_job_output;
+
+    use super::DeadLetters;
+
+
+    #[test]
+    fn test_dead_letters() {
+        test_wrapper(|env| {
+            env.create_script("example.sh", &[])?;
+            let script = Arc::new(env.load_script("example.sh")?);
+            let dir = env.tempdir()?.join("dead-letters");
+
+            let mut req = dummy_web_request();
+            req.body = "payload".into();
+            let job = Job::new(script, None, Request::Web(req));
+            let mut output = dummy_job_output();
+            output.success = false;
+            output.exit_code = Some(1);
+
+            // Nothing is saved if the directory is not set
+            let letters = DeadLetters::new();
+            assert!(letters.save(&job, 5, Some(&output))?.is_none());
+            assert!(letters.get(job.id())?.is_none());
+
+            letters.set_dir(Some(&dir))?;
+            let path = letters.save(&job, 5, Some(&output))?.unwrap();
+            assert_eq!(path, dir.join(format!("{}.json", job.id())));
+            let mode = fs::metadata(&path)?.permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+
+            let letter = letters.get(job.id())?.unwrap();
+            assert_eq!(letter.entry, job.journal_entry(5)?.unwrap());
+            assert_eq!(letter.entry.request.body, "payload");
+            assert_eq!(letter.attempts, 1);
+            assert_eq!(letter.exit_code, Some(1));
+            assert!(!letter.timed_out);
+
+            // Paths outside of the directory can't be read
+            assert!(letters.get("../dead-letters/x")?.is_none());
+            assert!(letters.get("")?.is_none());
+
+            letters.remove(job.id())?;
+            assert!(letters.get(job.id())?.is_none());
+            assert!(!path.exists());
+
+            fs::remove_dir_all(&dir)?;
+            Ok(())
+        });
+    }
+}
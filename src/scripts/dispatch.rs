@@ -0,0 +1,305 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Running the jobs on remote workers.
+//!
+//! An instance with workers configured doesn't run the jobs started by the
+//! HTTP server itself: it sends them to the `/worker/run` endpoint of other
+//! Fisher instances, in turn, and waits for their output. The request which
+//! started the job is sent as it's stored in the journal, so the worker can
+//! rebuild the job with its own copy of the hook. Retries and status hooks
+//! are still handled by the instance which received the webhook.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use serde_json;
+use url::Url;
+
+use common::prelude::*;
+use common::config::WorkersConfig;
+use scripts::journal::JournalEntry;
+use scripts::jobs::JobOutput;
+use utils::http_client;
+
+
+/// A job sent to a worker.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DispatchedJob {
+    pub entry: JournalEntry,
+    /// Which execution of the job this is, starting from 1.
+    pub attempt: u32,
+    /// How long the instance which sent the job waits for its output, in
+    /// seconds.
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+
+/// The output of a job, as it's sent back by the workers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub success: bool,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub timed_out: bool,
+}
+
+impl<'a> From<&'a JobOutput> for RemoteOutput {
+    fn from(output: &'a JobOutput) -> Self {
+        RemoteOutput {
+            stdout: output.stdout.clone(),
+            stderr: output.stderr.clone(),
+            success: output.success,
+            exit_code: output.exit_code,
+            signal: output.signal,
+            timed_out: output.timed_out,
+        }
+    }
+}
+
+
+/// The body of the responses sent back by the workers.
+#[derive(Debug, Deserialize)]
+struct WorkerResponse {
+    result: RemoteOutput,
+}
+
+
+/// Send the jobs to the configured workers.
+#[derive(Debug)]
+pub struct Dispatcher {
+    workers: Vec<Url>,
+    token: Option<String>,
+    next: AtomicUsize,
+}
+
+impl Dispatcher {
+    /// Create a new dispatcher, if there are workers in the configuration.
+    pub fn from_config(config: &WorkersConfig) -> Result<Option<Self>> {
+        if config.urls.is_empty() {
+            return Ok(None);
+        }
+
+        let mut workers = Vec::with_capacity(config.urls.len());
+        for url in &config.urls {
+            // Workers can serve their endpoints under a base path
+            let base = if url.ends_with('/') {
+                url.clone()
+            } else {
+                format!("{}/", url)
+            };
+
+            let worker = Url::parse(&base)
+                .and_then(|base| base.join("worker/run"))
+                .map_err(|_| {
+                    ErrorKind::InvalidInput(
+                        format!("invalid worker URL: {}", url),
+                    )
+                })?;
+            http_client::check_url(&worker)?;
+            workers.push(worker);
+        }
+
+        Ok(Some(Dispatcher {
+            workers,
+            token: config.token.clone(),
+            next: AtomicUsize::new(0),
+        }))
+    }
+
+    /// Run the job on one of the workers, waiting for its output at most
+    /// for the timeout, if any.
+    pub fn run(
+        &self,
+        job: &DispatchedJob,
+        timeout: Option<Duration>,
+    ) -> Result<RemoteOutput> {
+        let body = serde_json::to_vec(job)?;
+        let mut headers = vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+        ];
+        if let Some(ref token) = self.token {
+            headers.push(
+                ("Authorization".to_string(), format!("Bearer {}", token)),
+            );
+        }
+
+        // Workers are picked in turn, trying the next ones if a worker is
+        // not reachable or didn't accept the job
+        let first = self.next.fetch_add(1, Ordering::SeqCst);
+        let mut reasons = Vec::new();
+        for i in 0..self.workers.len() {
+            let url = &self.workers[(first + i) % self.workers.len()];
+
+            let stream = match http_client::connect(url, timeout) {
+                Ok(stream) => stream,
+                Err(error) => {
+                    reasons.push(format!("{}: {}", url, error));
+                    continue;
+                }
+            };
+
+            // The job might have run if the connection broke after sending
+            // it, so it's not sent to other workers
+            let response =
+                http_client::exchange(stream, "POST", url, &headers, &body)?;
+            match response.status {
+                200 => {
+                    let response: WorkerResponse =
+                        serde_json::from_slice(&response.body)?;
+                    return Ok(response.result);
+                }
+                // The worker doesn't know the hook or it's not accepting
+                // new jobs
+                404 | 503 => reasons.push(
+                    format!("{}: status code {}", url, response.status),
+                ),
+                status => {
+                    return Err(ErrorKind::InvalidInput(format!(
+                        "worker {} answered with status code {}", url, status,
+                    )).into());
+                }
+            }
+        }
+
+        Err(ErrorKind::InvalidInput(format!(
+            "no worker accepted the job ({})", reasons.join(", "),
+        )).into())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use serde_json;
+
+    use common::config::WorkersConfig;
+    use scripts::journal::{JournalEntry, StoredRequest};
+    use utils::testing::*;
+
+    use super::{DispatchedJob, Dispatcher, RemoteOutput};
+
+
+    fn dispatched_job() -> DispatchedJob {
+        DispatchedJob {
+            entry: JournalEntry {
+                job_id: "job-id".into(),
+                hook: "example.sh".into(),
+                provider: None,
+                priority: 0,
+                received_at: Some(1000),
+                request: StoredRequest::new(&dummy_web_request()).unwrap(),
+            },
+            attempt: 2,
+            timeout: None,
+        }
+    }
+
+
+    /// Start a fake worker answering with the responses, returning its URL.
+    fn fake_worker(
+        responses: Vec<String>,
+    ) -> (String, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let handle = thread::spawn(move || {
+            for response in responses {
+                let (mut conn, _) = listener.accept().unwrap();
+
+                // Read until the whole job is received
+                let mut received = Vec::new();
+                let mut buffer = [0; 4096];
+                while !received.ends_with(b"}") {
+                    let len = conn.read(&mut buffer).unwrap();
+                    if len == 0 {
+                        break;
+                    }
+                    received.extend_from_slice(&buffer[..len]);
+                }
+
+                let received = String::from_utf8(received).unwrap();
+                assert!(received.starts_with("POST /worker/run HTTP/1.0"));
+                assert!(received.contains("Authorization: Bearer token"));
+
+                let body = received.splitn(2, "\r\n\r\n").nth(1).unwrap();
+                let job: DispatchedJob = serde_json::from_str(body).unwrap();
+                assert_eq!(job, dispatched_job());
+
+                conn.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        (url, handle)
+    }
+
+
+    #[test]
+    fn test_from_config() {
+        let config = WorkersConfig::default();
+        assert!(Dispatcher::from_config(&config).unwrap().is_none());
+
+        let config = WorkersConfig {
+            urls: vec!["not a url".into()],
+            token: None,
+        };
+        assert!(Dispatcher::from_config(&config).is_err());
+    }
+
+
+    #[test]
+    fn test_run() {
+        let output = RemoteOutput {
+            stdout: "hello".into(),
+            stderr: String::new(),
+            success: true,
+            exit_code: Some(0),
+            signal: None,
+            timed_out: false,
+        };
+        // Workers answer like the other JSON endpoints
+        let body = json!({"status": "ok", "result": output}).to_string();
+
+        // The second worker is tried if the first one is not accepting jobs
+        let (busy, busy_handle) = fake_worker(vec![
+            "HTTP/1.0 503 Service Unavailable\r\n\r\n".into(),
+            "HTTP/1.0 500 Internal Server Error\r\n\r\n".into(),
+        ]);
+        let (worker, worker_handle) = fake_worker(vec![
+            format!("HTTP/1.0 200 OK\r\n\r\n{}", body),
+        ]);
+
+        let dispatcher = Dispatcher::from_config(&WorkersConfig {
+            urls: vec![busy, worker],
+            token: Some("token".into()),
+        }).unwrap().unwrap();
+
+        let job = dispatched_job();
+        assert_eq!(dispatcher.run(&job, None).unwrap(), output);
+
+        // Other errors are not retried on the other workers
+        assert!(dispatcher.run(&job, None).is_err());
+
+        busy_handle.join().unwrap();
+        worker_handle.join().unwrap();
+    }
+}
@@ -0,0 +1,477 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! How the scripts of the jobs are executed.
+//!
+//! Scripts are executed directly by default. Hooks declaring an image are
+//! run inside a container instead, with the container runtime's CLI: only
+//! the directories of the job are mounted in it, at the same paths, and the
+//! environment of the job is passed through. Hooks can also be launched with
+//! firejail, which applies a profile and a seccomp filter to them. Scripts
+//! without the executable bit are passed to their interpreter.
+
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+use std::process;
+use std::result::Result as StdResult;
+
+use serde::de::{Error as DeError, Deserialize, Deserializer};
+use users;
+
+use scripts::interpreter::Interpreter;
+use utils;
+
+
+/// The variables not passed to the containers, since they would break the
+/// environment of the image.
+static HOST_ONLY_ENV: &[&'static str] = &["PATH"];
+
+/// The command launching the scripts with firejail.
+const FIREJAIL: &'static str = "firejail";
+
+
+/// The environment variables of a job.
+#[derive(Debug, Clone, Default)]
+pub struct JobEnv {
+    vars: BTreeMap<OsString, OsString>,
+}
+
+impl JobEnv {
+    pub fn new() -> Self {
+        JobEnv {
+            vars: BTreeMap::new(),
+        }
+    }
+
+    /// Set the variable, replacing its previous value.
+    pub fn set<K, V>(&mut self, key: K, value: V)
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        let (key, value) = (key.as_ref(), value.as_ref());
+        self.vars.insert(key.to_os_string(), value.to_os_string());
+    }
+
+    pub fn iter(&self) -> btree_map::Iter<OsString, OsString> {
+        self.vars.iter()
+    }
+}
+
+
+/// The image of the container a hook runs in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContainerImage(String);
+
+impl ContainerImage {
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<'de> Deserialize<'de> for ContainerImage {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> StdResult<ContainerImage, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        // The name is passed as an argument of the runtime's CLI
+        if name.is_empty() || name.starts_with('-')
+            || name.chars().any(char::is_whitespace)
+        {
+            return Err(D::Error::custom(
+                format!("invalid container image: {}", name),
+            ));
+        }
+        Ok(ContainerImage(name))
+    }
+}
+
+
+/// How firejail restricts a hook.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirejailConfig {
+    /// The name or the path of the profile, if not the default one.
+    profile: Option<String>,
+    /// The syscalls blocked in addition to the default ones, if seccomp is
+    /// enabled.
+    seccomp: Option<Vec<String>>,
+}
+
+impl FirejailConfig {
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_ref().map(|profile| profile.as_str())
+    }
+
+    pub fn seccomp(&self) -> Option<&[String]> {
+        self.seccomp.as_ref().map(|seccomp| seccomp.as_slice())
+    }
+
+    /// Return the arguments of firejail, before the script.
+    fn args(&self) -> Vec<String> {
+        let mut args = vec!["--quiet".to_string()];
+        match self.profile {
+            Some(ref profile) => args.push(format!("--profile={}", profile)),
+            None => args.push("--noprofile".into()),
+        }
+        match self.seccomp {
+            Some(ref syscalls) if syscalls.is_empty() => {
+                args.push("--seccomp".into());
+            }
+            Some(ref syscalls) => {
+                args.push(format!("--seccomp={}", syscalls.join(",")));
+            }
+            None => {}
+        }
+        args
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawFirejailConfig {
+    profile: Option<String>,
+    seccomp: Option<Vec<String>>,
+}
+
+impl<'de> Deserialize<'de> for FirejailConfig {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> StdResult<FirejailConfig, D::Error> {
+        let raw = RawFirejailConfig::deserialize(deserializer)?;
+
+        // Both are passed as arguments of firejail
+        if let Some(ref profile) = raw.profile {
+            if profile.is_empty() || profile.starts_with('-')
+                || profile.chars().any(char::is_whitespace)
+            {
+                return Err(D::Error::custom(
+                    format!("invalid firejail profile: {}", profile),
+                ));
+            }
+        }
+        for syscall in raw.seccomp.iter().flat_map(|list| list.iter()) {
+            let valid = syscall.chars().all(|c| match c {
+                'a'...'z' | '0'...'9' | '_' => true,
+                _ => false,
+            });
+            if syscall.is_empty() || !valid {
+                return Err(D::Error::custom(
+                    format!("invalid syscall name: {}", syscall),
+                ));
+            }
+        }
+
+        Ok(FirejailConfig {
+            profile: raw.profile,
+            seccomp: raw.seccomp,
+        })
+    }
+}
+
+
+/// What needs to be executed for a job.
+#[derive(Debug)]
+pub struct ExecSpec<'a> {
+    /// A name unique to this execution of the job.
+    pub name: &'a str,
+    pub script: &'a Path,
+    /// The interpreter of the script, if it's not executable.
+    pub interpreter: Option<&'a Interpreter>,
+    pub env: &'a JobEnv,
+    pub working_directory: &'a Path,
+    /// The directories the script needs to access, including the working
+    /// directory.
+    pub directories: &'a [&'a Path],
+}
+
+impl<'a> ExecSpec<'a> {
+    /// Return the program executing the script and its arguments.
+    fn command_line(&self) -> Vec<OsString> {
+        match self.interpreter {
+            Some(interpreter) => interpreter.command_line(self.script),
+            None => vec![self.script.into()],
+        }
+    }
+}
+
+
+/// A way to execute the scripts of the jobs.
+pub trait Executor {
+    /// Return the command executing the script.
+    fn command(&self, spec: &ExecSpec) -> process::Command;
+
+    /// Clean up after the command exited or was killed.
+    fn cleanup(&self, _spec: &ExecSpec) {}
+}
+
+
+/// Execute the scripts directly on the host.
+#[derive(Debug, Clone, Copy)]
+pub struct DirectExecutor;
+
+impl Executor for DirectExecutor {
+    fn command(&self, spec: &ExecSpec) -> process::Command {
+        let line = spec.command_line();
+        let mut command = host_command(&line[0], spec);
+        command.args(&line[1..]);
+        command
+    }
+}
+
+
+/// Execute the scripts inside a container of the image.
+#[derive(Debug, Clone)]
+pub struct ContainerExecutor<'a> {
+    runtime: &'a str,
+    image: &'a ContainerImage,
+}
+
+impl<'a> ContainerExecutor<'a> {
+    pub fn new(runtime: &'a str, image: &'a ContainerImage) -> Self {
+        ContainerExecutor { runtime, image }
+    }
+
+    /// Return the arguments of the runtime's CLI.
+    fn args(&self, spec: &ExecSpec) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec![
+            "run".into(), "--rm".into(), "--interactive".into(),
+            "--name".into(), spec.name.into(),
+        ];
+
+        // Files created in the mounted directories must be removable by
+        // Fisher afterwards
+        args.push("--user".into());
+        args.push(format!(
+            "{}:{}", users::get_current_uid(), users::get_current_gid(),
+        ).into());
+
+        for dir in spec.directories {
+            args.push("--volume".into());
+            args.push(volume(dir, false));
+        }
+        args.push("--volume".into());
+        args.push(volume(spec.script, true));
+        args.push("--workdir".into());
+        args.push(spec.working_directory.into());
+
+        // Only the names are passed, so the values are read from the
+        // environment of the CLI and don't show up in the process list
+        for (key, _) in spec.env.iter() {
+            if HOST_ONLY_ENV.iter().any(|name| OsStr::new(name) == key) {
+                continue;
+            }
+            args.push("--env".into());
+            args.push(key.clone());
+        }
+
+        args.push(self.image.name().into());
+        args.extend(spec.command_line());
+        args
+    }
+}
+
+impl<'a> Executor for ContainerExecutor<'a> {
+    fn command(&self, spec: &ExecSpec) -> process::Command {
+        // The CLI itself runs with the environment of the job
+        let mut command = host_command(self.runtime, spec);
+        command.args(self.args(spec));
+        command
+    }
+
+    fn cleanup(&self, spec: &ExecSpec) {
+        // Killing the CLI doesn't stop the container, and this fails if it
+        // was already removed
+        let mut command = host_command(self.runtime, spec);
+        command
+            .args(&["rm", "--force", spec.name])
+            .stdin(process::Stdio::null())
+            .stdout(process::Stdio::null())
+            .stderr(process::Stdio::null());
+        if let Ok(mut child) = utils::spawn_tracked(&mut command) {
+            let _ = child.wait();
+            utils::release_tracked(&child);
+        }
+    }
+}
+
+
+/// Execute the scripts with firejail.
+#[derive(Debug, Clone)]
+pub struct FirejailExecutor<'a> {
+    config: &'a FirejailConfig,
+}
+
+impl<'a> FirejailExecutor<'a> {
+    pub fn new(config: &'a FirejailConfig) -> Self {
+        FirejailExecutor { config }
+    }
+}
+
+impl<'a> Executor for FirejailExecutor<'a> {
+    fn command(&self, spec: &ExecSpec) -> process::Command {
+        let mut command = host_command(FIREJAIL, spec);
+        command.args(self.config.args());
+        command.arg("--");
+        command.args(spec.command_line());
+        command
+    }
+}
+
+
+/// Return the command running the program on the host, in the working
+/// directory and with the environment of the job.
+fn host_command<P: AsRef<OsStr>>(
+    program: P,
+    spec: &ExecSpec,
+) -> process::Command {
+    let mut command = process::Command::new(program);
+    command.env_clear();
+    for (key, value) in spec.env.iter() {
+        command.env(key, value);
+    }
+    command.current_dir(spec.working_directory);
+    command
+}
+
+
+/// Mount the path at the same place inside the container.
+fn volume(path: &Path, readonly: bool) -> OsString {
+    let mut volume = path.as_os_str().to_os_string();
+    volume.push(":");
+    volume.push(path);
+    if readonly {
+        volume.push(":ro");
+    }
+    volume
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use serde_json;
+
+    use scripts::interpreter::Interpreter;
+
+    use super::{ContainerExecutor, ContainerImage, ExecSpec, FirejailConfig};
+    use super::JobEnv;
+
+
+    #[test]
+    fn test_container_image() {
+        let parse = |value: &str| serde_json::from_str::<ContainerImage>(value);
+
+        assert_eq!(parse(r#""alpine:3.6""#).unwrap().name(), "alpine:3.6");
+        for invalid in &[r#""""#, r#""--privileged""#, r#""a b""#, "1"] {
+            assert!(parse(invalid).is_err());
+        }
+    }
+
+
+    #[test]
+    fn test_firejail_config() {
+        let parse = |value: &str| serde_json::from_str::<FirejailConfig>(value);
+
+        let config = parse("{}").unwrap();
+        assert_eq!(config.args(), vec!["--quiet", "--noprofile"]);
+
+        let config = parse(r#"{"profile": "deploy", "seccomp": []}"#).unwrap();
+        assert_eq!(config.profile(), Some("deploy"));
+        assert_eq!(
+            config.args(), vec!["--quiet", "--profile=deploy", "--seccomp"],
+        );
+
+        let config = parse(r#"{"seccomp": ["ptrace", "mount"]}"#).unwrap();
+        assert_eq!(config.args(), vec![
+            "--quiet", "--noprofile", "--seccomp=ptrace,mount",
+        ]);
+
+        for invalid in &[
+            r#"{"profile": ""}"#,
+            r#"{"profile": "--debug"}"#,
+            r#"{"seccomp": ["ptrace,mount"]}"#,
+            r#"{"seccomp": [""]}"#,
+            r#"{"profil": "deploy"}"#,
+        ] {
+            assert!(parse(invalid).is_err());
+        }
+    }
+
+
+    #[test]
+    fn test_container_args() {
+        let mut env = JobEnv::new();
+        env.set("PATH", "/usr/bin");
+        env.set("FISHER_JOB_ID", "1");
+        env.set("HOME", "/tmp/work");
+
+        let image = ContainerImage("alpine".into());
+        let executor = ContainerExecutor::new("docker", &image);
+        let directories = [Path::new("/tmp/work"), Path::new("/tmp/req")];
+        let args = executor.args(&ExecSpec {
+            name: "fisher-1-1",
+            script: Path::new("/srv/hooks/deploy.sh"),
+            interpreter: None,
+            env: &env,
+            working_directory: Path::new("/tmp/work"),
+            directories: &directories,
+        });
+        let args = args.iter().map(|arg| arg.to_str().unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(&args[..5], &["run", "--rm", "--interactive", "--name",
+                                  "fisher-1-1"]);
+        assert_eq!(&args[7..], &[
+            "--volume", "/tmp/work:/tmp/work",
+            "--volume", "/tmp/req:/tmp/req",
+            "--volume", "/srv/hooks/deploy.sh:/srv/hooks/deploy.sh:ro",
+            "--workdir", "/tmp/work",
+            "--env", "FISHER_JOB_ID",
+            "--env", "HOME",
+            "alpine", "/srv/hooks/deploy.sh",
+        ]);
+    }
+
+
+    #[test]
+    fn test_interpreter_command_line() {
+        let env = JobEnv::new();
+        let interpreter = Interpreter::parse("python3 -u").unwrap();
+        let mut spec = ExecSpec {
+            name: "fisher-1-1",
+            script: Path::new("/srv/hooks/deploy.py"),
+            interpreter: None,
+            env: &env,
+            working_directory: Path::new("/tmp/work"),
+            directories: &[],
+        };
+        assert_eq!(spec.command_line(), vec!["/srv/hooks/deploy.py"]);
+
+        // The script is passed to its interpreter
+        spec.interpreter = Some(&interpreter);
+        assert_eq!(
+            spec.command_line(), vec!["python3", "-u", "/srv/hooks/deploy.py"],
+        );
+
+        let image = ContainerImage("alpine".into());
+        let args = ContainerExecutor::new("docker", &image).args(&spec);
+        assert_eq!(&args[args.len() - 4..], &[
+            "alpine", "python3", "-u", "/srv/hooks/deploy.py",
+        ]);
+    }
+}
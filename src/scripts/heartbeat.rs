@@ -0,0 +1,236 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Heartbeats allow hooks to declare how often they expect deliveries, so
+//! Fisher can notice when the upstream service goes silent.
+//!
+//! Deliveries are recorded as they arrive, and a background thread
+//! periodically checks if any hook went over its interval, emitting a
+//! `heartbeat_missed` status event only once for each period of silence.
+
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use common::prelude::*;
+use common::errors::print_warning;
+use processor::STATUS_EVENTS_PRIORITY;
+use providers::HeartbeatMissed;
+use scripts::{Repository, Script};
+
+
+/// How often the monitor checks for missed heartbeats.
+const CHECK_INTERVAL: u64 = 10;
+
+
+#[derive(Debug)]
+struct HeartbeatStatus {
+    since: Instant,
+    last_seen: Option<SystemTime>,
+    reported: bool,
+}
+
+impl HeartbeatStatus {
+    fn new(now: Instant) -> Self {
+        HeartbeatStatus {
+            since: now,
+            last_seen: None,
+            reported: false,
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub struct Heartbeats {
+    statuses: Mutex<HashMap<String, HeartbeatStatus>>,
+}
+
+impl Heartbeats {
+    pub fn new() -> Self {
+        Heartbeats {
+            statuses: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record a delivery for the hook, resetting its heartbeat.
+    pub fn record(&self, hook: &str) {
+        let mut statuses = self.statuses.lock().unwrap();
+
+        let now = Instant::now();
+        let status = statuses
+            .entry(hook.to_string())
+            .or_insert_with(|| HeartbeatStatus::new(now));
+
+        status.since = now;
+        status.last_seen = Some(SystemTime::now());
+        status.reported = false;
+    }
+
+    /// Return the heartbeats missed by the provided scripts.
+    pub fn check<I: Iterator<Item = Arc<Script>>>(
+        &self, scripts: I,
+    ) -> Vec<HeartbeatMissed> {
+        self.check_at(scripts, Instant::now())
+    }
+
+    fn check_at<I: Iterator<Item = Arc<Script>>>(
+        &self, scripts: I, now: Instant,
+    ) -> Vec<HeartbeatMissed> {
+        let mut statuses = self.statuses.lock().unwrap();
+
+        let mut missed = Vec::new();
+        for script in scripts {
+            let interval = match script.heartbeat() {
+                Some(interval) => interval,
+                None => continue,
+            };
+
+            // Hooks never seen before get a full interval to receive
+            // their first delivery
+            let status = statuses
+                .entry(script.name().to_string())
+                .or_insert_with(|| HeartbeatStatus::new(now));
+
+            if status.reported {
+                continue;
+            }
+
+            // Deliveries recorded after the check started are fine
+            let elapsed = if now > status.since {
+                now.duration_since(status.since)
+            } else {
+                Duration::from_secs(0)
+            };
+
+            if elapsed >= Duration::from_secs(interval) {
+                status.reported = true;
+                missed.push(HeartbeatMissed {
+                    hook_name: script.name().to_string(),
+                    interval: interval,
+                    last_seen: status.last_seen
+                        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                        .map(|d| d.as_secs()),
+                });
+            }
+        }
+
+        missed
+    }
+}
+
+
+/// Background thread queueing the status hooks of missed heartbeats.
+pub struct HeartbeatMonitor {
+    stop: mpsc::Sender<()>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl HeartbeatMonitor {
+    pub fn new<A: ProcessorApiTrait<Repository> + 'static>(
+        hooks: Arc<Repository>, processor: A,
+    ) -> Self {
+        let (stop_send, stop_recv) = mpsc::channel();
+
+        let handle = thread::spawn(move || {
+            let interval = Duration::from_secs(CHECK_INTERVAL);
+
+            // Stop when asked to, or when the monitor is dropped
+            while let Err(mpsc::RecvTimeoutError::Timeout) =
+                stop_recv.recv_timeout(interval)
+            {
+                for missed in hooks.missed_heartbeats() {
+                    print_warning(&format!(
+                        "hook {} didn't receive any delivery in {} seconds",
+                        missed.hook_name,
+                        missed.interval,
+                    ));
+
+                    for job in hooks.heartbeat_jobs(missed) {
+                        let _ = processor.queue(job, STATUS_EVENTS_PRIORITY);
+                    }
+                }
+            }
+        });
+
+        HeartbeatMonitor {
+            stop: stop_send,
+            handle: handle,
+        }
+    }
+
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.handle.join();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use common::prelude::*;
+    use scripts::test_utils::*;
+
+    use super::Heartbeats;
+
+
+    #[test]
+    fn test_heartbeats() {
+        test_wrapper(|env| {
+            env.create_script(
+                "watched.sh",
+                &[r#"#!/bin/bash"#, r#"## Fisher: {"heartbeat": "60"}"#],
+            )?;
+            env.create_script("unwatched.sh", &[r#"#!/bin/bash"#])?;
+            let scripts = vec![
+                Arc::new(env.load_script("watched.sh")?),
+                Arc::new(env.load_script("unwatched.sh")?),
+            ];
+
+            let heartbeats = Heartbeats::new();
+            let start = Instant::now();
+            let check = |after: u64| {
+                heartbeats.check_at(
+                    scripts.iter().cloned(),
+                    start + Duration::from_secs(after),
+                )
+            };
+
+            // The first check starts the countdown
+            assert!(check(0).is_empty());
+            assert!(check(30).is_empty());
+
+            // The heartbeat is reported only once
+            let missed = check(60);
+            assert_eq!(missed.len(), 1);
+            assert_eq!(missed[0].hook_name, "watched.sh");
+            assert_eq!(missed[0].interval, 60);
+            assert_eq!(missed[0].last_seen, None);
+            assert!(check(120).is_empty());
+
+            // A delivery resets the heartbeat
+            heartbeats.record("watched.sh");
+            let missed = check(1000);
+            assert_eq!(missed.len(), 1);
+            assert!(missed[0].last_seen.is_some());
+
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,291 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use common::prelude::*;
+use scripts::{JobOutput, Termination};
+
+
+/// How many job results are remembered.
+const MAX_RESULTS: usize = 100;
+
+
+/// The result of a job which already ended.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct JobResult {
+    pub hook: String,
+    /// The identifier of the request which started the job.
+    pub request_id: Option<String>,
+    pub request_ip: IpAddr,
+    pub success: bool,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    /// Whether the job was killed because it didn't exit in time.
+    pub timed_out: bool,
+    /// Whether the job was stopped because Fisher was shutting down.
+    pub interrupted: bool,
+    /// How the job ended, if it had to be stopped.
+    pub termination: Option<Termination>,
+    /// The files the output of the job was saved to, if any.
+    pub stdout_log: Option<String>,
+    pub stderr_log: Option<String>,
+    /// The artifacts collected after the job ended.
+    pub artifacts: Vec<String>,
+    /// The URLs the artifacts were uploaded to, if they were uploaded.
+    pub artifact_urls: Vec<String>,
+    /// When the job started and ended, as UNIX timestamps.
+    pub started_at: u64,
+    pub finished_at: u64,
+    /// How long the job ran, in milliseconds.
+    pub duration_ms: u64,
+}
+
+impl JobResult {
+    pub fn outcome(&self) -> JobOutcome {
+        if self.timed_out {
+            JobOutcome::TimedOut
+        } else if self.success {
+            JobOutcome::Success
+        } else {
+            JobOutcome::Failed
+        }
+    }
+}
+
+
+/// How a job ended. Jobs killed because they didn't exit in time are not
+/// considered failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobOutcome {
+    Success,
+    Failed,
+    TimedOut,
+}
+
+impl FromStr for JobOutcome {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "success" => Ok(JobOutcome::Success),
+            "failed" => Ok(JobOutcome::Failed),
+            "timed-out" => Ok(JobOutcome::TimedOut),
+            _ => Err(ErrorKind::InvalidInput(
+                format!("invalid job outcome: {}", s),
+            ).into()),
+        }
+    }
+}
+
+
+/// The results of the most recent jobs.
+#[derive(Debug)]
+pub struct JobHistory {
+    results: Mutex<VecDeque<JobResult>>,
+    last_failure: Mutex<Option<u64>>,
+}
+
+impl JobHistory {
+    pub fn new() -> Self {
+        JobHistory {
+            results: Mutex::new(VecDeque::with_capacity(MAX_RESULTS)),
+            last_failure: Mutex::new(None),
+        }
+    }
+
+    /// Record the output of a job, forgetting the oldest one if needed.
+    pub fn record(&self, output: &JobOutput) {
+        let finished_at = now();
+        if !output.success {
+            *self.last_failure.lock().unwrap() = Some(finished_at);
+        }
+
+        let mut results = self.results.lock().unwrap();
+        if results.len() >= MAX_RESULTS {
+            results.pop_back();
+        }
+        results.push_front(JobResult {
+            hook: output.script_name.clone(),
+            request_id: output.request_id.clone(),
+            request_ip: output.request_ip,
+            success: output.success,
+            exit_code: output.exit_code,
+            signal: output.signal,
+            timed_out: output.timed_out,
+            interrupted: output.interrupted,
+            termination: output.termination,
+            stdout_log: log_path(&output.stdout_log),
+            stderr_log: log_path(&output.stderr_log),
+            artifacts: output.artifacts
+                .iter()
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect(),
+            artifact_urls: output.artifact_urls.clone(),
+            started_at: finished_at.saturating_sub(output.duration.as_secs()),
+            finished_at,
+            duration_ms: millis(output.duration),
+        });
+    }
+
+    /// Return the recorded results of the hook with the provided outcome,
+    /// starting from the most recent one. Missing filters match every
+    /// result.
+    pub fn query(
+        &self,
+        hook: Option<&str>,
+        outcome: Option<JobOutcome>,
+        limit: Option<usize>,
+    ) -> Vec<JobResult> {
+        self.results
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|result| hook.map_or(true, |hook| result.hook == hook))
+            .filter(|result| outcome.map_or(true, |o| result.outcome() == o))
+            .take(limit.unwrap_or(MAX_RESULTS))
+            .cloned()
+            .collect()
+    }
+
+    /// Return when the last failed job ended, even if its result was
+    /// already forgotten.
+    pub fn last_failure(&self) -> Option<u64> {
+        *self.last_failure.lock().unwrap()
+    }
+}
+
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_nanos()) / 1_000_000
+}
+
+
+fn log_path(path: &Option<PathBuf>) -> Option<String> {
+    path.as_ref().map(|path| path.to_string_lossy().into_owned())
+}
+
+
+/// Return the current time as a UNIX timestamp.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use utils::testing::*;
+
+    use super::{JobHistory, JobOutcome, MAX_RESULTS};
+
+
+    #[test]
+    fn test_job_history() {
+        let history = JobHistory::new();
+        assert!(history.query(None, None, None).is_empty());
+
+        let mut output = dummy_job_output();
+        for i in 0..MAX_RESULTS + 10 {
+            output.exit_code = Some(i as i32);
+            history.record(&output);
+        }
+
+        // Only the most recent results are kept, newest first
+        let recent = history.query(None, None, None);
+        assert_eq!(recent.len(), MAX_RESULTS);
+        assert_eq!(recent[0].exit_code, Some(MAX_RESULTS as i32 + 9));
+        assert_eq!(recent[MAX_RESULTS - 1].exit_code, Some(10));
+        assert_eq!(recent[0].hook, output.script_name);
+        assert_eq!(recent[0].request_id, output.request_id);
+        assert!(history.last_failure().is_none());
+
+        // Failures are remembered after their result is forgotten
+        output.success = false;
+        history.record(&output);
+        let failed_at = history.last_failure().unwrap();
+        assert_eq!(failed_at, history.query(None, None, None)[0].finished_at);
+
+        output.success = true;
+        for _ in 0..MAX_RESULTS {
+            history.record(&output);
+        }
+        assert!(
+            history
+                .query(None, None, None)
+                .iter()
+                .all(|result| result.success)
+        );
+        assert_eq!(history.last_failure(), Some(failed_at));
+    }
+
+
+    #[test]
+    fn test_job_history_query() {
+        let history = JobHistory::new();
+
+        let mut output = dummy_job_output();
+        output.duration = Duration::from_millis(2500);
+        history.record(&output);
+
+        output.script_name = "other".into();
+        output.success = false;
+        history.record(&output);
+
+        output.timed_out = true;
+        history.record(&output);
+
+        let result = &history.query(Some("test"), None, None)[0];
+        assert_eq!(result.duration_ms, 2500);
+        assert_eq!(result.started_at + 2, result.finished_at);
+        assert_eq!(result.request_ip, output.request_ip);
+        assert_eq!(result.outcome(), JobOutcome::Success);
+
+        // Filters are combined together
+        assert_eq!(history.query(Some("other"), None, None).len(), 2);
+        assert_eq!(history.query(Some("missing"), None, None).len(), 0);
+        let failed = history.query(None, Some(JobOutcome::Failed), None);
+        assert_eq!(failed.len(), 1);
+        assert!(!failed[0].timed_out);
+        let timed_out = history.query(
+            Some("other"), Some(JobOutcome::TimedOut), None,
+        );
+        assert_eq!(timed_out.len(), 1);
+        assert_eq!(
+            history.query(Some("test"), Some(JobOutcome::TimedOut), None),
+            vec![],
+        );
+
+        // The most recent results are returned first
+        let limited = history.query(None, None, Some(1));
+        assert_eq!(limited.len(), 1);
+        assert!(limited[0].timed_out);
+
+        assert_eq!(
+            "timed-out".parse::<JobOutcome>().unwrap(),
+            JobOutcome::TimedOut,
+        );
+        assert!("maybe".parse::<JobOutcome>().is_err());
+    }
+}
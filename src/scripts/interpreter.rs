@@ -0,0 +1,237 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The interpreters of the scripts without the executable bit.
+//!
+//! Scripts synced from places which don't preserve the permissions, like
+//! some git checkouts, lose their executable bit. Instead of being skipped
+//! they can be run through the interpreter configured for their extension,
+//! or through the one in their shebang.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str;
+
+use common::prelude::*;
+use common::config::ScriptsConfig;
+
+
+/// How much of the script is read looking for the shebang, which is the
+/// same limit as recent Linux kernels.
+const MAX_SHEBANG_LENGTH: u64 = 256;
+
+
+/// A program running a script, passed as its last argument.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interpreter {
+    program: String,
+    args: Vec<String>,
+}
+
+impl Interpreter {
+    /// Parse a command line, such as `python3 -u`.
+    pub fn parse(command: &str) -> Option<Self> {
+        let mut parts = command.split_whitespace().map(|p| p.to_string());
+        parts.next().map(|program| Interpreter {
+            program: program,
+            args: parts.collect(),
+        })
+    }
+
+    /// Read the interpreter from the shebang of the script, if it has one.
+    pub fn from_shebang(script: &Path) -> Result<Option<Self>> {
+        // Only the start of the file is read, since binaries can be huge
+        // and have no line breaks
+        let mut start = Vec::new();
+        File::open(script)?
+            .take(MAX_SHEBANG_LENGTH)
+            .read_to_end(&mut start)?;
+        if !start.starts_with(b"#!") {
+            return Ok(None);
+        }
+
+        // Longer shebangs are truncated, like the kernel does
+        let end = start.iter().position(|&c| c == b'\n')
+            .unwrap_or(start.len());
+        let line = match str::from_utf8(&start[2..end]) {
+            Ok(line) => line.trim(),
+            Err(..) => return Ok(None),
+        };
+
+        // Like the kernel does, everything after the program is passed to
+        // it as a single argument
+        let (program, arg) = match line.find(char::is_whitespace) {
+            Some(pos) => (&line[..pos], line[pos..].trim()),
+            None => (line, ""),
+        };
+        if program.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Interpreter {
+            program: program.to_string(),
+            args: if arg.is_empty() { vec![] } else { vec![arg.into()] },
+        }))
+    }
+
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+
+    /// Return the command line executing the script.
+    pub fn command_line(&self, script: &Path) -> Vec<OsString> {
+        let mut line: Vec<OsString> = vec![self.program.clone().into()];
+        line.extend(self.args.iter().map(|arg| arg.into()));
+        line.push(script.into());
+        line
+    }
+}
+
+
+/// How the scripts without the executable bit are handled.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Interpreters {
+    enabled: bool,
+    extensions: HashMap<String, Interpreter>,
+}
+
+impl Interpreters {
+    pub fn from_config(config: &ScriptsConfig) -> Result<Self> {
+        let mut extensions = HashMap::new();
+        for (extension, command) in &config.interpreters {
+            let extension = extension.trim_left_matches('.');
+            let interpreter = match Interpreter::parse(command) {
+                Some(interpreter) => interpreter,
+                None => {
+                    return Err(ErrorKind::InvalidInput(format!(
+                        "empty interpreter for the .{} extension", extension,
+                    )).into());
+                }
+            };
+            extensions.insert(extension.to_string(), interpreter);
+        }
+
+        Ok(Interpreters {
+            enabled: config.run_non_executable,
+            extensions: extensions,
+        })
+    }
+
+    /// Check if the non-executable scripts should be run.
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Find the interpreter of the script, looking first at its extension
+    /// and then at its shebang.
+    pub fn find(&self, script: &Path) -> Result<Option<Interpreter>> {
+        let extension = script.extension().and_then(|ext| ext.to_str());
+        if let Some(found) = extension.and_then(|e| self.extensions.get(e)) {
+            return Ok(Some(found.clone()));
+        }
+        Interpreter::from_shebang(script)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::Path;
+
+    use common::config::ScriptsConfig;
+    use utils;
+
+    use super::{Interpreter, Interpreters};
+
+
+    #[test]
+    fn test_interpreter_parse() {
+        let interpreter = Interpreter::parse("  python3 -u  ").unwrap();
+        assert_eq!(interpreter.program(), "python3");
+        assert_eq!(
+            interpreter.command_line(Path::new("/srv/a.py")),
+            vec!["python3", "-u", "/srv/a.py"],
+        );
+
+        assert!(Interpreter::parse("").is_none());
+        assert!(Interpreter::parse("   ").is_none());
+    }
+
+
+    #[test]
+    fn test_interpreter_from_shebang() {
+        let dir = utils::create_temp_dir().unwrap();
+        let shebang = |name: &str, content: &[u8]| {
+            let path = dir.join(name);
+            File::create(&path).unwrap().write_all(content).unwrap();
+            Interpreter::from_shebang(&path).unwrap()
+                .map(|i| i.command_line(Path::new("s")))
+        };
+
+        assert_eq!(shebang("a", b"#!/bin/sh\necho\n"), Some(vec![
+            "/bin/sh".into(), "s".into(),
+        ]));
+        assert_eq!(shebang("b", b"#! /usr/bin/env python3 -u\n"), Some(vec![
+            "/usr/bin/env".into(), "python3 -u".into(), "s".into(),
+        ]));
+        assert_eq!(shebang("c", b"echo\n"), None);
+        assert_eq!(shebang("d", b"#!\n"), None);
+        assert_eq!(shebang("e", b""), None);
+        assert_eq!(shebang("f", b"\xff\xfe\x00\n"), None);
+
+        // Only the start of the file is read, even without line breaks
+        assert_eq!(shebang("g", &vec![b'x'; 1 << 20]), None);
+        let mut long = b"#!/bin/sh ".to_vec();
+        long.extend(vec![b'a'; 1000]);
+        let args = shebang("h", &long).unwrap();
+        assert_eq!(args[1].len(), 256 - 10);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+
+    #[test]
+    fn test_interpreters_find() {
+        let dir = utils::create_temp_dir().unwrap();
+        File::create(dir.join("a.py")).unwrap()
+            .write_all(b"#!/bin/sh\n").unwrap();
+        File::create(dir.join("b.sh")).unwrap()
+            .write_all(b"#!/bin/bash\n").unwrap();
+        File::create(dir.join("c.txt")).unwrap();
+
+        let mut config = ScriptsConfig::default();
+        config.run_non_executable = true;
+        config.interpreters.insert(".py".into(), "python3 -u".into());
+        let interpreters = Interpreters::from_config(&config).unwrap();
+        assert!(interpreters.enabled());
+
+        // The extension takes precedence over the shebang
+        let find = |name: &str| interpreters.find(&dir.join(name)).unwrap()
+            .map(|i| i.program().to_string());
+        assert_eq!(find("a.py"), Some("python3".into()));
+        assert_eq!(find("b.sh"), Some("/bin/bash".into()));
+        assert_eq!(find("c.txt"), None);
+
+        config.interpreters.insert("rb".into(), " ".into());
+        assert!(Interpreters::from_config(&config).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::net::IpAddr;
+
+use serde_json;
+
+use common::prelude::*;
+use utils::Cidr;
+
+
+/// Filter the IP addresses allowed to call an hook.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IpFilter {
+    #[serde(default)]
+    allow: Vec<Cidr>,
+    #[serde(default)]
+    deny: Vec<Cidr>,
+}
+
+impl IpFilter {
+    /// Parse the content of the `Fisher-IpFilter` configuration comment.
+    pub fn new(config: &str) -> Result<Self> {
+        let filter: IpFilter = serde_json::from_str(config)?;
+
+        if filter.allow.is_empty() && filter.deny.is_empty() {
+            return Err(ErrorKind::InvalidInput(
+                "the IP filter must allow or deny at least one address".into(),
+            ).into());
+        }
+
+        Ok(filter)
+    }
+
+    /// Check if the IP address is allowed. Denied addresses win over the
+    /// allowed ones, and every address is allowed if the allowlist is empty.
+    pub fn allows(&self, ip: &IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::IpFilter;
+
+
+    #[test]
+    fn test_ip_filter() {
+        let allows = |filter: &IpFilter, ip: &str| {
+            filter.allows(&ip.parse().unwrap())
+        };
+
+        let filter = IpFilter::new(r#"{"allow": ["10.0.0.0/8"]}"#).unwrap();
+        assert!(allows(&filter, "10.1.1.1"));
+        assert!(!allows(&filter, "11.1.1.1"));
+
+        let filter = IpFilter::new(r#"{"deny": ["10.0.0.1", "::1"]}"#).unwrap();
+        assert!(allows(&filter, "10.0.0.2"));
+        assert!(!allows(&filter, "10.0.0.1"));
+        assert!(!allows(&filter, "::1"));
+
+        // Denied addresses win over the allowed ones
+        let filter = IpFilter::new(
+            r#"{"allow": ["10.0.0.0/8"], "deny": ["10.0.0.0/24"]}"#,
+        ).unwrap();
+        assert!(allows(&filter, "10.0.1.1"));
+        assert!(!allows(&filter, "10.0.0.1"));
+
+        for invalid in &[
+            r#"{}"#,
+            r#"{"allow": []}"#,
+            r#"{"allow": ["10.0.0.0/33"]}"#,
+            r#"{"allow": "10.0.0.0/8"}"#,
+            r#"{"from": ["10.0.0.0/8"]}"#,
+        ] {
+            assert!(IpFilter::new(invalid).is_err());
+        }
+    }
+}
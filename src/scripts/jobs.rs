@@ -13,24 +13,51 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::cmp;
 use std::collections::HashMap;
+use std::io::{self, Read, Write};
 use std::process;
 use std::os::unix::process::ExitStatusExt;
 use std::os::unix::process::CommandExt;
 use std::fs;
 use std::env;
-use std::path::PathBuf;
-use std::io::Write;
-use std::sync::Arc;
-use std::net::IpAddr;
-
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::net::{IpAddr, Ipv4Addr};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+use nix::sys::signal::{kill, Signal};
+use serde_json;
 use nix::unistd::{setpgid, Pid};
 use users;
 
 use common::prelude::*;
+use common::errors::print_warning;
+use common::config::{BodyStorageConfig, JobCgroupConfig, ResourceLimits};
 use common::state::UniqueId;
 
+use events::{JobEvent, JobEventKind, JobEvents};
+use metrics::Metrics;
+
 use scripts::Script;
+use scripts::artifacts;
+use scripts::script::HookStdin;
+use scripts::cgroup::{self, JobCgroup, ResourceUsage};
+use scripts::dead_letters::DeadLetters;
+use scripts::dispatch::{DispatchedJob, Dispatcher, RemoteOutput};
+use scripts::executor::{ContainerExecutor, DirectExecutor, ExecSpec, Executor};
+use scripts::executor::{FirejailExecutor, JobEnv};
+use scripts::history;
+use scripts::journal::{JobJournal, JournalEntry, StoredRequest};
+use scripts::logs::JobLogs;
+use scripts::uploader::ArtifactUploader;
+use scripts::orphans;
+use scripts::sandbox::Sandbox;
+use scripts::scheduling;
+use scripts::storage::{self, StoredBody};
 use utils;
 use requests::Request;
 use providers::Provider;
@@ -40,11 +67,126 @@ static DEFAULT_ENV: &[&'static str] = &[
     "PATH", "LC_ALL", "LANG",
 ];
 
+/// How often a script with a timeout is checked, in milliseconds.
+const TIMEOUT_CHECK_INTERVAL: u64 = 50;
+
+/// How long to wait for the workers in addition to the timeout of the jobs,
+/// in seconds, to account for the time spent in their queue.
+const DISPATCH_MARGIN: u64 = 60;
+
+/// How many query string parameters (or form fields) are exposed to the
+/// scripts.
+const MAX_PARAMS: usize = 32;
+
+/// The maximum size of a query string parameter (or form field) exposed to
+/// the scripts.
+const MAX_PARAM_SIZE: usize = 4096;
+
+// Exit codes with a special meaning, taken from sysexits.h
+const EXIT_DATAERR: i32 = 65;
+const EXIT_TEMPFAIL: i32 = 75;
+const EXIT_CONFIG: i32 = 78;
+
+/// How many times jobs failing temporarily are retried, if their hook allows
+/// fewer retries.
+const TEMPFAIL_MIN_RETRIES: u32 = 3;
+
+
+/// How a script which had to be stopped ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Termination {
+    /// The script exited after receiving `SIGTERM`.
+    #[serde(rename = "sigterm")]
+    Terminated,
+    /// The script was still running after the grace period, and it was
+    /// killed with `SIGKILL`.
+    #[serde(rename = "sigkill")]
+    Killed,
+}
+
+
+/// Tells the running jobs to stop, when Fisher is shutting down.
+#[derive(Debug, Default)]
+pub struct StopSignal {
+    stopped: AtomicBool,
+}
+
+impl StopSignal {
+    pub fn new() -> Self {
+        StopSignal {
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    /// Ask all the running jobs to stop.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+
+/// How the outcome of a job is interpreted, based on its exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitKind {
+    Success,
+    /// The job failed, and it's retried if its hook allows it.
+    Failure,
+    /// The job failed temporarily, and it should be retried later.
+    TempFailure,
+    /// The request can't be processed, so running the job again is useless.
+    Rejected,
+    /// The hook is misconfigured, and it needs the attention of an operator.
+    ConfigError,
+}
+
 
 #[derive(Debug)]
 pub struct Context {
     pub environment: HashMap<String, String>,
     pub username: String,
+    pub body_storage: BodyStorageConfig,
+    pub metrics: Arc<Metrics>,
+    pub events: Arc<JobEvents>,
+    /// Where the jobs which ended are recorded.
+    pub journal: Arc<JobJournal>,
+    /// Where the jobs which failed after all their retries are saved.
+    pub dead_letters: Arc<DeadLetters>,
+    /// After how long jobs are killed, if their hook doesn't set a timeout.
+    pub timeout: Option<Duration>,
+    /// How long the jobs being stopped can take to exit before being killed.
+    pub kill_grace_period: Duration,
+    /// Tells the running jobs to stop.
+    pub stop: Arc<StopSignal>,
+    /// Where the output of the jobs is saved, if it's saved.
+    pub logs: Option<JobLogs>,
+    /// Where the artifacts of the jobs are uploaded, if they're uploaded.
+    pub uploader: Option<ArtifactUploader>,
+    /// The resource limits of the jobs, if their hook doesn't set them.
+    pub limits: ResourceLimits,
+    /// The cgroup the jobs are run in.
+    pub cgroup: JobCgroupConfig,
+    /// Whether the jobs run in a sandbox, if their hook doesn't say so.
+    pub sandbox: bool,
+    /// Where the persistent working directories of the hooks are created.
+    pub workdirs: Option<PathBuf>,
+    /// Where the temporary directories of the jobs are created, if not in
+    /// the system one.
+    pub temp_dir: Option<PathBuf>,
+    /// The scripts run in the working directory of every job, before and
+    /// after it.
+    pub pre_wrapper: Option<PathBuf>,
+    pub post_wrapper: Option<PathBuf>,
+    /// The CLI of the container runtime running the hooks with an image.
+    pub container_runtime: String,
+    /// Whether the working directory of failed jobs is kept, if their hook
+    /// doesn't say so.
+    pub keep_workdir_on_failure: bool,
+    /// The workers the jobs are run on, if they're not run locally.
+    pub dispatcher: Option<Arc<Dispatcher>>,
 }
 
 impl Default for Context {
@@ -59,6 +201,26 @@ impl Default for Context {
         Context {
             environment: HashMap::new(),
             username,
+            body_storage: BodyStorageConfig::default(),
+            metrics: Arc::new(Metrics::new()),
+            events: Arc::new(JobEvents::new()),
+            journal: Arc::new(JobJournal::new()),
+            dead_letters: Arc::new(DeadLetters::new()),
+            timeout: None,
+            kill_grace_period: Duration::from_secs(5),
+            stop: Arc::new(StopSignal::new()),
+            logs: None,
+            uploader: None,
+            limits: ResourceLimits::default(),
+            cgroup: JobCgroupConfig::default(),
+            sandbox: false,
+            workdirs: None,
+            temp_dir: None,
+            pre_wrapper: None,
+            post_wrapper: None,
+            container_runtime: "docker".into(),
+            keep_workdir_on_failure: false,
+            dispatcher: None,
         }
     }
 }
@@ -66,9 +228,22 @@ impl Default for Context {
 
 #[derive(Debug, Clone)]
 pub struct Job {
+    id: String,
+    /// Which execution of the job this is, starting from 1.
+    attempt: u32,
     script: Arc<Script>,
     provider: Option<Arc<Provider>>,
+    /// The environment computed by the provider while validating the
+    /// request, used instead of asking the provider again.
+    provider_env: Option<HashMap<String, String>>,
     request: Request,
+    /// The UNIX timestamp of when the request which started the job was
+    /// received.
+    received_at: u64,
+    reply: Option<Arc<Mutex<mpsc::Sender<JobOutput>>>>,
+    /// Whether the job was sent by another instance, which takes care of
+    /// retrying it and of the status hooks.
+    dispatched: bool,
 }
 
 impl Job {
@@ -78,20 +253,132 @@ impl Job {
         request: Request,
     ) -> Job {
         Job {
+            id: utils::request_id(),
+            attempt: 1,
             script,
             provider,
+            provider_env: None,
             request,
+            received_at: history::now(),
+            reply: None,
+            dispatched: false,
+        }
+    }
+
+    /// Mark the job as received from another instance, running it as the
+    /// provided attempt.
+    pub fn dispatched(mut self, attempt: u32) -> Job {
+        self.attempt = attempt;
+        self.dispatched = true;
+        self
+    }
+
+    /// Use the environment computed by the provider while validating the
+    /// request, instead of asking the provider when the job is run.
+    pub fn with_provider_env(
+        mut self,
+        env: Option<HashMap<String, String>>,
+    ) -> Job {
+        self.provider_env = env;
+        self
+    }
+
+    /// Change the unique identifier of the job, for example when it's
+    /// restored from the journal.
+    pub fn with_id(mut self, id: String) -> Job {
+        self.id = id;
+        self
+    }
+
+    /// Change when the request which started the job was received, for
+    /// example when it's restored from the journal.
+    pub fn with_received_at(mut self, received_at: u64) -> Job {
+        self.received_at = received_at;
+        self
+    }
+
+    /// Return the unique identifier of the job.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Return which execution of the job this is, starting from 1.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Check if the job should be run again after this execution.
+    fn should_retry(&self, kind: ExitKind) -> bool {
+        if self.dispatched {
+            return false;
+        }
+
+        let max_retries = self.script.max_retries();
+        match kind {
+            ExitKind::Failure => self.attempt <= max_retries,
+            ExitKind::TempFailure => {
+                self.attempt <= cmp::max(max_retries, TEMPFAIL_MIN_RETRIES)
+            }
+            ExitKind::Success | ExitKind::Rejected | ExitKind::ConfigError => {
+                false
+            }
         }
     }
 
+    /// Create an event about the job.
+    pub fn event(&self, kind: JobEventKind) -> JobEvent {
+        JobEvent::new(kind, self.script.name(), &self.id, self.request_id())
+    }
+
+    /// Send the output of the job to the channel when it ends.
+    pub fn reply_to(mut self, sender: mpsc::Sender<JobOutput>) -> Job {
+        self.reply = Some(Arc::new(Mutex::new(sender)));
+        self
+    }
+
     pub fn request_ip(&self) -> IpAddr {
         match self.request {
             Request::Web(ref req) => req.source,
             Request::Status(ref req) => req.source_ip(),
+            // Scheduled jobs are started by Fisher itself
+            Request::Scheduled(..) => IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+        }
+    }
+
+    /// Return the identifier of the request which started the job, if it
+    /// came from the HTTP server.
+    pub fn request_id(&self) -> Option<&str> {
+        match self.request {
+            Request::Web(ref req) => Some(&req.id),
+            Request::Status(..) | Request::Scheduled(..) => None,
         }
     }
 
+    /// Return how the job is stored in the journal, if it was started by
+    /// the HTTP server.
+    pub fn journal_entry(
+        &self, priority: isize,
+    ) -> Result<Option<JournalEntry>> {
+        let req = match self.request {
+            Request::Web(ref req) => req,
+            Request::Status(..) | Request::Scheduled(..) => return Ok(None),
+        };
+
+        Ok(Some(JournalEntry {
+            job_id: self.id.clone(),
+            hook: self.script.name().to_string(),
+            provider: self.provider.as_ref().map(|p| p.name().to_string()),
+            priority,
+            received_at: Some(self.received_at),
+            request: StoredRequest::new(req)?,
+        }))
+    }
+
     pub fn trigger_status_hooks(&self) -> bool {
+        if self.dispatched {
+            return false;
+        }
+
         if let Some(ref provider) = self.provider {
             provider.trigger_status_hooks(&self.request)
         } else {
@@ -99,60 +386,411 @@ impl Job {
         }
     }
 
+    /// Run the job, either locally or on one of the workers.
+    fn run(&self, ctx: &Context) -> Result<JobOutput> {
+        // Only the jobs started by the HTTP server can be sent to the
+        // workers, and jobs received from other instances always run here
+        let remote = match ctx.dispatcher {
+            Some(ref dispatcher) if !self.dispatched => {
+                let entry = self.journal_entry(self.script.priority())?;
+                entry.map(|entry| (dispatcher, entry))
+            }
+            _ => None,
+        };
+
+        let mut output = if let Some((dispatcher, entry)) = remote {
+            // Leave the worker the time to kill the job if it times out
+            let timeout = self.script.timeout().or(ctx.timeout).map(|t| {
+                t + ctx.kill_grace_period + Duration::from_secs(DISPATCH_MARGIN)
+            });
+            let job = DispatchedJob {
+                entry,
+                attempt: self.attempt,
+                timeout: timeout.map(|t| t.as_secs()),
+            };
+            JobOutput::remote(self, dispatcher.run(&job, timeout)?)
+        } else {
+            self.process(ctx)?
+        };
+
+        // Keep the output around, so failures can be investigated later
+        if let Some(ref logs) = ctx.logs {
+            let saved = logs.save(
+                &self.id, self.attempt, &output.stdout, &output.stderr,
+            );
+            match saved {
+                Ok((stdout, stderr)) => {
+                    output.stdout_log = Some(stdout);
+                    output.stderr_log = Some(stderr);
+                }
+                Err(mut error) => {
+                    let name = self.script.name().to_string();
+                    error.set_location(ErrorLocation::HookProcessing(name));
+                    error.pretty_print();
+                }
+            }
+        }
+
+        Ok(output)
+    }
+
     fn process(&self, ctx: &Context) -> Result<JobOutput> {
-        let mut command = process::Command::new(&self.script.exec());
+        // Load the body if it was spooled to disk
+        let request = self.request.with_body()?;
 
         // Prepare the command's environment variables
-        self.prepare_env(&mut command, ctx);
+        let mut env = JobEnv::new();
+        self.prepare_env(&mut env, ctx, &request);
+
+        // The files of the request are saved in a random directory, which
+        // is also the working directory unless the hook keeps its own
+        let persistent = if self.script.persistent_workdir() {
+            Some(self.persistent_workdir(ctx)?)
+        } else {
+            None
+        };
+        let request_directory = match ctx.temp_dir {
+            Some(ref base) => utils::create_temp_dir_in(base)?,
+            None => utils::create_temp_dir()?,
+        };
+        let mut files = RequestFiles::new(request_directory.clone());
+        orphans::mark(&request_directory)?;
+        let working_directory =
+            persistent.unwrap_or_else(|| request_directory.clone());
+        env.set("HOME", &working_directory);
+        env.set("FISHER_REQUEST_DIR", &request_directory);
+
+        // Tell the script which job it is, and if it's being retried
+        self.metadata_env(&mut env);
+
+        // Set the request IP and ID
+        env.set("FISHER_REQUEST_IP", self.request_ip().to_string());
+        if let Some(id) = self.request_id() {
+            env.set("FISHER_REQUEST_ID", id);
+        }
 
-        // Use a random working directory
-        let working_directory = utils::create_temp_dir()?;
-        command.current_dir(working_directory.to_str().unwrap());
-        command.env("HOME", working_directory.to_str().unwrap());
+        // Tell scheduled scripts when they were supposed to run
+        if let Request::Scheduled(ref run) = *request {
+            env.set("FISHER_SCHEDULED_AT", run.time.to_string());
+        }
+
+        // Expose the query string parameters and the form fields
+        if let Request::Web(ref req) = *request {
+            env.set("FISHER_REQUEST_METHOD", &req.method);
+            env.set("FISHER_REQUEST_URL", &req.url);
+            for (key, value) in params_env("PARAM", &req.params) {
+                env.set(key, value);
+            }
+            if let Some(form) = req.form() {
+                for (key, value) in params_env("FORM", &form) {
+                    env.set(key, value);
+                }
+            }
+        }
+
+        // Save the request body, unless the script only reads it from stdin
+        let stdin = self.script.stdin();
+        files.body = if stdin == Some(HookStdin::BodyOnly) {
+            None
+        } else {
+            self.save_request_body(&request, &request_directory, ctx)?
+        };
+        let input = match (stdin, &*request) {
+            (Some(..), &Request::Web(ref req)) => {
+                Some(req.body_bytes().to_vec())
+            }
+            _ => None,
+        };
+        if let Some(ref body) = files.body {
+            body.apply_env(&mut env);
+        }
+        if files.body.is_some() || input.is_some() {
+            env.set(
+                "FISHER_REQUEST_BODY_ENCODING",
+                request.web()?.body_encoding(),
+            );
+        }
 
-        // Set the request IP
-        command.env("FISHER_REQUEST_IP", self.request_ip().to_string());
+        // Save the headers and the details of the request next to its body
+        self.save_request_info(&request, &request_directory)?;
 
-        // Save the request body
-        let request_body = self.save_request_body(&working_directory)?;
-        if let Some(path) = request_body {
-            command.env("FISHER_REQUEST_BODY", path.to_str().unwrap());
+        // Split multipart bodies in their parts
+        if let Some(index) =
+            self.save_multipart(&request, &request_directory)?
+        {
+            env.set("FISHER_REQUEST_MULTIPART", index);
         }
 
         // Tell the provider to prepare the directory
         if let Some(ref provider) = self.provider {
-            provider.prepare_directory(&self.request, &working_directory)?;
+            provider.prepare_directory(&request, &request_directory)?;
+        }
+
+        // Point status hooks to the output of the job they're about, since
+        // their working directory might not be the request one
+        if let Request::Status(ref event) = *request {
+            for (key, value) in event.output_env(&request_directory) {
+                env.set(key, value);
+            }
         }
 
         // Apply the custom environment
         for (key, value) in ctx.environment.iter() {
-            command.env(&key, &value);
+            env.set(&key, &value);
+        }
+
+        // Apply the variables the hook defines over the request
+        if let Some(hook_env) = self.script.env() {
+            for (key, value) in hook_env.render(&request) {
+                env.set(key, value);
+            }
+        }
+
+        // Inject the secrets, which are read again every time
+        if let Some(secrets) = self.script.secrets() {
+            for (key, value) in secrets.load()? {
+                env.set(key, value);
+            }
+        }
+
+        // Every execution of the job gets its own cgroup
+        let cgroup = JobCgroup::create(
+            &ctx.cgroup, &format!("fisher-{}-{}", self.id, self.attempt),
+        )?;
+        let procs_fd = cgroup.as_ref().map(|cgroup| cgroup.procs_fd());
+
+        // The directories the script needs to access
+        let body_directory = files.body.as_ref()
+            .and_then(|body| body.directory())
+            .map(|dir| dir.to_path_buf());
+        let mut directories = vec![working_directory.as_path()];
+        if request_directory != working_directory {
+            directories.push(request_directory.as_path());
+        }
+        if let Some(ref dir) = body_directory {
+            directories.push(dir);
         }
 
+        // Scripts not fully trusted only see the directories they need, while
+        // containers and firejail already isolate them from the host
+        let (image, firejail) = (self.script.image(), self.script.firejail());
+        let sandbox = if image.is_none() && firejail.is_none()
+            && self.script.sandbox().unwrap_or(ctx.sandbox)
+        {
+            Some(Sandbox::new(Path::new(self.script.exec()), &directories)?)
+        } else {
+            None
+        };
+
+        // Run the script directly, inside a container of its image or with
+        // firejail
+        let direct = DirectExecutor;
+        let container = image.map(|image| {
+            ContainerExecutor::new(&ctx.container_runtime, image)
+        });
+        let jail = firejail.map(FirejailExecutor::new);
+        let executor: &Executor = match (&container, &jail) {
+            (&Some(ref container), _) => container,
+            (&None, &Some(ref jail)) => jail,
+            (&None, &None) => &direct,
+        };
+        let name = format!("fisher-{}-{}", self.id, self.attempt);
+        let spec = ExecSpec {
+            name: &name,
+            script: Path::new(self.script.exec()),
+            interpreter: self.script.interpreter(),
+            env: &env,
+            working_directory: &working_directory,
+            directories: &directories,
+        };
+        let mut command = executor.command(&spec);
+
         // Make sure the process is isolated
-        command.before_exec(|| {
+        let limits = self.script.limits().or(&ctx.limits);
+        let (nice, io_class) = (self.script.nice(), self.script.io_class());
+        let umask = self.script.umask();
+        command.before_exec(move || {
             // If a new process group is not created, the job still works fine
             let _ = setpgid(Pid::this(), Pid::from_raw(0));
 
+            // The script is not started outside of its cgroup, if its limits
+            // can't be applied or if it can't be sandboxed
+            if let Some(fd) = procs_fd {
+                cgroup::join(fd)?;
+            }
+            apply_limits(&limits)?;
+            scheduling::apply(nice, io_class)?;
+            if let Some(umask) = umask {
+                umask.apply();
+            }
+            if let Some(ref sandbox) = sandbox {
+                sandbox.enter()?;
+            }
             Ok(())
         });
 
-        // Execute the hook
-        let output = command.output()?;
+        // The hook is not executed if the wrapper run before every job fails,
+        // and the job fails with the output of the wrapper
+        let dirs = (working_directory.as_path(), request_directory.as_path());
+        let pre_output = match ctx.pre_wrapper {
+            Some(ref wrapper) => {
+                let output = self.run_wrapper(wrapper, ctx, dirs, None)?;
+                if output.status.success() {
+                    None
+                } else {
+                    Some(output)
+                }
+            }
+            None => None,
+        };
+
+        // Execute the hook, stopping it if it takes too long or if Fisher is
+        // shutting down
+        let timeout = self.script.timeout().or(ctx.timeout);
+        let GroupOutput { output, timed_out, interrupted, termination } =
+            match pre_output {
+                Some(output) => GroupOutput::exited(output),
+                None => output_in_group(
+                    &mut command,
+                    input,
+                    timeout,
+                    ctx.kill_grace_period,
+                    &ctx.stop,
+                )?,
+            };
+        if let (true, Some(timeout)) = (timed_out, timeout) {
+            let name = self.script.name().to_string();
+            let mut error: Error =
+                ErrorKind::HookTimedOut(timeout.as_secs()).into();
+            error.set_location(ErrorLocation::HookProcessing(name));
+            error.pretty_print();
+        }
+        executor.cleanup(&spec);
+
+        // Tell the wrapper run after every job how the job went, without
+        // changing its outcome if the wrapper fails
+        if let Some(ref wrapper) = ctx.post_wrapper {
+            let outcome = Some((&output.status, timed_out));
+            match self.run_wrapper(wrapper, ctx, dirs, outcome) {
+                Ok(ref output) if output.status.success() => {}
+                Ok(..) => print_warning(&format!(
+                    "the wrapper {} failed after the job {}",
+                    wrapper.to_string_lossy(),
+                    self.id,
+                )),
+                Err(error) => error.pretty_print(),
+            }
+        }
 
-        // Remove the temp directory
-        fs::remove_dir_all(&working_directory)?;
+        // The artifacts are collected before the working directory is gone
+        let (artifacts, artifact_urls) =
+            self.collect_artifacts(&working_directory, ctx);
+
+        // The temp directory and the request body are removed, while
+        // persistent working directories are kept for the next run. The temp
+        // directory of failed jobs can be kept too, to investigate what went
+        // wrong
+        let failed = timed_out || !output.status.success();
+        let keep = self.script.keep_workdir_on_failure()
+            .unwrap_or(ctx.keep_workdir_on_failure);
+        if failed && keep {
+            self.keep_workdir(&request_directory, ctx);
+            files.kept = true;
+        }
+        drop(files);
+
+        let mut output = JobOutput::new(self, output);
+        output.timed_out = timed_out;
+        output.interrupted = interrupted;
+        output.termination = termination;
+        output.artifacts = artifacts;
+        output.artifact_urls = artifact_urls;
+
+        // The cgroup is removed when it goes out of scope, along with any
+        // process the script left behind
+        if let Some(ref cgroup) = cgroup {
+            output.resources = cgroup.usage();
+        }
 
         // Return the job output
-        Ok(JobOutput::new(self, output))
+        Ok(output)
     }
 
-    fn prepare_env(&self, command: &mut process::Command, ctx: &Context) {
-        // First of all clear the environment
-        command.env_clear();
+    /// Tell the process which job it's part of.
+    fn metadata_env(&self, env: &mut JobEnv) {
+        env.set("FISHER_JOB_ID", &self.id);
+        env.set("FISHER_HOOK_NAME", self.script.name());
+        env.set("FISHER_RECEIVED_AT", self.received_at.to_string());
+        env.set("FISHER_ATTEMPT", self.attempt.to_string());
+        if let Some(ref provider) = self.provider {
+            env.set("FISHER_PROVIDER", provider.name());
+        }
+    }
+
+    /// Run a wrapper script in the working directory of the job, with the
+    /// same timeout. The wrapper run after the job also receives its outcome.
+    fn run_wrapper(
+        &self,
+        wrapper: &Path,
+        ctx: &Context,
+        (working_directory, request_directory): (&Path, &Path),
+        outcome: Option<(&process::ExitStatus, bool)>,
+    ) -> Result<process::Output> {
+        let mut env = JobEnv::new();
+        self.prepare_env(&mut env, ctx, &self.request);
+        self.metadata_env(&mut env);
+        env.set("HOME", working_directory);
+        env.set("FISHER_REQUEST_DIR", request_directory);
+        for (key, value) in ctx.environment.iter() {
+            env.set(&key, &value);
+        }
+
+        if let Some((status, timed_out)) = outcome {
+            let flag = |value: bool| if value { "1" } else { "0" };
+            let optional = |value: Option<i32>| {
+                value.map(|value| value.to_string()).unwrap_or_default()
+            };
+            env.set(
+                "FISHER_JOB_SUCCESS", flag(status.success() && !timed_out),
+            );
+            env.set("FISHER_JOB_EXIT_CODE", optional(status.code()));
+            env.set("FISHER_JOB_SIGNAL", optional(status.signal()));
+            env.set("FISHER_JOB_TIMED_OUT", flag(timed_out));
+        }
+
+        // Wrappers are always executed on the host
+        let mut command = DirectExecutor.command(&ExecSpec {
+            name: "",
+            script: wrapper,
+            interpreter: None,
+            env: &env,
+            working_directory: working_directory,
+            directories: &[working_directory, request_directory],
+        });
+
+        // Processes left behind by the wrapper are killed with it
+        command.before_exec(|| {
+            let _ = setpgid(Pid::this(), Pid::from_raw(0));
+            Ok(())
+        });
+
+        Ok(output_in_group(
+            &mut command,
+            None,
+            self.script.timeout().or(ctx.timeout),
+            ctx.kill_grace_period,
+            &ctx.stop,
+        )?.output)
+    }
 
+    fn prepare_env(
+        &self,
+        env: &mut JobEnv,
+        ctx: &Context,
+        request: &Request,
+    ) {
         // Set the USER environment variable with the correct username
-        command.env("USER", ctx.username.clone());
+        env.set("USER", ctx.username.clone());
 
         // Apply the default environment
         // This is done (instead of the automatic inheritage) to whitelist
@@ -163,37 +801,224 @@ impl Job {
                 continue;
             }
 
-            command.env(key, value);
+            env.set(key, value);
+        }
+
+        // The locale and the timezone of the hook replace the inherited ones
+        if let Some(locale) = self.script.locale() {
+            env.set("LANG", locale.name());
+            env.set("LC_ALL", locale.name());
+        }
+        if let Some(timezone) = self.script.timezone() {
+            env.set("TZ", timezone.name());
         }
 
         // Apply the hook-specific environment
         if let Some(ref provider) = self.provider {
-            for (key, value) in provider.env(&self.request) {
+            let vars = match self.provider_env {
+                Some(ref vars) => vars.clone(),
+                None => provider.env(request),
+            };
+            for (key, value) in vars {
                 let real_key = format!(
                     "FISHER_{}_{}",
                     provider.name().to_uppercase(),
                     key
                 );
-                command.env(real_key, value);
+                env.set(real_key, value);
+            }
+        }
+    }
+
+    /// Return the working directory preserved between the runs of the hook,
+    /// creating it if it doesn't exist yet.
+    fn persistent_workdir(&self, ctx: &Context) -> Result<PathBuf> {
+        let path = match ctx.workdirs {
+            Some(ref workdirs) => workdirs.join(self.script.name()),
+            None => {
+                return Err(ErrorKind::InvalidInput(
+                    "persistent working directories are not enabled".into(),
+                ).into());
+            }
+        };
+
+        fs::create_dir_all(&path)?;
+        Ok(path)
+    }
+
+    /// Keep the working directory of a failed execution, moving it next to
+    /// the logs of the job if they're saved, and tell where it is.
+    fn keep_workdir(&self, workdir: &Path, ctx: &Context) {
+        let mut path = workdir.to_path_buf();
+        if let Some(ref logs) = ctx.logs {
+            match logs.keep_workdir(&self.id, self.attempt, workdir) {
+                Ok(kept) => path = kept,
+                Err(mut error) => {
+                    let name = self.script.name().to_string();
+                    error.set_location(ErrorLocation::HookProcessing(name));
+                    error.pretty_print();
+                }
+            }
+        }
+
+        print_warning(&format!(
+            "the working directory of the failed job {} was kept in {}",
+            self.id,
+            path.to_string_lossy(),
+        ));
+    }
+
+    /// Copy the artifacts declared by the hook next to the logs of the job,
+    /// uploading them if an object store is configured, and return their
+    /// paths and their URLs. Failures are printed without failing the job.
+    fn collect_artifacts(
+        &self, workdir: &Path, ctx: &Context,
+    ) -> (Vec<PathBuf>, Vec<String>) {
+        let patterns = self.script.artifacts();
+        if patterns.is_empty() {
+            return (Vec::new(), Vec::new());
+        }
+
+        // Without the logs the artifacts are only kept until they're uploaded
+        let (dest, temporary) = match (&ctx.logs, &ctx.uploader) {
+            (&Some(ref logs), _) => {
+                (logs.artifacts_dir(&self.id, self.attempt), false)
+            }
+            (&None, &Some(..)) => match utils::create_temp_dir() {
+                Ok(dir) => (dir, true),
+                Err(error) => {
+                    self.print_artifacts_error(error);
+                    return (Vec::new(), Vec::new());
+                }
+            },
+            // The hooks declaring artifacts are refused when they're loaded
+            (&None, &None) => return (Vec::new(), Vec::new()),
+        };
+
+        let mut collected = match artifacts::collect(patterns, workdir, &dest) {
+            Ok(collected) => collected,
+            Err(error) => {
+                self.print_artifacts_error(error);
+                Vec::new()
+            }
+        };
+
+        let mut urls = Vec::new();
+        if let Some(ref uploader) = ctx.uploader {
+            let uploaded =
+                uploader.upload(&self.id, self.attempt, &dest, &collected);
+            match uploaded {
+                Ok(uploaded) => {
+                    urls = uploaded.iter().map(|url| url.to_string()).collect();
+                }
+                Err(error) => self.print_artifacts_error(error),
             }
         }
+
+        if temporary {
+            collected.clear();
+            if let Err(error) = fs::remove_dir_all(&dest) {
+                self.print_artifacts_error(error.into());
+            }
+        }
+
+        (collected, urls)
     }
 
-    fn save_request_body(&self, base: &PathBuf) -> Result<Option<PathBuf>> {
+    fn print_artifacts_error(&self, mut error: Error) {
+        let name = self.script.name().to_string();
+        error.set_location(ErrorLocation::HookProcessing(name));
+        error.pretty_print();
+    }
+
+    fn save_request_body(
+        &self, request: &Request, base: &PathBuf, ctx: &Context,
+    ) -> Result<Option<StoredBody>> {
         // Get the request body, even if some request kinds don't have one
-        let body = match self.request {
-            Request::Web(ref req) => &req.body,
-            Request::Status(..) => return Ok(None),
+        let req = match *request {
+            Request::Web(ref req) => req,
+            Request::Status(..) | Request::Scheduled(..) => return Ok(None),
+        };
+
+        // Hooks can override the globally configured backend
+        let kind = self.script.body_storage()
+            .unwrap_or(ctx.body_storage.backend);
+        let storage = storage::backend(kind, &ctx.body_storage)?;
+
+        // The body is stored exactly as it was received
+        Ok(Some(storage.store(req.body_bytes(), base)?))
+    }
+
+    /// Save the headers of the request in the `request_headers` file, one
+    /// `Name: value` per line, and its details in `request_meta`.
+    fn save_request_info(
+        &self, request: &Request, base: &PathBuf,
+    ) -> Result<()> {
+        let req = match *request {
+            Request::Web(ref req) => req,
+            Request::Status(..) | Request::Scheduled(..) => return Ok(()),
+        };
+
+        let mut headers = req.headers.iter().collect::<Vec<_>>();
+        headers.sort();
+        let mut content = String::new();
+        for (name, value) in headers {
+            content.push_str(&format!("{}: {}\n", name, value));
+        }
+        fs::File::create(base.join("request_headers"))?
+            .write_all(content.as_bytes())?;
+
+        let meta = json!({
+            "id": req.id,
+            "job_id": self.id,
+            "source_ip": req.source.to_string(),
+            "method": req.method,
+            "url": req.url,
+            "received_at": self.received_at,
+            "started_at": history::now(),
+        });
+        fs::File::create(base.join("request_meta"))?
+            .write_all(serde_json::to_string(&meta)?.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Save each part of multipart bodies in its own file, returning the path
+    /// of the index describing them.
+    fn save_multipart(
+        &self, request: &Request, base: &PathBuf,
+    ) -> Result<Option<PathBuf>> {
+        let parts = match *request {
+            Request::Web(ref req) => match req.multipart() {
+                Ok(Some(parts)) => parts,
+                // Malformed bodies are still available as they are
+                Ok(None) | Err(..) => return Ok(None),
+            },
+            Request::Status(..) | Request::Scheduled(..) => return Ok(None),
         };
 
-        let mut path = base.clone();
-        path.push("request_body");
+        // The names of the files are never chosen by the client
+        let dir = base.join("multipart");
+        fs::create_dir(&dir)?;
+
+        let mut index = Vec::with_capacity(parts.len());
+        for (i, part) in parts.into_iter().enumerate() {
+            let path = dir.join(format!("part-{}", i));
+            fs::File::create(&path)?.write_all(&part.data)?;
+
+            index.push(json!({
+                "name": part.name,
+                "filename": part.filename,
+                "content_type": part.content_type,
+                "path": path.to_string_lossy(),
+            }));
+        }
 
-        // Write the request body on disk
-        let mut file = fs::File::create(&path)?;
-        write!(file, "{}\n", body)?;
+        let index_path = base.join("multipart.json");
+        fs::File::create(&index_path)?
+            .write_all(serde_json::to_string(&index)?.as_bytes())?;
 
-        Ok(Some(path))
+        Ok(Some(index_path))
     }
 }
 
@@ -202,7 +1027,113 @@ impl JobTrait<Script> for Job {
     type Output = JobOutput;
 
     fn execute(&self, ctx: &Context) -> Result<JobOutput> {
-        self.process(ctx)
+        ctx.events.publish(self.event(JobEventKind::Started));
+
+        let start = Instant::now();
+        let mut result = self.run(ctx);
+
+        // Jobs which couldn't be started are counted as failed
+        let duration = start.elapsed();
+        let success = result.as_ref().map(|out| out.success).unwrap_or(false);
+        ctx.metrics.job_finished(self.script.name(), duration, success);
+        if let Ok(ref mut output) = result {
+            output.duration = duration;
+            ctx.metrics.job_resources(self.script.name(), &output.resources);
+        }
+        ctx.events.publish(self.event(if success {
+            JobEventKind::Finished
+        } else {
+            JobEventKind::Failed
+        }));
+
+        // Jobs which couldn't be started are not retried
+        let kind = result
+            .as_ref()
+            .map(|output| output.exit_kind())
+            .unwrap_or(ExitKind::Failure);
+
+        // Only the last execution of jobs being retried is reported
+        if result.is_ok() && self.should_retry(kind) {
+            ctx.events.publish(self.event(JobEventKind::Queued));
+            if let Ok(ref mut output) = result {
+                output.trigger_status_hooks = false;
+            }
+            return result;
+        }
+
+        // Keep the requests of the jobs which won't run again, so they can
+        // be requeued later, unless the script rejected them
+        if !success && kind != ExitKind::Rejected && !self.dispatched {
+            let saved = ctx.dead_letters.save(
+                self, self.script.priority(), result.as_ref().ok(),
+            );
+            match saved {
+                Ok(path) => {
+                    if let Ok(ref mut output) = result {
+                        output.dead_letter = path;
+                    }
+                }
+                Err(mut error) => {
+                    let name = self.script.name().to_string();
+                    error.set_location(ErrorLocation::HookProcessing(name));
+                    error.pretty_print();
+                }
+            }
+        }
+
+        // The job won't run again, so it doesn't need to be restored
+        if let Err(error) = ctx.journal.done(self) {
+            error.pretty_print();
+        }
+
+        // If the client is waiting for the output, send it back
+        if let Some(ref reply) = self.reply {
+            if let Ok(ref output) = result {
+                let _ = reply.lock().unwrap().send(output.clone());
+            }
+        }
+
+        result
+    }
+
+    fn retry(&self, output: &JobOutput) -> Option<(Job, Duration)> {
+        if !self.should_retry(output.exit_kind()) {
+            return None;
+        }
+
+        let mut job = self.clone();
+        job.attempt += 1;
+        Some((job, self.script.retry_delay(self.attempt)))
+    }
+
+    fn superseded(&self, ctx: &Context) -> bool {
+        let coalesce = if let Some(coalesce) = self.script.coalesce() {
+            coalesce
+        } else {
+            return false;
+        };
+
+        if coalesce.start(&self.id) {
+            return false;
+        }
+
+        ctx.events.publish(self.event(JobEventKind::Coalesced));
+        if let Err(error) = ctx.journal.done(self) {
+            error.pretty_print();
+        }
+        true
+    }
+
+    fn dropped(&self, ctx: &Context) {
+        ctx.metrics.job_dropped(self.script.name());
+        ctx.events.publish(self.event(JobEventKind::Dropped));
+        if let Err(error) = ctx.journal.done(self) {
+            error.pretty_print();
+        }
+    }
+
+    fn stop_running(ctx: &Context) {
+        ctx.stop.stop();
     }
 
     fn script_id(&self) -> UniqueId {
@@ -223,14 +1154,50 @@ pub struct JobOutput {
     pub success: bool,
     pub exit_code: Option<i32>,
     pub signal: Option<i32>,
+    /// Whether the job was killed because it didn't exit in time.
+    pub timed_out: bool,
+    /// Whether the job was stopped because Fisher was shutting down.
+    pub interrupted: bool,
+    /// How the job ended, if it had to be stopped.
+    pub termination: Option<Termination>,
+    /// The files the stdout and the stderr were saved to, if any.
+    pub stdout_log: Option<PathBuf>,
+    pub stderr_log: Option<PathBuf>,
+    /// The artifacts collected after the job ended.
+    pub artifacts: Vec<PathBuf>,
+    /// The URLs the artifacts were uploaded to, if they were uploaded.
+    pub artifact_urls: Vec<String>,
+    /// The file the request was saved to, if the job failed after all its
+    /// retries.
+    pub dead_letter: Option<PathBuf>,
+    /// The resources used by the job, if it ran in its own cgroup.
+    pub resources: ResourceUsage,
+    /// How long the job ran.
+    pub duration: Duration,
 
     pub script_name: String,
     pub request_ip: IpAddr,
+    pub request_id: Option<String>,
 
     pub trigger_status_hooks: bool,
 }
 
 impl JobOutput {
+    /// Interpret the outcome of the job. Jobs killed by a signal or because
+    /// they timed out are simply failed.
+    pub fn exit_kind(&self) -> ExitKind {
+        if self.success {
+            return ExitKind::Success;
+        }
+
+        match self.exit_code {
+            Some(EXIT_DATAERR) => ExitKind::Rejected,
+            Some(EXIT_TEMPFAIL) => ExitKind::TempFailure,
+            Some(EXIT_CONFIG) => ExitKind::ConfigError,
+            _ => ExitKind::Failure,
+        }
+    }
+
     fn new<'a>(job: &'a Job, output: process::Output) -> Self {
         JobOutput {
             stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
@@ -239,116 +1206,1235 @@ impl JobOutput {
             success: output.status.success(),
             exit_code: output.status.code(),
             signal: output.status.signal(),
+            timed_out: false,
+            interrupted: false,
+            termination: None,
+            stdout_log: None,
+            stderr_log: None,
+            artifacts: Vec::new(),
+            artifact_urls: Vec::new(),
+            dead_letter: None,
+            resources: ResourceUsage::default(),
+            duration: Duration::from_secs(0),
 
             script_name: job.script_name().into(),
             request_ip: job.request_ip(),
+            request_id: job.request_id().map(|id| id.to_string()),
 
             trigger_status_hooks: job.trigger_status_hooks(),
         }
     }
-}
 
+    fn remote<'a>(job: &'a Job, output: RemoteOutput) -> Self {
+        JobOutput {
+            stdout: output.stdout,
+            stderr: output.stderr,
+
+            success: output.success,
+            exit_code: output.exit_code,
+            signal: output.signal,
+            timed_out: output.timed_out,
+            interrupted: false,
+            termination: None,
+            stdout_log: None,
+            stderr_log: None,
+            artifacts: Vec::new(),
+            artifact_urls: Vec::new(),
+            dead_letter: None,
+            resources: ResourceUsage::default(),
+            duration: Duration::from_secs(0),
 
-#[cfg(test)]
-mod tests {
-    use std::collections::HashMap;
-    use std::env;
-    use std::ffi::OsString;
-    use std::fs::File;
-    use std::io::Read;
-    use std::path::{Path, PathBuf};
-    use std::sync::Arc;
+            script_name: job.script_name().into(),
+            request_ip: job.request_ip(),
+            request_id: job.request_id().map(|id| id.to_string()),
 
-    use users;
+            trigger_status_hooks: job.trigger_status_hooks(),
+        }
+    }
+}
 
-    use common::prelude::*;
-    use requests::Request;
-    use scripts::test_utils::*;
-    use utils;
 
-    use super::{Job, Context, DEFAULT_ENV};
 
+/// Return the environment variables of the query string parameters or of the
+/// form fields, named `FISHER_<KIND>_<NAME>`. Too big parameters and the ones
+/// which can't be put in the environment are skipped.
+fn params_env(
+    kind: &str,
+    params: &HashMap<String, String>,
+) -> Vec<(String, String)> {
+    let mut keys = params.keys().collect::<Vec<_>>();
+    keys.sort();
 
-    fn parse_env(content: &str) -> HashMap<&str, &str> {
-        let mut result = HashMap::new();
+    keys.into_iter()
+        .filter(|key| !key.is_empty())
+        .map(|key| (key, &params[key]))
+        .filter(|&(_, value)| {
+            value.len() <= MAX_PARAM_SIZE && !value.contains('\0')
+        })
+        .take(MAX_PARAMS)
+        .map(|(key, value)| {
+            let name = format!("FISHER_{}_{}", kind, utils::env_name(key));
+            (name, value.clone())
+        })
+        .collect()
+}
 
-        for line in content.split("\n") {
-            // Skip empty lines
-            if line.trim() == "" {
-                continue;
-            }
 
-            let (key, value) = utils::parse_env(line).unwrap();
+/// Spawn the command with its output captured, writing the input to its
+/// stdin in the background so scripts which don't read it aren't blocked.
+fn spawn(
+    command: &mut process::Command,
+    input: Option<Vec<u8>>,
+) -> Result<process::Child> {
+    let stdin = if input.is_some() {
+        process::Stdio::piped()
+    } else {
+        process::Stdio::null()
+    };
+    let mut child = utils::spawn_tracked(
+        command
+            .stdin(stdin)
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped()),
+    )?;
+
+    if let Some(input) = input {
+        let pipe = child.stdin.take();
+        thread::spawn(move || {
+            // The pipe is closed when the thread ends, and writing fails if
+            // the script exited without reading everything
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.write_all(&input);
+            }
+        });
+    }
+
+    Ok(child)
+}
+
+
+/// The temporary files of an execution of a job, which are removed when
+/// dropped, even if the job didn't run.
+struct RequestFiles {
+    directory: PathBuf,
+    body: Option<StoredBody>,
+    /// Whether the directory was kept to investigate a failure.
+    kept: bool,
+}
+
+impl RequestFiles {
+    fn new(directory: PathBuf) -> Self {
+        RequestFiles {
+            directory,
+            body: None,
+            kept: false,
+        }
+    }
+}
+
+impl Drop for RequestFiles {
+    fn drop(&mut self) {
+        // A failed removal doesn't change the outcome of the job, and the
+        // leftovers are removed when Fisher starts again
+        if let Some(body) = self.body.take() {
+            if let Err(error) = body.cleanup() {
+                error.pretty_print();
+            }
+        }
+        if !self.kept {
+            if let Err(error) = fs::remove_dir_all(&self.directory) {
+                if error.kind() != io::ErrorKind::NotFound {
+                    Error::from(error).pretty_print();
+                }
+            }
+        }
+    }
+}
+
+
+/// The output of a script run in its own process group.
+struct GroupOutput {
+    output: process::Output,
+    /// Whether the script was stopped because it didn't exit in time.
+    timed_out: bool,
+    /// Whether the script was stopped because Fisher is shutting down.
+    interrupted: bool,
+    /// How the script ended, if it was stopped.
+    termination: Option<Termination>,
+}
+
+impl GroupOutput {
+    /// The output of a script which exited on its own.
+    fn exited(output: process::Output) -> Self {
+        GroupOutput {
+            output,
+            timed_out: false,
+            interrupted: false,
+            termination: None,
+        }
+    }
+}
+
+
+/// Run the command like `Command::output` does, killing the processes the
+/// script left behind in its process group once it exits. If the script
+/// doesn't exit before the timeout, or if it's asked to stop, the whole group
+/// is stopped: the processes are first asked to exit, and killed if they're
+/// still running after the grace period.
+fn output_in_group(
+    command: &mut process::Command,
+    input: Option<Vec<u8>>,
+    timeout: Option<Duration>,
+    grace_period: Duration,
+    stop: &StopSignal,
+) -> Result<GroupOutput> {
+    let mut child = spawn(command, input)?;
+
+    // The pipes are drained in the background, so scripts with a lot of
+    // output don't block while writing it
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut terminated_at = None;
+    let mut timed_out = false;
+    let mut killed = false;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        let now = Instant::now();
+        match terminated_at {
+            None => {
+                timed_out = deadline.map(|d| now >= d).unwrap_or(false);
+                if timed_out || stop.is_stopped() {
+                    signal_group(&child, Signal::SIGTERM);
+                    terminated_at = Some(now);
+                }
+            }
+            Some(at) if now >= at + grace_period => {
+                signal_group(&child, Signal::SIGKILL);
+                killed = true;
+                break child.wait()?;
+            }
+            Some(..) => {}
+        }
+
+        thread::sleep(Duration::from_millis(TIMEOUT_CHECK_INTERVAL));
+    };
+    utils::release_tracked(&child);
+
+    // Don't leave behind the processes the script started, since they would
+    // outlive the job and keep the pipes open. The script itself was already
+    // waited for, so only its group is signalled
+    let _ = kill(Pid::from_raw(-(child.id() as i32)), Signal::SIGKILL);
+
+    let output = process::Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    };
+    Ok(GroupOutput {
+        output,
+        timed_out,
+        interrupted: terminated_at.is_some() && !timed_out,
+        termination: terminated_at.map(|_| {
+            if killed {
+                Termination::Killed
+            } else {
+                Termination::Terminated
+            }
+        }),
+    })
+}
+
+
+/// Apply the resource limits to the current process, setting both the soft
+/// and the hard limit so the script can't raise them again. This is called
+/// in the child process, right before the script is executed.
+fn apply_limits(limits: &ResourceLimits) -> io::Result<()> {
+    let all = [
+        (libc::RLIMIT_CPU, limits.cpu_time.as_ref().map(|t| t.as_u64())),
+        (libc::RLIMIT_AS, limits.address_space.as_ref().map(|s| s.as_u64())),
+        (libc::RLIMIT_NOFILE, limits.open_files),
+        (libc::RLIMIT_CORE, limits.core_size.as_ref().map(|s| s.as_u64())),
+    ];
+
+    for &(resource, value) in &all {
+        if let Some(value) = value {
+            let limit = libc::rlimit {
+                rlim_cur: value as libc::rlim_t,
+                rlim_max: value as libc::rlim_t,
+            };
+            if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+
+/// Send the signal to the process and to the processes it started, since
+/// scripts run in their own process group.
+fn signal_group(child: &process::Child, signal: Signal) {
+    let group = Pid::from_raw(-(child.id() as i32));
+    if kill(group, signal).is_err() {
+        let _ = kill(Pid::from_raw(child.id() as i32), signal);
+    }
+}
+
+
+fn drain<R: Read + Send + 'static>(
+    pipe: Option<R>,
+) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buffer);
+        }
+        buffer
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+    use std::ffi::OsString;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    use std::os::unix::process::{CommandExt, ExitStatusExt};
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use libc;
+    use nix::unistd::{setpgid, Pid};
+    use serde_json;
+    use users;
+
+    use common::prelude::*;
+    use common::config::{BodyStorageConfig, BodyStorageKind};
+    use common::config::ResourceLimits;
+    use events::{JobEventKind, JobState};
+    use providers::StatusEvent;
+    use requests::Request;
+    use scripts::{DeadLetters, JobLogs, ScheduledRun};
+    use scripts::interpreter::Interpreter;
+    use scripts::test_utils::*;
+    use utils;
+    use utils::testing::dummy_job_output;
+
+    use super::{output_in_group, params_env, Job, Context, DEFAULT_ENV};
+    use super::{ExitKind, StopSignal, Termination};
+    use super::{MAX_PARAMS, MAX_PARAM_SIZE};
+
+
+    fn parse_env(content: &str) -> HashMap<&str, &str> {
+        let mut result = HashMap::new();
+
+        for line in content.split("\n") {
+            // Skip empty lines
+            if line.trim() == "" {
+                continue;
+            }
+
+            let (key, value) = utils::parse_env(line).unwrap();
             result.insert(key, value);
         }
 
-        result
+        result
+    }
+
+
+    fn create_job(env: &TestEnv, name: &str, req: Request) -> Result<Job> {
+        let script = env.load_script(name)?;
+        let (_, provider, provider_env) = script.validate(&req);
+
+        Ok(Job::new(Arc::new(script), provider, req)
+            .with_provider_env(provider_env))
+    }
+
+
+    fn content<P: AsRef<Path>>(base: P, name: &str) -> Result<String> {
+        let mut file = File::open(&base.as_ref().join(name))?;
+
+        let mut buf = String::new();
+        file.read_to_string(&mut buf)?;
+
+        Ok(buf)
+    }
+
+
+    #[test]
+    fn test_job_creation() {
+        test_wrapper(|env| {
+            // Create an example script
+            env.create_script("example.sh", &[])?;
+
+            // Create a new job from the script
+            let req = dummy_web_request().into();
+            let job = create_job(env, "example.sh", req)?;
+            assert_eq!(job.script_name(), "example.sh");
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_job_execution() {
+        test_wrapper(|env| {
+            let ctx = Context::default();
+            let req: Request = dummy_web_request().into();
+
+            // Create a successful script
+            env.create_script("success.sh", &[
+                "#!/bin/bash",
+                "exit 0",
+            ])?;
+
+            // Create a failing script
+            env.create_script("fail.sh", &[
+                "#!/bin/bash",
+                "exit 1",
+            ])?;
+
+            // Execute the successful script
+            let job = create_job(env, "success.sh", req.clone())?;
+            let result = job.process(&ctx)?;
+            assert!(result.success);
+            assert_eq!(result.exit_code, Some(0));
+
+            // Execute the failing script
+            let job = create_job(env, "fail.sh", req.clone())?;
+            let result = job.process(&ctx)?;
+            assert!(!result.success);
+            assert_eq!(result.exit_code, Some(1));
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_timeout() {
+        test_wrapper(|env| {
+            let ctx = Context::default();
+            let req: Request = dummy_web_request().into();
+
+            env.create_script("slow.sh", &[
+                "#!/bin/bash",
+                r#"## Fisher: {"timeout": "1s"}"#,
+                "echo started",
+                "sleep 30",
+            ])?;
+
+            let start = Instant::now();
+            let job = create_job(env, "slow.sh", req.clone())?;
+            let result = job.process(&ctx)?;
+            assert!(start.elapsed() < Duration::from_secs(10));
+
+            // The script is stopped, but its output is kept
+            assert!(!result.success);
+            assert!(result.timed_out);
+            assert_eq!(result.exit_code, None);
+            assert_eq!(result.signal, Some(15));
+            assert_eq!(result.stdout, "started\n");
+
+            // The global timeout applies to hooks without one
+            env.create_script("global.sh", &[
+                "#!/bin/bash",
+                "sleep 30",
+            ])?;
+            let ctx = Context {
+                timeout: Some(Duration::from_secs(1)),
+                .. Context::default()
+            };
+
+            let start = Instant::now();
+            let job = create_job(env, "global.sh", req.clone())?;
+            let result = job.process(&ctx)?;
+            assert!(start.elapsed() < Duration::from_secs(10));
+            assert!(result.timed_out);
+            assert_eq!(result.signal, Some(15));
+
+            // Hooks exiting in time are not affected
+            env.create_script("quick.sh", &["#!/bin/bash", "echo quick"])?;
+            let job = create_job(env, "quick.sh", req)?;
+            let result = job.process(&ctx)?;
+            assert!(result.success);
+            assert!(!result.timed_out);
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_output_in_group_timeout() {
+        test_wrapper(|env| {
+            // The script and the processes it starts ignore SIGTERM
+            env.create_script("stubborn.sh", &[
+                "#!/bin/bash",
+                "trap '' TERM",
+                "echo started",
+                "sleep 30 &",
+                "sleep 30",
+            ])?;
+
+            let mut command = Command::new(
+                env.scripts_dir().join("stubborn.sh"),
+            );
+            command.before_exec(|| {
+                let _ = setpgid(Pid::this(), Pid::from_raw(0));
+                Ok(())
+            });
+
+            let start = Instant::now();
+            let result = output_in_group(
+                &mut command,
+                None,
+                Some(Duration::from_millis(500)),
+                Duration::from_millis(500),
+                &StopSignal::new(),
+            )?;
+
+            // The background process doesn't keep the output open
+            assert!(start.elapsed() < Duration::from_secs(10));
+            assert!(result.timed_out);
+            assert!(!result.interrupted);
+            assert_eq!(result.termination, Some(Termination::Killed));
+            assert_eq!(result.output.status.signal(), Some(9));
+            assert_eq!(result.output.stdout, b"started\n");
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_output_in_group_background() {
+        test_wrapper(|env| {
+            let out = env.tempdir()?;
+            env.create_script("background.sh", &[
+                "#!/bin/bash",
+                "sleep 30 &",
+                r#"echo "$!" > "$1/pid""#,
+                "echo done",
+            ])?;
+
+            let mut command = Command::new(
+                env.scripts_dir().join("background.sh"),
+            );
+            command.arg(&out);
+            command.before_exec(|| {
+                let _ = setpgid(Pid::this(), Pid::from_raw(0));
+                Ok(())
+            });
+
+            // The background process is killed when the script exits
+            let start = Instant::now();
+            let result = output_in_group(
+                &mut command,
+                None,
+                None,
+                Duration::from_millis(500),
+                &StopSignal::new(),
+            )?;
+            assert!(start.elapsed() < Duration::from_secs(10));
+            assert!(!result.timed_out);
+            assert!(result.termination.is_none());
+            assert!(result.output.status.success());
+            assert_eq!(result.output.stdout, b"done\n");
+
+            // Wait for the killed process to be reaped by its new parent
+            let pid = content(&out, "pid")?.trim().parse().unwrap();
+            let start = Instant::now();
+            while unsafe { libc::kill(pid, 0) } == 0 {
+                assert!(start.elapsed() < Duration::from_secs(5));
+                thread::sleep(Duration::from_millis(50));
+            }
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_output_in_group_stop() {
+        test_wrapper(|env| {
+            // The script exits cleanly when it's asked to
+            env.create_script("graceful.sh", &[
+                "#!/bin/bash",
+                "trap 'echo stopping; exit 1' TERM",
+                "echo started",
+                "while true; do sleep 0.1; done",
+            ])?;
+
+            let mut command = Command::new(
+                env.scripts_dir().join("graceful.sh"),
+            );
+            command.before_exec(|| {
+                let _ = setpgid(Pid::this(), Pid::from_raw(0));
+                Ok(())
+            });
+
+            let stop = Arc::new(StopSignal::new());
+            let stop_later = stop.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(500));
+                stop_later.stop();
+            });
+
+            let result = output_in_group(
+                &mut command, None, None, Duration::from_secs(10), &stop,
+            )?;
+            assert!(result.interrupted);
+            assert!(!result.timed_out);
+            assert_eq!(result.termination, Some(Termination::Terminated));
+            assert_eq!(result.output.status.code(), Some(1));
+            assert_eq!(result.output.stdout, b"started\nstopping\n");
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_reply() {
+        test_wrapper(|env| {
+            let req: Request = dummy_web_request().into();
+            env.create_script("hello.sh", &[
+                "#!/bin/bash",
+                "echo hello",
+            ])?;
+
+            let (reply_send, reply_recv) = mpsc::channel();
+            let job = create_job(env, "hello.sh", req)?.reply_to(reply_send);
+            job.execute(&Context::default())?;
+
+            assert_eq!(reply_recv.recv()?.stdout, "hello\n");
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_retry() {
+        test_wrapper(|env| {
+            let req: Request = dummy_web_request().into();
+            env.create_script("flaky.sh", &[
+                "#!/bin/bash",
+                r#"## Fisher: {"max-retries": 1, "backoff": "2s"}"#,
+                r#"echo "${FISHER_ATTEMPT}""#,
+                "exit 1",
+            ])?;
+
+            let ctx = Context::default();
+            let (reply_send, reply_recv) = mpsc::channel();
+            let job = create_job(env, "flaky.sh", req)?.reply_to(reply_send);
+            assert_eq!(job.attempt(), 1);
+
+            // The first failure is retried without being reported
+            let output = job.execute(&ctx)?;
+            assert_eq!(output.stdout, "1\n");
+            assert!(!output.trigger_status_hooks);
+            assert!(reply_recv.try_recv().is_err());
+
+            let (retry, delay) = job.retry(&output).unwrap();
+            assert_eq!(retry.id(), job.id());
+            assert_eq!(retry.attempt(), 2);
+            assert_eq!(delay, Duration::from_secs(2));
+
+            // The last failure is reported as usual
+            let output = retry.execute(&ctx)?;
+            assert_eq!(output.stdout, "2\n");
+            assert!(output.trigger_status_hooks);
+            assert_eq!(reply_recv.recv()?.stdout, "2\n");
+            assert!(retry.retry(&output).is_none());
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_exit_codes() {
+        test_wrapper(|env| {
+            for &(name, code) in &[
+                ("tempfail.sh", 75), ("rejected.sh", 65), ("config.sh", 78),
+            ] {
+                env.create_script(name, &[
+                    "#!/bin/bash",
+                    r#"## Fisher: {"max-retries": 1}"#,
+                    &format!("exit {}", code),
+                ])?;
+            }
+
+            let dead_letters = Arc::new(DeadLetters::new());
+            dead_letters.set_dir(Some(&env.tempdir()?))?;
+            let ctx = Context {
+                dead_letters: dead_letters.clone(),
+                .. Context::default()
+            };
+            let job = |name| {
+                create_job(env, name, dummy_web_request().into())
+            };
+
+            // Temporary failures are retried more than the hook allows
+            let mut tempfail = job("tempfail.sh")?;
+            for attempt in 1..4 {
+                let output = tempfail.execute(&ctx)?;
+                assert_eq!(output.exit_kind(), ExitKind::TempFailure);
+                assert!(output.dead_letter.is_none());
+                tempfail = tempfail.retry(&output).unwrap().0;
+                assert_eq!(tempfail.attempt(), attempt + 1);
+            }
+            let output = tempfail.execute(&ctx)?;
+            assert!(tempfail.retry(&output).is_none());
+            assert!(output.dead_letter.is_some());
+
+            // Rejected requests are never retried nor saved
+            let rejected = job("rejected.sh")?;
+            let output = rejected.execute(&ctx)?;
+            assert_eq!(output.exit_kind(), ExitKind::Rejected);
+            assert!(rejected.retry(&output).is_none());
+            assert!(output.dead_letter.is_none());
+            assert!(dead_letters.get(rejected.id())?.is_none());
+
+            // Misconfigured hooks are not retried, but the request is saved
+            let config = job("config.sh")?;
+            let output = config.execute(&ctx)?;
+            assert_eq!(output.exit_kind(), ExitKind::ConfigError);
+            assert!(config.retry(&output).is_none());
+            assert!(output.dead_letter.is_some());
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_limits() {
+        test_wrapper(|env| {
+            env.create_script("limited.sh", &[
+                "#!/bin/bash",
+                r#"## Fisher: {"limits": {"open-files": 64, "core-size": 0}}"#,
+                "ulimit -n -c -t",
+            ])?;
+
+            // The limits of the hook override the global ones
+            let ctx = Context {
+                limits: ResourceLimits {
+                    cpu_time: Some(5.into()),
+                    open_files: Some(128),
+                    .. ResourceLimits::default()
+                },
+                .. Context::default()
+            };
+
+            let req: Request = dummy_web_request().into();
+            let job = create_job(env, "limited.sh", req)?;
+            let output = job.execute(&ctx)?;
+            assert!(output.success);
+
+            let limits = output.stdout.lines()
+                .map(|line| line.rsplit(' ').next().unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(limits, vec!["64", "0", "5"]);
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_scheduling() {
+        test_wrapper(|env| {
+            env.create_script("nice.sh", &[
+                "#!/bin/bash",
+                r#"## Fisher: {"nice": 5, "io-class": "idle"}"#,
+                "nice",
+            ])?;
+
+            let req: Request = dummy_web_request().into();
+            let job = create_job(env, "nice.sh", req)?;
+            let output = job.execute(&Context::default())?;
+            assert!(output.success);
+            assert_eq!(output.stdout, "5\n");
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_runtime() {
+        test_wrapper(|env| {
+            env.create_script("runtime.sh", &[
+                "#!/bin/bash",
+                concat!(
+                    r#"## Fisher: {"umask": "027", "locale": "C", "#,
+                    r#""timezone": "UTC"}"#,
+                ),
+                "umask",
+                r#"echo "${LANG} ${LC_ALL} ${TZ}""#,
+                "touch created",
+                "stat -c %a created",
+            ])?;
+
+            let req: Request = dummy_web_request().into();
+            let job = create_job(env, "runtime.sh", req)?;
+            let output = job.execute(&Context::default())?;
+            assert!(output.success);
+            assert_eq!(output.stdout, "0027\nC C UTC\n640\n");
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_interpreter() {
+        test_wrapper(|env| {
+            env.create_script("interpreted.sh", &[
+                "#!/bin/sh",
+                r#"echo "$0 $1""#,
+            ])?;
+            let path = env.scripts_dir().join("interpreted.sh");
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o644))?;
+
+            // The interpreter receives the path of the script
+            let mut script = env.load_script("interpreted.sh")?;
+            let interpreter = Interpreter::from_shebang(&path)?;
+            script.set_interpreter(interpreter.unwrap());
+            let expected = format!("{} \n", script.exec());
+
+            let req: Request = dummy_web_request().into();
+            let job = Job::new(Arc::new(script), None, req);
+            let output = job.execute(&Context::default())?;
+            assert!(output.success);
+            assert_eq!(output.stdout, expected);
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_scheduled_job() {
+        test_wrapper(|env| {
+            env.create_script("scheduled.sh", &[
+                "#!/bin/bash",
+                r#"## Fisher-Schedule: "0 3 * * *""#,
+                r#"echo "${FISHER_SCHEDULED_AT}""#,
+                r#"echo "${FISHER_REQUEST_IP}""#,
+                r#"echo "${FISHER_REQUEST_BODY:-none}""#,
+            ])?;
+
+            let req: Request = ScheduledRun { time: 10800 }.into();
+            let job = create_job(env, "scheduled.sh", req)?;
+            assert_eq!(job.request_id(), None);
+            assert!(job.journal_entry(0)?.is_none());
+
+            let output = job.execute(&Context::default())?;
+            assert!(output.success);
+            assert_eq!(output.stdout, "10800\n127.0.0.1\nnone\n");
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_persistent_workdir() {
+        test_wrapper(|env| {
+            env.create_script("stateful.sh", &[
+                "#!/bin/bash",
+                r#"## Fisher: {"persistent-workdir": true}"#,
+                r#"echo "run" >> runs"#,
+                r#"wc -l < runs"#,
+                r#"[[ "$(pwd)" != "${FISHER_REQUEST_DIR}" ]]"#,
+                r#"[[ -f "${FISHER_REQUEST_DIR}/request_body" ]]"#,
+            ])?;
+
+            // The hook can't run if persistent directories are disabled
+            let req = dummy_web_request().into();
+            let job = create_job(env, "stateful.sh", req)?;
+            assert!(job.execute(&Context::default()).is_err());
+
+            let workdirs = env.tempdir()?;
+            let mut ctx = Context::default();
+            ctx.workdirs = Some(workdirs.clone());
+
+            // The content of the directory is kept between the runs
+            for expected in &["1\n", "2\n"] {
+                let req = dummy_web_request().into();
+                let output = create_job(env, "stateful.sh", req)?
+                    .execute(&ctx)?;
+                assert!(output.success);
+                assert_eq!(&output.stdout, expected);
+            }
+            assert!(workdirs.join("stateful.sh").join("runs").exists());
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_wrappers() {
+        test_wrapper(|env| {
+            env.create_script("wrapped.sh", &[
+                "#!/bin/bash",
+                r#"touch "${OUT}/ran""#,
+                "cat prepared",
+                "exit 3",
+            ])?;
+
+            let wrappers = env.tempdir()?;
+            env.create_script_into(&wrappers, "pre.sh", &[
+                "#!/bin/bash",
+                r#"echo "${FISHER_HOOK_NAME}" > prepared"#,
+            ])?;
+            env.create_script_into(&wrappers, "failing.sh", &[
+                "#!/bin/bash",
+                "echo not ready >&2",
+                "exit 1",
+            ])?;
+            env.create_script_into(&wrappers, "post.sh", &[
+                "#!/bin/bash",
+                r#"s="${FISHER_JOB_SUCCESS}/${FISHER_JOB_EXIT_CODE}""#,
+                r#"echo "${s}/${FISHER_JOB_TIMED_OUT}" > "${OUT}/post""#,
+            ])?;
+
+            let out = env.tempdir()?;
+            let mut ctx = Context::default();
+            ctx.environment.insert("OUT".into(), out.to_str().unwrap().into());
+            ctx.pre_wrapper = Some(wrappers.join("pre.sh"));
+            ctx.post_wrapper = Some(wrappers.join("post.sh"));
+
+            // The wrappers share the working directory of the hook
+            let req = dummy_web_request().into();
+            let output = create_job(env, "wrapped.sh", req)?.execute(&ctx)?;
+            assert_eq!(output.stdout, "wrapped.sh\n");
+            assert_eq!(output.exit_code, Some(3));
+            assert_eq!(content(&out, "post")?, "0/3/0\n");
+
+            // The hook doesn't run if the first wrapper fails
+            fs::remove_file(out.join("ran"))?;
+            ctx.pre_wrapper = Some(wrappers.join("failing.sh"));
+            let req = dummy_web_request().into();
+            let output = create_job(env, "wrapped.sh", req)?.execute(&ctx)?;
+            assert!(!output.success);
+            assert_eq!(output.stderr, "not ready\n");
+            assert!(!out.join("ran").exists());
+            assert_eq!(content(&out, "post")?, "0/1/0\n");
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_container() {
+        test_wrapper(|env| {
+            env.create_script("contained.sh", &[
+                "#!/bin/bash",
+                r#"## Fisher: {"image": "alpine:3.6"}"#,
+            ])?;
+
+            // A fake runtime printing how it's called
+            let bin = env.tempdir()?;
+            env.create_script_into(&bin, "runtime.sh", &[
+                "#!/bin/bash",
+                r#"if [[ "$1" == "rm" ]]; then"#,
+                r#"    echo "$@" > "${OUT}/rm""#,
+                "else",
+                r#"    echo "$@""#,
+                "fi",
+            ])?;
+
+            let out = env.tempdir()?;
+            let mut ctx = Context::default();
+            ctx.environment.insert("OUT".into(), out.to_str().unwrap().into());
+            ctx.container_runtime = bin.join("runtime.sh")
+                .to_str().unwrap().to_string();
+
+            let req = dummy_web_request().into();
+            let job = create_job(env, "contained.sh", req)?;
+            let output = job.execute(&ctx)?;
+            assert!(output.success);
+
+            // The values of the variables are not in the arguments
+            let exec = env.scripts_dir().join("contained.sh");
+            assert!(output.stdout.starts_with(&format!(
+                "run --rm --interactive --name fisher-{}-1 ", job.id(),
+            )));
+            assert!(output.stdout.ends_with(&format!(
+                " alpine:3.6 {}\n", exec.to_str().unwrap(),
+            )));
+            assert!(output.stdout.contains(" --env FISHER_JOB_ID "));
+            assert!(output.stdout.contains(" --env OUT "));
+            assert!(!output.stdout.contains(" --env PATH "));
+            assert!(!output.stdout.contains(out.to_str().unwrap()));
+
+            // The container is always removed
+            assert_eq!(
+                content(&out, "rm")?,
+                format!("rm --force fisher-{}-1\n", job.id()),
+            );
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_temp_dir() {
+        test_wrapper(|env| {
+            env.create_script("where.sh", &[
+                "#!/bin/bash",
+                "pwd",
+            ])?;
+
+            let base = env.tempdir()?;
+            let mut ctx = Context::default();
+            ctx.temp_dir = Some(base.clone());
+
+            // The working directory is created in the base and removed
+            let req = dummy_web_request().into();
+            let output = create_job(env, "where.sh", req)?.execute(&ctx)?;
+            assert!(output.success);
+            assert!(output.stdout.starts_with(base.to_str().unwrap()));
+            assert_eq!(fs::read_dir(&base)?.count(), 0);
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_cleanup_on_error() {
+        test_wrapper(|env| {
+            let secrets = env.tempdir()?.join("secrets");
+            env.create_script("secret.sh", &[
+                "#!/bin/bash",
+                &format!(
+                    r#"## Fisher: {{"secrets": "{}"}}"#, secrets.display(),
+                ),
+                "true",
+            ])?;
+
+            let (base, tmpfs) = (env.tempdir()?, env.tempdir()?);
+            let ctx = Context {
+                temp_dir: Some(base.clone()),
+                body_storage: BodyStorageConfig {
+                    backend: BodyStorageKind::Tmpfs,
+                    tmpfs_path: tmpfs.to_str().unwrap().into(),
+                    .. BodyStorageConfig::default()
+                },
+                .. Context::default()
+            };
+
+            // The job fails after the body is stored, since the secrets are
+            // missing, but nothing is left behind
+            let req = dummy_web_request().into();
+            let job = create_job(env, "secret.sh", req)?;
+            assert!(job.execute(&ctx).is_err());
+            assert_eq!(fs::read_dir(&base)?.count(), 0);
+            assert_eq!(fs::read_dir(&tmpfs)?.count(), 0);
+
+            Ok(())
+        })
     }
 
 
-    fn create_job(env: &TestEnv, name: &str, req: Request) -> Result<Job> {
-        let script = env.load_script(name)?;
-        let (_, provider) = script.validate(&req);
+    #[test]
+    fn test_job_logs() {
+        test_wrapper(|env| {
+            env.create_script("noisy.sh", &[
+                "#!/bin/bash",
+                "echo out",
+                "echo err >&2",
+                "exit 1",
+            ])?;
 
-        Ok(Job::new(Arc::new(script), provider, req))
-    }
+            let logs_dir = env.tempdir()?;
+            let ctx = Context {
+                logs: Some(JobLogs::new(&logs_dir, 0)),
+                .. Context::default()
+            };
 
+            let req: Request = dummy_web_request().into();
+            let job = create_job(env, "noisy.sh", req)?;
+            let output = job.execute(&ctx)?;
 
-    fn content<P: AsRef<Path>>(base: P, name: &str) -> Result<String> {
-        let mut file = File::open(&base.as_ref().join(name))?;
+            let job_dir = logs_dir.join(job.id());
+            assert_eq!(output.stdout_log, Some(job_dir.join("1.stdout")));
+            assert_eq!(output.stderr_log, Some(job_dir.join("1.stderr")));
+            assert_eq!(content(&job_dir, "1.stdout")?, "out\n");
+            assert_eq!(content(&job_dir, "1.stderr")?, "err\n");
 
-        let mut buf = String::new();
-        file.read_to_string(&mut buf)?;
+            // Logs are not saved if they're not configured
+            let output = job.execute(&Context::default())?;
+            assert!(output.stdout_log.is_none());
+            assert!(output.stderr_log.is_none());
 
-        Ok(buf)
+            Ok(())
+        })
     }
 
 
     #[test]
-    fn test_job_creation() {
+    fn test_job_keep_workdir() {
         test_wrapper(|env| {
-            // Create an example script
-            env.create_script("example.sh", &[])?;
+            env.create_script("failing.sh", &[
+                "#!/bin/bash",
+                "echo partial > build.log",
+                "exit 1",
+            ])?;
+            env.create_script("passing.sh", &[
+                "#!/bin/bash",
+                "echo done > build.log",
+            ])?;
+            env.create_script("discarded.sh", &[
+                "#!/bin/bash",
+                r#"## Fisher: {"keep-workdir-on-failure": false}"#,
+                "exit 1",
+            ])?;
 
-            // Create a new job from the script
-            let req = dummy_web_request().into();
-            let job = create_job(env, "example.sh", req)?;
-            assert_eq!(job.script_name(), "example.sh");
+            let logs_dir = env.tempdir()?;
+            let ctx = Context {
+                logs: Some(JobLogs::new(&logs_dir, 0)),
+                keep_workdir_on_failure: true,
+                .. Context::default()
+            };
+
+            // The directory of failed jobs is moved next to their logs
+            let req: Request = dummy_web_request().into();
+            let job = create_job(env, "failing.sh", req)?;
+            assert!(!job.execute(&ctx)?.success);
+            let workdir = logs_dir.join(job.id()).join("1.workdir");
+            assert_eq!(content(&workdir, "build.log")?, "partial\n");
+            assert_eq!(content(&workdir, "request_body")?, "");
+
+            for name in &["passing.sh", "discarded.sh"] {
+                let req: Request = dummy_web_request().into();
+                let job = create_job(env, name, req)?;
+                job.execute(&ctx)?;
+                assert!(!logs_dir.join(job.id()).join("1.workdir").exists());
+            }
 
             Ok(())
-        });
+        })
     }
 
 
     #[test]
-    fn test_job_execution() {
+    fn test_job_artifacts() {
         test_wrapper(|env| {
-            let ctx = Context::default();
+            env.create_script("build.sh", &[
+                "#!/bin/bash",
+                r#"## Fisher: {"artifacts": ["dist/*"]}"#,
+                "mkdir dist",
+                "echo built > dist/app.txt",
+                "echo ignored > build.log",
+            ])?;
+
+            let logs_dir = env.tempdir()?;
+            let ctx = Context {
+                logs: Some(JobLogs::new(&logs_dir, 0)),
+                .. Context::default()
+            };
+
+            // The artifacts are kept after the working directory is removed
             let req: Request = dummy_web_request().into();
+            let job = create_job(env, "build.sh", req)?;
+            let output = job.execute(&ctx)?;
+            assert!(output.success);
 
-            // Create a successful script
-            env.create_script("success.sh", &[
+            let dir = logs_dir.join(job.id()).join("1.artifacts");
+            let expected = vec![dir.join("dist").join("app.txt")];
+            assert_eq!(output.artifacts, expected);
+            assert_eq!(content(&dir.join("dist"), "app.txt")?, "built\n");
+            assert!(!dir.join("build.log").exists());
+
+            // Nothing is collected without a place to keep the artifacts
+            let req: Request = dummy_web_request().into();
+            let job = create_job(env, "build.sh", req)?;
+            assert!(job.execute(&Context::default())?.artifacts.is_empty());
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_coalesced() {
+        test_wrapper(|env| {
+            env.create_script("coalesced.sh", &[
                 "#!/bin/bash",
-                "exit 0",
+                r#"## Fisher-Coalesce: {"window": "1m"}"#,
             ])?;
 
-            // Create a failing script
+            let ctx = Context::default();
+            let script = Arc::new(env.load_script("coalesced.sh")?);
+            let coalesce = script.coalesce().unwrap();
+
+            let job = || {
+                let job = Job::new(
+                    script.clone(), None, dummy_web_request().into(),
+                );
+                coalesce.push(String::new(), job.id());
+                job
+            };
+            let first = job();
+            let second = job();
+
+            // Only the most recent job is run
+            assert!(first.superseded(&ctx));
+            assert!(!second.superseded(&ctx));
+
+            let status = ctx.events.status(first.id()).unwrap();
+            assert_eq!(status.state, JobState::Coalesced);
+            assert!(ctx.events.status(second.id()).is_none());
+
+            Ok(())
+        })
+    }
+
+
+    #[test]
+    fn test_job_events() {
+        test_wrapper(|env| {
+            let req: Request = dummy_web_request().into();
             env.create_script("fail.sh", &[
                 "#!/bin/bash",
                 "exit 1",
             ])?;
 
-            // Execute the successful script
-            let job = create_job(env, "success.sh", req.clone())?;
-            let result = job.process(&ctx)?;
-            assert!(result.success);
-            assert_eq!(result.exit_code, Some(0));
-
-            // Execute the failing script
-            let job = create_job(env, "fail.sh", req.clone())?;
-            let result = job.process(&ctx)?;
-            assert!(!result.success);
-            assert_eq!(result.exit_code, Some(1));
+            let ctx = Context::default();
+            let events = ctx.events.subscribe();
+            let job = create_job(env, "fail.sh", req)?;
+            job.execute(&ctx)?;
+
+            let timeout = Duration::from_secs(1);
+            for kind in &[JobEventKind::Started, JobEventKind::Failed] {
+                let event = events.next(timeout).unwrap().unwrap();
+                assert_eq!(event.event, *kind);
+                assert_eq!(event.hook, "fail.sh");
+                assert_eq!(event.job, job.id());
+                assert_eq!(event.request_id, Some("dummy-request".into()));
+            }
 
             Ok(())
         })
@@ -392,7 +2478,7 @@ mod tests {
             assert_eq!(&content(&out, "executed")?, "executed\n");
 
             // Ensure the request body was provided
-            assert_eq!(&content(&out, "request_body")?, "a body!\n");
+            assert_eq!(&content(&out, "request_body")?, "a body!");
 
             // Get the script working directory
             let working_directory = content(&out, "pwd")?;
@@ -404,8 +2490,13 @@ mod tests {
             // Calculate the list of expected environment variables
             let extra_env = vec![
                 // Variables set by Fisher
-                "FISHER_TESTING_ENV", "FISHER_REQUEST_IP",
-                "FISHER_REQUEST_BODY", "HOME", "USER",
+                "FISHER_TESTING_ENV", "FISHER_REQUEST_IP", "FISHER_REQUEST_ID",
+                "FISHER_REQUEST_METHOD", "FISHER_REQUEST_URL",
+                "FISHER_JOB_ID", "FISHER_HOOK_NAME", "FISHER_PROVIDER",
+                "FISHER_RECEIVED_AT", "FISHER_ATTEMPT",
+                "FISHER_PARAM_ENV", "FISHER_REQUEST_DIR",
+                "FISHER_REQUEST_BODY", "FISHER_REQUEST_BODY_ENCODING",
+                "HOME", "USER",
                 // Variables set by bash
                 "PWD", "SHLVL", "_",
             ];
@@ -427,7 +2518,20 @@ mod tests {
             // Ensure environment variables are correct
             assert_eq!(&env_vars["FISHER_TESTING_ENV"], &out.to_str().unwrap());
             assert_eq!(&env_vars["FISHER_REQUEST_IP"], &"127.0.0.1");
+            assert_eq!(&env_vars["FISHER_REQUEST_ID"], &"dummy-request");
+            assert_eq!(&env_vars["FISHER_REQUEST_METHOD"], &"POST");
+            assert_eq!(&env_vars["FISHER_REQUEST_URL"], &"/hook/example.sh");
+            assert_eq!(env_vars["FISHER_JOB_ID"].len(), 32);
+            assert_eq!(&env_vars["FISHER_HOOK_NAME"], &"dump.sh");
+            assert_eq!(&env_vars["FISHER_PROVIDER"], &"Testing");
+            assert_eq!(&env_vars["FISHER_ATTEMPT"], &"1");
+            assert!(env_vars["FISHER_RECEIVED_AT"].parse::<u64>().unwrap() > 0);
+            assert_eq!(&env_vars["FISHER_PARAM_ENV"], &out.to_str().unwrap());
+            assert_eq!(&env_vars["FISHER_REQUEST_BODY_ENCODING"], &"utf-8");
             assert_eq!(&env_vars["HOME"], &working_directory.trim());
+            assert_eq!(
+                &env_vars["FISHER_REQUEST_DIR"], &working_directory.trim(),
+            );
             assert_eq!(
                 &env_vars["USER"],
                 &users::get_current_username().unwrap()
@@ -443,6 +2547,28 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_params_env() {
+        let mut params = HashMap::new();
+        params.insert("event-type".to_string(), "push".to_string());
+        params.insert("big".to_string(), "a".repeat(MAX_PARAM_SIZE + 1));
+        params.insert("nul".to_string(), "a\0b".to_string());
+        params.insert(String::new(), "empty".to_string());
+
+        assert_eq!(params_env("PARAM", &params), vec![
+            ("FISHER_PARAM_EVENT_TYPE".to_string(), "push".to_string()),
+        ]);
+
+        // Only the first parameters are exposed
+        let params = (0..MAX_PARAMS + 10)
+            .map(|i| (format!("p{:03}", i), i.to_string()))
+            .collect::<HashMap<_, _>>();
+        let env = params_env("FORM", &params);
+        assert_eq!(env.len(), MAX_PARAMS);
+        assert_eq!(env[0], ("FISHER_FORM_P000".to_string(), "0".to_string()));
+    }
+
+
     #[test]
     fn test_job_environment_with_extra_env() {
         test_wrapper(|mut env| {
@@ -469,6 +2595,352 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_job_environment_with_templates() {
+        test_wrapper(|env| {
+            env.create_script("templated.sh", &[
+                "#!/bin/bash",
+                concat!(
+                    r#"## Fisher-Env: {"B": "{{ payload.ref }}", "#,
+                    r#""E": "{{ params.env }}!"}"#,
+                ),
+                r#"echo "${B}/${E}""#,
+            ])?;
+
+            let mut req = dummy_web_request();
+            req.body = r#"{"ref": "master"}"#.into();
+            req.params.insert("env".into(), "prod".into());
+
+            let job = create_job(env, "templated.sh", req.into())?;
+            let output = job.execute(&Context::default())?;
+            assert_eq!(output.stdout, "master/prod!\n");
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_job_secrets() {
+        test_wrapper(|env| {
+            let secrets = env.tempdir()?.join("secrets");
+            env.create_script("secret.sh", &[
+                "#!/bin/bash",
+                &format!(
+                    r#"## Fisher: {{"secrets": "{}"}}"#, secrets.display(),
+                ),
+                r#"echo "${DEPLOY_TOKEN}""#,
+            ])?;
+
+            // Jobs can't run without their secrets
+            let req: Request = dummy_web_request().into();
+            let job = create_job(env, "secret.sh", req)?;
+            assert!(job.execute(&Context::default()).is_err());
+
+            fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .mode(0o600)
+                .open(&secrets)?
+                .write_all(b"DEPLOY_TOKEN=hunter2\n")?;
+            let output = job.execute(&Context::default())?;
+            assert_eq!(output.stdout, "hunter2\n");
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_job_stdin() {
+        test_wrapper(|env| {
+            let modes = [("copy.sh", "body"), ("only.sh", "body-only")];
+            for &(name, mode) in &modes {
+                env.create_script(name, &[
+                    "#!/bin/bash",
+                    &format!(r#"## Fisher: {{"stdin": "{}"}}"#, mode),
+                    r#"echo "${FISHER_REQUEST_BODY:-none}" >&2"#,
+                    r#"echo "${FISHER_REQUEST_BODY_ENCODING}" >&2"#,
+                    "tr a-z A-Z",
+                ])?;
+            }
+            env.create_script("naked.sh", &[
+                "#!/bin/bash",
+                "cat",
+            ])?;
+
+            let mut req = dummy_web_request();
+            req.body = "a body!".into();
+
+            // The body is also stored if the script asks so
+            let job = create_job(env, "copy.sh", req.clone().into())?;
+            let output = job.execute(&Context::default())?;
+            assert_eq!(output.stdout, "A BODY!");
+            assert!(output.stderr.ends_with("/request_body\nutf-8\n"));
+
+            let job = create_job(env, "only.sh", req.clone().into())?;
+            let output = job.execute(&Context {
+                timeout: Some(Duration::from_secs(30)),
+                .. Context::default()
+            })?;
+            assert_eq!(output.stdout, "A BODY!");
+            assert_eq!(output.stderr, "none\nutf-8\n");
+
+            // Scripts don't receive anything by default
+            let job = create_job(env, "naked.sh", req.into())?;
+            assert_eq!(job.execute(&Context::default())?.stdout, "");
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_job_request_info() {
+        test_wrapper(|env| {
+            env.create_script("info.sh", &[
+                "#!/bin/bash",
+                r#"cat "${FISHER_REQUEST_DIR}/request_headers""#,
+                r#"cat "${FISHER_REQUEST_DIR}/request_meta" >&2"#,
+            ])?;
+
+            let mut req = dummy_web_request();
+            req.headers.insert("X-GitHub-Event".into(), "push".into());
+            req.headers.insert("Content-Type".into(), "text/plain".into());
+
+            let job = create_job(env, "info.sh", req.into())?
+                .with_received_at(1000);
+            let output = job.execute(&Context::default())?;
+            assert!(output.success);
+            assert_eq!(
+                output.stdout,
+                "Content-Type: text/plain\nX-GitHub-Event: push\n",
+            );
+
+            let meta: serde_json::Value =
+                serde_json::from_str(&output.stderr)?;
+            assert_eq!(meta["id"].as_str(), Some("dummy-request"));
+            assert_eq!(meta["job_id"].as_str(), Some(job.id()));
+            assert_eq!(meta["source_ip"].as_str(), Some("127.0.0.1"));
+            assert_eq!(meta["method"].as_str(), Some("POST"));
+            assert_eq!(meta["url"].as_str(), Some("/hook/example.sh"));
+            assert_eq!(meta["received_at"].as_u64(), Some(1000));
+            assert!(meta["started_at"].as_u64().unwrap() >= 1000);
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_job_status_output() {
+        test_wrapper(|env| {
+            env.create_script("status.sh", &[
+                "#!/bin/bash",
+                r#"## Fisher-Status: {"events": ["job_failed"]}"#,
+                "cd /",
+                r#"cat "${FISHER_STATUS_STDOUT}""#,
+                r#"cat "${FISHER_STATUS_STDERR}" >&2"#,
+            ])?;
+
+            let mut output = dummy_job_output();
+            output.success = false;
+            let event = StatusEvent::JobFailed(output);
+
+            // The output is readable from outside the working directory
+            let job = create_job(env, "status.sh", event.into())?;
+            let output = job.execute(&Context::default())?;
+            assert!(output.success);
+            assert_eq!(output.stdout, "hello world");
+            assert_eq!(output.stderr, "something happened");
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_job_dead_letters() {
+        test_wrapper(|env| {
+            env.create_script("flaky.sh", &[
+                "#!/bin/bash",
+                r#"## Fisher: {"max-retries": 1}"#,
+                "exit 1",
+            ])?;
+
+            let dead_letters = Arc::new(DeadLetters::new());
+            dead_letters.set_dir(Some(&env.tempdir()?))?;
+            let ctx = Context {
+                dead_letters: dead_letters.clone(),
+                .. Context::default()
+            };
+
+            let req: Request = dummy_web_request().into();
+            let job = create_job(env, "flaky.sh", req)?;
+
+            // Jobs which are going to be retried are not saved
+            let output = job.execute(&ctx)?;
+            assert!(!output.success);
+            assert!(output.dead_letter.is_none());
+
+            let (retry, _) = job.retry(&output).unwrap();
+            let output = retry.execute(&ctx)?;
+            assert!(output.dead_letter.unwrap().exists());
+
+            let letter = dead_letters.get(job.id())?.unwrap();
+            assert_eq!(letter.entry.hook, "flaky.sh");
+            assert_eq!(letter.attempts, 2);
+            assert_eq!(letter.exit_code, Some(1));
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_job_environment_with_tmpfs_body_storage() {
+        test_wrapper(|mut env| {
+            let tmpfs = env.tempdir()?;
+
+            // Create a context storing bodies in the fake tmpfs
+            let ctx = Context {
+                body_storage: BodyStorageConfig {
+                    backend: BodyStorageKind::Tmpfs,
+                    tmpfs_path: tmpfs.to_str().unwrap().into(),
+                    .. BodyStorageConfig::default()
+                },
+                .. Context::default()
+            };
+
+            // Get the execution environment
+            let out = collect_env(&mut env, &ctx)?;
+
+            // Ensure the body was stored in the tmpfs
+            assert_eq!(&content(&out, "request_body")?, "a body!");
+            let env_content = content(&out, "env")?;
+            let env_vars = parse_env(&env_content);
+            assert!(env_vars["FISHER_REQUEST_BODY"].starts_with(
+                tmpfs.to_str().unwrap()
+            ));
+
+            // Ensure the body was removed after the job
+            assert_eq!(fs::read_dir(&tmpfs)?.count(), 0);
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_job_environment_with_binary_body() {
+        test_wrapper(|env| {
+            env.create_script("binary.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Testing: {}"#,
+                r#"b="${FISHER_TESTING_ENV}""#,
+                r#"echo "${FISHER_REQUEST_BODY_ENCODING}" > "${b}/encoding""#,
+                r#"cat "${FISHER_REQUEST_BODY}" > "${b}/request_body""#,
+            ])?;
+            let out = env.tempdir()?;
+
+            // Create a request with a body that isn't valid UTF-8
+            let raw = vec![0x50, 0x4b, 0x03, 0x04, 0xff, 0xfe, 0x00];
+            let mut req = dummy_web_request();
+            req.body = String::from_utf8_lossy(&raw).into_owned();
+            req.binary_body = Some(raw.clone());
+            req.params.insert("env".into(), out.to_str().unwrap().into());
+
+            let job = create_job(env, "binary.sh", req.into())?;
+            job.process(&Context::default())?;
+
+            // The body must be stored verbatim
+            let mut stored = Vec::new();
+            File::open(out.join("request_body"))?.read_to_end(&mut stored)?;
+            assert_eq!(stored, raw);
+            assert_eq!(&content(&out, "encoding")?, "binary\n");
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_job_environment_with_form_body() {
+        test_wrapper(|env| {
+            env.create_script("form.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Testing: {}"#,
+                r#"b="${FISHER_TESTING_ENV}""#,
+                r#"echo "${FISHER_FORM_EVENT_TYPE}" > "${b}/form""#,
+            ])?;
+            let out = env.tempdir()?;
+
+            let mut req = dummy_web_request();
+            req.headers.insert(
+                "Content-Type".into(),
+                "application/x-www-form-urlencoded".into(),
+            );
+            req.body = "event-type=merge+request".into();
+            req.params.insert("env".into(), out.to_str().unwrap().into());
+
+            let job = create_job(env, "form.sh", req.into())?;
+            job.process(&Context::default())?;
+
+            assert_eq!(&content(&out, "form")?, "merge request\n");
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_job_environment_with_multipart_body() {
+        test_wrapper(|env| {
+            env.create_script("multipart.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Testing: {}"#,
+                r#"b="${FISHER_TESTING_ENV}""#,
+                r#"cat "${FISHER_REQUEST_MULTIPART}" > "${b}/index""#,
+                r#"cat multipart/part-1 > "${b}/part""#,
+            ])?;
+            let out = env.tempdir()?;
+
+            let mut req = dummy_web_request();
+            req.headers.insert(
+                "content-type".into(),
+                "multipart/form-data; boundary=xyz".into(),
+            );
+            req.body = concat!(
+                "--xyz\r\n",
+                "Content-Disposition: form-data; name=\"a\"\r\n\r\n",
+                "1\r\n",
+                "--xyz\r\n",
+                "Content-Disposition: form-data; name=\"b\"; ",
+                "filename=\"../b.txt\"\r\n\r\n",
+                "hello\r\n",
+                "--xyz--",
+            ).into();
+            req.params.insert("env".into(), out.to_str().unwrap().into());
+
+            let job = create_job(env, "multipart.sh", req.into())?;
+            job.process(&Context::default())?;
+
+            // The files are named by Fisher, not by the client
+            assert_eq!(&content(&out, "part")?, "hello");
+
+            let index: serde_json::Value =
+                serde_json::from_str(&content(&out, "index")?)?;
+            assert_eq!(index[0]["name"].as_str(), Some("a"));
+            assert!(index[0]["filename"].is_null());
+            assert_eq!(index[1]["name"].as_str(), Some("b"));
+            assert_eq!(index[1]["filename"].as_str(), Some("../b.txt"));
+            assert!(index[1]["path"].as_str().unwrap().ends_with("part-1"));
+
+            Ok(())
+        });
+    }
+
+
     #[test]
     fn test_job_environment_with_altered_user() {
         test_wrapper(|mut env| {
@@ -0,0 +1,313 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde_json;
+
+use common::prelude::*;
+use requests::Request;
+use scripts::jobs::Job;
+use web::WebRequest;
+
+
+/// A job accepted by the HTTP server, as it's stored in the journal.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub job_id: String,
+    pub hook: String,
+    /// The name of the provider which validated the request, if any.
+    pub provider: Option<String>,
+    pub priority: isize,
+    /// The UNIX timestamp of when the request was received, missing in the
+    /// entries written by older versions.
+    pub received_at: Option<u64>,
+    pub request: StoredRequest,
+}
+
+
+/// The request which started a job, with its body loaded in memory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredRequest {
+    pub id: String,
+    pub method: String,
+    /// Missing in the requests stored by older versions.
+    #[serde(default)]
+    pub url: String,
+    pub source: IpAddr,
+    pub headers: HashMap<String, String>,
+    pub params: HashMap<String, String>,
+    pub body: String,
+    pub binary_body: Option<Vec<u8>>,
+}
+
+impl StoredRequest {
+    pub fn new(req: &WebRequest) -> Result<Self> {
+        // Spooled bodies are removed when the job ends, so they need to be
+        // stored in the journal too
+        let loaded = req.load_spooled()?;
+        let req = loaded.as_ref().unwrap_or(req);
+
+        Ok(StoredRequest {
+            id: req.id.clone(),
+            method: req.method.clone(),
+            url: req.url.clone(),
+            source: req.source,
+            headers: req.headers.clone(),
+            params: req.params.clone(),
+            body: req.body.clone(),
+            binary_body: req.binary_body.clone(),
+        })
+    }
+
+    pub fn into_request(self) -> Request {
+        Request::Web(WebRequest {
+            id: self.id,
+            method: self.method,
+            url: self.url,
+            source: self.source,
+            headers: self.headers,
+            params: self.params,
+            body: self.body,
+            binary_body: self.binary_body,
+            spooled: None,
+        })
+    }
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Record {
+    Queued(JournalEntry),
+    Done { job_id: String },
+}
+
+
+/// Append-only file recording the jobs accepted by the HTTP server and the
+/// ones which ended, so the jobs still pending when Fisher stops can be
+/// queued again when it starts.
+#[derive(Debug)]
+pub struct JobJournal {
+    file: Mutex<Option<(PathBuf, File)>>,
+}
+
+impl JobJournal {
+    pub fn new() -> Self {
+        JobJournal {
+            file: Mutex::new(None),
+        }
+    }
+
+    /// Start recording the jobs in the file, or stop recording them if no
+    /// file is provided. The jobs still pending in the file are returned,
+    /// so they can be queued again.
+    pub fn open(&self, path: Option<&Path>) -> Result<Vec<JournalEntry>> {
+        let mut guard = self.file.lock()?;
+
+        let unchanged = match (guard.as_ref(), path) {
+            (Some(&(ref current, _)), Some(path)) => current == path,
+            (None, None) => true,
+            _ => false,
+        };
+        if unchanged {
+            return Ok(Vec::new());
+        }
+
+        *guard = None;
+        let path = if let Some(path) = path {
+            path
+        } else {
+            return Ok(Vec::new());
+        };
+
+        let pending = read_pending(path)?;
+
+        // Compact the journal, keeping only the pending jobs
+        let mut temp_path = OsString::from(path.as_os_str());
+        temp_path.push(".tmp");
+        {
+            let mut compacted = File::create(&temp_path)?;
+            for entry in &pending {
+                write_record(&mut compacted, &Record::Queued(entry.clone()))?;
+            }
+        }
+        fs::rename(&temp_path, path)?;
+
+        let handle = OpenOptions::new().append(true).open(path)?;
+        *guard = Some((path.to_path_buf(), handle));
+
+        Ok(pending)
+    }
+
+    /// Record the job was queued, if it was started by the HTTP server.
+    pub fn queued(&self, job: &Job, priority: isize) -> Result<()> {
+        let mut guard = self.file.lock()?;
+
+        if let Some((_, ref mut file)) = *guard {
+            if let Some(entry) = job.journal_entry(priority)? {
+                write_record(file, &Record::Queued(entry))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Record the job ended, so it's not queued again.
+    pub fn done(&self, job: &Job) -> Result<()> {
+        if job.request_id().is_some() {
+            self.forget(job.id())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Remove the job from the pending ones, even if it didn't run.
+    pub fn forget(&self, job_id: &str) -> Result<()> {
+        let mut guard = self.file.lock()?;
+
+        if let Some((_, ref mut file)) = *guard {
+            write_record(file, &Record::Done {
+                job_id: job_id.to_string(),
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+
+fn write_record(file: &mut File, record: &Record) -> Result<()> {
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+
+    // Each record is written at once, and it must reach the disk before
+    // the job goes on
+    file.write_all(line.as_bytes())?;
+    file.sync_data()?;
+
+    Ok(())
+}
+
+
+fn read_pending(path: &Path) -> Result<Vec<JournalEntry>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
+            return Ok(Vec::new());
+        }
+        Err(error) => return Err(error.into()),
+    };
+
+    let mut pending: Vec<JournalEntry> = Vec::new();
+    for line in BufReader::new(file).lines() {
+        // Records cut short by a crash are ignored
+        match serde_json::from_str(&line?) {
+            Ok(Record::Queued(entry)) => pending.push(entry),
+            Ok(Record::Done { job_id }) => {
+                pending.retain(|entry| entry.job_id != job_id);
+            }
+            Err(..) => {}
+        }
+    }
+
+    Ok(pending)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+
+    use requests::Request;
+    use scripts::Job;
+    use scripts::test_utils::*;
+
+    use super::JobJournal;
+
+
+    #[test]
+    fn test_journal() {
+        test_wrapper(|env| {
+            env.create_script("example.sh", &[])?;
+            let script = Arc::new(env.load_script("example.sh")?);
+            let path = env.tempdir()?.join("queue");
+
+            let mut req = dummy_web_request();
+            req.binary_body = Some(vec![0, 159, 146, 150]);
+            let job = |req| Job::new(script.clone(), None, Request::Web(req));
+            let job1 = job(dummy_web_request());
+            let job2 = job(req.clone());
+            let job3 = job(dummy_web_request());
+
+            // Nothing is recorded before the journal is opened
+            let journal = JobJournal::new();
+            journal.queued(&job1, 0)?;
+            assert!(journal.open(Some(&path))?.is_empty());
+
+            // Opening the same file again doesn't return anything
+            journal.queued(&job2, 5)?;
+            journal.queued(&job3, 0)?;
+            journal.done(&job3)?;
+            assert!(journal.open(Some(&path))?.is_empty());
+
+            // Records cut short by a crash are ignored
+            OpenOptions::new()
+                .append(true)
+                .open(&path)?
+                .write_all(b"{\"op\":\"que")?;
+
+            // The pending jobs are returned when the file is reopened
+            journal.open(None)?;
+            let pending = journal.open(Some(&path))?;
+            assert_eq!(pending.len(), 1);
+            assert_eq!(pending[0].job_id, job2.id());
+            assert_eq!(pending[0].hook, "example.sh");
+            assert_eq!(pending[0].provider, None);
+            assert_eq!(pending[0].priority, 5);
+
+            // The request is stored with its body
+            match pending[0].request.clone().into_request() {
+                Request::Web(restored) => {
+                    assert_eq!(restored.id, req.id);
+                    assert_eq!(restored.source, req.source);
+                    assert_eq!(restored.body_bytes(), req.body_bytes());
+                }
+                _ => panic!("wrong request kind"),
+            }
+
+            // The journal is compacted when it's opened
+            let mut content = String::new();
+            File::open(&path)?.read_to_string(&mut content)?;
+            assert_eq!(content.lines().count(), 1);
+
+            // Jobs which ended are not returned anymore
+            journal.done(&job2)?;
+            journal.open(None)?;
+            assert!(journal.open(Some(&path))?.is_empty());
+
+            fs::remove_file(&path)?;
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,226 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Per-job log files.
+//!
+//! The output of every execution of a job is saved in the directory of the
+//! job, named after its ID, as `<attempt>.stdout` and `<attempt>.stderr`.
+//! Failed executions can also keep their working directory there, as
+//! `<attempt>.workdir`, and the artifacts of the hooks are collected in
+//! `<attempt>.artifacts`. The directories of the jobs older than the retention
+//! are removed when new logs are saved.
+
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use common::prelude::*;
+
+
+/// Where the output of the jobs is saved.
+#[derive(Debug, Clone)]
+pub struct JobLogs {
+    path: PathBuf,
+    /// How long the logs are kept, or `None` to keep them forever.
+    retention: Option<Duration>,
+}
+
+impl JobLogs {
+    pub fn new<P: Into<PathBuf>>(path: P, retention: u64) -> Self {
+        JobLogs {
+            path: path.into(),
+            // A zero retention keeps the logs forever
+            retention: if retention > 0 {
+                Some(Duration::from_secs(retention))
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Save the output of an execution of the job, returning the paths of
+    /// the files containing its stdout and its stderr.
+    pub fn save(
+        &self,
+        job_id: &str,
+        attempt: u32,
+        stdout: &str,
+        stderr: &str,
+    ) -> Result<(PathBuf, PathBuf)> {
+        self.cleanup()?;
+
+        let dir = self.path.join(job_id);
+        fs::create_dir_all(&dir)?;
+
+        let stdout_path = dir.join(format!("{}.stdout", attempt));
+        write_log(&stdout_path, stdout)?;
+        let stderr_path = dir.join(format!("{}.stderr", attempt));
+        write_log(&stderr_path, stderr)?;
+
+        Ok((stdout_path, stderr_path))
+    }
+
+    /// Move the working directory of an execution of the job next to its
+    /// logs, returning its new path.
+    pub fn keep_workdir(
+        &self, job_id: &str, attempt: u32, workdir: &Path,
+    ) -> Result<PathBuf> {
+        let dir = self.path.join(job_id);
+        fs::create_dir_all(&dir)?;
+
+        // Renaming fails if the logs are on a different filesystem
+        let path = dir.join(format!("{}.workdir", attempt));
+        fs::rename(workdir, &path)?;
+
+        Ok(path)
+    }
+
+    /// Return the directory the artifacts of an execution of the job are
+    /// collected in.
+    pub fn artifacts_dir(&self, job_id: &str, attempt: u32) -> PathBuf {
+        self.path.join(job_id).join(format!("{}.artifacts", attempt))
+    }
+
+    /// Remove the logs of the jobs older than the retention.
+    fn cleanup(&self) -> Result<()> {
+        let retention = if let Some(retention) = self.retention {
+            retention
+        } else {
+            return Ok(());
+        };
+
+        let entries = match fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
+                return Ok(());
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        let now = SystemTime::now();
+        for entry in entries {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_dir() {
+                continue;
+            }
+
+            // The directory is modified every time the job is retried
+            let age = now.duration_since(metadata.modified()?)
+                .unwrap_or_else(|_| Duration::from_secs(0));
+            if age > retention {
+                fs::remove_dir_all(entry.path())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+fn write_log(path: &Path, content: &str) -> Result<()> {
+    // The output of the scripts might contain secrets
+    fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(content.as_bytes())?;
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use std::thread;
+    use std::time::Duration;
+
+    use common::prelude::*;
+    use utils;
+
+    use super::JobLogs;
+
+
+    fn content(path: &Path) -> Result<String> {
+        let mut buf = String::new();
+        File::open(path)?.read_to_string(&mut buf)?;
+        Ok(buf)
+    }
+
+
+    #[test]
+    fn test_save() {
+        let dir = utils::create_temp_dir().unwrap();
+        let logs = JobLogs::new(dir.join("logs"), 0);
+
+        let (stdout, stderr) = logs.save("job", 1, "out", "err").unwrap();
+        assert_eq!(stdout, dir.join("logs").join("job").join("1.stdout"));
+        assert_eq!(stderr, dir.join("logs").join("job").join("1.stderr"));
+        assert_eq!(content(&stdout).unwrap(), "out");
+        assert_eq!(content(&stderr).unwrap(), "err");
+
+        let mode = fs::metadata(&stdout).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // Every execution of the job has its own files
+        let (stdout, _) = logs.save("job", 2, "retried", "").unwrap();
+        assert_eq!(content(&stdout).unwrap(), "retried");
+        assert!(dir.join("logs").join("job").join("1.stdout").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+
+    #[test]
+    fn test_keep_workdir() {
+        let dir = utils::create_temp_dir().unwrap();
+        let logs = JobLogs::new(dir.join("logs"), 0);
+
+        let workdir = utils::create_temp_dir().unwrap();
+        File::create(workdir.join("request_body")).unwrap();
+
+        let kept = logs.keep_workdir("job", 1, &workdir).unwrap();
+        assert_eq!(kept, dir.join("logs").join("job").join("1.workdir"));
+        assert!(kept.join("request_body").exists());
+        assert!(!workdir.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+
+    #[test]
+    fn test_retention() {
+        let dir = utils::create_temp_dir().unwrap();
+        let logs = JobLogs::new(&dir, 1);
+
+        logs.save("old", 1, "", "").unwrap();
+        thread::sleep(Duration::from_millis(2100));
+
+        // Logs older than the retention are removed when new ones are saved
+        logs.save("new", 1, "", "").unwrap();
+        assert!(!dir.join("old").exists());
+        assert!(dir.join("new").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
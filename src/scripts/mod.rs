@@ -14,13 +14,48 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 #[cfg(test)]
-mod test_utils;
+pub mod test_utils;
+mod artifacts;
+mod cgroup;
+mod coalesce;
 mod collector;
+mod dead_letters;
+mod dispatch;
+mod executor;
+mod heartbeat;
+mod interpreter;
+mod history;
+mod ip_filter;
 mod jobs;
+mod journal;
+mod logs;
+mod orphans;
+mod rate_limit;
 mod repository;
+mod runtime;
+mod sandbox;
+mod schedule;
+mod scheduling;
 mod script;
+mod secrets;
+mod storage;
+mod template;
+mod uploader;
 
 pub use self::repository::{Blueprint, Repository};
 pub use self::repository::{ScriptsIter, StatusJobsIter};
 pub use self::script::{Script, ScriptProvider};
-pub use self::jobs::{Job, JobOutput, Context as JobContext};
+pub use self::jobs::{Job, JobOutput, Context as JobContext, ExitKind};
+pub use self::jobs::{StopSignal, Termination};
+pub use self::cgroup::ResourceUsage;
+pub use self::dead_letters::{DeadLetter, DeadLetters};
+pub use self::dispatch::{DispatchedJob, Dispatcher, RemoteOutput};
+pub use self::heartbeat::HeartbeatMonitor;
+pub use self::interpreter::Interpreters;
+pub use self::history::{JobOutcome, JobResult};
+pub use self::journal::{JobJournal, JournalEntry, StoredRequest};
+pub use self::logs::JobLogs;
+pub use self::orphans::{sweep as sweep_orphaned_workdirs, ORPHANED_MIN_AGE};
+pub use self::rate_limit::RateLimitResult;
+pub use self::schedule::{ScheduleMonitor, ScheduledRun};
+pub use self::uploader::ArtifactUploader;
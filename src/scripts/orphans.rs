@@ -0,0 +1,225 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Working directories and request bodies left behind by the jobs.
+//!
+//! Every temporary directory created for a job contains a marker file with
+//! the PID of the Fisher process which created it. When Fisher starts, the
+//! marked directories older than a threshold are removed, unless the process
+//! which created them is still running (for example another instance sharing
+//! the same temporary directory). Bodies uploaded to an object store are
+//! recorded in the working directory of their job, and removed with it.
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use libc;
+use url::Url;
+
+use common::prelude::*;
+use common::errors::print_warning;
+use utils;
+
+
+/// The name of the marker file.
+const MARKER: &'static str = ".fisher-workdir";
+
+/// The name of the file recording where the body of the request was
+/// uploaded.
+const REMOTE_BODY: &'static str = ".fisher-remote-body";
+
+/// How old a directory must be to be considered orphaned.
+pub const ORPHANED_MIN_AGE: u64 = 3600;
+
+
+/// Mark the directory as created for a job by this process.
+pub fn mark(dir: &Path) -> Result<()> {
+    let pid = unsafe { libc::getpid() };
+    File::create(dir.join(MARKER))?.write_all(pid.to_string().as_bytes())?;
+    Ok(())
+}
+
+
+/// Record in the directory of the job the URL its request body is uploaded
+/// to, so it's removed along with the directory if the job is orphaned.
+pub fn mark_remote(dir: &Path, url: &Url) -> Result<()> {
+    File::create(dir.join(REMOTE_BODY))?.write_all(url.as_str().as_bytes())?;
+    Ok(())
+}
+
+
+/// Remove the orphaned directories in the base directory, returning their
+/// paths.
+pub fn sweep(base: &Path, min_age: Duration) -> Result<Vec<PathBuf>> {
+    let own_pid = unsafe { libc::getpid() };
+
+    let mut removed = Vec::new();
+    for entry in fs::read_dir(base)? {
+        let path = entry?.path();
+        let marker = path.join(MARKER);
+
+        // Directories without the marker weren't created for a job
+        let mut content = String::new();
+        match File::open(&marker) {
+            Ok(mut file) => {
+                file.read_to_string(&mut content)?;
+            }
+            Err(..) => continue,
+        }
+
+        // This process didn't start any job yet, so if the PID is its own
+        // the directory was created before a reboot
+        if let Ok(pid) = content.trim().parse::<libc::pid_t>() {
+            if pid != own_pid && is_running(pid) {
+                continue;
+            }
+        }
+
+        let age = fs::metadata(&marker)?.modified()?.elapsed();
+        if age.map(|age| age < min_age).unwrap_or(true) {
+            continue;
+        }
+
+        // A failed removal is not retried, and the object store might be
+        // configured to expire objects anyway
+        if let Some(url) = remote_body(&path) {
+            let _ = utils::http_request("DELETE", &url, &[], b"");
+        }
+
+        if let Err(error) = fs::remove_dir_all(&path) {
+            let mut error = Error::from(error);
+            error.set_location(
+                ErrorLocation::File(path.to_string_lossy().into_owned(), None),
+            );
+            error.pretty_print();
+            continue;
+        }
+
+        print_warning(&format!(
+            "removed the orphaned working directory {}",
+            path.to_string_lossy(),
+        ));
+        removed.push(path);
+    }
+
+    Ok(removed)
+}
+
+
+fn remote_body(dir: &Path) -> Option<Url> {
+    let mut content = String::new();
+    File::open(dir.join(REMOTE_BODY))
+        .and_then(|mut file| file.read_to_string(&mut content))
+        .ok()
+        .and_then(|_| Url::parse(content.trim()).ok())
+}
+
+
+fn is_running(pid: libc::pid_t) -> bool {
+    // The signal 0 only checks if the process exists
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || ::std::io::Error::last_os_error().raw_os_error()
+        == Some(libc::EPERM)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    use url::Url;
+
+    use utils;
+
+    use super::{mark, mark_remote, sweep, MARKER};
+
+
+    #[test]
+    fn test_sweep() {
+        let base = utils::create_temp_dir().unwrap();
+
+        // Directories of this process and of dead processes are removed
+        let own = utils::create_temp_dir_in(&base).unwrap();
+        mark(&own).unwrap();
+        let dead = utils::create_temp_dir_in(&base).unwrap();
+        File::create(dead.join(MARKER)).unwrap()
+            .write_all(b"999999999").unwrap();
+
+        // Directories of running processes and unmarked ones are kept
+        let running = utils::create_temp_dir_in(&base).unwrap();
+        File::create(running.join(MARKER)).unwrap().write_all(b"1").unwrap();
+        let unmarked = utils::create_temp_dir_in(&base).unwrap();
+
+        // Nothing is old enough yet
+        let min_age = Duration::from_secs(3600);
+        assert!(sweep(&base, min_age).unwrap().is_empty());
+
+        let mut removed = sweep(&base, Duration::from_secs(0)).unwrap();
+        removed.sort();
+        let mut expected = vec![own.clone(), dead.clone()];
+        expected.sort();
+        assert_eq!(removed, expected);
+        assert!(!own.exists());
+        assert!(!dead.exists());
+        assert!(running.exists());
+        assert!(unmarked.exists());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+
+    #[test]
+    fn test_sweep_remote_body() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut received = Vec::new();
+            let mut buffer = [0; 1024];
+            while !received.ends_with(b"\r\n\r\n") {
+                let len = conn.read(&mut buffer).unwrap();
+                if len == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buffer[..len]);
+            }
+            conn.write_all(b"HTTP/1.0 200 OK\r\n\r\n").unwrap();
+            String::from_utf8_lossy(&received).into_owned()
+        });
+
+        let base = utils::create_temp_dir().unwrap();
+        let dir = utils::create_temp_dir_in(&base).unwrap();
+        mark(&dir).unwrap();
+        let url = Url::parse(&format!("http://{}/bodies/fisher-a", addr))
+            .unwrap();
+        mark_remote(&dir, &url).unwrap();
+
+        // The uploaded body is removed along with the directory
+        let removed = sweep(&base, Duration::from_secs(0)).unwrap();
+        assert_eq!(removed, vec![dir.clone()]);
+        assert!(!dir.exists());
+        assert!(server.join().unwrap().starts_with(
+            "DELETE /bodies/fisher-a HTTP/1.0\r\n",
+        ));
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
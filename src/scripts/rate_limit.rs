@@ -0,0 +1,220 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Per-hook rate limits.
+//!
+//! The limiter remembers when the last `max` executions of the hook are
+//! scheduled to start: a new execution is allowed only if the oldest of them
+//! is older than the interval. When executions over the limit are delayed
+//! instead of rejected, the limiter reserves the first free slot in the
+//! future, and the processor waits until then before running the job.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde_json;
+
+use common::prelude::*;
+use utils;
+
+
+/// What to do with the requests over the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum RateLimitPolicy {
+    /// Reject the requests with the `429 Too Many Requests` status code.
+    #[serde(rename = "reject")]
+    Reject,
+    /// Accept the requests, but run the jobs only when they're allowed.
+    #[serde(rename = "delay")]
+    Delay,
+}
+
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RateLimitConfig {
+    max: usize,
+    per: String,
+    policy: Option<RateLimitPolicy>,
+}
+
+
+/// The outcome of a request to the rate limiter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitResult {
+    /// The job can run immediately.
+    Allowed,
+    /// The job can run only after this delay.
+    Delayed(Duration),
+    /// The request must be rejected, and it can be retried after this time.
+    Rejected(Duration),
+}
+
+
+#[derive(Debug)]
+pub struct HookRateLimit {
+    max: usize,
+    interval: Duration,
+    policy: RateLimitPolicy,
+    slots: Mutex<VecDeque<Instant>>,
+}
+
+impl HookRateLimit {
+    /// Parse the content of the `Fisher-RateLimit` configuration comment.
+    pub fn new(config: &str) -> Result<Self> {
+        let config: RateLimitConfig = serde_json::from_str(config)?;
+
+        if config.max == 0 {
+            return Err(ErrorKind::InvalidInput(
+                "the rate limit must allow at least one request".into(),
+            ).into());
+        }
+
+        let interval = match config.per.as_str() {
+            "second" => 1,
+            "minute" => 60,
+            "hour" => 60 * 60,
+            "day" => 60 * 60 * 24,
+            other => utils::parse_time(other)? as u64,
+        };
+        if interval == 0 {
+            return Err(
+                ErrorKind::InvalidTimeString(config.per.clone()).into(),
+            );
+        }
+
+        Ok(HookRateLimit {
+            max: config.max,
+            interval: Duration::from_secs(interval),
+            policy: config.policy.unwrap_or(RateLimitPolicy::Reject),
+            slots: Mutex::new(VecDeque::with_capacity(config.max)),
+        })
+    }
+
+    /// Reserve a slot for a new execution of the hook.
+    pub fn reserve(&self) -> RateLimitResult {
+        self.reserve_at(Instant::now())
+    }
+
+    fn reserve_at(&self, now: Instant) -> RateLimitResult {
+        let mut slots = self.slots.lock().unwrap();
+
+        // The first moment a new execution is allowed
+        let allowed_at = if slots.len() < self.max {
+            now
+        } else {
+            let allowed_at = slots[0] + self.interval;
+            if allowed_at > now {
+                allowed_at
+            } else {
+                now
+            }
+        };
+
+        if allowed_at > now && self.policy == RateLimitPolicy::Reject {
+            return RateLimitResult::Rejected(allowed_at - now);
+        }
+
+        slots.push_back(allowed_at);
+        if slots.len() > self.max {
+            slots.pop_front();
+        }
+
+        if allowed_at > now {
+            RateLimitResult::Delayed(allowed_at - now)
+        } else {
+            RateLimitResult::Allowed
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{HookRateLimit, RateLimitPolicy, RateLimitResult};
+
+
+    #[test]
+    fn test_parse() {
+        let limit = HookRateLimit::new(r#"{"max": 5, "per": "minute"}"#)
+            .unwrap();
+        assert_eq!(limit.max, 5);
+        assert_eq!(limit.interval, Duration::from_secs(60));
+        assert_eq!(limit.policy, RateLimitPolicy::Reject);
+
+        let limit = HookRateLimit::new(
+            r#"{"max": 1, "per": "10m", "policy": "delay"}"#,
+        ).unwrap();
+        assert_eq!(limit.interval, Duration::from_secs(600));
+        assert_eq!(limit.policy, RateLimitPolicy::Delay);
+
+        for invalid in &[
+            r#"{"max": 0, "per": "minute"}"#,
+            r#"{"max": 1, "per": "0"}"#,
+            r#"{"max": 1, "per": "fortnight"}"#,
+            r#"{"max": 1, "per": "minute", "policy": "ignore"}"#,
+            r#"{"max": 1, "per": "minute", "unknown": true}"#,
+            r#"{"max": 1}"#,
+        ] {
+            assert!(HookRateLimit::new(invalid).is_err());
+        }
+    }
+
+
+    #[test]
+    fn test_reject() {
+        let limit = HookRateLimit::new(r#"{"max": 2, "per": "minute"}"#)
+            .unwrap();
+        let now = Instant::now();
+
+        assert_eq!(limit.reserve_at(now), RateLimitResult::Allowed);
+        assert_eq!(limit.reserve_at(now), RateLimitResult::Allowed);
+
+        // Rejected requests don't use any slot
+        let later = now + Duration::from_secs(20);
+        for _ in 0..2 {
+            assert_eq!(
+                limit.reserve_at(later),
+                RateLimitResult::Rejected(Duration::from_secs(40))
+            );
+        }
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(limit.reserve_at(later), RateLimitResult::Allowed);
+    }
+
+
+    #[test]
+    fn test_delay() {
+        let limit = HookRateLimit::new(
+            r#"{"max": 1, "per": "minute", "policy": "delay"}"#,
+        ).unwrap();
+        let now = Instant::now();
+
+        // Every request over the limit is delayed after the previous one
+        assert_eq!(limit.reserve_at(now), RateLimitResult::Allowed);
+        assert_eq!(
+            limit.reserve_at(now),
+            RateLimitResult::Delayed(Duration::from_secs(60))
+        );
+        assert_eq!(
+            limit.reserve_at(now),
+            RateLimitResult::Delayed(Duration::from_secs(120))
+        );
+    }
+}
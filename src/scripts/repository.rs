@@ -18,11 +18,19 @@ use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 
 use common::prelude::*;
+use common::structs::HooksHealth;
 use common::state::{State, UniqueId};
-use providers::{Provider, StatusEvent, StatusEventKind};
+use events::JobEvents;
+use providers::{HeartbeatMissed, Provider, StatusEvent, StatusEventKind};
 use requests::Request;
 use scripts::collector::Collector;
-use scripts::jobs::{Job, JobOutput};
+use scripts::dead_letters::DeadLetters;
+use scripts::heartbeat::Heartbeats;
+use scripts::interpreter::Interpreters;
+use scripts::history::{self, JobHistory, JobOutcome, JobResult};
+use scripts::jobs::{ExitKind, Job, JobOutput};
+use scripts::journal::{JobJournal, JournalEntry};
+use scripts::schedule::{ScheduledRun, Schedules};
 use scripts::script::{Script, ScriptProvider};
 
 
@@ -55,15 +63,19 @@ impl Iterator for ScriptsIter {
 
 pub struct StatusJobsIter {
     inner: Arc<RwLock<RepositoryInner>>,
-    event: StatusEvent,
+    /// The events still to be handled, starting from the current one.
+    events: Vec<StatusEvent>,
     count: usize,
 }
 
 impl StatusJobsIter {
-    fn new(inner: Arc<RwLock<RepositoryInner>>, event: StatusEvent) -> Self {
+    fn new(
+        inner: Arc<RwLock<RepositoryInner>>,
+        events: Vec<StatusEvent>,
+    ) -> Self {
         StatusJobsIter {
             inner,
-            event,
+            events,
             count: 0,
         }
     }
@@ -73,26 +85,32 @@ impl Iterator for StatusJobsIter {
     type Item = Job;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.count += 1;
-
         let inner = match self.inner.read() {
             Ok(guard) => guard,
             Err(poisoned) => poisoned.into_inner(),
         };
 
-        if let Some(all) = inner.status_hooks.get(&self.event.kind()) {
-            if let Some(hp) = all.get(self.count - 1).cloned() {
-                Some(Job::new(
+        while let Some(event) = self.events.first().cloned() {
+            self.count += 1;
+
+            let hp = inner
+                .status_hooks
+                .get(&event.kind())
+                .and_then(|all| all.get(self.count - 1).cloned());
+            if let Some(hp) = hp {
+                return Some(Job::new(
                     hp.script,
                     Some(hp.provider),
-                    Request::Status(self.event.clone()),
-                ))
-            } else {
-                None
+                    Request::Status(event),
+                ));
             }
-        } else {
-            None
+
+            // Every hook got the event, move on to the next one
+            self.events.remove(0);
+            self.count = 0;
         }
+
+        None
     }
 }
 
@@ -102,7 +120,9 @@ struct RepositoryInner {
     scripts: Vec<Arc<Script>>,
     by_id: HashMap<UniqueId, Arc<Script>>,
     by_name: HashMap<String, Arc<Script>>,
+    by_route: HashMap<String, Arc<Script>>,
     status_hooks: HashMap<StatusEventKind, Vec<ScriptProvider>>,
+    loaded_at: u64,
 }
 
 impl RepositoryInner {
@@ -111,11 +131,27 @@ impl RepositoryInner {
             scripts: Vec::new(),
             by_id: HashMap::new(),
             by_name: HashMap::new(),
+            by_route: HashMap::new(),
             status_hooks: HashMap::new(),
+            loaded_at: history::now(),
         }
     }
 
-    pub fn insert(&mut self, script: Arc<Script>) {
+    pub fn insert(&mut self, script: Arc<Script>) -> Result<()> {
+        // Routes must point to a single script
+        for route in script.routes() {
+            if let Some(existing) = self.by_route.get(route) {
+                return Err(ErrorKind::DuplicateRoute(
+                    route.clone(),
+                    existing.name().to_string(),
+                    script.name().to_string(),
+                ).into());
+            }
+        }
+        for route in script.routes() {
+            self.by_route.insert(route.clone(), script.clone());
+        }
+
         self.scripts.push(script.clone());
         self.by_id.insert(script.id(), script.clone());
         self.by_name
@@ -135,17 +171,29 @@ impl RepositoryInner {
                 }
             }
         }
+
+        Ok(())
     }
 
     pub fn get_by_name(&self, name: &str) -> Option<Arc<Script>> {
         self.by_name.get(name).cloned()
     }
+
+    pub fn get_by_route(&self, route: &str) -> Option<Arc<Script>> {
+        self.by_route.get(route.trim_matches('/')).cloned()
+    }
 }
 
 
 #[derive(Debug)]
 pub struct Repository {
     inner: Arc<RwLock<RepositoryInner>>,
+    heartbeats: Arc<Heartbeats>,
+    history: Arc<JobHistory>,
+    events: Arc<JobEvents>,
+    journal: Arc<JobJournal>,
+    dead_letters: Arc<DeadLetters>,
+    schedules: Arc<Schedules>,
 }
 
 impl Repository {
@@ -155,6 +203,113 @@ impl Repository {
             Err(poisoned) => poisoned.get_ref().get_by_name(name),
         }
     }
+
+    /// Return the script reachable at the custom route, if any.
+    pub fn get_by_route(&self, route: &str) -> Option<Arc<Script>> {
+        match self.inner.read() {
+            Ok(inner) => inner.get_by_route(route),
+            Err(poisoned) => poisoned.get_ref().get_by_route(route),
+        }
+    }
+
+    /// Record a delivery to a hook, resetting its heartbeat.
+    pub fn record_delivery(&self, name: &str) {
+        self.heartbeats.record(name);
+    }
+
+    /// Return all the heartbeats missed since the last check.
+    pub fn missed_heartbeats(&self) -> Vec<HeartbeatMissed> {
+        self.heartbeats.check(self.iter())
+    }
+
+    /// Return the jobs of the scheduled hooks which are due, with their
+    /// priority.
+    pub fn scheduled_jobs(&self) -> Vec<(Job, isize)> {
+        self.schedules
+            .due(self.iter())
+            .into_iter()
+            .map(|(script, time)| {
+                let priority = script.priority();
+                let request = Request::Scheduled(ScheduledRun { time });
+                (Job::new(script, None, request), priority)
+            })
+            .collect()
+    }
+
+    /// Return the results of the most recent jobs matching the filters,
+    /// newest first.
+    pub fn recent_jobs(
+        &self,
+        hook: Option<&str>,
+        outcome: Option<JobOutcome>,
+        limit: Option<usize>,
+    ) -> Vec<JobResult> {
+        self.history.query(hook, outcome, limit)
+    }
+
+    /// Return the events about the lifecycle of the jobs.
+    pub fn events(&self) -> Arc<JobEvents> {
+        self.events.clone()
+    }
+
+    /// Return the journal of the jobs accepted by the HTTP server.
+    pub fn journal(&self) -> Arc<JobJournal> {
+        self.journal.clone()
+    }
+
+    /// Return where the jobs which failed after all their retries are saved.
+    pub fn dead_letters(&self) -> Arc<DeadLetters> {
+        self.dead_letters.clone()
+    }
+
+    /// Recreate a job from its entry in the journal, with its original
+    /// priority. Nothing is returned if its hook doesn't exist anymore.
+    pub fn restore_job(&self, entry: JournalEntry) -> Option<(Job, isize)> {
+        let script = if let Some(script) = self.get_by_name(&entry.hook) {
+            script
+        } else {
+            return None;
+        };
+
+        let provider = entry.provider.as_ref().and_then(|name| {
+            script
+                .providers()
+                .iter()
+                .find(|provider| provider.name() == name)
+                .cloned()
+        });
+
+        let mut job = Job::new(script, provider, entry.request.into_request())
+            .with_id(entry.job_id);
+        if let Some(received_at) = entry.received_at {
+            job = job.with_received_at(received_at);
+        }
+        Some((job, entry.priority))
+    }
+
+    /// Get some insights about the health of the loaded hooks.
+    pub fn health(&self) -> HooksHealth {
+        let (hooks_loaded, last_reload) = match self.inner.read() {
+            Ok(inner) => (inner.scripts.len(), inner.loaded_at),
+            Err(poisoned) => {
+                let inner = poisoned.get_ref();
+                (inner.scripts.len(), inner.loaded_at)
+            }
+        };
+
+        HooksHealth {
+            hooks_loaded,
+            last_reload,
+            last_failure: self.history.last_failure(),
+        }
+    }
+
+    /// Return the status jobs to run for a missed heartbeat.
+    pub fn heartbeat_jobs(&self, missed: HeartbeatMissed) -> StatusJobsIter {
+        StatusJobsIter::new(
+            self.inner.clone(), vec![StatusEvent::HeartbeatMissed(missed)],
+        )
+    }
 }
 
 impl ScriptsRepositoryTrait for Repository {
@@ -175,17 +330,27 @@ impl ScriptsRepositoryTrait for Repository {
     }
 
     fn jobs_after_output(&self, output: JobOutput) -> Option<StatusJobsIter> {
+        self.history.record(&output);
+
         if !output.trigger_status_hooks {
             return None;
         }
 
-        let event = if output.success {
-            StatusEvent::JobCompleted(output)
+        let events = if output.success {
+            vec![StatusEvent::JobCompleted(output)]
+        } else if output.exit_kind() == ExitKind::Rejected {
+            vec![StatusEvent::JobRejected(output)]
+        } else if output.dead_letter.is_some() {
+            // The failure is still reported to the usual hooks
+            vec![
+                StatusEvent::JobFailed(output.clone()),
+                StatusEvent::JobDeadLettered(output),
+            ]
         } else {
-            StatusEvent::JobFailed(output)
+            vec![StatusEvent::JobFailed(output)]
         };
 
-        Some(StatusJobsIter::new(self.inner.clone(), event))
+        Some(StatusJobsIter::new(self.inner.clone(), events))
     }
 }
 
@@ -194,8 +359,16 @@ impl ScriptsRepositoryTrait for Repository {
 pub struct Blueprint {
     added: Vec<Arc<Script>>,
     collect_paths: Vec<(PathBuf, bool)>,
+    interpreters: Arc<Interpreters>,
+    artifacts_collected: bool,
 
     inner: Arc<RwLock<RepositoryInner>>,
+    heartbeats: Arc<Heartbeats>,
+    history: Arc<JobHistory>,
+    events: Arc<JobEvents>,
+    journal: Arc<JobJournal>,
+    dead_letters: Arc<DeadLetters>,
+    schedules: Arc<Schedules>,
     state: Arc<State>,
 }
 
@@ -204,8 +377,16 @@ impl Blueprint {
         Blueprint {
             added: Vec::new(),
             collect_paths: Vec::new(),
+            interpreters: Arc::new(Interpreters::default()),
+            artifacts_collected: true,
 
             inner: Arc::new(RwLock::new(RepositoryInner::new())),
+            heartbeats: Arc::new(Heartbeats::new()),
+            history: Arc::new(JobHistory::new()),
+            events: Arc::new(JobEvents::new()),
+            journal: Arc::new(JobJournal::new()),
+            dead_letters: Arc::new(DeadLetters::new()),
+            schedules: Arc::new(Schedules::new()),
             state: state,
         }
     }
@@ -223,6 +404,19 @@ impl Blueprint {
         Ok(())
     }
 
+    /// Set how the scripts without the executable bit are collected. This
+    /// applies from the next reload.
+    pub fn set_interpreters(&mut self, interpreters: Interpreters) {
+        self.interpreters = Arc::new(interpreters);
+    }
+
+    /// Set whether the artifacts of the jobs are collected, refusing the
+    /// scripts which declare them otherwise. This applies from the next
+    /// reload.
+    pub fn set_artifacts_collected(&mut self, collected: bool) {
+        self.artifacts_collected = collected;
+    }
+
     pub fn collect_path<P: AsRef<Path>>(
         &mut self,
         path: P,
@@ -240,15 +434,27 @@ impl Blueprint {
 
         // Add manually added scripts
         for script in &self.added {
-            inner.insert(script.clone());
+            inner.insert(script.clone())?;
         }
 
         // Collect scripts from paths
         let mut collector;
         for &(ref p, recursive) in &self.collect_paths {
-            collector = Collector::new(p, self.state.clone(), recursive)?;
+            collector = Collector::new(
+                p, self.state.clone(), recursive, self.interpreters.clone(),
+            )?;
             for script in collector {
-                inner.insert(script?);
+                let script = script?;
+                if !self.artifacts_collected && !script.artifacts().is_empty()
+                {
+                    return Err(ErrorKind::InvalidInput(format!(
+                        "the hook {} declares artifacts, but neither \
+                         jobs.logs.path nor jobs.artifacts-upload.endpoint \
+                         is set",
+                        script.name(),
+                    )).into());
+                }
+                inner.insert(script)?;
             }
         }
 
@@ -263,6 +469,12 @@ impl Blueprint {
     pub fn repository(&self) -> Repository {
         Repository {
             inner: self.inner.clone(),
+            heartbeats: self.heartbeats.clone(),
+            history: self.history.clone(),
+            events: self.events.clone(),
+            journal: self.journal.clone(),
+            dead_letters: self.dead_letters.clone(),
+            schedules: self.schedules.clone(),
         }
     }
 }
@@ -275,7 +487,9 @@ mod tests {
 
     use common::prelude::*;
     use providers::StatusEventKind;
+    use scripts::Job;
     use scripts::test_utils::*;
+    use utils::testing::dummy_job_output;
 
     use super::{Blueprint, Repository};
 
@@ -316,6 +530,7 @@ mod tests {
             for script in &["first.sh", "second.sh", "third.sh"] {
                 assert!(repository.get_by_name(script).is_some());
             }
+            assert_eq!(repository.health().hooks_loaded, 3);
 
             Ok(())
         });
@@ -411,6 +626,75 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_scripts_can_be_found_by_route() {
+        test_wrapper(|env| {
+            env.create_script("a.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Route: /deploy/a"#,
+                r#"## Fisher-Route: /a"#,
+            ])?;
+            env.create_script("b.sh", &[r#"#!/bin/bash"#])?;
+
+            let mut blueprint = Blueprint::new(env.state());
+            blueprint.collect_path(env.scripts_dir(), false)?;
+            let repository = blueprint.repository();
+
+            for route in &["deploy/a", "/deploy/a/", "a"] {
+                let script = repository.get_by_route(route).expect(route);
+                assert_eq!(script.name(), "a.sh");
+            }
+            assert!(repository.get_by_route("b.sh").is_none());
+
+            // Two scripts can't share the same route
+            env.create_script("c.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Route: /a"#,
+            ])?;
+            assert_err!(blueprint.reload(), ErrorKind::DuplicateRoute(..));
+            assert!(repository.get_by_route("a").is_some());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_jobs_can_be_restored() {
+        test_wrapper(|env| {
+            env.create_script("a.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Testing: {}"#,
+            ])?;
+
+            let mut blueprint = Blueprint::new(env.state());
+            blueprint.collect_path(env.scripts_dir(), false)?;
+            let repository = blueprint.repository();
+
+            let script = repository.get_by_name("a.sh").unwrap();
+            let provider = script.providers()[0].clone();
+            let job = Job::new(
+                script, Some(provider), dummy_web_request().into(),
+            ).with_received_at(1000);
+            let entry = job.journal_entry(10)?.unwrap();
+            assert_eq!(entry.received_at, Some(1000));
+
+            // The job keeps its identifier, provider, priority and the time
+            // its request was received
+            let (restored, priority) =
+                repository.restore_job(entry.clone()).unwrap();
+            assert_eq!(restored.id(), job.id());
+            assert_eq!(restored.journal_entry(10)?, Some(entry.clone()));
+            assert_eq!(priority, 10);
+
+            // Jobs of hooks which don't exist anymore can't be restored
+            fs::remove_file(env.scripts_dir().join("a.sh"))?;
+            blueprint.reload()?;
+            assert!(repository.restore_job(entry).is_none());
+
+            Ok(())
+        });
+    }
+
     #[test]
     fn test_no_changes_applied_if_blueprint_reload_fails() {
         test_wrapper(|env| {
@@ -459,6 +743,27 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_artifacts_refused_if_not_collected() {
+        test_wrapper(|env| {
+            env.create_script("build.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher: {"artifacts": ["dist/*"]}"#,
+            ])?;
+
+            let mut blueprint = Blueprint::new(env.state());
+            blueprint.set_artifacts_collected(false);
+            assert!(blueprint.collect_path(env.scripts_dir(), false).is_err());
+
+            blueprint.set_artifacts_collected(true);
+            blueprint.reload()?;
+            assert!(blueprint.repository().get_by_name("build.sh").is_some());
+
+            Ok(())
+        });
+    }
+
+
     #[test]
     fn test_status_hooks_are_correctly_stored() {
         // Check in the internal data structure
@@ -530,4 +835,55 @@ mod tests {
             Ok(())
         })
     }
+
+
+    #[test]
+    fn test_jobs_after_output() {
+        test_wrapper(|env| {
+            env.create_script("status-failed.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Status: {"events": ["job_failed"]}"#,
+            ])?;
+            env.create_script("status-dead.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Status: {"events": ["job_dead_lettered"]}"#,
+            ])?;
+            env.create_script("status-rejected.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Status: {"events": ["job_rejected"]}"#,
+            ])?;
+
+            let mut blueprint = Blueprint::new(env.state());
+            blueprint.collect_path(&env.scripts_dir(), false)?;
+            let repository = blueprint.repository();
+
+            let names = |output| {
+                repository
+                    .jobs_after_output(output)
+                    .unwrap()
+                    .map(|job| job.script_name().to_string())
+                    .collect::<Vec<_>>()
+            };
+
+            let mut output = dummy_job_output();
+            assert!(names(output.clone()).is_empty());
+
+            output.success = false;
+            assert_eq!(names(output.clone()), vec!["status-failed.sh"]);
+
+            // Jobs which failed for the last time trigger both events
+            output.dead_letter = Some("dead.json".into());
+            assert_eq!(
+                names(output.clone()),
+                vec!["status-failed.sh", "status-dead.sh"],
+            );
+
+            // Rejected requests are not reported as failures
+            output.exit_code = Some(65);
+            output.dead_letter = None;
+            assert_eq!(names(output), vec!["status-rejected.sh"]);
+
+            Ok(())
+        })
+    }
 }
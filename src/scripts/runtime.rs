@@ -0,0 +1,169 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The umask, the locale and the timezone of the jobs.
+//!
+//! Hooks can pick them instead of inheriting the ones of Fisher, so the
+//! modes of the files they create and the timestamps they print don't
+//! depend on the host. The umask is set in the child process right before
+//! the script is executed, while the locale and the timezone are passed
+//! through the environment.
+
+use std::result::Result as StdResult;
+
+use libc;
+use serde::de::{Error as DeError, Deserialize, Deserializer};
+
+
+/// The umask of the processes of a job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Umask(u32);
+
+impl Umask {
+    pub fn value(&self) -> u32 {
+        self.0
+    }
+
+    /// Set the umask of the current process. This is called in the child
+    /// process, right before the script is executed.
+    pub fn apply(&self) {
+        unsafe {
+            libc::umask(self.0 as libc::mode_t);
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Umask {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> StdResult<Umask, D::Error> {
+        // The umask is written in octal, as a string so it's not mistaken
+        // for a decimal number
+        let raw = String::deserialize(deserializer)?;
+        let octal = raw.len() <= 4 && raw.chars().all(|c| match c {
+            '0'...'7' => true,
+            _ => false,
+        });
+        match u32::from_str_radix(&raw, 8) {
+            Ok(value) if octal && value <= 0o777 => Ok(Umask(value)),
+            _ => Err(D::Error::custom(format!("invalid umask: {}", raw))),
+        }
+    }
+}
+
+
+/// The locale of a job, such as `C.UTF-8`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Locale(String);
+
+impl Locale {
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<'de> Deserialize<'de> for Locale {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> StdResult<Locale, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        let valid = !name.is_empty() && name.chars().all(|c| match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '_' | '-' | '.' | '@' => true,
+            _ => false,
+        });
+        if !valid {
+            return Err(D::Error::custom(format!("invalid locale: {}", name)));
+        }
+        Ok(Locale(name))
+    }
+}
+
+
+/// The timezone of a job, such as `UTC` or `Europe/Rome`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timezone(String);
+
+impl Timezone {
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<'de> Deserialize<'de> for Timezone {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> StdResult<Timezone, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        // Names of the tz database can't point outside of it
+        let valid = !name.is_empty() && !name.contains("..")
+            && name.chars().all(|c| match c {
+                'a'...'z' | 'A'...'Z' | '0'...'9' => true,
+                '_' | '-' | '+' | '/' | ':' | '.' => true,
+                _ => false,
+            });
+        if !valid {
+            return Err(D::Error::custom(
+                format!("invalid timezone: {}", name),
+            ));
+        }
+        Ok(Timezone(name))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use super::{Locale, Timezone, Umask};
+
+
+    #[test]
+    fn test_umask() {
+        let parse = |value: &str| serde_json::from_str::<Umask>(value);
+
+        assert_eq!(parse(r#""022""#).unwrap().value(), 0o022);
+        assert_eq!(parse(r#""0077""#).unwrap().value(), 0o077);
+        assert_eq!(parse(r#""7""#).unwrap().value(), 0o007);
+        for invalid in &[r#""""#, r#""088""#, r#""1000""#, r#""00022""#, "22"] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+
+    #[test]
+    fn test_locale() {
+        let parse = |value: &str| serde_json::from_str::<Locale>(value);
+
+        assert_eq!(parse(r#""C.UTF-8""#).unwrap().name(), "C.UTF-8");
+        assert_eq!(parse(r#""de_DE@euro""#).unwrap().name(), "de_DE@euro");
+        for invalid in &[r#""""#, r#""en US""#, r#""C;rm""#] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+
+    #[test]
+    fn test_timezone() {
+        let parse = |value: &str| serde_json::from_str::<Timezone>(value);
+
+        assert_eq!(parse(r#""UTC""#).unwrap().name(), "UTC");
+        assert_eq!(parse(r#""Europe/Rome""#).unwrap().name(), "Europe/Rome");
+        assert_eq!(parse(r#""UTC+2""#).unwrap().name(), "UTC+2");
+        for invalid in &[r#""""#, r#""../../etc/passwd""#, r#""a b""#] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,533 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Mount and PID namespace sandbox for the jobs.
+//!
+//! Sandboxed scripts run in new user, mount and PID namespaces. Their root
+//! directory is an empty tmpfs containing read-only bind mounts of the
+//! system directories, of a few files of `/etc` and of the script itself,
+//! the working directory of the job, a private `/tmp` and a fresh `/proc`.
+//!
+//! Everything is prepared before the script is spawned, since only raw
+//! system calls can be used between the fork and the exec.
+
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::ptr;
+
+use libc;
+use users;
+
+use common::prelude::*;
+use utils;
+
+
+/// The directories of the host visible to the sandboxed scripts.
+static SYSTEM_DIRS: &[&str] = &[
+    "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/usr", "/dev",
+];
+
+/// The paths in `/etc` visible to the sandboxed scripts, needed to load
+/// libraries, resolve names and users, and verify TLS certificates.
+static ETC_PATHS: &[&str] = &[
+    "/etc/ld.so.cache", "/etc/resolv.conf", "/etc/hosts", "/etc/passwd",
+    "/etc/group", "/etc/ssl/certs", "/etc/pki/tls/certs",
+    "/etc/pki/ca-trust/extracted", "/etc/ca-certificates",
+];
+
+/// Where the old root is moved before being detached, inside the new one.
+const OLD_ROOT: &str = ".old-root";
+
+
+#[derive(Debug)]
+enum Step {
+    Mkdir(CString),
+    Touch(CString),
+    Symlink { target: CString, path: CString },
+    Bind { source: CString, target: CString, writable: bool },
+    Tmpfs(CString),
+    Proc(CString),
+}
+
+
+/// The sandbox of an execution of a job, whose root directory is removed
+/// when dropped.
+#[derive(Debug)]
+pub struct Sandbox {
+    root: PathBuf,
+    root_c: CString,
+    old_root: CString,
+    old_root_inside: CString,
+    working_directory: CString,
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+    steps: Vec<Step>,
+    created: HashSet<PathBuf>,
+}
+
+impl Sandbox {
+    /// Prepare the sandbox of a script, which can only write to the
+    /// provided directories.
+    pub fn new(script: &Path, writable: &[&Path]) -> Result<Self> {
+        let root = utils::create_temp_dir()?;
+        let working_directory = writable.first().cloned()
+            .unwrap_or_else(|| Path::new("/"));
+
+        // The user running Fisher is the only one mapped in the sandbox
+        let uid = users::get_current_uid();
+        let gid = users::get_current_gid();
+
+        let mut sandbox = Sandbox {
+            root_c: cstring(&root)?,
+            old_root: cstring(&root.join(OLD_ROOT))?,
+            old_root_inside: cstring(&Path::new("/").join(OLD_ROOT))?,
+            working_directory: cstring(working_directory)?,
+            uid_map: format!("{} {} 1", uid, uid).into_bytes(),
+            gid_map: format!("{} {} 1", gid, gid).into_bytes(),
+            steps: Vec::new(),
+            created: HashSet::new(),
+            root,
+        };
+
+        for dir in SYSTEM_DIRS {
+            sandbox.expose(Path::new(dir), false)?;
+        }
+        for path in ETC_PATHS {
+            sandbox.expose_resolved(Path::new(path))?;
+        }
+        sandbox.mount(Path::new("/tmp"), Step::Tmpfs)?;
+        sandbox.mount(Path::new("/proc"), Step::Proc)?;
+
+        // The other files in the directory of the script may be secret
+        sandbox.expose_resolved(script)?;
+        for dir in writable {
+            sandbox.expose(dir, true)?;
+        }
+
+        Ok(sandbox)
+    }
+
+    /// Make a path of the host visible in the sandbox, if it exists.
+    fn expose(&mut self, path: &Path, writable: bool) -> Result<()> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
+                return Ok(());
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        // Symlinks like /bin -> usr/bin are recreated as they are
+        if metadata.file_type().is_symlink() {
+            let target = fs::read_link(path)?;
+            let inside = self.inside(path);
+            self.mkdir_parents(path)?;
+            self.steps.push(Step::Symlink {
+                target: cstring(&target)?,
+                path: cstring(&inside)?,
+            });
+            return Ok(());
+        }
+
+        self.mkdir(path)?;
+        self.steps.push(Step::Bind {
+            source: cstring(path)?,
+            target: cstring(&self.inside(path))?,
+            writable,
+        });
+        Ok(())
+    }
+
+    /// Make a file or a directory of the host visible in the sandbox as
+    /// read-only, if it exists. Symlinks are followed, since their target
+    /// is usually not exposed.
+    fn expose_resolved(&mut self, path: &Path) -> Result<()> {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
+                return Ok(());
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        if metadata.is_dir() {
+            self.mkdir(path)?;
+        } else {
+            self.mkdir_parents(path)?;
+            if self.created.insert(path.to_path_buf()) {
+                let inside = cstring(&self.inside(path))?;
+                self.steps.push(Step::Touch(inside));
+            }
+        }
+        self.steps.push(Step::Bind {
+            source: cstring(path)?,
+            target: cstring(&self.inside(path))?,
+            writable: false,
+        });
+        Ok(())
+    }
+
+    fn mount<F: Fn(CString) -> Step>(
+        &mut self, path: &Path, step: F,
+    ) -> Result<()> {
+        self.mkdir(path)?;
+        let target = cstring(&self.inside(path))?;
+        self.steps.push(step(target));
+
+        // The directories inside the mount must be created again
+        let path = path.to_path_buf();
+        self.created.retain(|dir| !dir.starts_with(&path) || *dir == path);
+        Ok(())
+    }
+
+    fn mkdir(&mut self, path: &Path) -> Result<()> {
+        self.mkdir_parents(path)?;
+        if self.created.insert(path.to_path_buf()) {
+            let inside = cstring(&self.inside(path))?;
+            self.steps.push(Step::Mkdir(inside));
+        }
+        Ok(())
+    }
+
+    fn mkdir_parents(&mut self, path: &Path) -> Result<()> {
+        let mut current = PathBuf::from("/");
+        let components = path.components().collect::<Vec<_>>();
+        let parents = components.len().saturating_sub(1);
+        for component in components.iter().take(parents) {
+            if let Component::Normal(name) = *component {
+                current.push(name);
+                if self.created.insert(current.clone()) {
+                    let inside = cstring(&self.inside(&current))?;
+                    self.steps.push(Step::Mkdir(inside));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn inside(&self, path: &Path) -> PathBuf {
+        self.root.join(path.strip_prefix("/").unwrap_or(path))
+    }
+
+    /// Move the current process into the sandbox. This is called in the
+    /// child process right before the script is executed, so it only uses
+    /// raw system calls. The process forks, and the script runs in the
+    /// child while the parent waits for it.
+    pub fn enter(&self) -> io::Result<()> {
+        unsafe {
+            check(libc::unshare(
+                libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWPID,
+            ))?;
+
+            // Older kernels don't need the setgroups file to be written
+            let _ = write_file(b"/proc/self/setgroups\0", b"deny");
+            write_file(b"/proc/self/uid_map\0", &self.uid_map)?;
+            write_file(b"/proc/self/gid_map\0", &self.gid_map)?;
+
+            // Only the children of the process join the PID namespace
+            match check(libc::fork())? {
+                0 => {}
+                pid => wait_and_exit(pid),
+            }
+
+            // The script is killed as soon as the parent is
+            check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+
+            self.setup_root()
+        }
+    }
+
+    unsafe fn setup_root(&self) -> io::Result<()> {
+        // Mounts must not propagate back to the host
+        check(libc::mount(
+            ptr::null(), b"/\0".as_ptr() as *const libc::c_char, ptr::null(),
+            libc::MS_REC | libc::MS_PRIVATE, ptr::null(),
+        ))?;
+        mount_fs(&self.root_c, b"tmpfs\0")?;
+
+        for step in &self.steps {
+            match *step {
+                Step::Mkdir(ref path) => {
+                    if libc::mkdir(path.as_ptr(), 0o755) != 0
+                        && io::Error::last_os_error().raw_os_error()
+                            != Some(libc::EEXIST)
+                    {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Step::Touch(ref path) => {
+                    // Files inside the other mounts already exist
+                    let fd = check(libc::open(
+                        path.as_ptr(),
+                        libc::O_RDONLY | libc::O_CREAT | libc::O_CLOEXEC,
+                        0o644,
+                    ))?;
+                    libc::close(fd);
+                }
+                Step::Symlink { ref target, ref path } => {
+                    check(libc::symlink(target.as_ptr(), path.as_ptr()))?;
+                }
+                Step::Bind { ref source, ref target, writable } => {
+                    bind(source, target, writable)?;
+                }
+                Step::Tmpfs(ref path) => mount_fs(path, b"tmpfs\0")?,
+                Step::Proc(ref path) => mount_fs(path, b"proc\0")?,
+            }
+        }
+
+        // Swap the root directory, and forget about the old one
+        check(libc::mkdir(self.old_root.as_ptr(), 0o700))?;
+        check(libc::syscall(
+            libc::SYS_pivot_root, self.root_c.as_ptr(), self.old_root.as_ptr(),
+        ) as libc::c_int)?;
+        check(libc::chdir(b"/\0".as_ptr() as *const libc::c_char))?;
+        check(libc::umount2(self.old_root_inside.as_ptr(), libc::MNT_DETACH))?;
+        check(libc::rmdir(self.old_root_inside.as_ptr()))?;
+
+        // Nothing outside of the mounts can be changed
+        check(libc::mount(
+            ptr::null(), b"/\0".as_ptr() as *const libc::c_char, ptr::null(),
+            libc::MS_REMOUNT | libc::MS_RDONLY | libc::MS_NOSUID
+                | libc::MS_NODEV,
+            ptr::null(),
+        ))?;
+
+        check(libc::chdir(self.working_directory.as_ptr()))?;
+        Ok(())
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        // The mounts only existed in the namespace of the script, so the
+        // root is still an empty directory
+        let _ = fs::remove_dir(&self.root);
+    }
+}
+
+
+unsafe fn mount_fs(target: &CString, kind: &[u8]) -> io::Result<()> {
+    check(libc::mount(
+        kind.as_ptr() as *const libc::c_char, target.as_ptr(),
+        kind.as_ptr() as *const libc::c_char,
+        libc::MS_NOSUID | libc::MS_NODEV, ptr::null(),
+    )).map(|_| ())
+}
+
+
+unsafe fn bind(
+    source: &CString, target: &CString, writable: bool,
+) -> io::Result<()> {
+    check(libc::mount(
+        source.as_ptr(), target.as_ptr(), ptr::null(),
+        libc::MS_BIND | libc::MS_REC, ptr::null(),
+    ))?;
+    if writable {
+        return Ok(());
+    }
+
+    // The flags of the host mount are locked in user namespaces, and the
+    // remount fails if they're not kept
+    let mut stat: libc::statvfs = ::std::mem::zeroed();
+    check(libc::statvfs(target.as_ptr(), &mut stat))?;
+    let mut flags = libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY;
+    for &(st, ms) in &[
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ] {
+        if stat.f_flag & st != 0 {
+            flags |= ms;
+        }
+    }
+
+    check(libc::mount(
+        ptr::null(), target.as_ptr(), ptr::null(), flags, ptr::null(),
+    )).map(|_| ())
+}
+
+
+unsafe fn write_file(path: &[u8], content: &[u8]) -> io::Result<()> {
+    let fd = check(libc::open(
+        path.as_ptr() as *const libc::c_char, libc::O_WRONLY,
+    ))?;
+    let result = libc::write(
+        fd, content.as_ptr() as *const libc::c_void, content.len(),
+    );
+    libc::close(fd);
+
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+
+/// Wait for the sandboxed script, and exit the same way it did.
+unsafe fn wait_and_exit(pid: libc::pid_t) -> ! {
+    // The descriptors Fisher uses to check if the script was started must
+    // be closed, otherwise it would wait for the script to end
+    let max = libc::sysconf(libc::_SC_OPEN_MAX);
+    for fd in 3..(if max > 0 { max as libc::c_int } else { 1024 }) {
+        libc::close(fd);
+    }
+
+    // Stopping the job is left to the script, and it's killed along with
+    // this process if it doesn't exit in time
+    libc::signal(libc::SIGTERM, libc::SIG_IGN);
+
+    let mut status = 0;
+    while libc::waitpid(pid, &mut status, 0) < 0 {
+        if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+            libc::_exit(1);
+        }
+    }
+
+    let signal = status & 0x7f;
+    if signal != 0 {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::kill(libc::getpid(), signal);
+        libc::_exit(128 + signal);
+    }
+    libc::_exit((status >> 8) & 0xff);
+}
+
+
+fn check<T: Default + PartialOrd>(result: T) -> io::Result<T> {
+    if result < T::default() {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+
+fn cstring(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| {
+        ErrorKind::InvalidInput(
+            format!("invalid path: {}", path.display()),
+        ).into()
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::fs;
+    use std::path::Path;
+
+    use utils;
+
+    use super::{Sandbox, Step};
+
+
+    fn describe(step: &Step) -> String {
+        let s = |c: &CString| c.to_str().unwrap().to_string();
+        match *step {
+            Step::Mkdir(ref path) => format!("mkdir {}", s(path)),
+            Step::Touch(ref path) => format!("touch {}", s(path)),
+            Step::Symlink { ref target, ref path } => {
+                format!("symlink {} {}", s(target), s(path))
+            }
+            Step::Bind { ref source, ref target, writable } => format!(
+                "bind {} {} {}", s(source), s(target),
+                if writable { "rw" } else { "ro" },
+            ),
+            Step::Tmpfs(ref path) => format!("tmpfs {}", s(path)),
+            Step::Proc(ref path) => format!("proc {}", s(path)),
+        }
+    }
+
+
+    #[test]
+    fn test_steps() {
+        let work = utils::create_temp_dir().unwrap();
+        let sandbox = Sandbox::new(
+            Path::new("/nonexistent/example.sh"), &[&work],
+        ).unwrap();
+        let root = sandbox.root.to_str().unwrap().to_string();
+        let steps = sandbox.steps.iter()
+            .map(|step| describe(step).replace(&root, "<root>"))
+            .collect::<Vec<_>>();
+
+        // The system directories are read-only, and /tmp is private
+        assert!(steps.contains(&"bind /usr <root>/usr ro".to_string()));
+        assert!(steps.contains(&"tmpfs <root>/tmp".to_string()));
+
+        // Only some files of /etc are exposed
+        assert!(!steps.contains(&"bind /etc <root>/etc ro".to_string()));
+        assert!(steps.contains(&"touch <root>/etc/passwd".to_string()));
+        assert!(
+            steps.contains(&"bind /etc/passwd <root>/etc/passwd ro".into())
+        );
+        assert!(steps.contains(&"proc <root>/proc".to_string()));
+
+        // Only the working directory is writable, and it's mounted last so
+        // it's not hidden by the other mounts
+        let work = work.to_str().unwrap();
+        assert_eq!(
+            steps.last(),
+            Some(&format!("bind {} <root>{} rw", work, work)),
+        );
+        assert_eq!(steps.iter().filter(|s| s.ends_with(" rw")).count(), 1);
+
+        // Missing directories are not exposed
+        assert!(!steps.iter().any(|s| s.contains("/nonexistent")));
+
+        let root = sandbox.root.clone();
+        assert!(root.exists());
+        drop(sandbox);
+        assert!(!root.exists());
+
+        fs::remove_dir(work).unwrap();
+    }
+
+
+    #[test]
+    fn test_script_exposed() {
+        let scripts = utils::create_temp_dir().unwrap();
+        let script = scripts.join("example.sh");
+        fs::File::create(&script).unwrap();
+
+        let sandbox = Sandbox::new(&script, &[]).unwrap();
+        let root = sandbox.root.to_str().unwrap().to_string();
+        let steps = sandbox.steps.iter()
+            .map(|step| describe(step).replace(&root, "<root>"))
+            .collect::<Vec<_>>();
+
+        // Only the script is visible, not the directory containing it
+        let scripts = scripts.to_str().unwrap().to_string();
+        let script = script.to_str().unwrap().to_string();
+        assert!(steps.contains(&format!("touch <root>{}", script)));
+        assert!(
+            steps.contains(&format!("bind {} <root>{} ro", script, script))
+        );
+        assert!(!steps.iter().any(|s| s.starts_with(
+            &format!("bind {} ", scripts),
+        )));
+
+        drop(sandbox);
+        fs::remove_dir_all(scripts).unwrap();
+    }
+}
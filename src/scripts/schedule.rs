@@ -0,0 +1,466 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Schedules allow hooks to be executed periodically, without waiting for
+//! incoming webhooks.
+//!
+//! Schedules use the classic five fields cron syntax, evaluated in UTC. A
+//! background thread checks every second if any hook is due, queueing a job
+//! for it with a synthetic request. Runs missed while Fisher was stopped are
+//! not recovered.
+
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use common::prelude::*;
+use events::JobEventKind;
+use scripts::{Repository, Script};
+
+
+/// How often the monitor checks for scheduled hooks, in seconds.
+const CHECK_INTERVAL: u64 = 1;
+
+/// How many days are searched for the next run. The calendar repeats itself
+/// every 400 years, but the days of the week line up again every 28 years.
+const MAX_SEARCHED_DAYS: u64 = 366 * 28;
+
+
+/// The synthetic request of the jobs started by a schedule.
+#[derive(Debug, Clone)]
+pub struct ScheduledRun {
+    /// When the job was scheduled to run, as a UNIX timestamp.
+    pub time: u64,
+}
+
+
+/// A parsed cron schedule, like `0 3 * * *`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    // Each field is a bitmask of the allowed values
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // Whether the days were restricted, since a day matches if either the
+    // day of the month or the day of the week match
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl CronSchedule {
+    pub fn parse(expr: &str) -> Result<Self> {
+        let invalid = || -> Error {
+            ErrorKind::InvalidSchedule(expr.to_string()).into()
+        };
+
+        let fields = expr.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err(invalid());
+        }
+
+        let field = |index: usize, min: u32, max: u32| {
+            parse_field(fields[index], min, max).ok_or_else(&invalid)
+        };
+
+        // Both 0 and 7 are Sunday
+        let mut weekdays = field(4, 0, 7)?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+
+        let schedule = CronSchedule {
+            minutes: field(0, 0, 59)?,
+            hours: field(1, 0, 23)?,
+            days: field(2, 1, 31)?,
+            months: field(3, 1, 12)?,
+            weekdays,
+            any_day: fields[2].starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        };
+
+        // Reject schedules which never run, like the 30th of February
+        if schedule.next_after(0).is_none() {
+            return Err(invalid());
+        }
+
+        Ok(schedule)
+    }
+
+    /// Return the first time the schedule runs after the provided one, as a
+    /// UNIX timestamp.
+    pub fn next_after(&self, timestamp: u64) -> Option<u64> {
+        let start = timestamp / 60 + 1;
+        let mut day = start / 1440;
+        let mut first_minute = start % 1440;
+
+        for _ in 0..MAX_SEARCHED_DAYS {
+            if self.matches_day(day) {
+                for minute in first_minute..1440 {
+                    if self.hours & (1 << (minute / 60)) != 0
+                        && self.minutes & (1 << (minute % 60)) != 0
+                    {
+                        return Some((day * 1440 + minute) * 60);
+                    }
+                }
+            }
+
+            day += 1;
+            first_minute = 0;
+        }
+
+        None
+    }
+
+    fn matches_day(&self, day: u64) -> bool {
+        let (month, day_of_month) = civil_from_days(day);
+        // The 1st of January 1970 was a Thursday
+        let weekday = (day + 4) % 7;
+
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+
+        let day_matches = self.days & (1 << day_of_month) != 0;
+        let weekday_matches = self.weekdays & (1 << weekday) != 0;
+        if self.any_day || self.any_weekday {
+            day_matches && weekday_matches
+        } else {
+            day_matches || weekday_matches
+        }
+    }
+}
+
+
+/// Parse a field of a cron schedule, returning the bitmask of the values it
+/// allows.
+fn parse_field(field: &str, min: u32, max: u32) -> Option<u64> {
+    let number = |value: &str| -> Option<u32> {
+        match value.parse() {
+            Ok(num) if num >= min && num <= max => Some(num),
+            _ => None,
+        }
+    };
+
+    let mut mask: u64 = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(pos) => (&part[..pos], Some(&part[pos + 1..])),
+            None => (part, None),
+        };
+
+        let step = match step {
+            Some(step) => match step.parse() {
+                Ok(step) if step > 0 => step,
+                _ => return None,
+            },
+            None => 1,
+        };
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some(pos) = range.find('-') {
+            match (number(&range[..pos]), number(&range[pos + 1..])) {
+                (Some(start), Some(end)) if start <= end => (start, end),
+                _ => return None,
+            }
+        } else {
+            match number(range) {
+                // Steps without a range go up to the maximum value
+                Some(start) if part.contains('/') => (start, max),
+                Some(start) => (start, start),
+                None => return None,
+            }
+        };
+
+        let mut value = start;
+        while value <= end {
+            mask |= 1 << value;
+            value += step;
+        }
+    }
+
+    Some(mask)
+}
+
+
+/// Return the month and the day of the month of the day, counted from the
+/// UNIX epoch.
+fn civil_from_days(day: u64) -> (u64, u64) {
+    let (_, month, day_of_month) = civil_date(day);
+    (month, day_of_month)
+}
+
+
+/// Return the year, the month and the day of the month of the day, counted
+/// from the UNIX epoch.
+pub fn civil_date(day: u64) -> (u64, u64, u64) {
+    // Shift the epoch to the 1st of March of the year 0, so leap days are
+    // at the end of the year
+    let z = day + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524
+        - day_of_era / 146_096) / 365;
+    let day_of_year =
+        day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+
+    let day_of_month = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+
+    // January and February belong to the next year
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day_of_month)
+}
+
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+
+/// When each scheduled hook runs next.
+#[derive(Debug)]
+pub struct Schedules {
+    next: Mutex<HashMap<String, (CronSchedule, u64)>>,
+}
+
+impl Schedules {
+    pub fn new() -> Self {
+        Schedules {
+            next: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Return the scripts which are due, along with when they were
+    /// scheduled to run.
+    pub fn due<I: Iterator<Item = Arc<Script>>>(
+        &self, scripts: I,
+    ) -> Vec<(Arc<Script>, u64)> {
+        self.due_at(scripts, now())
+    }
+
+    fn due_at<I: Iterator<Item = Arc<Script>>>(
+        &self, scripts: I, now: u64,
+    ) -> Vec<(Arc<Script>, u64)> {
+        let mut next = self.next.lock().unwrap();
+
+        let mut due = Vec::new();
+        for script in scripts {
+            let schedule = match script.schedule() {
+                Some(schedule) => schedule,
+                None => continue,
+            };
+
+            // Hooks never seen before, or whose schedule changed, start
+            // counting from now
+            let known = match next.get(script.name()) {
+                Some(&(ref known, _)) => known == schedule,
+                None => false,
+            };
+            if !known {
+                if let Some(at) = schedule.next_after(now) {
+                    next.insert(
+                        script.name().to_string(), (schedule.clone(), at),
+                    );
+                }
+                continue;
+            }
+
+            let entry = next.get_mut(script.name()).unwrap();
+            if now >= entry.1 {
+                due.push((script.clone(), entry.1));
+
+                // Only one job is queued even if more runs were missed
+                if let Some(at) = schedule.next_after(now) {
+                    entry.1 = at;
+                }
+            }
+        }
+
+        due
+    }
+}
+
+
+/// Background thread queueing the jobs of the scheduled hooks.
+pub struct ScheduleMonitor {
+    stop: mpsc::Sender<()>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl ScheduleMonitor {
+    pub fn new<A: ProcessorApiTrait<Repository> + 'static>(
+        hooks: Arc<Repository>, processor: A,
+    ) -> Self {
+        let (stop_send, stop_recv) = mpsc::channel();
+
+        let handle = thread::spawn(move || {
+            let interval = Duration::from_secs(CHECK_INTERVAL);
+
+            // Stop when asked to, or when the monitor is dropped
+            while let Err(mpsc::RecvTimeoutError::Timeout) =
+                stop_recv.recv_timeout(interval)
+            {
+                for (job, priority) in hooks.scheduled_jobs() {
+                    hooks.events().publish(job.event(JobEventKind::Queued));
+                    let _ = processor.queue(job, priority);
+                }
+            }
+        });
+
+        ScheduleMonitor {
+            stop: stop_send,
+            handle: handle,
+        }
+    }
+
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.handle.join();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use common::prelude::*;
+    use scripts::test_utils::*;
+
+    use super::{civil_date, civil_from_days, CronSchedule, Schedules};
+
+
+    // Tuesday 14 November 2017, 12:34:56 UTC
+    const NOW: u64 = 1_510_662_896;
+
+
+    fn next(expr: &str) -> u64 {
+        CronSchedule::parse(expr).unwrap().next_after(NOW).unwrap()
+    }
+
+
+    #[test]
+    fn test_civil_from_days() {
+        assert_eq!(civil_from_days(0), (1, 1));
+        assert_eq!(civil_from_days(59), (3, 1));
+        // 29 February 2000, 31 December 2016 and 14 November 2017
+        assert_eq!(civil_from_days(11_016), (2, 29));
+        assert_eq!(civil_from_days(17_166), (12, 31));
+        assert_eq!(civil_from_days(17_484), (11, 14));
+
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(11_016), (2000, 2, 29));
+        assert_eq!(civil_date(17_166), (2016, 12, 31));
+    }
+
+
+    #[test]
+    fn test_parse() {
+        for valid in &[
+            "* * * * *", "0 3 * * *", "*/15 * * * *", "0 9-17 * * 1-5",
+            "0,30 * 1,15 * *", "5/10 * * * 0", "0 0 * * 7", "0 0 29 2 *",
+        ] {
+            assert!(CronSchedule::parse(valid).is_ok(), "{}", valid);
+        }
+
+        for invalid in &[
+            "", "* * * *", "* * * * * *", "60 * * * *", "* 24 * * *",
+            "* * 0 * *", "* * * 13 *", "* * * * 8", "*/0 * * * *",
+            "5-1 * * * *", "a * * * *", "0 0 30 2 *",
+        ] {
+            assert!(CronSchedule::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+
+    #[test]
+    fn test_next_after() {
+        // Runs are always strictly in the future
+        assert_eq!(next("* * * * *"), NOW - 56 + 60);
+        assert_eq!(next("*/15 * * * *"), NOW - 34 * 60 - 56 + 45 * 60);
+
+        // 03:00 of the next day
+        let midnight = NOW - NOW % 86_400;
+        assert_eq!(next("0 3 * * *"), midnight + 86_400 + 3 * 3600);
+
+        // The next Sunday is the 19th of November, and 7 is Sunday too
+        assert_eq!(next("0 0 * * 0"), midnight + 5 * 86_400);
+        assert_eq!(next("0 0 * * 7"), midnight + 5 * 86_400);
+
+        // Either the day of the month or the day of the week must match
+        assert_eq!(next("0 0 1 * 0"), midnight + 5 * 86_400);
+        // The day of the week alone is considered if the other is a star
+        assert_eq!(next("0 0 * 12 3"), midnight + 22 * 86_400);
+
+        // The next 29th of February is in 2020
+        assert_eq!(next("0 0 29 2 *"), 1_582_934_400);
+    }
+
+
+    #[test]
+    fn test_due() {
+        test_wrapper(|env| {
+            env.create_script("hourly.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Schedule: "0 * * * *""#,
+            ])?;
+            env.create_script("naked.sh", &[r#"#!/bin/bash"#])?;
+            let scripts = vec![
+                Arc::new(env.load_script("hourly.sh")?),
+                Arc::new(env.load_script("naked.sh")?),
+            ];
+            let next_hour = NOW - NOW % 3600 + 3600;
+
+            let schedules = Schedules::new();
+            let due = |now: u64| {
+                schedules
+                    .due_at(scripts.iter().cloned(), now)
+                    .into_iter()
+                    .map(|(script, at)| (script.name().to_string(), at))
+                    .collect::<Vec<_>>()
+            };
+
+            // Nothing runs when a schedule is first seen
+            assert!(due(NOW).is_empty());
+            assert!(due(next_hour - 1).is_empty());
+
+            // Each run is returned only once
+            assert_eq!(due(next_hour), vec![("hourly.sh".into(), next_hour)]);
+            assert!(due(next_hour + 1).is_empty());
+
+            // Missed runs are merged together
+            let later = next_hour + 3 * 3600 + 10;
+            assert_eq!(due(later), vec![
+                ("hourly.sh".into(), next_hour + 3600),
+            ]);
+            assert!(due(later + 60).is_empty());
+
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! CPU and IO scheduling of the jobs.
+//!
+//! Hooks can lower the priority of their jobs, so heavyweight builds don't
+//! slow down the rest of the machine or the HTTP server. Both priorities are
+//! set in the child process right before the script is executed, and they're
+//! inherited by all the processes it starts.
+
+use std::io;
+use std::result::Result as StdResult;
+
+use libc;
+use serde::de::{Error as DeError, Deserialize, Deserializer};
+
+
+/// The lowest niceness, which gives the process the most CPU time.
+const MIN_NICENESS: i32 = -20;
+
+/// The highest niceness, which gives the process the least CPU time.
+const MAX_NICENESS: i32 = 19;
+
+// Constants of the ioprio_set syscall, not exported by libc
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+
+/// The niceness of the processes of a job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Niceness(i32);
+
+impl Niceness {
+    pub fn value(&self) -> i32 {
+        self.0
+    }
+}
+
+impl<'de> Deserialize<'de> for Niceness {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> StdResult<Niceness, D::Error> {
+        let value = i32::deserialize(deserializer)?;
+        if value < MIN_NICENESS || value > MAX_NICENESS {
+            return Err(D::Error::custom(format!(
+                "the niceness must be between {} and {}",
+                MIN_NICENESS, MAX_NICENESS,
+            )));
+        }
+        Ok(Niceness(value))
+    }
+}
+
+
+/// The IO scheduling class of the processes of a job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum IoClass {
+    /// The processes share the disk with the other ones, with a priority
+    /// derived from their niceness.
+    #[serde(rename = "best-effort")]
+    BestEffort,
+    /// The processes use the disk only when no other process needs it.
+    #[serde(rename = "idle")]
+    Idle,
+}
+
+impl IoClass {
+    /// Return the value expected by the kernel for this class.
+    fn ioprio(&self, niceness: i32) -> libc::c_int {
+        match *self {
+            // The same level the kernel picks if no class is set
+            IoClass::BestEffort => {
+                (2 << IOPRIO_CLASS_SHIFT) | ((niceness - MIN_NICENESS) / 5)
+            }
+            IoClass::Idle => 3 << IOPRIO_CLASS_SHIFT,
+        }
+    }
+}
+
+
+/// Apply the priorities to the current process. This is called in the child
+/// process, right before the script is executed.
+pub fn apply(
+    niceness: Option<Niceness>,
+    io_class: Option<IoClass>,
+) -> io::Result<()> {
+    if let Some(niceness) = niceness {
+        let result = unsafe {
+            // The type of the first argument differs between the C libraries
+            libc::setpriority(libc::PRIO_PROCESS as _, 0, niceness.value())
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    if let Some(io_class) = io_class {
+        let ioprio = io_class.ioprio(niceness.map(|n| n.value()).unwrap_or(0));
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use super::{IoClass, Niceness};
+
+
+    #[test]
+    fn test_niceness() {
+        let parse = |value: &str| serde_json::from_str::<Niceness>(value);
+
+        assert_eq!(parse("10").unwrap().value(), 10);
+        assert_eq!(parse("-20").unwrap().value(), -20);
+        assert_eq!(parse("19").unwrap().value(), 19);
+        for invalid in &["20", "-21", "\"10\"", "1.5"] {
+            assert!(parse(invalid).is_err());
+        }
+    }
+
+
+    #[test]
+    fn test_ioprio() {
+        assert_eq!(IoClass::Idle.ioprio(0), 3 << 13);
+        assert_eq!(IoClass::BestEffort.ioprio(0), (2 << 13) | 4);
+        assert_eq!(IoClass::BestEffort.ioprio(19), (2 << 13) | 7);
+        assert_eq!(IoClass::BestEffort.ioprio(-20), 2 << 13);
+    }
+}
@@ -13,18 +13,86 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::cmp;
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 
 use regex::Regex;
 use serde_json;
+use toml;
 
 use common::prelude::*;
+use common::config::{BodyStorageKind, ResourceLimits};
 use common::state::{IdKind, State, UniqueId};
 
 use providers::Provider;
 use requests::{Request, RequestType};
+use scripts::artifacts::ArtifactPattern;
+use scripts::coalesce::HookCoalesce;
+use scripts::executor::{ContainerImage, FirejailConfig};
+use scripts::interpreter::Interpreter;
+use scripts::ip_filter::IpFilter;
+use scripts::rate_limit::{HookRateLimit, RateLimitResult};
+use scripts::schedule::CronSchedule;
+use scripts::runtime::{Locale, Timezone, Umask};
+use scripts::scheduling::{IoClass, Niceness};
+use scripts::secrets::HookSecrets;
+use scripts::template::HookEnv;
+use utils::TimeString;
+
+
+/// The timeout of synchronous scripts, if they don't set one.
+const DEFAULT_SYNC_TIMEOUT: u64 = 30;
+
+/// How long to wait before retrying a failed job for the first time, in
+/// seconds, if the script doesn't set it.
+const DEFAULT_RETRY_BACKOFF: u64 = 10;
+
+/// The maximum time to wait before retrying a failed job, in seconds.
+const MAX_RETRY_DELAY: u64 = 3600;
+
+/// The line opening and closing the TOML frontmatter of a script.
+const FRONTMATTER_DELIMITER: &str = "## +++";
+
+/// The paths used by the other endpoints, which can't be used by routes.
+static RESERVED_ROUTES: &[&str] = &[
+    "hook", "jobs", "health", "metrics", "admin", "worker",
+];
+
+
+/// The HTTP methods scripts can be called with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum HookMethod {
+    #[serde(rename = "GET")]
+    Get,
+    #[serde(rename = "POST")]
+    Post,
+}
+
+impl HookMethod {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HookMethod::Get => "GET",
+            HookMethod::Post => "POST",
+        }
+    }
+}
+
+
+/// What scripts receive on their standard input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum HookStdin {
+    /// The request body, which is also stored as usual.
+    #[serde(rename = "body")]
+    Body,
+    /// The request body, which is not stored anywhere else.
+    #[serde(rename = "body-only")]
+    BodyOnly,
+}
 
 
 #[derive(Debug, Clone)]
@@ -38,16 +106,78 @@ lazy_static! {
     static ref PREFERENCES_HEADER_RE: Regex = Regex::new(
         r"## Fisher: (.*)"
     ).unwrap();
+    static ref REQUIRE_ALL_HEADER_RE: Regex = Regex::new(
+        r"## Fisher-RequireAll: (.*)"
+    ).unwrap();
+    static ref RATE_LIMIT_HEADER_RE: Regex = Regex::new(
+        r"## Fisher-RateLimit: (.*)"
+    ).unwrap();
+    static ref IP_FILTER_HEADER_RE: Regex = Regex::new(
+        r"## Fisher-IpFilter: (.*)"
+    ).unwrap();
+    static ref ROUTE_HEADER_RE: Regex = Regex::new(
+        r"## Fisher-Route: (.*)"
+    ).unwrap();
+    static ref CONCURRENCY_HEADER_RE: Regex = Regex::new(
+        r"## Fisher-Concurrency: (.*)"
+    ).unwrap();
+    static ref COALESCE_HEADER_RE: Regex = Regex::new(
+        r"## Fisher-Coalesce: (.*)"
+    ).unwrap();
+    static ref SCHEDULE_HEADER_RE: Regex = Regex::new(
+        r"## Fisher-Schedule: (.*)"
+    ).unwrap();
+    static ref ENV_HEADER_RE: Regex = Regex::new(
+        r"## Fisher-Env: (.*)"
+    ).unwrap();
+    static ref DENY_HEADER_RE: Regex = Regex::new(
+        r"## Fisher-Deny-([a-zA-Z]+): (.*)"
+    ).unwrap();
     static ref PROVIDER_HEADER_RE: Regex = Regex::new(
         r"## Fisher-([a-zA-Z]+): (.*)"
     ).unwrap();
+    static ref CONFIG_FILE_HEADER_RE: Regex = Regex::new(
+        r"## Fisher-Config-File: (.*)"
+    ).unwrap();
+    static ref FRONTMATTER_KEY_RE: Regex = Regex::new(
+        r"^([a-zA-Z]+|Deny-[a-zA-Z]+)$"
+    ).unwrap();
 }
 
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct Preferences {
     priority: Option<isize>,
     parallel: Option<bool>,
+    #[serde(rename = "body-storage")]
+    body_storage: Option<BodyStorageKind>,
+    heartbeat: Option<TimeString>,
+    sync: Option<bool>,
+    timeout: Option<TimeString>,
+    methods: Option<Vec<HookMethod>>,
+    #[serde(rename = "max-retries")]
+    max_retries: Option<u32>,
+    backoff: Option<TimeString>,
+    limits: Option<ResourceLimits>,
+    sandbox: Option<bool>,
+    delay: Option<TimeString>,
+    debounce: Option<TimeString>,
+    #[serde(rename = "persistent-workdir")]
+    persistent_workdir: Option<bool>,
+    #[serde(rename = "keep-workdir-on-failure")]
+    keep_workdir_on_failure: Option<bool>,
+    stdin: Option<HookStdin>,
+    secrets: Option<String>,
+    nice: Option<Niceness>,
+    #[serde(rename = "io-class")]
+    io_class: Option<IoClass>,
+    umask: Option<Umask>,
+    locale: Option<Locale>,
+    timezone: Option<Timezone>,
+    image: Option<ContainerImage>,
+    firejail: Option<FirejailConfig>,
+    artifacts: Option<Vec<ArtifactPattern>>,
 }
 
 impl Preferences {
@@ -55,6 +185,29 @@ impl Preferences {
         Preferences {
             priority: None,
             parallel: None,
+            body_storage: None,
+            heartbeat: None,
+            sync: None,
+            timeout: None,
+            methods: None,
+            max_retries: None,
+            backoff: None,
+            limits: None,
+            sandbox: None,
+            delay: None,
+            debounce: None,
+            persistent_workdir: None,
+            keep_workdir_on_failure: None,
+            stdin: None,
+            secrets: None,
+            nice: None,
+            io_class: None,
+            umask: None,
+            locale: None,
+            timezone: None,
+            image: None,
+            firejail: None,
+            artifacts: None,
         }
     }
 
@@ -65,7 +218,88 @@ impl Preferences {
 
     #[inline]
     fn parallel(&self) -> bool {
-        self.parallel.unwrap_or(true)
+        // Jobs sharing the same working directory would step on each other
+        self.parallel.unwrap_or(!self.persistent_workdir())
+    }
+
+    #[inline]
+    fn persistent_workdir(&self) -> bool {
+        self.persistent_workdir.unwrap_or(false)
+    }
+
+    #[inline]
+    fn heartbeat(&self) -> Option<u64> {
+        // A zero interval disables the heartbeat
+        match self.heartbeat {
+            Some(ref time) if time.as_u64() > 0 => Some(time.as_u64()),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    fn sync(&self) -> bool {
+        self.sync.unwrap_or(false)
+    }
+
+    #[inline]
+    fn timeout(&self) -> Option<Duration> {
+        // Synchronous scripts always have a timeout, since the client is
+        // waiting for them
+        match self.timeout {
+            Some(ref time) if time.as_u64() > 0 => {
+                Some(Duration::from_secs(time.as_u64()))
+            }
+            _ if self.sync() => Some(Duration::from_secs(DEFAULT_SYNC_TIMEOUT)),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    fn max_retries(&self) -> u32 {
+        self.max_retries.unwrap_or(0)
+    }
+
+    #[inline]
+    fn backoff(&self) -> u64 {
+        match self.backoff {
+            Some(ref time) => time.as_u64(),
+            None => DEFAULT_RETRY_BACKOFF,
+        }
+    }
+
+    #[inline]
+    fn limits(&self) -> ResourceLimits {
+        self.limits.clone().unwrap_or_default()
+    }
+
+    #[inline]
+    fn delay(&self) -> Option<Duration> {
+        // A zero delay runs the jobs right away
+        match self.delay {
+            Some(ref time) if time.as_u64() > 0 => {
+                Some(Duration::from_secs(time.as_u64()))
+            }
+            _ => None,
+        }
+    }
+
+    #[inline]
+    fn debounce(&self) -> Option<Duration> {
+        match self.debounce {
+            Some(ref time) if time.as_u64() > 0 => {
+                Some(Duration::from_secs(time.as_u64()))
+            }
+            _ => None,
+        }
+    }
+
+    #[inline]
+    fn methods(&self) -> Vec<HookMethod> {
+        // Crawlers issue GET requests, so they're allowed only on request
+        match self.methods {
+            Some(ref methods) => methods.clone(),
+            None => vec![HookMethod::Post],
+        }
     }
 }
 
@@ -73,45 +307,368 @@ impl Preferences {
 struct LoadHeadersOutput {
     preferences: Preferences,
     providers: Vec<Arc<Provider>>,
+    deny: Vec<Arc<Provider>>,
+    require_all: bool,
+    rate_limit: Option<HookRateLimit>,
+    ip_filter: Option<IpFilter>,
+    routes: Vec<String>,
+    concurrency: Option<usize>,
+    coalesce: Option<HookCoalesce>,
+    schedule: Option<CronSchedule>,
+    env: Option<HookEnv>,
 }
 
 
-fn load_headers(file: &str) -> Result<LoadHeadersOutput> {
+/// Parse the maximum number of jobs of the script running at the same time.
+fn parse_concurrency(value: &str) -> Result<usize> {
+    match serde_json::from_str(value) {
+        Ok(0) => Err(ErrorKind::InvalidInput(
+            "the concurrency limit can't be zero".into(),
+        ).into()),
+        Ok(limit) => Ok(limit),
+        Err(error) => Err(error.into()),
+    }
+}
+
+
+/// Parse a cron schedule, provided as a JSON string.
+fn parse_schedule(value: &str) -> Result<CronSchedule> {
+    let expr: String = serde_json::from_str(value)?;
+    CronSchedule::parse(&expr)
+}
+
+
+/// Parse a route, returning it without the leading and trailing slashes.
+fn parse_route(route: &str) -> Result<String> {
+    let route = route.trim();
+    let invalid = |reason: &str| -> Result<String> {
+        Err(ErrorKind::InvalidInput(
+            format!("invalid route {}: {}", route, reason),
+        ).into())
+    };
+
+    if !route.starts_with('/') {
+        return invalid("it must start with a slash");
+    }
+    let trimmed = route.trim_matches('/');
+    if trimmed.is_empty() {
+        return invalid("it's empty");
+    }
+
+    // The route must be matchable by the HTTP server
+    let valid = trimmed.chars().all(|c| match c {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '.' | '/' | '_' | '-' => true,
+        _ => false,
+    });
+    if !valid {
+        return invalid("it contains characters not allowed");
+    }
+
+    let first = trimmed.split('/').next().unwrap();
+    if RESERVED_ROUTES.contains(&first) {
+        return invalid("it's used by Fisher");
+    }
+
+    Ok(trimmed.to_string())
+}
+
+
+/// A configuration comment, and where it comes from.
+struct HeaderLine {
+    content: String,
+    file: String,
+    line: Option<u32>,
+}
+
+impl HeaderLine {
+    fn location(&self) -> ErrorLocation {
+        ErrorLocation::File(self.file.clone(), self.line)
+    }
+}
+
+
+/// Read the configuration comments of the script, converting the ones in
+/// the frontmatter and in the configuration file it points to, if any.
+fn read_header_lines(file: &str) -> Result<Vec<HeaderLine>> {
     let f = File::open(file).unwrap();
     let reader = BufReader::new(f);
 
-    let mut content;
+    let mut lines = Vec::new();
     let mut line_number: u32 = 0;
-    let mut providers = vec![];
-    let mut preferences = None;
+    // Where the frontmatter being read started, and its content so far
+    let mut frontmatter: Option<(u32, String)> = None;
     for line in reader.lines() {
         line_number += 1;
-        content = line.unwrap();
+        let content = line.unwrap();
+
+        if content.trim_right() == FRONTMATTER_DELIMITER {
+            frontmatter = match frontmatter.take() {
+                Some((start, source)) => {
+                    let parsed = parse_frontmatter(&source, file, Some(start))?;
+                    lines.extend(parsed);
+                    None
+                }
+                None => Some((line_number, String::new())),
+            };
+            continue;
+        }
+
+        if let Some((_, ref mut source)) = frontmatter {
+            // The frontmatter is made of comments, which are uncommented
+            if !content.starts_with("##") {
+                let mut error: Error = ErrorKind::InvalidInput(
+                    "every line of the frontmatter must start with ##".into(),
+                ).into();
+                error.set_location(
+                    ErrorLocation::File(file.into(), Some(line_number)),
+                );
+                return Err(error);
+            }
+            let uncommented = &content[2..];
+            source.push_str(if uncommented.starts_with(' ') {
+                &uncommented[1..]
+            } else {
+                uncommented
+            });
+            source.push('\n');
+            continue;
+        }
 
         // Just ignore everything after an empty line
         if content == "" {
             break;
         }
 
+        if let Some(cap) = CONFIG_FILE_HEADER_RE.captures(&content) {
+            // Relative paths start from the script's directory
+            let dir = Path::new(file).parent().unwrap_or_else(|| Path::new(""));
+            let path = dir.join(cap[1].trim()).to_string_lossy().into_owned();
+
+            let mut source = String::new();
+            let read = File::open(&path)
+                .and_then(|mut config| config.read_to_string(&mut source));
+            if let Err(error) = read {
+                let mut error: Error = error.into();
+                error.set_location(
+                    ErrorLocation::File(file.into(), Some(line_number)),
+                );
+                return Err(error);
+            }
+
+            lines.extend(parse_frontmatter(&source, &path, None)?);
+            continue;
+        }
+
+        lines.push(HeaderLine {
+            content: content,
+            file: file.into(),
+            line: Some(line_number),
+        });
+    }
+
+    if let Some((start, _)) = frontmatter {
+        let mut error: Error = ErrorKind::InvalidInput(
+            "the frontmatter is never closed".into(),
+        ).into();
+        error.set_location(ErrorLocation::File(file.into(), Some(start)));
+        return Err(error);
+    }
+
+    Ok(lines)
+}
+
+
+/// Convert a TOML frontmatter to the equivalent configuration comments. Its
+/// top-level keys are the names of the comments, without `Fisher-`.
+fn parse_frontmatter(
+    source: &str, file: &str, line: Option<u32>,
+) -> Result<Vec<HeaderLine>> {
+    let location = || ErrorLocation::File(file.into(), line);
+
+    let table: toml::value::Table = match toml::from_str(source) {
+        Ok(table) => table,
+        Err(error) => {
+            let mut error =
+                Error::new(ErrorKind::GenericError(Box::new(error)));
+            error.set_location(location());
+            return Err(error);
+        }
+    };
+
+    let mut lines = Vec::new();
+    for (key, value) in table {
+        if !FRONTMATTER_KEY_RE.is_match(&key) {
+            let mut error: Error = ErrorKind::InvalidInput(
+                format!("invalid key in the frontmatter: {}", key),
+            ).into();
+            error.set_location(location());
+            return Err(error);
+        }
+
+        // Routes are not JSON, and a script can have more than one
+        let values = match (key.as_str(), value) {
+            ("Route", toml::Value::Array(routes)) => routes,
+            (_, value) => vec![value],
+        };
+        for value in values {
+            let data = match (key.as_str(), value) {
+                ("Route", toml::Value::String(route)) => route,
+                (_, value) => serde_json::to_string(&value)?,
+            };
+            let content = if key == "Fisher" {
+                format!("## Fisher: {}", data)
+            } else {
+                format!("## Fisher-{}: {}", key, data)
+            };
+            lines.push(HeaderLine {
+                content,
+                file: file.into(),
+                line,
+            });
+        }
+    }
+
+    Ok(lines)
+}
+
+
+fn load_headers(file: &str) -> Result<LoadHeadersOutput> {
+    let mut providers = vec![];
+    let mut deny = vec![];
+    let mut preferences = None;
+    let mut require_all = false;
+    let mut rate_limit = None;
+    let mut ip_filter = None;
+    let mut routes = vec![];
+    let mut concurrency = None;
+    let mut coalesce = None;
+    let mut schedule = None;
+    let mut env = None;
+    for header in read_header_lines(file)? {
+        let content = &header.content;
+
         if preferences.is_none() {
-            if let Some(cap) = PREFERENCES_HEADER_RE.captures(&content) {
-                preferences = Some(serde_json::from_str(&cap[1])?);
+            if let Some(cap) = PREFERENCES_HEADER_RE.captures(content) {
+                match serde_json::from_str(&cap[1]) {
+                    Ok(parsed) => preferences = Some(parsed),
+                    Err(error) => {
+                        let mut error: Error = error.into();
+                        error.set_location(header.location());
+                        return Err(error);
+                    }
+                }
                 continue; // Don't capture anything else for this line
             }
         }
 
-        if let Some(cap) = PROVIDER_HEADER_RE.captures(&content) {
+        if let Some(cap) = REQUIRE_ALL_HEADER_RE.captures(content) {
+            match serde_json::from_str(&cap[1]) {
+                Ok(parsed) => require_all = parsed,
+                Err(error) => {
+                    let mut error: Error = error.into();
+                    error.set_location(header.location());
+                    return Err(error);
+                }
+            }
+            continue;
+        }
+
+        if let Some(cap) = RATE_LIMIT_HEADER_RE.captures(content) {
+            match HookRateLimit::new(&cap[1]) {
+                Ok(parsed) => rate_limit = Some(parsed),
+                Err(mut error) => {
+                    error.set_location(header.location());
+                    return Err(error);
+                }
+            }
+            continue;
+        }
+
+        if let Some(cap) = IP_FILTER_HEADER_RE.captures(content) {
+            match IpFilter::new(&cap[1]) {
+                Ok(parsed) => ip_filter = Some(parsed),
+                Err(mut error) => {
+                    error.set_location(header.location());
+                    return Err(error);
+                }
+            }
+            continue;
+        }
+
+        if let Some(cap) = ROUTE_HEADER_RE.captures(content) {
+            match parse_route(&cap[1]) {
+                Ok(parsed) => routes.push(parsed),
+                Err(mut error) => {
+                    error.set_location(header.location());
+                    return Err(error);
+                }
+            }
+            continue;
+        }
+
+        if let Some(cap) = CONCURRENCY_HEADER_RE.captures(content) {
+            match parse_concurrency(&cap[1]) {
+                Ok(parsed) => concurrency = Some(parsed),
+                Err(mut error) => {
+                    error.set_location(header.location());
+                    return Err(error);
+                }
+            }
+            continue;
+        }
+
+        if let Some(cap) = COALESCE_HEADER_RE.captures(content) {
+            match HookCoalesce::new(&cap[1]) {
+                Ok(parsed) => coalesce = Some(parsed),
+                Err(mut error) => {
+                    error.set_location(header.location());
+                    return Err(error);
+                }
+            }
+            continue;
+        }
+
+        if let Some(cap) = SCHEDULE_HEADER_RE.captures(content) {
+            match parse_schedule(&cap[1]) {
+                Ok(parsed) => schedule = Some(parsed),
+                Err(mut error) => {
+                    error.set_location(header.location());
+                    return Err(error);
+                }
+            }
+            continue;
+        }
+
+        if let Some(cap) = ENV_HEADER_RE.captures(content) {
+            match HookEnv::new(&cap[1]) {
+                Ok(parsed) => env = Some(parsed),
+                Err(mut error) => {
+                    error.set_location(header.location());
+                    return Err(error);
+                }
+            }
+            continue;
+        }
+
+        // Deny rules are providers rejecting the requests they accept
+        let deny_cap = DENY_HEADER_RE.captures(content);
+        let is_deny = deny_cap.is_some();
+        let cap = deny_cap.or_else(|| PROVIDER_HEADER_RE.captures(content));
+
+        if let Some(cap) = cap {
             let name = &cap[1];
             let data = &cap[2];
 
             match Provider::new(name, data) {
                 Ok(provider) => {
-                    providers.push(Arc::new(provider));
+                    if is_deny {
+                        deny.push(Arc::new(provider));
+                    } else {
+                        providers.push(Arc::new(provider));
+                    }
                 }
                 Err(mut error) => {
-                    error.set_location(
-                        ErrorLocation::File(file.into(), Some(line_number)),
-                    );
+                    error.set_location(header.location());
                     return Err(error);
                 }
             }
@@ -125,6 +682,15 @@ fn load_headers(file: &str) -> Result<LoadHeadersOutput> {
             Preferences::empty()
         },
         providers: providers,
+        deny: deny,
+        require_all: require_all,
+        rate_limit: rate_limit,
+        ip_filter: ip_filter,
+        routes: routes,
+        concurrency: concurrency,
+        coalesce: coalesce,
+        schedule: schedule,
+        env: env,
     })
 }
 
@@ -134,8 +700,39 @@ pub struct Script {
     id: UniqueId,
     name: String,
     exec: String,
+    interpreter: Option<Interpreter>,
     priority: isize,
-    parallel: bool,
+    concurrency: Option<usize>,
+    body_storage: Option<BodyStorageKind>,
+    heartbeat: Option<u64>,
+    sync: bool,
+    timeout: Option<Duration>,
+    methods: Vec<HookMethod>,
+    max_retries: u32,
+    backoff: u64,
+    limits: ResourceLimits,
+    sandbox: Option<bool>,
+    persistent_workdir: bool,
+    keep_workdir_on_failure: Option<bool>,
+    stdin: Option<HookStdin>,
+    secrets: Option<HookSecrets>,
+    nice: Option<Niceness>,
+    io_class: Option<IoClass>,
+    umask: Option<Umask>,
+    locale: Option<Locale>,
+    timezone: Option<Timezone>,
+    image: Option<ContainerImage>,
+    firejail: Option<FirejailConfig>,
+    artifacts: Vec<ArtifactPattern>,
+    delay: Option<Duration>,
+    require_all: bool,
+    rate_limit: Option<HookRateLimit>,
+    coalesce: Option<HookCoalesce>,
+    schedule: Option<CronSchedule>,
+    env: Option<HookEnv>,
+    ip_filter: Option<IpFilter>,
+    routes: Vec<String>,
+    deny: Vec<Arc<Provider>>,
     pub(crate) providers: Vec<Arc<Provider>>,
 }
 
@@ -147,32 +744,147 @@ impl Script {
     ) -> Result<Self> {
         let headers = load_headers(&exec)?;
 
+        // Scripts which can't be run in parallel are limited to one job
+        let concurrency = if headers.preferences.parallel() {
+            headers.concurrency
+        } else {
+            Some(1)
+        };
+
+        // Debouncing is coalescing all the pending jobs together
+        let coalesce = match (headers.preferences.debounce(), headers.coalesce)
+        {
+            (Some(..), Some(..)) => {
+                return Err(ErrorKind::InvalidInput(
+                    "debounce can't be used with Fisher-Coalesce".into(),
+                ).into());
+            }
+            (Some(window), None) => Some(HookCoalesce::debounce(window)),
+            (None, coalesce) => coalesce,
+        };
+
+        // Containers are already isolated by their runtime
+        if headers.preferences.image.is_some()
+            && headers.preferences.firejail.is_some()
+        {
+            return Err(ErrorKind::InvalidInput(
+                "firejail can't be used with an image".into(),
+            ).into());
+        }
+
+        // Relative paths of the secrets start from the script's directory
+        let secrets = headers.preferences.secrets.as_ref().map(|path| {
+            let dir = Path::new(&exec).parent();
+            HookSecrets::new(dir.unwrap_or_else(|| Path::new("")).join(path))
+        });
+
+        // The accessors borrow the preferences, so they must be called before
+        // their fields are moved into the script
+        let priority = headers.preferences.priority();
+        let heartbeat = headers.preferences.heartbeat();
+        let sync = headers.preferences.sync();
+        let timeout = headers.preferences.timeout();
+        let methods = headers.preferences.methods();
+        let max_retries = headers.preferences.max_retries();
+        let backoff = headers.preferences.backoff();
+        let limits = headers.preferences.limits();
+        let persistent_workdir = headers.preferences.persistent_workdir();
+        let delay = headers.preferences.delay();
+
         Ok(Script {
             id: state.next_id(IdKind::HookId),
             name: name,
             exec: exec,
-            priority: headers.preferences.priority(),
-            parallel: headers.preferences.parallel(),
+            interpreter: None,
+            priority: priority,
+            concurrency: concurrency,
+            body_storage: headers.preferences.body_storage,
+            heartbeat: heartbeat,
+            sync: sync,
+            timeout: timeout,
+            methods: methods,
+            max_retries: max_retries,
+            backoff: backoff,
+            limits: limits,
+            sandbox: headers.preferences.sandbox,
+            persistent_workdir: persistent_workdir,
+            keep_workdir_on_failure:
+                headers.preferences.keep_workdir_on_failure,
+            stdin: headers.preferences.stdin,
+            secrets: secrets,
+            nice: headers.preferences.nice,
+            io_class: headers.preferences.io_class,
+            umask: headers.preferences.umask,
+            locale: headers.preferences.locale,
+            timezone: headers.preferences.timezone,
+            image: headers.preferences.image,
+            firejail: headers.preferences.firejail,
+            artifacts: headers.preferences.artifacts.unwrap_or_default(),
+            delay: delay,
+            require_all: headers.require_all,
+            rate_limit: headers.rate_limit,
+            coalesce: coalesce,
+            schedule: headers.schedule,
+            env: headers.env,
+            ip_filter: headers.ip_filter,
+            routes: headers.routes,
+            deny: headers.deny,
             providers: headers.providers,
         })
     }
 
+    /// Validate the request, returning its type, the provider which accepted
+    /// it, and the environment the provider computed while validating it.
     pub fn validate(
         &self,
         req: &Request,
-    ) -> (RequestType, Option<Arc<Provider>>) {
-        if !self.providers.is_empty() {
+    ) -> (
+        RequestType,
+        Option<Arc<Provider>>,
+        Option<HashMap<String, String>>,
+    ) {
+        // Requests from addresses not allowed never reach the providers
+        if let (&Some(ref filter), Ok(web)) = (&self.ip_filter, req.web()) {
+            if !filter.allows(&web.source) {
+                return (RequestType::Invalid, None, None);
+            }
+        }
+
+        // Deny rules win over every other provider, but they only match the
+        // requests which would execute the hook
+        for rule in &self.deny {
+            if rule.validate(req) == RequestType::ExecuteHook {
+                return (RequestType::Invalid, None, None);
+            }
+        }
+
+        if self.require_all && !self.providers.is_empty() {
+            // Every provider must accept the request, and the first one
+            // decides its type and environment
+            let mut accepted = None;
+            for provider in &self.providers {
+                let (result, env) = provider.validate_with_env(req);
+
+                if result == RequestType::Invalid {
+                    return (RequestType::Invalid, None, None);
+                } else if accepted.is_none() {
+                    accepted = Some((result, provider.clone(), env));
+                }
+            }
+            let (result, provider, env) = accepted.unwrap();
+            (result, Some(provider), env)
+        } else if !self.providers.is_empty() {
             // Check every provider if they're present
             for provider in &self.providers {
-                let result = provider.validate(req);
+                let (result, env) = provider.validate_with_env(req);
 
                 if result != RequestType::Invalid {
-                    return (result, Some(provider.clone()));
+                    return (result, Some(provider.clone()), env);
                 }
             }
-            (RequestType::Invalid, None)
+            (RequestType::Invalid, None, None)
         } else {
-            (RequestType::ExecuteHook, None)
+            (RequestType::ExecuteHook, None, None)
         }
     }
 
@@ -184,71 +896,248 @@ impl Script {
         &self.exec
     }
 
+    /// The interpreter running the script, if it's not executable.
+    pub fn interpreter(&self) -> Option<&Interpreter> {
+        self.interpreter.as_ref()
+    }
+
+    pub(in scripts) fn set_interpreter(&mut self, interpreter: Interpreter) {
+        self.interpreter = Some(interpreter);
+    }
+
     pub fn priority(&self) -> isize {
         self.priority
     }
-}
 
-impl ScriptTrait for Script {
-    type Id = UniqueId;
+    pub fn providers(&self) -> &[Arc<Provider>] {
+        &self.providers
+    }
 
-    fn id(&self) -> UniqueId {
-        self.id
+    pub fn body_storage(&self) -> Option<BodyStorageKind> {
+        self.body_storage
     }
 
-    fn can_be_parallel(&self) -> bool {
-        self.parallel
+    /// Reserve a slot for a new execution of the hook, if it's rate limited.
+    pub fn rate_limit(&self) -> RateLimitResult {
+        if let Some(ref limit) = self.rate_limit {
+            limit.reserve()
+        } else {
+            RateLimitResult::Allowed
+        }
     }
-}
 
+    /// Return how the pending jobs of the hook are coalesced, if they are.
+    pub fn coalesce(&self) -> Option<&HookCoalesce> {
+        self.coalesce.as_ref()
+    }
 
-#[cfg(test)]
-mod tests {
-    use common::prelude::*;
-    use requests::{Request, RequestType};
-    use scripts::test_utils::*;
+    /// Return when the hook is executed periodically, if it is.
+    pub fn schedule(&self) -> Option<&CronSchedule> {
+        self.schedule.as_ref()
+    }
 
+    /// Return the environment variables templated over the requests, if
+    /// the hook defines any.
+    pub fn env(&self) -> Option<&HookEnv> {
+        self.env.as_ref()
+    }
 
-    #[test]
-    fn test_scripts_are_loaded_properly() {
-        // This little helper avoids repeating code all the time
-        fn create_and_assert(
-            env: &TestEnv,
-            name: &str,
-            content: &[&str],
-            priority: isize,
-            parallel: bool,
-            providers: &[&str],
-        ) -> Result<()> {
-            // Create and load the script
-            env.create_script(name, content)?;
-            let script = env.load_script(name)?;
+    /// The maximum number of seconds allowed between two deliveries.
+    pub fn heartbeat(&self) -> Option<u64> {
+        self.heartbeat
+    }
 
-            // Check if the basic attributes are loaded properly
-            assert_eq!(script.name(), name);
-            assert_eq!(script.priority(), priority);
-            assert_eq!(script.can_be_parallel(), parallel);
+    /// Whether the client waits for the output of the script.
+    pub fn is_sync(&self) -> bool {
+        self.sync
+    }
 
-            // Check if the executable path is correct
-            assert_eq!(
-                script.exec(),
-                env.scripts_dir().join(name).to_str().unwrap()
-            );
+    /// After how long the script is killed.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
 
-            assert_eq!(script.providers.len(), providers.len());
-            for provider in &script.providers {
-                assert!(providers.contains(&provider.name()));
-            }
+    /// The HTTP methods the script can be called with.
+    /// The resource limits of the jobs, overriding the global ones.
+    pub fn limits(&self) -> &ResourceLimits {
+        &self.limits
+    }
 
-            Ok(())
-        }
+    /// How long the jobs wait before being run.
+    pub fn delay(&self) -> Option<Duration> {
+        self.delay
+    }
 
-        test_wrapper(|env| {
-            // Check if naked scripts are loaded properly
-            create_and_assert(
-                &env,
-                "naked.sh",
-                &[r#"#!/bin/bash"#, r#"echo "This is a naked script""#],
+    /// Whether the jobs run in a sandbox, overriding the global setting.
+    pub fn sandbox(&self) -> Option<bool> {
+        self.sandbox
+    }
+
+    /// Whether the working directory is preserved between the jobs.
+    pub fn persistent_workdir(&self) -> bool {
+        self.persistent_workdir
+    }
+
+    /// Whether the working directory of failed jobs is kept, overriding the
+    /// global setting.
+    pub fn keep_workdir_on_failure(&self) -> Option<bool> {
+        self.keep_workdir_on_failure
+    }
+
+    /// What the script receives on its standard input, if anything.
+    pub fn stdin(&self) -> Option<HookStdin> {
+        self.stdin
+    }
+
+    /// Where the secrets injected in the environment are stored, if any.
+    pub fn secrets(&self) -> Option<&HookSecrets> {
+        self.secrets.as_ref()
+    }
+
+    /// The niceness of the jobs, if it's changed.
+    pub fn nice(&self) -> Option<Niceness> {
+        self.nice
+    }
+
+    /// The IO scheduling class of the jobs, if it's changed.
+    pub fn io_class(&self) -> Option<IoClass> {
+        self.io_class
+    }
+
+    /// The umask of the jobs, if it's changed.
+    pub fn umask(&self) -> Option<Umask> {
+        self.umask
+    }
+
+    /// The locale of the jobs, if it's changed.
+    pub fn locale(&self) -> Option<&Locale> {
+        self.locale.as_ref()
+    }
+
+    /// The timezone of the jobs, if it's changed.
+    pub fn timezone(&self) -> Option<&Timezone> {
+        self.timezone.as_ref()
+    }
+
+    /// The image of the container the jobs run in, if they don't run on the
+    /// host.
+    pub fn image(&self) -> Option<&ContainerImage> {
+        self.image.as_ref()
+    }
+
+    /// How firejail restricts the jobs, if they're launched with it.
+    pub fn firejail(&self) -> Option<&FirejailConfig> {
+        self.firejail.as_ref()
+    }
+
+    /// The patterns of the files collected after the jobs end.
+    pub fn artifacts(&self) -> &[ArtifactPattern] {
+        &self.artifacts
+    }
+
+    pub fn methods(&self) -> &[HookMethod] {
+        &self.methods
+    }
+
+    /// Check if the script can be called with the HTTP method.
+    pub fn allows_method(&self, method: &str) -> bool {
+        self.methods.iter().any(|allowed| allowed.name() == method)
+    }
+
+    /// The custom paths the script is reachable at, without the leading
+    /// slash. Scripts with custom routes aren't reachable at `/hook/<name>`.
+    pub fn routes(&self) -> &[String] {
+        &self.routes
+    }
+
+    /// How many times a failed job of the script is retried.
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries
+    }
+
+    /// How long to wait before the retry of a failed job, doubling the
+    /// delay at every retry.
+    pub fn retry_delay(&self, retry: u32) -> Duration {
+        // The delay is capped anyway, so there's no need to go further
+        let exponent = cmp::min(retry.saturating_sub(1), 32);
+        let delay = self.backoff.saturating_mul(1 << exponent);
+        Duration::from_secs(cmp::min(delay, MAX_RETRY_DELAY))
+    }
+
+    /// Whether more than one job of the script can run at the same time.
+    pub fn can_be_parallel(&self) -> bool {
+        self.concurrency != Some(1)
+    }
+}
+
+impl ScriptTrait for Script {
+    type Id = UniqueId;
+
+    fn id(&self) -> UniqueId {
+        self.id
+    }
+
+    fn max_concurrency(&self) -> Option<usize> {
+        self.concurrency
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use common::prelude::*;
+    use requests::{Request, RequestType};
+    use scripts::RateLimitResult;
+    use super::HookMethod;
+    use scripts::test_utils::*;
+
+
+    #[test]
+    fn test_scripts_are_loaded_properly() {
+        // This little helper avoids repeating code all the time
+        fn create_and_assert(
+            env: &TestEnv,
+            name: &str,
+            content: &[&str],
+            priority: isize,
+            parallel: bool,
+            providers: &[&str],
+        ) -> Result<()> {
+            // Create and load the script
+            env.create_script(name, content)?;
+            let script = env.load_script(name)?;
+
+            // Check if the basic attributes are loaded properly
+            assert_eq!(script.name(), name);
+            assert_eq!(script.priority(), priority);
+            assert_eq!(script.can_be_parallel(), parallel);
+
+            // Check if the executable path is correct
+            assert_eq!(
+                script.exec(),
+                env.scripts_dir().join(name).to_str().unwrap()
+            );
+
+            assert_eq!(script.providers.len(), providers.len());
+            for provider in &script.providers {
+                assert!(providers.contains(&provider.name()));
+            }
+
+            Ok(())
+        }
+
+        test_wrapper(|env| {
+            // Check if naked scripts are loaded properly
+            create_and_assert(
+                &env,
+                "naked.sh",
+                &[r#"#!/bin/bash"#, r#"echo "This is a naked script""#],
                 0,
                 true,
                 &[],
@@ -372,6 +1261,722 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_require_all_providers() {
+        test_wrapper(|env| {
+            env.create_script(
+                "any.sh",
+                &[
+                    r#"#!/bin/bash"#,
+                    r#"## Fisher-Standalone: {"secret": "abcde"}"#,
+                    r#"## Fisher-Testing: {}"#,
+                ],
+            )?;
+            env.create_script(
+                "all.sh",
+                &[
+                    r#"#!/bin/bash"#,
+                    r#"## Fisher-RequireAll: true"#,
+                    r#"## Fisher-Testing: {}"#,
+                    r#"## Fisher-Standalone: {"secret": "abcde"}"#,
+                ],
+            )?;
+            env.create_script(
+                "wrong.sh",
+                &[
+                    r#"#!/bin/bash"#,
+                    r#"## Fisher-RequireAll: {}"#,
+                    r#"## Fisher-Testing: {}"#,
+                ],
+            )?;
+
+            let any = env.load_script("any.sh")?;
+            let all = env.load_script("all.sh")?;
+            assert!(env.load_script("wrong.sh").is_err());
+
+            // Only one of the providers accepts this request
+            let req = Request::Web(dummy_web_request());
+            assert_eq!(any.validate(&req).0, RequestType::ExecuteHook);
+            assert_eq!(all.validate(&req).0, RequestType::Invalid);
+
+            // Both providers accept this request, and the first one is used
+            let mut req = dummy_web_request();
+            req.headers.insert("X-Fisher-Secret".into(), "abcde".into());
+            let (result, provider, _) = all.validate(&Request::Web(req));
+            assert_eq!(result, RequestType::ExecuteHook);
+            assert_eq!(provider.unwrap().name(), "Testing");
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_deny_rules() {
+        test_wrapper(|env| {
+            env.create_script(
+                "deny.sh",
+                &[
+                    r#"#!/bin/bash"#,
+                    r#"## Fisher-Testing: {}"#,
+                    r#"## Fisher-Deny-Standalone: {"secret": "abcde"}"#,
+                ],
+            )?;
+            env.create_script(
+                "deny-only.sh",
+                &[
+                    r#"#!/bin/bash"#,
+                    r#"## Fisher-Deny-Standalone: {"from": ["127.0.0.1"]}"#,
+                ],
+            )?;
+
+            let deny = env.load_script("deny.sh")?;
+            let deny_only = env.load_script("deny-only.sh")?;
+
+            // Deny rules aren't counted as normal providers
+            assert_eq!(deny.providers.len(), 1);
+            assert!(deny_only.providers.is_empty());
+
+            // The deny rule doesn't match this request
+            let req = Request::Web(dummy_web_request());
+            assert_eq!(deny.validate(&req).0, RequestType::ExecuteHook);
+
+            // The deny rule matches this request, even if Testing accepts it
+            let mut req = dummy_web_request();
+            req.headers.insert("X-Fisher-Secret".into(), "abcde".into());
+            let req = Request::Web(req);
+            assert_eq!(deny.validate(&req).0, RequestType::Invalid);
+
+            // Requests from 127.0.0.1 are denied
+            assert_eq!(deny_only.validate(&req).0, RequestType::Invalid);
+            let mut req = dummy_web_request();
+            req.source = "10.0.0.1".parse().unwrap();
+            assert_eq!(
+                deny_only.validate(&Request::Web(req)).0,
+                RequestType::ExecuteHook
+            );
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_ip_filter() {
+        test_wrapper(|env| {
+            env.create_script("filtered.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-IpFilter: {"allow": ["10.0.0.0/8"]}"#,
+                r#"## Fisher-Testing: {}"#,
+            ])?;
+            let script = env.load_script("filtered.sh")?;
+            assert_eq!(script.providers.len(), 1);
+
+            let request = |ip: &str| {
+                let mut req = dummy_web_request();
+                req.source = ip.parse().unwrap();
+                Request::Web(req)
+            };
+
+            assert_eq!(
+                script.validate(&request("10.1.1.1")).0,
+                RequestType::ExecuteHook
+            );
+            assert_eq!(
+                script.validate(&request("11.1.1.1")).0,
+                RequestType::Invalid
+            );
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_invalid_config_comments_are_rejected() {
+        test_wrapper(|env| {
+            for &(name, comment) in &[
+                ("typo-prefs.sh", r#"## Fisher: {"priorty": 5}"#),
+                ("wrong-prefs.sh", r#"## Fisher: {"priority": "high"}"#),
+                ("typo-provider.sh", r#"## Fisher-Standalone: {"scret": 1}"#),
+                ("wrong-provider.sh", r#"## Fisher-Standalone: {"from": 1}"#),
+                ("wrong-limit.sh", r#"## Fisher-RateLimit: {"max": 1}"#),
+                ("wrong-filter.sh", r#"## Fisher-IpFilter: {"allow": "a"}"#),
+                ("relative-route.sh", r#"## Fisher-Route: deploy"#),
+                ("reserved-route.sh", r#"## Fisher-Route: /health"#),
+                ("weird-route.sh", r#"## Fisher-Route: /a b"#),
+                ("zero-concurrency.sh", r#"## Fisher-Concurrency: 0"#),
+                ("wrong-concurrency.sh", r#"## Fisher-Concurrency: "2""#),
+                ("wrong-method.sh", r#"## Fisher: {"methods": ["PUT"]}"#),
+                ("wrong-nice.sh", r#"## Fisher: {"nice": 20}"#),
+                ("wrong-io-class.sh", r#"## Fisher: {"io-class": "rt"}"#),
+                ("wrong-image.sh", r#"## Fisher: {"image": "-it"}"#),
+                ("wrong-firejail.sh", r#"## Fisher: {"firejail": "x"}"#),
+                ("wrong-artifacts.sh", r#"## Fisher: {"artifacts": ["/*"]}"#),
+                ("wrong-umask.sh", r#"## Fisher: {"umask": 22}"#),
+                ("unclosed-frontmatter.sh", "## +++"),
+                ("wrong-timezone.sh", r#"## Fisher: {"timezone": "../x"}"#),
+            ] {
+                env.create_script(name, &[
+                    r#"#!/bin/bash"#, r#"## Fisher-Testing: {}"#, comment,
+                ])?;
+
+                // The error must point to the wrong line
+                let error = env.load_script(name).err().expect(name);
+                let path = env.scripts_dir().join(name);
+                match *error.location() {
+                    ErrorLocation::File(ref file, line) => {
+                        assert_eq!(file, path.to_str().unwrap());
+                        assert_eq!(line, Some(3));
+                    }
+                    ref other => panic!("wrong location: {:?}", other),
+                }
+            }
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_script_heartbeat() {
+        test_wrapper(|env| {
+            env.create_script(
+                "heartbeat.sh",
+                &[r#"#!/bin/bash"#, r#"## Fisher: {"heartbeat": "1h30m"}"#],
+            )?;
+            env.create_script(
+                "disabled.sh",
+                &[r#"#!/bin/bash"#, r#"## Fisher: {"heartbeat": "0"}"#],
+            )?;
+            env.create_script("naked.sh", &[r#"#!/bin/bash"#])?;
+
+            let script = env.load_script("heartbeat.sh")?;
+            assert_eq!(script.heartbeat(), Some(5400));
+            assert_eq!(env.load_script("disabled.sh")?.heartbeat(), None);
+            assert_eq!(env.load_script("naked.sh")?.heartbeat(), None);
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_script_sync() {
+        test_wrapper(|env| {
+            env.create_script(
+                "sync.sh",
+                &[r#"#!/bin/bash"#, r#"## Fisher: {"sync": true}"#],
+            )?;
+            env.create_script(
+                "sync-timeout.sh",
+                &[
+                    r#"#!/bin/bash"#,
+                    r#"## Fisher: {"sync": true, "timeout": "5s"}"#,
+                ],
+            )?;
+            env.create_script(
+                "timeout.sh",
+                &[r#"#!/bin/bash"#, r#"## Fisher: {"timeout": "1m"}"#],
+            )?;
+            env.create_script("naked.sh", &[r#"#!/bin/bash"#])?;
+
+            let script = env.load_script("sync.sh")?;
+            assert!(script.is_sync());
+            assert_eq!(script.timeout(), Some(Duration::from_secs(30)));
+
+            let script = env.load_script("sync-timeout.sh")?;
+            assert!(script.is_sync());
+            assert_eq!(script.timeout(), Some(Duration::from_secs(5)));
+
+            let script = env.load_script("timeout.sh")?;
+            assert!(!script.is_sync());
+            assert_eq!(script.timeout(), Some(Duration::from_secs(60)));
+
+            let script = env.load_script("naked.sh")?;
+            assert!(!script.is_sync());
+            assert_eq!(script.timeout(), None);
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_script_sandbox() {
+        test_wrapper(|env| {
+            env.create_script(
+                "sandboxed.sh",
+                &[r#"#!/bin/bash"#, r#"## Fisher: {"sandbox": true}"#],
+            )?;
+            env.create_script(
+                "trusted.sh",
+                &[r#"#!/bin/bash"#, r#"## Fisher: {"sandbox": false}"#],
+            )?;
+            env.create_script("naked.sh", &[r#"#!/bin/bash"#])?;
+
+            assert_eq!(env.load_script("sandboxed.sh")?.sandbox(), Some(true));
+            assert_eq!(env.load_script("trusted.sh")?.sandbox(), Some(false));
+            assert_eq!(env.load_script("naked.sh")?.sandbox(), None);
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_script_image() {
+        test_wrapper(|env| {
+            env.create_script(
+                "container.sh",
+                &[r#"#!/bin/bash"#, r#"## Fisher: {"image": "alpine:3.6"}"#],
+            )?;
+            env.create_script("naked.sh", &[r#"#!/bin/bash"#])?;
+
+            env.create_script("both.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher: {"image": "alpine:3.6", "firejail": {}}"#,
+            ])?;
+
+            let script = env.load_script("container.sh")?;
+            assert_eq!(script.image().unwrap().name(), "alpine:3.6");
+            assert!(env.load_script("naked.sh")?.image().is_none());
+            assert!(env.load_script("both.sh").is_err());
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_script_firejail() {
+        test_wrapper(|env| {
+            env.create_script("jailed.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher: {"firejail": {"profile": "deploy"}}"#,
+            ])?;
+            env.create_script("naked.sh", &[r#"#!/bin/bash"#])?;
+
+            let script = env.load_script("jailed.sh")?;
+            assert_eq!(script.firejail().unwrap().profile(), Some("deploy"));
+            assert!(script.firejail().unwrap().seccomp().is_none());
+            assert!(env.load_script("naked.sh")?.firejail().is_none());
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_script_artifacts() {
+        test_wrapper(|env| {
+            env.create_script("build.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher: {"artifacts": ["dist/*", "./report.html"]}"#,
+            ])?;
+            env.create_script("naked.sh", &[r#"#!/bin/bash"#])?;
+
+            let script = env.load_script("build.sh")?;
+            assert_eq!(script.artifacts().len(), 2);
+            assert!(script.artifacts()[0].matches("dist/app.tar.gz"));
+            assert!(script.artifacts()[1].matches("report.html"));
+            assert!(env.load_script("naked.sh")?.artifacts().is_empty());
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_script_secrets() {
+        test_wrapper(|env| {
+            env.create_script("relative.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher: {"secrets": "secrets/deploy.env"}"#,
+            ])?;
+            env.create_script("absolute.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher: {"secrets": "/etc/fisher/secrets"}"#,
+            ])?;
+            env.create_script("naked.sh", &[r#"#!/bin/bash"#])?;
+
+            let relative = env.load_script("relative.sh")?;
+            assert_eq!(
+                relative.secrets().unwrap().path(),
+                env.scripts_dir().join("secrets").join("deploy.env"),
+            );
+
+            let absolute = env.load_script("absolute.sh")?;
+            assert_eq!(
+                absolute.secrets().unwrap().path(),
+                Path::new("/etc/fisher/secrets"),
+            );
+
+            assert!(env.load_script("naked.sh")?.secrets().is_none());
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_script_persistent_workdir() {
+        test_wrapper(|env| {
+            env.create_script("stateful.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher: {"persistent-workdir": true}"#,
+            ])?;
+            env.create_script("parallel.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher: {"persistent-workdir": true, "parallel": true}"#,
+            ])?;
+            env.create_script("naked.sh", &[r#"#!/bin/bash"#])?;
+
+            // Jobs sharing the directory are not run in parallel by default
+            let stateful = env.load_script("stateful.sh")?;
+            assert!(stateful.persistent_workdir());
+            assert_eq!(stateful.max_concurrency(), Some(1));
+
+            let parallel = env.load_script("parallel.sh")?;
+            assert!(parallel.persistent_workdir());
+            assert_eq!(parallel.max_concurrency(), None);
+
+            let naked = env.load_script("naked.sh")?;
+            assert!(!naked.persistent_workdir());
+            assert_eq!(naked.max_concurrency(), None);
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_script_methods() {
+        test_wrapper(|env| {
+            env.create_script(
+                "get.sh",
+                &[r#"#!/bin/bash"#, r#"## Fisher: {"methods": ["GET"]}"#],
+            )?;
+            env.create_script("naked.sh", &[r#"#!/bin/bash"#])?;
+
+            let script = env.load_script("get.sh")?;
+            assert_eq!(script.methods(), &[HookMethod::Get]);
+            assert!(script.allows_method("GET"));
+            assert!(!script.allows_method("POST"));
+
+            // Only POST requests are allowed by default
+            let script = env.load_script("naked.sh")?;
+            assert_eq!(script.methods(), &[HookMethod::Post]);
+            assert!(script.allows_method("POST"));
+            assert!(!script.allows_method("GET"));
+            assert!(!script.allows_method("post"));
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_script_frontmatter() {
+        test_wrapper(|env| {
+            env.create_script("frontmatter.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## +++"#,
+                r#"## [Fisher]"#,
+                r#"## priority = 10"#,
+                r#"## methods = ["GET", "POST"]"#,
+                r#"##"#,
+                r#"## [Testing]"#,
+                r#"##"#,
+                r#"## [Deny-Standalone]"#,
+                r#"## from = ["127.0.0.1"]"#,
+                r#"## +++"#,
+                r#"## Fisher-Route: /deploy"#,
+            ])?;
+            env.create_script("config-file.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Config-File: config-file.toml"#,
+            ])?;
+            env.create_script("wrong-key.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## +++"#,
+                r#"## "Fisher Testing" = {}"#,
+                r#"## +++"#,
+            ])?;
+            env.create_script("uncommented.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## +++"#,
+                r#"[Testing]"#,
+                r#"## +++"#,
+            ])?;
+
+            let mut config = File::create(
+                env.scripts_dir().join("config-file.toml"),
+            )?;
+            config.write_all(b"Route = [\"/a\", \"/b\"]\n\n[Testing]\n")?;
+
+            // The frontmatter can be mixed with the normal comments
+            let script = env.load_script("frontmatter.sh")?;
+            assert_eq!(script.priority(), 10);
+            assert_eq!(script.methods(), &[HookMethod::Get, HookMethod::Post]);
+            assert_eq!(script.providers.len(), 1);
+            assert_eq!(script.deny.len(), 1);
+            assert_eq!(script.routes(), &["deploy".to_string()]);
+
+            let script = env.load_script("config-file.sh")?;
+            assert_eq!(script.providers.len(), 1);
+            assert_eq!(script.routes(), &["a".to_string(), "b".to_string()]);
+
+            for name in &["wrong-key.sh", "uncommented.sh"] {
+                assert!(env.load_script(name).is_err(), "{}", name);
+            }
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_script_routes() {
+        test_wrapper(|env| {
+            env.create_script("routes.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Route: /deploy/frontend"#,
+                r#"## Fisher-Route: /deploy/www/"#,
+            ])?;
+            env.create_script("naked.sh", &[r#"#!/bin/bash"#])?;
+
+            let script = env.load_script("routes.sh")?;
+            assert_eq!(script.routes(), &[
+                "deploy/frontend".to_string(), "deploy/www".to_string(),
+            ]);
+            assert!(env.load_script("naked.sh")?.routes().is_empty());
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_script_retries() {
+        test_wrapper(|env| {
+            env.create_script("retried.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher: {"max-retries": 3, "backoff": "1m"}"#,
+            ])?;
+            env.create_script("default.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher: {"max-retries": 30}"#,
+            ])?;
+            env.create_script("naked.sh", &[r#"#!/bin/bash"#])?;
+
+            let script = env.load_script("retried.sh")?;
+            assert_eq!(script.max_retries(), 3);
+            assert_eq!(script.retry_delay(1), Duration::from_secs(60));
+            assert_eq!(script.retry_delay(2), Duration::from_secs(120));
+            assert_eq!(script.retry_delay(3), Duration::from_secs(240));
+
+            // The delay doesn't grow forever
+            let script = env.load_script("default.sh")?;
+            assert_eq!(script.retry_delay(1), Duration::from_secs(10));
+            assert_eq!(script.retry_delay(30), Duration::from_secs(3600));
+
+            assert_eq!(env.load_script("naked.sh")?.max_retries(), 0);
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_script_concurrency() {
+        test_wrapper(|env| {
+            env.create_script("limited.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Concurrency: 2"#,
+            ])?;
+            env.create_script("serial.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Concurrency: 1"#,
+            ])?;
+            env.create_script("not-parallel.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher: {"parallel": false}"#,
+                r#"## Fisher-Concurrency: 4"#,
+            ])?;
+            env.create_script("naked.sh", &[r#"#!/bin/bash"#])?;
+
+            let limited = env.load_script("limited.sh")?;
+            assert!(limited.providers.is_empty());
+            assert_eq!(limited.max_concurrency(), Some(2));
+            assert!(limited.can_be_parallel());
+
+            let serial = env.load_script("serial.sh")?;
+            assert_eq!(serial.max_concurrency(), Some(1));
+            assert!(!serial.can_be_parallel());
+
+            // Scripts which can't be parallel are always limited to one job
+            let script = env.load_script("not-parallel.sh")?;
+            assert_eq!(script.max_concurrency(), Some(1));
+
+            let naked = env.load_script("naked.sh")?;
+            assert_eq!(naked.max_concurrency(), None);
+            assert!(naked.can_be_parallel());
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_script_rate_limit() {
+        test_wrapper(|env| {
+            env.create_script("limited.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-RateLimit: {"max": 1, "per": "hour"}"#,
+            ])?;
+            env.create_script("naked.sh", &[r#"#!/bin/bash"#])?;
+
+            let limited = env.load_script("limited.sh")?;
+            assert!(limited.providers.is_empty());
+            assert_eq!(limited.rate_limit(), RateLimitResult::Allowed);
+            match limited.rate_limit() {
+                RateLimitResult::Rejected(..) => {}
+                other => panic!("wrong result: {:?}", other),
+            }
+
+            let naked = env.load_script("naked.sh")?;
+            for _ in 0..10 {
+                assert_eq!(naked.rate_limit(), RateLimitResult::Allowed);
+            }
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_script_coalesce() {
+        test_wrapper(|env| {
+            env.create_script("coalesced.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Coalesce: {"window": "1m", "key": "BRANCH"}"#,
+            ])?;
+            env.create_script("naked.sh", &[r#"#!/bin/bash"#])?;
+
+            let coalesced = env.load_script("coalesced.sh")?;
+            assert!(coalesced.providers.is_empty());
+            let coalesce = coalesced.coalesce().unwrap();
+            assert_eq!(coalesce.window(), Duration::from_secs(60));
+
+            let naked = env.load_script("naked.sh")?;
+            assert!(naked.coalesce().is_none());
+
+            env.create_script("invalid.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Coalesce: {"window": "0"}"#,
+            ])?;
+            assert!(env.load_script("invalid.sh").is_err());
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_script_delay() {
+        test_wrapper(|env| {
+            env.create_script(
+                "delayed.sh",
+                &[r#"#!/bin/bash"#, r#"## Fisher: {"delay": "30s"}"#],
+            )?;
+            env.create_script(
+                "debounced.sh",
+                &[r#"#!/bin/bash"#, r#"## Fisher: {"debounce": "1m"}"#],
+            )?;
+            env.create_script("naked.sh", &[r#"#!/bin/bash"#])?;
+
+            let delayed = env.load_script("delayed.sh")?;
+            assert_eq!(delayed.delay(), Some(Duration::from_secs(30)));
+            assert!(delayed.coalesce().is_none());
+
+            // Debounced scripts coalesce all their pending jobs
+            let debounced = env.load_script("debounced.sh")?;
+            assert_eq!(debounced.delay(), None);
+            let coalesce = debounced.coalesce().unwrap();
+            assert_eq!(coalesce.window(), Duration::from_secs(60));
+            assert_eq!(coalesce.key(&HashMap::new()), "");
+
+            let naked = env.load_script("naked.sh")?;
+            assert_eq!(naked.delay(), None);
+
+            env.create_script("conflict.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher: {"debounce": "1m"}"#,
+                r#"## Fisher-Coalesce: {"window": "1m"}"#,
+            ])?;
+            assert!(env.load_script("conflict.sh").is_err());
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_script_env() {
+        test_wrapper(|env| {
+            env.create_script("templated.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Env: {"DEPLOY_BRANCH": "{{ payload.ref }}"}"#,
+            ])?;
+            env.create_script("naked.sh", &[r#"#!/bin/bash"#])?;
+
+            let templated = env.load_script("templated.sh")?;
+            assert!(templated.providers.is_empty());
+            assert!(templated.env().is_some());
+            assert!(env.load_script("naked.sh")?.env().is_none());
+
+            env.create_script("invalid.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Env: {"BRANCH": "{{ body.ref }}"}"#,
+            ])?;
+            assert!(env.load_script("invalid.sh").is_err());
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_script_schedule() {
+        test_wrapper(|env| {
+            env.create_script("nightly.sh", &[
+                r#"#!/bin/bash"#,
+                r#"## Fisher-Schedule: "0 3 * * *""#,
+            ])?;
+            env.create_script("naked.sh", &[r#"#!/bin/bash"#])?;
+
+            let nightly = env.load_script("nightly.sh")?;
+            assert!(nightly.providers.is_empty());
+            assert_eq!(nightly.schedule().unwrap().next_after(0), Some(10800));
+
+            let naked = env.load_script("naked.sh")?;
+            assert!(naked.schedule().is_none());
+
+            // Schedules must be JSON strings
+            for invalid in &["0 3 * * *", r#""0 3 * *""#] {
+                env.create_script("invalid.sh", &[
+                    r#"#!/bin/bash"#,
+                    &format!("## Fisher-Schedule: {}", invalid),
+                ])?;
+                assert!(env.load_script("invalid.sh").is_err());
+            }
+
+            Ok(())
+        });
+    }
+
+
     #[test]
     fn test_script_ids_are_unique() {
         test_wrapper(|env| {
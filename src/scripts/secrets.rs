@@ -0,0 +1,199 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Secrets injected in the environment of the jobs.
+//!
+//! The secrets of a hook are stored outside of it, either in a file with a
+//! `NAME=value` definition per line or in a directory with a file per secret
+//! (named after the variable, like the ones mounted by Kubernetes). They're
+//! read every time a job runs, so they can be rotated without reloading
+//! Fisher.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use common::prelude::*;
+use utils;
+
+
+/// Where the secrets of a hook are stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookSecrets {
+    path: PathBuf,
+}
+
+impl HookSecrets {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        HookSecrets { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read the secrets, as the environment variables to set.
+    pub fn load(&self) -> Result<Vec<(String, String)>> {
+        let mut secrets = if fs::metadata(&self.path)?.is_dir() {
+            self.load_dir()?
+        } else {
+            let content = read_secret(&self.path)?;
+            let mut secrets = Vec::new();
+            for line in content.lines() {
+                // Empty lines and comments are ignored
+                if line.trim().is_empty() || line.starts_with('#') {
+                    continue;
+                }
+
+                let (name, value) = utils::parse_env(line)?;
+                secrets.push((name.to_string(), value.to_string()));
+            }
+            secrets
+        };
+
+        for &(ref name, _) in &secrets {
+            // Variables set by Fisher can't be overridden
+            if !utils::is_env_name(name) || name.starts_with("FISHER_") {
+                return Err(ErrorKind::InvalidInput(format!(
+                    "invalid secret name in {}: {}",
+                    self.path.to_string_lossy(),
+                    name,
+                )).into());
+            }
+        }
+
+        secrets.sort();
+        Ok(secrets)
+    }
+
+    fn load_dir(&self) -> Result<Vec<(String, String)>> {
+        let mut secrets = Vec::new();
+
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+
+            // Hidden files are skipped, since Kubernetes uses them to swap
+            // the secrets atomically
+            if name.starts_with('.') || !entry.path().is_file() {
+                continue;
+            }
+
+            let mut value = read_secret(&entry.path())?;
+            if value.ends_with('\n') {
+                value.pop();
+            }
+            secrets.push((name, value));
+        }
+
+        Ok(secrets)
+    }
+}
+
+
+fn read_secret(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+
+    // Secrets everyone can read are not secrets anymore
+    let mode = file.metadata()?.permissions().mode();
+    if mode & 0o007 != 0 {
+        return Err(ErrorKind::InvalidInput(format!(
+            "the secrets file {} is accessible by other users",
+            path.to_string_lossy(),
+        )).into());
+    }
+
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    Ok(content)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    use std::path::Path;
+
+    use utils;
+
+    use super::HookSecrets;
+
+
+    fn write(path: &Path, content: &str) {
+        fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .mode(0o600)
+            .open(path)
+            .unwrap()
+            .write_all(content.as_bytes())
+            .unwrap();
+    }
+
+
+    #[test]
+    fn test_load_file() {
+        let dir = utils::create_temp_dir().unwrap();
+        let path = dir.join("secrets");
+
+        write(&path, "# Deploy keys\nTOKEN=abc=\n\nPASSWORD= spaced \n");
+        assert_eq!(HookSecrets::new(&path).load().unwrap(), vec![
+            ("PASSWORD".to_string(), " spaced ".to_string()),
+            ("TOKEN".to_string(), "abc=".to_string()),
+        ]);
+
+        for invalid in &["TOKEN", "FISHER_TOKEN=a", "MY-TOKEN=a"] {
+            fs::remove_file(&path).unwrap();
+            write(&path, invalid);
+            assert!(HookSecrets::new(&path).load().is_err());
+        }
+
+        // Files readable by everyone are rejected
+        write(&path, "TOKEN=abc");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644))
+            .unwrap();
+        assert!(HookSecrets::new(&path).load().is_err());
+
+        assert!(HookSecrets::new(dir.join("missing")).load().is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+
+    #[test]
+    fn test_load_dir() {
+        let dir = utils::create_temp_dir().unwrap();
+
+        write(&dir.join("TOKEN"), "abc\n");
+        write(&dir.join("KEY"), "multi\nline");
+        write(&dir.join(".hidden"), "ignored");
+        fs::create_dir(dir.join("nested")).unwrap();
+        assert_eq!(HookSecrets::new(&dir).load().unwrap(), vec![
+            ("KEY".to_string(), "multi\nline".to_string()),
+            ("TOKEN".to_string(), "abc".to_string()),
+        ]);
+
+        File::create(dir.join("bad-name")).unwrap();
+        fs::set_permissions(
+            dir.join("bad-name"), fs::Permissions::from_mode(0o600),
+        ).unwrap();
+        assert!(HookSecrets::new(&dir).load().is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
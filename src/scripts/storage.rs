@@ -0,0 +1,366 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use rand::{self, Rng};
+use url::Url;
+
+use common::prelude::*;
+use common::config::{BodyStorageConfig, BodyStorageKind};
+use scripts::executor::JobEnv;
+use scripts::orphans;
+use utils;
+
+
+/// A place where request bodies can be stored before running a job.
+pub trait BodyStorage {
+    /// Store the body verbatim, returning where it's stored. The working
+    /// directory of the job is provided, but implementations are free to
+    /// ignore it.
+    fn store(&self, body: &[u8], working_directory: &Path)
+        -> Result<StoredBody>;
+}
+
+
+/// Return the storage backend of the provided kind.
+pub fn backend(
+    kind: BodyStorageKind,
+    config: &BodyStorageConfig,
+) -> Result<Box<BodyStorage>> {
+    Ok(match kind {
+        BodyStorageKind::Disk => Box::new(DiskStorage),
+        BodyStorageKind::Tmpfs => Box::new(TmpfsStorage {
+            path: PathBuf::from(&config.tmpfs_path),
+        }),
+        BodyStorageKind::ObjectStore => {
+            let url = if let Some(ref url) = config.object_store_url {
+                match Url::parse(url) {
+                    Ok(url) => url,
+                    Err(..) => {
+                        return Err(ErrorKind::InvalidInput(
+                            format!("invalid object store URL: {}", url),
+                        ).into());
+                    }
+                }
+            } else {
+                return Err(ErrorKind::InvalidInput(
+                    "the object store URL is not configured".into(),
+                ).into());
+            };
+            utils::http_client::check_url(&url)?;
+
+            Box::new(ObjectStoreStorage { url })
+        }
+    })
+}
+
+
+/// The location of a stored request body.
+#[derive(Debug, PartialEq, Eq)]
+pub enum StoredBody {
+    /// The body is in the working directory, and it's removed with it.
+    WorkingDirectory(PathBuf),
+    /// The body is in its own directory, which must be removed afterwards.
+    TempDirectory(PathBuf, PathBuf),
+    /// The body was uploaded to a remote location.
+    Remote(Url),
+}
+
+impl StoredBody {
+    /// Set the environment variables pointing to the body.
+    pub fn apply_env(&self, env: &mut JobEnv) {
+        match *self {
+            StoredBody::WorkingDirectory(ref file) |
+            StoredBody::TempDirectory(_, ref file) => {
+                env.set("FISHER_REQUEST_BODY", file);
+            }
+            StoredBody::Remote(ref url) => {
+                env.set("FISHER_REQUEST_BODY_URL", url.as_str());
+            }
+        }
+    }
+
+    /// Return the directory the body was stored in, if it's outside of the
+    /// working directory.
+    pub fn directory(&self) -> Option<&Path> {
+        match *self {
+            StoredBody::TempDirectory(ref dir, _) => Some(dir),
+            StoredBody::WorkingDirectory(..) | StoredBody::Remote(..) => None,
+        }
+    }
+
+    /// Remove the stored body, if it's not going to be removed automatically.
+    pub fn cleanup(self) -> Result<()> {
+        match self {
+            StoredBody::WorkingDirectory(..) => {}
+            StoredBody::TempDirectory(dir, _) => {
+                fs::remove_dir_all(&dir)?;
+            }
+            StoredBody::Remote(url) => {
+                // A failed removal doesn't affect the job, and the object
+                // store might be configured to expire objects anyway
+                let _ = utils::http_request("DELETE", &url, &[], b"");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+fn write_body(path: &Path, body: &[u8]) -> Result<()> {
+    let mut file = fs::File::create(path)?;
+    file.write_all(body)?;
+
+    Ok(())
+}
+
+
+/// Store the body in the job's working directory.
+pub struct DiskStorage;
+
+impl BodyStorage for DiskStorage {
+    fn store(&self, body: &[u8], working_directory: &Path)
+        -> Result<StoredBody>
+    {
+        let path = working_directory.join("request_body");
+        write_body(&path, body)?;
+
+        Ok(StoredBody::WorkingDirectory(path))
+    }
+}
+
+
+/// Store the body in a memory-backed filesystem, such as `/dev/shm`.
+pub struct TmpfsStorage {
+    path: PathBuf,
+}
+
+impl BodyStorage for TmpfsStorage {
+    fn store(&self, body: &[u8], _working_directory: &Path)
+        -> Result<StoredBody>
+    {
+        let dir = utils::create_temp_dir_in(&self.path)?;
+        let path = dir.join("request_body");
+
+        if let Err(error) = orphans::mark(&dir)
+            .and_then(|_| write_body(&path, body))
+        {
+            let _ = fs::remove_dir_all(&dir);
+            return Err(error);
+        }
+
+        Ok(StoredBody::TempDirectory(dir, path))
+    }
+}
+
+
+/// Upload the body to an object store, without touching the local disk.
+pub struct ObjectStoreStorage {
+    url: Url,
+}
+
+impl BodyStorage for ObjectStoreStorage {
+    fn store(&self, body: &[u8], working_directory: &Path)
+        -> Result<StoredBody>
+    {
+        // Use a random name for the object
+        let name: String = rand::thread_rng()
+            .gen_ascii_chars()
+            .take(20)
+            .collect();
+        let url = match self.url.join(&format!("fisher-{}", name)) {
+            Ok(url) => url,
+            Err(..) => {
+                return Err(ErrorKind::InvalidInput(
+                    format!("invalid object store URL: {}", self.url),
+                ).into());
+            }
+        };
+
+        // The object is recorded before being uploaded, so it's removed
+        // even if Fisher crashes in the middle of the upload
+        orphans::mark_remote(working_directory, &url)?;
+
+        let headers = vec![
+            ("Content-Type".to_string(), "application/octet-stream".into()),
+        ];
+        let status = utils::http_request("PUT", &url, &headers, body)?;
+
+        if status < 200 || status >= 300 {
+            return Err(ErrorKind::UnexpectedStatusCode(status).into());
+        }
+
+        Ok(StoredBody::Remote(url))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use common::config::{BodyStorageConfig, BodyStorageKind};
+    use scripts::test_utils::*;
+    use utils;
+
+    use super::{backend, StoredBody};
+
+
+    fn read(path: &::std::path::Path) -> String {
+        let mut buf = String::new();
+        File::open(path).unwrap().read_to_string(&mut buf).unwrap();
+        buf
+    }
+
+
+    #[test]
+    fn test_disk_storage() {
+        test_wrapper(|env| {
+            let dir = env.tempdir()?;
+
+            let storage = backend(
+                BodyStorageKind::Disk, &BodyStorageConfig::default(),
+            )?;
+            let stored = storage.store(b"hello\n", &dir)?;
+
+            assert_eq!(
+                stored,
+                StoredBody::WorkingDirectory(dir.join("request_body"))
+            );
+            assert_eq!(read(&dir.join("request_body")), "hello\n");
+
+            // The file is removed along with the working directory
+            stored.cleanup()?;
+            assert!(dir.join("request_body").exists());
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_tmpfs_storage() {
+        test_wrapper(|env| {
+            let working_directory = env.tempdir()?;
+            let tmpfs = env.tempdir()?;
+
+            let config = BodyStorageConfig {
+                tmpfs_path: tmpfs.to_str().unwrap().into(),
+                .. BodyStorageConfig::default()
+            };
+            let storage = backend(BodyStorageKind::Tmpfs, &config)?;
+            let stored = storage.store(b"hello\n", &working_directory)?;
+
+            let (dir, file) = match stored {
+                StoredBody::TempDirectory(ref dir, ref file) => {
+                    (dir.clone(), file.clone())
+                }
+                ref other => panic!("wrong stored body: {:?}", other),
+            };
+
+            // The body must not be in the working directory
+            assert!(dir.starts_with(&tmpfs));
+            assert!(!working_directory.join("request_body").exists());
+            assert_eq!(read(&file), "hello\n");
+
+            // The whole directory must be removed
+            stored.cleanup()?;
+            assert!(!dir.exists());
+
+            Ok(())
+        });
+    }
+
+
+    #[test]
+    fn test_object_store_storage() {
+        // The URL is required
+        assert!(backend(
+            BodyStorageKind::ObjectStore, &BodyStorageConfig::default(),
+        ).is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+
+            // Accept both the upload and the removal
+            for _ in 0..2 {
+                let (mut conn, _) = listener.accept().unwrap();
+
+                // Uploads end with the body, removals with the headers
+                let mut received = Vec::new();
+                let mut buffer = [0; 1024];
+                loop {
+                    let done = if received.starts_with(b"PUT") {
+                        received.ends_with(b"hello\n")
+                    } else {
+                        received.ends_with(b"\r\n\r\n")
+                    };
+                    if done {
+                        break;
+                    }
+
+                    let len = conn.read(&mut buffer).unwrap();
+                    if len == 0 {
+                        break;
+                    }
+                    received.extend_from_slice(&buffer[..len]);
+                }
+
+                conn.write_all(b"HTTP/1.0 200 OK\r\n\r\n").unwrap();
+                requests.push(String::from_utf8_lossy(&received).into_owned());
+            }
+
+            requests
+        });
+
+        let working_directory = utils::create_temp_dir().unwrap();
+
+        let config = BodyStorageConfig {
+            object_store_url: Some(format!("http://{}/bodies/", addr)),
+            .. BodyStorageConfig::default()
+        };
+        let storage = backend(BodyStorageKind::ObjectStore, &config).unwrap();
+        let stored = storage.store(b"hello\n", &working_directory).unwrap();
+
+        let url = match stored {
+            StoredBody::Remote(ref url) => url.clone(),
+            ref other => panic!("wrong stored body: {:?}", other),
+        };
+        assert!(url.path().starts_with("/bodies/fisher-"));
+        assert!(!working_directory.join("request_body").exists());
+
+        stored.cleanup().unwrap();
+        fs::remove_dir_all(&working_directory).unwrap();
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with(
+            &format!("PUT {} HTTP/1.0\r\n", url.path())
+        ));
+        assert!(requests[0].ends_with("\r\n\r\nhello\n"));
+        assert!(requests[1].starts_with(
+            &format!("DELETE {} HTTP/1.0\r\n", url.path())
+        ));
+    }
+}
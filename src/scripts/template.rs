@@ -0,0 +1,304 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Environment variables templated over the request.
+//!
+//! The values of the variables defined in the `Fisher-Env` configuration
+//! comment can contain placeholders like `{{ payload.ref }}`, replaced every
+//! time a job runs with the value taken from the request. The available
+//! placeholders are `payload.<path>` (a field of the JSON body, with array
+//! indexes as numbers), `headers.<name>` and `params.<name>`. Missing values
+//! are replaced with an empty string.
+
+use std::collections::BTreeMap;
+
+use serde_json::{self, Value};
+
+use common::prelude::*;
+use requests::Request;
+use utils;
+use web::WebRequest;
+
+
+/// The maximum size of a templated value exposed to the scripts.
+const MAX_VALUE_SIZE: usize = 4096;
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    /// A JSON pointer into the body of the request.
+    Payload(String),
+    Header(String),
+    Param(String),
+}
+
+
+/// A string with placeholders replaced by values of the request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self> {
+        let invalid = |reason: &str| -> Error {
+            ErrorKind::InvalidInput(
+                format!("invalid template \"{}\": {}", template, reason),
+            ).into()
+        };
+
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+
+            let end = match rest[start..].find("}}") {
+                Some(end) => start + end,
+                None => return Err(invalid("unclosed placeholder")),
+            };
+            let expr = rest[start + 2..end].trim();
+
+            let mut split = expr.splitn(2, '.');
+            let part = match (split.next(), split.next()) {
+                (Some("payload"), None) => Part::Payload(String::new()),
+                (Some("payload"), Some(path)) => Part::Payload(pointer(path)),
+                (Some("headers"), Some(name)) => Part::Header(name.into()),
+                (Some("params"), Some(name)) => Part::Param(name.into()),
+                _ => {
+                    return Err(invalid(
+                        &format!("unknown placeholder \"{}\"", expr),
+                    ));
+                }
+            };
+            parts.push(part);
+
+            rest = &rest[end + 2..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+
+        Ok(Template { parts })
+    }
+
+    /// Render the template, with the body of the request already parsed.
+    pub fn render(&self, req: &WebRequest, payload: Option<&Value>) -> String {
+        let mut result = String::new();
+
+        for part in &self.parts {
+            match *part {
+                Part::Text(ref text) => result.push_str(text),
+                Part::Payload(ref pointer) => {
+                    let value = payload.and_then(|p| p.pointer(pointer));
+                    match value {
+                        Some(&Value::String(ref string)) => {
+                            result.push_str(string);
+                        }
+                        Some(&Value::Null) | None => {}
+                        // Numbers, booleans, arrays and objects are rendered
+                        // as JSON
+                        Some(other) => result.push_str(&other.to_string()),
+                    }
+                }
+                Part::Header(ref name) => {
+                    // Header names are case insensitive
+                    let value = req.headers
+                        .iter()
+                        .find(|&(key, _)| key.eq_ignore_ascii_case(name))
+                        .map(|(_, value)| value.as_str());
+                    result.push_str(value.unwrap_or(""));
+                }
+                Part::Param(ref name) => {
+                    if let Some(value) = req.params.get(name) {
+                        result.push_str(value);
+                    }
+                }
+            }
+        }
+
+        result
+    }
+}
+
+
+/// Convert a dotted path into a JSON pointer.
+fn pointer(path: &str) -> String {
+    path.split('.')
+        .map(|key| format!("/{}", key.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+
+/// The environment variables a hook defines over the request.
+#[derive(Debug)]
+pub struct HookEnv {
+    vars: BTreeMap<String, Template>,
+}
+
+impl HookEnv {
+    /// Parse the content of the `Fisher-Env` configuration comment.
+    pub fn new(config: &str) -> Result<Self> {
+        let config: BTreeMap<String, String> = serde_json::from_str(config)?;
+
+        let mut vars = BTreeMap::new();
+        for (name, template) in config {
+            // Variables set by Fisher can't be overridden
+            if !utils::is_env_name(&name) || name.starts_with("FISHER_") {
+                return Err(ErrorKind::InvalidInput(
+                    format!("invalid environment variable name: {}", name),
+                ).into());
+            }
+
+            vars.insert(name, Template::parse(&template)?);
+        }
+
+        Ok(HookEnv { vars })
+    }
+
+    /// Return the variables rendered for the request. Only requests
+    /// received by the HTTP server have values to fill the templates with.
+    pub fn render(&self, request: &Request) -> Vec<(String, String)> {
+        let req = match *request {
+            Request::Web(ref req) => req,
+            Request::Status(..) | Request::Scheduled(..) => return Vec::new(),
+        };
+        let payload = serde_json::from_str::<Value>(&req.body).ok();
+
+        self.vars
+            .iter()
+            .map(|(name, template)| {
+                (name.clone(), template.render(req, payload.as_ref()))
+            })
+            // Values which can't be passed to the script are skipped
+            .filter(|&(_, ref value)| {
+                value.len() <= MAX_VALUE_SIZE && !value.contains('\0')
+            })
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use requests::Request;
+    use scripts::ScheduledRun;
+    use utils::testing::*;
+
+    use super::{HookEnv, Part, Template, MAX_VALUE_SIZE};
+
+
+    #[test]
+    fn test_parse() {
+        let template = Template::parse(
+            "deploy {{ payload.repository.full_name }}@{{payload.ref}}",
+        ).unwrap();
+        assert_eq!(template.parts, vec![
+            Part::Text("deploy ".into()),
+            Part::Payload("/repository/full_name".into()),
+            Part::Text("@".into()),
+            Part::Payload("/ref".into()),
+        ]);
+
+        let template = Template::parse(
+            "{{ headers.X-GitHub-Event }}{{ params.env }}{{ payload }}",
+        ).unwrap();
+        assert_eq!(template.parts, vec![
+            Part::Header("X-GitHub-Event".into()),
+            Part::Param("env".into()),
+            Part::Payload(String::new()),
+        ]);
+
+        assert_eq!(Template::parse("plain").unwrap().parts, vec![
+            Part::Text("plain".into()),
+        ]);
+        assert_eq!(Template::parse("{{ payload.a/b.~ }}").unwrap().parts, vec![
+            Part::Payload("/a~1b/~0".into()),
+        ]);
+
+        for invalid in &["{{ payload.ref", "{{ }}", "{{ env.HOME }}"] {
+            assert!(Template::parse(invalid).is_err());
+        }
+    }
+
+
+    #[test]
+    fn test_render() {
+        let mut req = dummy_web_request();
+        req.headers.insert("X-Event".into(), "push".into());
+        req.params.insert("env".into(), "prod".into());
+        let payload = serde_json::from_str(r#"{
+            "ref": "refs/heads/master",
+            "commits": [{"id": "abcd"}],
+            "size": 1,
+            "forced": false,
+            "before": null
+        }"#).unwrap();
+
+        let render = |template: &str| {
+            Template::parse(template).unwrap().render(&req, Some(&payload))
+        };
+        assert_eq!(render("{{ payload.ref }}"), "refs/heads/master");
+        assert_eq!(render("{{ payload.commits.0.id }}"), "abcd");
+        assert_eq!(render("{{payload.size}}/{{payload.forced}}"), "1/false");
+        assert_eq!(render("[{{ payload.before }}]"), "[]");
+        assert_eq!(render("[{{ payload.missing }}]"), "[]");
+        assert_eq!(render("{{ payload.commits }}"), r#"[{"id":"abcd"}]"#);
+        assert_eq!(render("{{ headers.x-event }}"), "push");
+        assert_eq!(render("{{ params.env }}-{{ params.no }}"), "prod-");
+
+        let template = Template::parse("{{ payload.ref }}").unwrap();
+        assert_eq!(template.render(&req, None), "");
+    }
+
+
+    #[test]
+    fn test_hook_env() {
+        let env = HookEnv::new(r#"{
+            "BRANCH": "{{ payload.ref }}",
+            "BIG": "{{ payload.big }}",
+            "STATIC": "value"
+        }"#).unwrap();
+
+        let mut req = dummy_web_request();
+        req.body = format!(
+            r#"{{"ref": "master", "big": "{}"}}"#,
+            "a".repeat(MAX_VALUE_SIZE + 1),
+        );
+        let req: Request = req.into();
+        assert_eq!(env.render(&req), vec![
+            ("BRANCH".to_string(), "master".to_string()),
+            ("STATIC".to_string(), "value".to_string()),
+        ]);
+
+        // Only requests received by the HTTP server are templated
+        let scheduled: Request = ScheduledRun { time: 0 }.into();
+        assert!(env.render(&scheduled).is_empty());
+
+        for invalid in &[
+            r#"{"FISHER_BRANCH": "master"}"#,
+            r#"{"A=B": "master"}"#,
+            r#"{"": "master"}"#,
+            r#"{"BRANCH": "{{ payload"}"#,
+            r#"["BRANCH"]"#,
+        ] {
+            assert!(HookEnv::new(invalid).is_err());
+        }
+    }
+}
@@ -103,10 +103,15 @@ impl TestEnv {
 
 pub fn dummy_web_request() -> WebRequest {
     WebRequest {
+        id: "dummy-request".into(),
+        method: "POST".into(),
+        url: "/hook/example.sh".into(),
         headers: HashMap::new(),
         params: HashMap::new(),
         source: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
         body: String::new(),
+        binary_body: None,
+        spooled: None,
     }
 }
 
@@ -0,0 +1,528 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{IpAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json;
+use url::Url;
+
+use common::prelude::*;
+use common::config::SiemConfig;
+use utils::{self, Signer};
+
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum SecurityEventKind {
+    ValidationFailed,
+    SourceBanned,
+    ValidationBurst,
+}
+
+impl SecurityEventKind {
+    fn name(&self) -> &'static str {
+        match *self {
+            SecurityEventKind::ValidationFailed => "validation_failed",
+            SecurityEventKind::SourceBanned => "source_banned",
+            SecurityEventKind::ValidationBurst => "validation_burst",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match *self {
+            SecurityEventKind::ValidationFailed => "Webhook validation failed",
+            SecurityEventKind::SourceBanned => "Source rate limited",
+            SecurityEventKind::ValidationBurst => {
+                "Burst of invalid webhooks"
+            }
+        }
+    }
+
+    // Severity on the CEF scale (0-10)
+    fn severity(&self) -> u8 {
+        match *self {
+            SecurityEventKind::ValidationFailed => 3,
+            SecurityEventKind::SourceBanned => 6,
+            SecurityEventKind::ValidationBurst => 8,
+        }
+    }
+
+    // Severity on the syslog scale (0-7, lower is worse)
+    fn syslog_severity(&self) -> u8 {
+        match *self {
+            SecurityEventKind::ValidationFailed => 5,
+            SecurityEventKind::SourceBanned => 4,
+            SecurityEventKind::ValidationBurst => 3,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct SecurityEvent {
+    kind: SecurityEventKind,
+    source: IpAddr,
+    hook: Option<String>,
+    count: Option<u64>,
+    timestamp: SystemTime,
+}
+
+impl SecurityEvent {
+    fn new(
+        kind: SecurityEventKind,
+        source: IpAddr,
+        hook: Option<&str>,
+    ) -> Self {
+        SecurityEvent {
+            kind,
+            source,
+            hook: hook.map(|h| h.to_string()),
+            count: None,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    fn timestamp_millis(&self) -> u64 {
+        match self.timestamp.duration_since(UNIX_EPOCH) {
+            Ok(since) => {
+                let millis = since.subsec_nanos() / 1_000_000;
+                since.as_secs() * 1000 + millis as u64
+            }
+            Err(..) => 0,
+        }
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::to_string(&json!({
+            "event": self.kind.name(),
+            "description": self.kind.description(),
+            "severity": self.kind.severity(),
+            "source_ip": self.source.to_string(),
+            "hook": self.hook,
+            "count": self.count,
+            "timestamp": self.timestamp_millis() / 1000,
+        })).unwrap()
+    }
+
+    fn to_cef(&self) -> String {
+        let mut extension = format!(
+            "rt={} src={}", self.timestamp_millis(), self.source,
+        );
+        if let Some(ref hook) = self.hook {
+            extension.push_str(&format!(
+                " cs1Label=hook cs1={}", cef_escape_extension(hook),
+            ));
+        }
+        if let Some(count) = self.count {
+            extension.push_str(&format!(" cnt={}", count));
+        }
+
+        format!(
+            "CEF:0|Fisher|Fisher|{}|{}|{}|{}|{}",
+            cef_escape_header(env!("CARGO_PKG_VERSION")),
+            self.kind.name(),
+            cef_escape_header(self.kind.description()),
+            self.kind.severity(),
+            extension,
+        )
+    }
+
+    fn to_syslog(&self) -> String {
+        // The events are sent with the local0 facility
+        format!(
+            "<{}>fisher: {}",
+            16 * 8 + self.kind.syslog_severity() as u16,
+            self.to_cef(),
+        )
+    }
+}
+
+
+fn cef_escape_header(input: &str) -> String {
+    input.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+
+fn cef_escape_extension(input: &str) -> String {
+    input
+        .replace('\\', "\\\\")
+        .replace('=', "\\=")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
+}
+
+
+#[derive(Debug, PartialEq, Eq)]
+enum Endpoint {
+    Http(Url),
+    SyslogUdp(String),
+    SyslogTcp(String),
+}
+
+impl Endpoint {
+    fn parse(input: &str) -> Result<Self> {
+        let url = match Url::parse(input) {
+            Ok(url) => url,
+            Err(..) => {
+                return Err(ErrorKind::InvalidInput(
+                    format!("invalid SIEM endpoint: {}", input),
+                ).into());
+            }
+        };
+
+        let addr = match (url.host_str(), url.port_or_known_default()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => format!("{}:514", host),
+            (None, _) => {
+                return Err(ErrorKind::InvalidInput(
+                    format!("missing host in the SIEM endpoint: {}", input),
+                ).into());
+            }
+        };
+
+        match url.scheme() {
+            "http" => Ok(Endpoint::Http(url)),
+            "udp" => Ok(Endpoint::SyslogUdp(addr)),
+            "tcp" => Ok(Endpoint::SyslogTcp(addr)),
+            scheme => Err(ErrorKind::InvalidInput(
+                format!("unsupported SIEM endpoint scheme: {}", scheme),
+            ).into()),
+        }
+    }
+
+    fn deliver(
+        &self, event: &SecurityEvent, signer: Option<&Signer>,
+    ) -> Result<()> {
+        match *self {
+            Endpoint::Http(ref url) => {
+                let body = event.to_json();
+
+                let mut headers = vec![
+                    ("Content-Type".into(), "application/json".into()),
+                ];
+                if let Some(signer) = signer {
+                    headers.push(signer.header(body.as_bytes()));
+                }
+
+                let status = utils::http_post(url, &headers, body.as_bytes())?;
+
+                if status < 200 || status >= 300 {
+                    return Err(ErrorKind::UnexpectedStatusCode(status).into());
+                }
+            }
+            Endpoint::SyslogUdp(ref addr) => {
+                let target = resolve(addr)?;
+                let socket = if target.is_ipv4() {
+                    UdpSocket::bind("0.0.0.0:0")?
+                } else {
+                    UdpSocket::bind("[::]:0")?
+                };
+                socket.send_to(event.to_syslog().as_bytes(), target)?;
+            }
+            Endpoint::SyslogTcp(ref addr) => {
+                let mut stream = TcpStream::connect(resolve(addr)?)?;
+                stream.set_write_timeout(Some(Duration::from_secs(10)))?;
+                write!(stream, "{}\n", event.to_syslog())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+fn resolve(addr: &str) -> Result<::std::net::SocketAddr> {
+    if let Some(resolved) = addr.to_socket_addrs()?.next() {
+        Ok(resolved)
+    } else {
+        Err(ErrorKind::InvalidInput(
+            format!("can't resolve the SIEM endpoint: {}", addr),
+        ).into())
+    }
+}
+
+
+#[derive(Debug)]
+struct BurstDetector {
+    threshold: u64,
+    interval: Duration,
+    data: HashMap<String, (Instant, u64)>,
+}
+
+impl BurstDetector {
+    fn new(threshold: u64, interval: u64) -> Self {
+        BurstDetector {
+            threshold,
+            interval: Duration::from_secs(interval),
+            data: HashMap::new(),
+        }
+    }
+
+    // Returns the number of failures if a burst was just detected
+    fn record(&mut self, hook: &str) -> Option<u64> {
+        if self.threshold == 0 {
+            return None;
+        }
+
+        let now = Instant::now();
+        let entry = self.data.entry(hook.to_string()).or_insert((now, 0));
+
+        // Start counting again if the interval expired
+        if now.duration_since(entry.0) > self.interval {
+            *entry = (now, 0);
+        }
+
+        entry.1 += 1;
+        if entry.1 == self.threshold {
+            Some(entry.1)
+        } else {
+            None
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub struct SiemSink {
+    sender: Mutex<mpsc::Sender<SecurityEvent>>,
+    bursts: Mutex<BurstDetector>,
+}
+
+impl SiemSink {
+    pub fn from_config(
+        config: &SiemConfig, signer: Option<Signer>,
+    ) -> Result<Option<Self>> {
+        let endpoint = if let Some(ref endpoint) = config.endpoint {
+            Endpoint::parse(endpoint)?
+        } else {
+            return Ok(None);
+        };
+
+        let (sender, receiver) = mpsc::channel::<SecurityEvent>();
+
+        // Events are delivered in a separate thread, to avoid slowing down
+        // the processing of incoming requests
+        thread::spawn(move || {
+            while let Ok(event) = receiver.recv() {
+                let result = endpoint.deliver(&event, signer.as_ref());
+                if let Err(error) = result {
+                    error.pretty_print();
+                }
+            }
+        });
+
+        Ok(Some(SiemSink {
+            sender: Mutex::new(sender),
+            bursts: Mutex::new(BurstDetector::new(
+                config.burst_threshold,
+                config.burst_interval.as_u64(),
+            )),
+        }))
+    }
+
+    pub fn validation_failed(&self, source: IpAddr, hook: &str) {
+        self.send(SecurityEvent::new(
+            SecurityEventKind::ValidationFailed, source, Some(hook),
+        ));
+
+        let burst = self.bursts.lock().unwrap().record(hook);
+        if let Some(count) = burst {
+            let mut event = SecurityEvent::new(
+                SecurityEventKind::ValidationBurst, source, Some(hook),
+            );
+            event.count = Some(count);
+
+            self.send(event);
+        }
+    }
+
+    pub fn source_banned(&self, source: IpAddr, hook: &str) {
+        self.send(SecurityEvent::new(
+            SecurityEventKind::SourceBanned, source, Some(hook),
+        ));
+    }
+
+    fn send(&self, event: SecurityEvent) {
+        // The sender thread never stops before the sink, so this can't fail
+        let _ = self.sender.lock().unwrap().send(event);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, UdpSocket};
+    use std::thread;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use serde_json;
+
+    use common::config::SigningConfig;
+    use utils::Signer;
+
+    use super::{BurstDetector, Endpoint, SecurityEvent, SecurityEventKind};
+
+
+    fn dummy_event(kind: SecurityEventKind) -> SecurityEvent {
+        let mut event = SecurityEvent::new(
+            kind, "127.0.0.1".parse().unwrap(), Some("example.sh"),
+        );
+        event.timestamp = UNIX_EPOCH + Duration::from_secs(1500000000);
+        event
+    }
+
+
+    #[test]
+    fn test_endpoint_parse() {
+        assert_eq!(
+            Endpoint::parse("http://127.0.0.1:8080/events").unwrap(),
+            Endpoint::Http("http://127.0.0.1:8080/events".parse().unwrap())
+        );
+        assert_eq!(
+            Endpoint::parse("udp://127.0.0.1").unwrap(),
+            Endpoint::SyslogUdp("127.0.0.1:514".into())
+        );
+        assert_eq!(
+            Endpoint::parse("tcp://siem.example.com:6514").unwrap(),
+            Endpoint::SyslogTcp("siem.example.com:6514".into())
+        );
+
+        assert!(Endpoint::parse("not an url").is_err());
+        assert!(Endpoint::parse("ftp://127.0.0.1").is_err());
+    }
+
+
+    #[test]
+    fn test_event_json() {
+        let mut event = dummy_event(SecurityEventKind::ValidationBurst);
+        event.count = Some(10);
+
+        let json: serde_json::Value =
+            serde_json::from_str(&event.to_json()).unwrap();
+        assert_eq!(json, json!({
+            "event": "validation_burst",
+            "description": "Burst of invalid webhooks",
+            "severity": 8,
+            "source_ip": "127.0.0.1",
+            "hook": "example.sh",
+            "count": 10,
+            "timestamp": 1500000000,
+        }));
+    }
+
+
+    #[test]
+    fn test_event_cef() {
+        let event = dummy_event(SecurityEventKind::ValidationFailed);
+        assert_eq!(event.to_syslog(), format!(
+            "<133>fisher: CEF:0|Fisher|Fisher|{}|validation_failed|{}|3|{}",
+            env!("CARGO_PKG_VERSION"),
+            "Webhook validation failed",
+            "rt=1500000000000 src=127.0.0.1 cs1Label=hook cs1=example.sh",
+        ));
+
+        // Extension values must be escaped
+        let mut event = dummy_event(SecurityEventKind::SourceBanned);
+        event.hook = Some("a=b\nc".into());
+        assert!(event.to_cef().ends_with(r"cs1=a\=b\nc"));
+    }
+
+
+    #[test]
+    fn test_burst_detector() {
+        let mut detector = BurstDetector::new(3, 60);
+
+        assert_eq!(detector.record("a"), None);
+        assert_eq!(detector.record("a"), None);
+        assert_eq!(detector.record("b"), None);
+        assert_eq!(detector.record("a"), Some(3));
+
+        // The burst is reported only once
+        assert_eq!(detector.record("a"), None);
+
+        // A zero threshold disables the detection
+        let mut detector = BurstDetector::new(0, 60);
+        for _ in 0..10 {
+            assert_eq!(detector.record("a"), None);
+        }
+    }
+
+
+    #[test]
+    fn test_deliver_syslog_udp() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let endpoint = Endpoint::parse(
+            &format!("udp://{}", socket.local_addr().unwrap()),
+        ).unwrap();
+
+        let event = dummy_event(SecurityEventKind::SourceBanned);
+        endpoint.deliver(&event, None).unwrap();
+
+        let mut buffer = [0; 1024];
+        let len = socket.recv(&mut buffer).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buffer[..len]),
+            event.to_syslog()
+        );
+    }
+
+
+    #[test]
+    fn test_deliver_http_signed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = Endpoint::parse(
+            &format!("http://{}/events", listener.local_addr().unwrap()),
+        ).unwrap();
+
+        let event = dummy_event(SecurityEventKind::SourceBanned);
+        let body = event.to_json();
+
+        let expected_body = body.clone();
+        let server = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+
+            // Read until the whole body is received
+            let mut received = Vec::new();
+            let mut buffer = [0; 1024];
+            while !received.ends_with(expected_body.as_bytes()) {
+                let len = conn.read(&mut buffer).unwrap();
+                if len == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buffer[..len]);
+            }
+
+            conn.write_all(b"HTTP/1.0 200 OK\r\n\r\n").unwrap();
+            String::from_utf8_lossy(&received).into_owned()
+        });
+
+        let signer = Signer::from_config(&SigningConfig {
+            secret: Some("secret".into()),
+            .. SigningConfig::default()
+        }).unwrap();
+        endpoint.deliver(&event, Some(&signer)).unwrap();
+
+        let (header, signature) = signer.header(body.as_bytes());
+        let received = server.join().unwrap();
+        assert!(received.contains(&format!("{}: {}\r\n", header, signature)));
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use common::prelude::*;
+
+
+/// Decode a base64 string, accepting both the standard and the URL-safe
+/// alphabets. The trailing padding is optional.
+pub fn from_base64(input: &str) -> Result<Vec<u8>> {
+    let trimmed = input.trim_right_matches('=');
+    let mut result = Vec::with_capacity(trimmed.len() * 3 / 4);
+
+    let mut buffer: u32 = 0;
+    let mut pending: u8 = 0;
+    for (i, byte) in trimmed.bytes().enumerate() {
+        let current = match byte {
+            b'A'...b'Z' => byte - b'A',
+            b'a'...b'z' => byte - b'a' + 26,
+            b'0'...b'9' => byte - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => {
+                return Err(
+                    ErrorKind::InvalidBase64Char(
+                        trimmed[i..].chars().next().unwrap(),
+                    ).into(),
+                );
+            }
+        };
+
+        buffer = (buffer << 6) | u32::from(current);
+        pending += 1;
+
+        if pending == 4 {
+            result.push((buffer >> 16) as u8);
+            result.push((buffer >> 8) as u8);
+            result.push(buffer as u8);
+            buffer = 0;
+            pending = 0;
+        }
+    }
+
+    match pending {
+        0 => {}
+        2 => {
+            result.push((buffer >> 4) as u8);
+        }
+        3 => {
+            result.push((buffer >> 10) as u8);
+            result.push((buffer >> 2) as u8);
+        }
+        _ => return Err(ErrorKind::InvalidBase64Length.into()),
+    }
+
+    Ok(result)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use common::prelude::*;
+
+    use super::from_base64;
+
+    #[test]
+    fn test_from_base64() {
+        assert_eq!(from_base64("").unwrap(), b"");
+        assert_eq!(from_base64("aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(from_base64("aGVsbG8").unwrap(), b"hello");
+        assert_eq!(from_base64("aGVsbG8h").unwrap(), b"hello!");
+        assert_eq!(from_base64("aGk=").unwrap(), b"hi");
+        assert_eq!(from_base64("-_8=").unwrap(), &[0xfb, 0xff]);
+        assert_eq!(from_base64("+/8=").unwrap(), &[0xfb, 0xff]);
+        assert_err!(from_base64("aGVsb"), ErrorKind::InvalidBase64Length);
+        assert_err!(from_base64("aG!s"), ErrorKind::InvalidBase64Char('!'));
+    }
+}
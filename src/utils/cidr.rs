@@ -0,0 +1,143 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::result::Result as StdResult;
+use std::str::FromStr;
+
+use serde::de::{Error as DeError, Visitor, Deserialize, Deserializer};
+
+use common::prelude::*;
+
+
+/// A range of IP addresses, in the CIDR notation (for example
+/// `10.0.0.0/8`). A single IP address is also accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Check if the IP address is part of this range.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, *ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Cidr> {
+        let error = || -> Error {
+            ErrorKind::InvalidInput(format!("invalid CIDR: {}", s)).into()
+        };
+
+        let mut parts = s.splitn(2, '/');
+        let addr = parts.next().unwrap().parse::<IpAddr>()?;
+
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = if let Some(prefix) = parts.next() {
+            prefix.parse::<u8>().map_err(|_| error())?
+        } else {
+            max_prefix
+        };
+        if prefix > max_prefix {
+            return Err(error());
+        }
+
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+struct CidrVisitor;
+
+impl<'de> Visitor<'de> for CidrVisitor {
+    type Value = Cidr;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an IP address or a CIDR range")
+    }
+
+    fn visit_str<E: DeError>(self, s: &str) -> StdResult<Cidr, E> {
+        s.parse().map_err(|e: Error| E::custom(e.to_string()))
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> StdResult<Cidr, D::Error> {
+        deserializer.deserialize_str(CidrVisitor)
+    }
+}
+
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let full_bytes = (prefix / 8) as usize;
+    if net[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+
+    let remaining_bits = prefix % 8;
+    if remaining_bits == 0 {
+        return true;
+    }
+
+    let mask = 0xffu8 << (8 - remaining_bits);
+    net[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::Cidr;
+
+
+    #[test]
+    fn test_cidr() {
+        let contains = |cidr: &str, ip: &str| {
+            cidr.parse::<Cidr>()
+                .unwrap()
+                .contains(&ip.parse::<IpAddr>().unwrap())
+        };
+
+        assert!(contains("10.0.0.0/8", "10.1.2.3"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+        assert!(contains("192.168.1.0/23", "192.168.0.42"));
+        assert!(!contains("192.168.1.0/24", "192.168.0.42"));
+        assert!(contains("127.0.0.1", "127.0.0.1"));
+        assert!(!contains("127.0.0.1", "127.0.0.2"));
+        assert!(contains("0.0.0.0/0", "1.2.3.4"));
+        assert!(contains("fd00::/8", "fd12::1"));
+        assert!(!contains("fd00::/8", "fe80::1"));
+        assert!(!contains("0.0.0.0/0", "::1"));
+
+        for invalid in &["", "10.0.0.0/33", "::/129", "10.0.0.0/a", "a/8"] {
+            assert!(invalid.parse::<Cidr>().is_err());
+        }
+    }
+}
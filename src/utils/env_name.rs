@@ -0,0 +1,60 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+
+/// Check if the string can be used as the name of an environment variable.
+pub fn is_env_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| match c {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '_' => true,
+        _ => false,
+    })
+}
+
+
+/// Convert an arbitrary key into a valid environment variable name, by
+/// uppercasing it and replacing everything except letters and numbers.
+pub fn env_name(key: &str) -> String {
+    key.chars()
+        .map(|c| match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' => c,
+            _ => '_',
+        })
+        .collect::<String>()
+        .to_uppercase()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{env_name, is_env_name};
+
+
+    #[test]
+    fn test_env_name() {
+        assert_eq!(env_name("region"), "REGION");
+        assert_eq!(env_name("event-type"), "EVENT_TYPE");
+        assert_eq!(env_name("a.b c"), "A_B_C");
+    }
+
+
+    #[test]
+    fn test_is_env_name() {
+        assert!(is_env_name("DEPLOY_KEY"));
+        assert!(is_env_name("token2"));
+        assert!(!is_env_name(""));
+        assert!(!is_env_name("A=B"));
+        assert!(!is_env_name("a-b"));
+    }
+}
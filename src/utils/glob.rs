@@ -0,0 +1,95 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+
+/// Check if the input matches the glob pattern. `*` matches any sequence of
+/// characters (including `/`), while `?` matches a single character.
+pub fn glob_matches(pattern: &str, input: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let input = input.chars().collect::<Vec<_>>();
+
+    let (mut p, mut i) = (0, 0);
+    // The position of the last `*`, and of the input when it was found
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while i < input.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == input[i])
+        {
+            p += 1;
+            i += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, i));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the last `*` match one more character
+            backtrack = Some((star, matched + 1));
+            p = star + 1;
+            i = matched + 1;
+        } else {
+            return false;
+        }
+    }
+
+    // Only trailing `*` can be left in the pattern
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+
+/// Check if the string is a glob pattern, rather than a plain string.
+pub fn is_glob(input: &str) -> bool {
+    input.contains('*') || input.contains('?')
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{glob_matches, is_glob};
+
+
+    #[test]
+    fn test_glob_matches() {
+        for &(pattern, input) in &[
+            ("master", "master"),
+            ("*", ""),
+            ("*", "anything/at/all"),
+            ("release/*", "release/1.0"),
+            ("release-?.?", "release-1.0"),
+            ("*-stable", "1.0-stable"),
+            ("a*b*c", "aXbYbZc"),
+            ("**", "abc"),
+        ] {
+            assert!(glob_matches(pattern, input), "{} {}", pattern, input);
+        }
+
+        for &(pattern, input) in &[
+            ("master", "main"),
+            ("master", "master2"),
+            ("release/*", "releases/1.0"),
+            ("release-?", "release-10"),
+            ("a*b*c", "aXbYbZ"),
+            ("?", ""),
+        ] {
+            assert!(!glob_matches(pattern, input), "{} {}", pattern, input);
+        }
+    }
+
+
+    #[test]
+    fn test_is_glob() {
+        assert!(is_glob("release/*"));
+        assert!(is_glob("v?"));
+        assert!(!is_glob("master"));
+    }
+}
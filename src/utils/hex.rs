@@ -16,6 +16,19 @@
 use common::prelude::*;
 
 
+pub fn to_hex(input: &[u8]) -> String {
+    static CHARS: &[u8] = b"0123456789abcdef";
+
+    let mut result = String::with_capacity(input.len() * 2);
+    for byte in input {
+        result.push(CHARS[(byte >> 4) as usize] as char);
+        result.push(CHARS[(byte & 0xf) as usize] as char);
+    }
+
+    result
+}
+
+
 pub fn from_hex(input: &str) -> Result<Vec<u8>> {
     let mut result = Vec::with_capacity(input.len() / 2);
 
@@ -58,7 +71,7 @@ pub fn from_hex(input: &str) -> Result<Vec<u8>> {
 mod tests {
     use common::prelude::*;
 
-    use super::from_hex;
+    use super::{from_hex, to_hex};
 
     #[test]
     fn test_from_hex() {
@@ -67,4 +80,11 @@ mod tests {
         assert_err!(from_hex("0"), ErrorKind::InvalidHexLength);
         assert_err!(from_hex("fg"), ErrorKind::InvalidHexChar('g'));
     }
+
+    #[test]
+    fn test_to_hex() {
+        assert_eq!(to_hex(b""), "");
+        assert_eq!(to_hex(b"hello"), "68656c6c6f");
+        assert_eq!(to_hex(&[0x00, 0x0f, 0xf0, 0xff]), "000ff0ff");
+    }
 }
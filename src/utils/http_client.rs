@@ -0,0 +1,491 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+#[cfg(feature = "tls")]
+use openssl::ssl::{SslConnector, SslMethod, SslStream};
+use url::Url;
+
+use common::prelude::*;
+
+
+/// A connection to an HTTP server, encrypted if the URL uses HTTPS.
+#[derive(Debug)]
+pub enum Stream {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(SslStream<TcpStream>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+
+/// Check if the client can connect to the URL, returning whether the
+/// connection is encrypted. HTTPS requires the `tls` feature.
+pub fn check_url(url: &Url) -> Result<bool> {
+    match url.scheme() {
+        "http" => Ok(false),
+        "https" if cfg!(feature = "tls") => Ok(true),
+        "https" => Err(ErrorKind::TlsError(
+            "Fisher was built without the tls feature".into(),
+        ).into()),
+        scheme => Err(ErrorKind::InvalidInput(
+            format!("unsupported URL scheme: {}", scheme),
+        ).into()),
+    }
+}
+
+
+/// Send a POST request to the provided URL, returning the status code of the
+/// response.
+pub fn post(
+    url: &Url,
+    headers: &[(String, String)],
+    body: &[u8],
+) -> Result<u16> {
+    request("POST", url, headers, body)
+}
+
+
+/// Send a request with the provided method to the URL, returning the status
+/// code of the response.
+pub fn request(
+    method: &str,
+    url: &Url,
+    headers: &[(String, String)],
+    body: &[u8],
+) -> Result<u16> {
+    let mut body = body;
+    let length = body.len() as u64;
+    request_streaming(method, url, headers, &mut body, length)
+}
+
+
+/// Send a request with the provided method to the URL, streaming the body of
+/// the given length from the reader, and return the status code of the
+/// response.
+pub fn request_streaming<R: Read>(
+    method: &str,
+    url: &Url,
+    headers: &[(String, String)],
+    body: &mut R,
+    length: u64,
+) -> Result<u16> {
+    let mut stream = connect(url, Some(Duration::from_secs(10)))?;
+    {
+        let mut writer = BufWriter::new(&mut stream);
+        send_head(&mut writer, method, url, headers, length)?;
+        if io::copy(&mut body.take(length), &mut writer)? != length {
+            return Err(ErrorKind::InvalidInput(
+                "the request body is shorter than its length".into(),
+            ).into());
+        }
+        writer.flush()?;
+    }
+
+    // Only the status line is relevant
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+
+    parse_status_line(&status_line)
+}
+
+
+/// A response received from an HTTP server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+
+/// Open a connection to the server at the URL, waiting for each read or write
+/// at most for the timeout, if any.
+pub fn connect(url: &Url, timeout: Option<Duration>) -> Result<Stream> {
+    let secure = check_url(url)?;
+
+    let host = if let Some(host) = url.host_str() {
+        host
+    } else {
+        return Err(ErrorKind::InvalidInput(
+            format!("missing host in URL: {}", url),
+        ).into());
+    };
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let stream = TcpStream::connect((host, port))?;
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+
+    if secure {
+        tls_handshake(stream, host)
+    } else {
+        Ok(Stream::Plain(stream))
+    }
+}
+
+
+/// Encrypt the connection, checking the certificate of the server against
+/// the system's trusted ones.
+#[cfg(feature = "tls")]
+fn tls_handshake(stream: TcpStream, host: &str) -> Result<Stream> {
+    let connector = SslConnector::builder(SslMethod::tls())
+        .map_err(|e| ErrorKind::TlsError(e.to_string()))?
+        .build();
+
+    connector.connect(host, stream)
+        .map(Stream::Tls)
+        .map_err(|e| ErrorKind::TlsError(e.to_string()).into())
+}
+
+#[cfg(not(feature = "tls"))]
+fn tls_handshake(_stream: TcpStream, _host: &str) -> Result<Stream> {
+    unreachable!("HTTPS URLs are refused without the tls feature");
+}
+
+
+/// Send a request on the connection, and return the whole response.
+pub fn exchange(
+    mut stream: Stream,
+    method: &str,
+    url: &Url,
+    headers: &[(String, String)],
+    body: &[u8],
+) -> Result<Response> {
+    let sent = send(&mut stream, method, url, headers, body);
+    let response = read_response(BufReader::new(stream));
+
+    match (sent, response) {
+        (Ok(()), response) => response,
+        // The server can answer and close the connection before reading the
+        // whole request, making sending the rest of it fail
+        (Err(..), Ok(response)) => Ok(response),
+        (Err(error), Err(..)) => Err(error),
+    }
+}
+
+
+fn read_response<R: BufRead>(mut reader: R) -> Result<Response> {
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    let status = parse_status_line(&status_line)?;
+
+    // Read the headers, looking for the length of the body
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+
+        let mut parts = line.splitn(2, ':');
+        if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                length = Some(value.trim().parse::<u64>()?);
+            }
+        }
+    }
+
+    // Without a length the body ends when the connection is closed
+    let mut body = Vec::new();
+    match length {
+        Some(length) => {
+            reader.take(length).read_to_end(&mut body)?;
+        }
+        None => {
+            reader.read_to_end(&mut body)?;
+        }
+    }
+
+    Ok(Response { status, body })
+}
+
+
+fn send<W: Write>(
+    stream: &mut W,
+    method: &str,
+    url: &Url,
+    headers: &[(String, String)],
+    body: &[u8],
+) -> Result<()> {
+    // The head and the body are sent together
+    let mut writer = BufWriter::new(stream);
+    send_head(&mut writer, method, url, headers, body.len() as u64)?;
+    writer.write_all(body)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+
+fn send_head<W: Write>(
+    stream: &mut W,
+    method: &str,
+    url: &Url,
+    headers: &[(String, String)],
+    length: u64,
+) -> Result<()> {
+    // The port is omitted by the URL when it's the default one
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or(""), port),
+        None => url.host_str().unwrap_or("").to_string(),
+    };
+
+    let mut path = url.path().to_string();
+    if let Some(query) = url.query() {
+        path.push('?');
+        path.push_str(query);
+    }
+
+    write!(stream, "{} {} HTTP/1.0\r\n", method, path)?;
+    write!(stream, "Host: {}\r\n", host)?;
+    write!(stream, "User-Agent: Fisher/{}\r\n", env!("CARGO_PKG_VERSION"))?;
+    write!(stream, "Content-Length: {}\r\n", length)?;
+    for &(ref key, ref value) in headers {
+        write!(stream, "{}: {}\r\n", key, value)?;
+    }
+    write!(stream, "\r\n")?;
+
+    Ok(())
+}
+
+
+fn parse_status_line(line: &str) -> Result<u16> {
+    let mut parts = line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some(version), Some(code)) if version.starts_with("HTTP/") => {
+            Ok(code.parse()?)
+        }
+        _ => Err(ErrorKind::InvalidInput(
+            format!("invalid HTTP response: {}", line.trim()),
+        ).into()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use url::Url;
+
+    use super::{check_url, connect, exchange, parse_status_line, post};
+    use super::{request, request_streaming};
+
+
+    #[test]
+    fn test_parse_status_line() {
+        assert_eq!(parse_status_line("HTTP/1.0 200 OK\r\n").unwrap(), 200);
+        assert_eq!(parse_status_line("HTTP/1.1 404 Not Found").unwrap(), 404);
+        assert!(parse_status_line("").is_err());
+        assert!(parse_status_line("SSH-2.0-OpenSSH").is_err());
+        assert!(parse_status_line("HTTP/1.1 abc").is_err());
+    }
+
+
+    #[test]
+    fn test_check_url() {
+        let url = |url: &str| Url::parse(url).unwrap();
+
+        assert!(!check_url(&url("http://example.com")).unwrap());
+        assert!(check_url(&url("ftp://example.com")).is_err());
+
+        // HTTPS is supported only with the tls feature
+        let https = check_url(&url("https://example.com"));
+        assert_eq!(https.ok(), if cfg!(feature = "tls") {
+            Some(true)
+        } else {
+            None
+        });
+    }
+
+
+    #[test]
+    fn test_post() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+
+            // Read until the whole body is received
+            let mut received = Vec::new();
+            let mut buffer = [0; 1024];
+            while !received.ends_with(b"hello") {
+                let len = conn.read(&mut buffer).unwrap();
+                if len == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buffer[..len]);
+            }
+
+            conn.write_all(b"HTTP/1.0 204 No Content\r\n\r\n").unwrap();
+            String::from_utf8_lossy(&received).into_owned()
+        });
+
+        let url = Url::parse(&format!("http://{}/events?a=b", addr)).unwrap();
+        let headers = vec![("X-Test".to_string(), "yes".to_string())];
+        assert_eq!(post(&url, &headers, b"hello").unwrap(), 204);
+
+        let received = server.join().unwrap();
+        assert!(received.starts_with("POST /events?a=b HTTP/1.0\r\n"));
+        assert!(received.contains("X-Test: yes\r\n"));
+        assert!(received.contains("Content-Length: 5\r\n"));
+        assert!(received.ends_with("\r\n\r\nhello"));
+
+        // Only HTTP and HTTPS are supported
+        let url = Url::parse("ftp://example.com").unwrap();
+        assert!(post(&url, &[], b"").is_err());
+    }
+
+
+    #[test]
+    fn test_request_method() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+
+            let mut buffer = [0; 1024];
+            let len = conn.read(&mut buffer).unwrap();
+
+            conn.write_all(b"HTTP/1.0 200 OK\r\n\r\n").unwrap();
+            String::from_utf8_lossy(&buffer[..len]).into_owned()
+        });
+
+        let url = Url::parse(&format!("http://{}/object", addr)).unwrap();
+        assert_eq!(request("DELETE", &url, &[], b"").unwrap(), 200);
+
+        let received = server.join().unwrap();
+        assert!(received.starts_with("DELETE /object HTTP/1.0\r\n"));
+    }
+
+
+    #[test]
+    fn test_request_streaming() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+
+            let mut received = Vec::new();
+            let mut buffer = [0; 1024];
+            while !received.ends_with(b"hello") {
+                let len = conn.read(&mut buffer).unwrap();
+                if len == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buffer[..len]);
+            }
+
+            conn.write_all(b"HTTP/1.0 200 OK\r\n\r\n").unwrap();
+            String::from_utf8_lossy(&received).into_owned()
+        });
+
+        // Only the declared length is sent
+        let url = Url::parse(&format!("http://{}/object", addr)).unwrap();
+        let mut body: &[u8] = b"hello world";
+        assert_eq!(
+            request_streaming("PUT", &url, &[], &mut body, 5).unwrap(),
+            200,
+        );
+
+        let received = server.join().unwrap();
+        assert!(received.contains("Content-Length: 5\r\n"));
+        assert!(received.ends_with("\r\n\r\nhello"));
+
+        // Bodies shorter than their length are refused
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(
+            &format!("http://{}/object", listener.local_addr().unwrap()),
+        ).unwrap();
+        let mut body: &[u8] = b"hi";
+        assert!(request_streaming("PUT", &url, &[], &mut body, 5).is_err());
+    }
+
+
+    #[test]
+    fn test_exchange() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let mut kept_open = Vec::new();
+            for &(response, keep_open) in &[
+                ("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello", true),
+                ("HTTP/1.0 404 Not Found\r\n\r\nnot found", false),
+            ] {
+                let (mut conn, _) = listener.accept().unwrap();
+
+                let mut buffer = [0; 1024];
+                conn.read(&mut buffer).unwrap();
+                conn.write_all(response.as_bytes()).unwrap();
+
+                if keep_open {
+                    kept_open.push(conn);
+                }
+            }
+        });
+
+        let url = Url::parse(&format!("http://{}/run", addr)).unwrap();
+
+        // The body is read up to its length, even if the connection is open
+        let stream = connect(&url, None).unwrap();
+        let response = exchange(stream, "POST", &url, &[], b"").unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"hello");
+
+        // Otherwise it's read until the connection is closed
+        let stream = connect(&url, None).unwrap();
+        let response = exchange(stream, "POST", &url, &[], b"").unwrap();
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body, b"not found");
+
+        server.join().unwrap();
+    }
+}
@@ -16,19 +16,41 @@
 #[cfg(test)]
 #[macro_use]
 pub mod testing;
-#[cfg(test)]
-mod parse_env;
 
 mod tempdir;
+mod tmpfs;
+mod reaper;
 mod net;
 mod hex;
+mod base64;
+mod cidr;
+mod env_name;
+mod glob;
+pub mod http_client;
+mod signing;
 mod parse_time;
+mod parse_size;
+mod parse_env;
+mod secrets;
+mod request_id;
 
 
-#[cfg(test)]
-pub use utils::parse_env::parse_env;
-
-pub use utils::tempdir::create_temp_dir;
+pub use utils::tempdir::{check_temp_base, create_temp_dir};
+pub use utils::tempdir::create_temp_dir_in;
+pub use utils::tmpfs::mount_tmpfs;
+pub use utils::reaper::{reap_orphans, running_as_init};
+pub use utils::reaper::{release_tracked, spawn_tracked};
 pub use utils::net::parse_forwarded_for;
-pub use utils::hex::from_hex;
+pub use utils::hex::{from_hex, to_hex};
+pub use utils::base64::from_base64;
+pub use utils::cidr::Cidr;
+pub use utils::env_name::{env_name, is_env_name};
+pub use utils::glob::{glob_matches, is_glob};
+pub use utils::http_client::post as http_post;
+pub use utils::http_client::request as http_request;
+pub use utils::signing::Signer;
 pub use utils::parse_time::{parse_time, TimeString};
+pub use utils::parse_size::{parse_size, SizeString};
+pub use utils::parse_env::parse_env;
+pub use utils::secrets::Secrets;
+pub use utils::request_id::request_id;
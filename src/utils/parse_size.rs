@@ -0,0 +1,150 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::result::Result as StdResult;
+use std::str::FromStr;
+
+use serde::de::{Error as DeError, Unexpected, Visitor, Deserialize};
+use serde::de::Deserializer;
+
+use common::prelude::*;
+
+
+/// Parse a size string and return the equivalent size in bytes.
+///
+/// Examples of size strings are "512" (for 512 bytes), "10k" (for 10240) or
+/// "1M" (for 1048576). The unit is case insensitive.
+pub fn parse_size(input: &str) -> Result<u64> {
+    let error =
+        || -> Error { ErrorKind::InvalidSizeString(input.into()).into() };
+
+    let split = input
+        .find(|c: char| !c.is_digit(10))
+        .unwrap_or_else(|| input.len());
+    if split == 0 {
+        return Err(error());
+    }
+
+    let number = input[..split].parse::<u64>()?;
+    let multiplier = match &input[split..].to_lowercase()[..] {
+        "" | "b" => 1,
+        "k" | "kb" => 1024,
+        "m" | "mb" => 1024 * 1024,
+        "g" | "gb" => 1024 * 1024 * 1024,
+        _ => return Err(error()),
+    };
+
+    number.checked_mul(multiplier).ok_or_else(error)
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeString(u64);
+
+impl SizeString {
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl From<u64> for SizeString {
+    fn from(num: u64) -> Self {
+        SizeString(num)
+    }
+}
+
+impl FromStr for SizeString {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<SizeString> {
+        Ok(SizeString(parse_size(s)?))
+    }
+}
+
+struct SizeStringVisitor;
+
+impl<'de> Visitor<'de> for SizeStringVisitor {
+    type Value = SizeString;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a positive number or a size string")
+    }
+
+    fn visit_str<E: DeError>(self, s: &str) -> StdResult<SizeString, E> {
+        match parse_size(s) {
+            Ok(size) => Ok(SizeString(size)),
+            Err(e) => Err(E::custom(e.to_string())),
+        }
+    }
+
+    fn visit_i64<E: DeError>(self, num: i64) -> StdResult<SizeString, E> {
+        if num < 0 {
+            return Err(E::invalid_value(Unexpected::Signed(num), &self));
+        }
+        Ok(SizeString(num as u64))
+    }
+
+    fn visit_u64<E>(self, num: u64) -> StdResult<SizeString, E> {
+        Ok(SizeString(num))
+    }
+}
+
+impl<'de> Deserialize<'de> for SizeString {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> StdResult<SizeString, D::Error> {
+        deserializer.deserialize_any(SizeStringVisitor)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use super::{parse_size, SizeString};
+
+
+    #[test]
+    fn test_parse_size() {
+        // Success
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("512b").unwrap(), 512);
+        assert_eq!(parse_size("10k").unwrap(), 10240);
+        assert_eq!(parse_size("10KB").unwrap(), 10240);
+        assert_eq!(parse_size("1M").unwrap(), 1048576);
+        assert_eq!(parse_size("2g").unwrap(), 2147483648);
+
+        // Failure
+        assert!(parse_size("").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("10q").is_err());
+        assert!(parse_size("1M1k").is_err());
+        assert!(parse_size("99999999999999999999").is_err());
+    }
+
+
+    #[test]
+    fn test_deserialize() {
+        let size = |input: &str| serde_json::from_str::<SizeString>(input);
+
+        assert_eq!(size("512").unwrap(), SizeString(512));
+        assert_eq!(size("\"10k\"").unwrap(), SizeString(10240));
+        assert!(size("-1").is_err());
+        assert!(size("\"10q\"").is_err());
+    }
+}
@@ -67,7 +67,7 @@ pub fn parse_time(input: &str) -> Result<usize> {
 }
 
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct TimeString(u64);
 
 impl TimeString {
@@ -0,0 +1,154 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Reaping of the orphaned processes.
+//!
+//! When Fisher runs as PID 1, for example as the entrypoint of a container,
+//! the processes left behind by the hooks are reparented to it, and they
+//! would stay zombies forever if nobody waited for them. The children Fisher
+//! waits for itself are tracked, so their exit status is not stolen.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::process::{Child, Command};
+use std::sync::Mutex;
+
+use libc;
+
+
+lazy_static! {
+    static ref TRACKED: Mutex<HashSet<u32>> = Mutex::new(HashSet::new());
+}
+
+
+/// Check if Fisher is running as PID 1.
+pub fn running_as_init() -> bool {
+    unsafe { libc::getpid() == 1 }
+}
+
+
+/// Spawn the command, tracking the child until it's released.
+pub fn spawn_tracked(command: &mut Command) -> io::Result<Child> {
+    // The lock is held while spawning, so the child can't be reaped before
+    // it's tracked
+    let mut tracked = TRACKED.lock().unwrap_or_else(|e| e.into_inner());
+    let child = command.spawn()?;
+    tracked.insert(child.id());
+    Ok(child)
+}
+
+
+/// Stop tracking the child, once its exit status was collected.
+pub fn release_tracked(child: &Child) {
+    let mut tracked = TRACKED.lock().unwrap_or_else(|e| e.into_inner());
+    tracked.remove(&child.id());
+}
+
+
+/// Wait for the exited children not tracked, returning how many of them
+/// were reaped.
+pub fn reap_orphans() -> usize {
+    reap_if(|_| true)
+}
+
+
+fn reap_if<F: Fn(u32) -> bool>(filter: F) -> usize {
+    let tracked = TRACKED.lock().unwrap_or_else(|e| e.into_inner());
+    let own_pid = unsafe { libc::getpid() };
+
+    let entries = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(..) => return 0,
+    };
+
+    let mut reaped = 0;
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let pid = match entry.file_name().to_str().map(|name| name.parse()) {
+            Some(Ok(pid)) => pid,
+            _ => continue,
+        };
+        if tracked.contains(&pid) || !filter(pid) {
+            continue;
+        }
+
+        match zombie_parent(pid) {
+            Some(parent) if parent == own_pid => {}
+            _ => continue,
+        }
+
+        let mut status = 0;
+        let result = unsafe {
+            libc::waitpid(pid as libc::pid_t, &mut status, libc::WNOHANG)
+        };
+        if result > 0 {
+            reaped += 1;
+        }
+    }
+
+    reaped
+}
+
+
+/// Return the parent of the process, if it's a zombie.
+fn zombie_parent(pid: u32) -> Option<libc::pid_t> {
+    let mut stat = String::new();
+    let read = File::open(format!("/proc/{}/stat", pid))
+        .and_then(|mut file| file.read_to_string(&mut stat));
+    if read.is_err() {
+        return None;
+    }
+
+    // The name of the process can contain spaces and parenthesis, so the
+    // fields are parsed after the last one
+    let start = match stat.rfind(')') {
+        Some(end) => end + 1,
+        None => return None,
+    };
+    let fields = stat[start..].split_whitespace().collect::<Vec<_>>();
+    match (fields.get(0), fields.get(1)) {
+        (Some(&"Z"), Some(parent)) => parent.parse().ok(),
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{reap_if, release_tracked, spawn_tracked, zombie_parent};
+
+
+    #[test]
+    fn test_reap() {
+        let mut tracked = spawn_tracked(&mut Command::new("true")).unwrap();
+        let untracked = Command::new("true").spawn().unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert!(zombie_parent(tracked.id()).is_some());
+        assert!(zombie_parent(untracked.id()).is_some());
+
+        // The children of the other tests are left alone, and the tracked
+        // ones are never reaped
+        let ids = [tracked.id(), untracked.id()];
+        assert_eq!(reap_if(|pid| ids.contains(&pid)), 1);
+        assert!(zombie_parent(untracked.id()).is_none());
+
+        assert!(tracked.wait().unwrap().success());
+        release_tracked(&tracked);
+    }
+}
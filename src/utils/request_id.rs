@@ -0,0 +1,42 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use rand::{self, Rng};
+
+use utils::to_hex;
+
+
+/// Generate a new random identifier for an incoming request.
+pub fn request_id() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::request_id;
+
+
+    #[test]
+    fn test_request_id() {
+        let first = request_id();
+        assert_eq!(first.len(), 32);
+        assert!(first.chars().all(|c| c.is_digit(16)));
+
+        assert_ne!(first, request_id());
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::result::Result as StdResult;
+
+use ring::constant_time;
+use serde::de::{Error as DeError, SeqAccess, Visitor, Deserialize,
+                Deserializer};
+
+
+/// A list of secrets accepted by a provider.
+///
+/// In the configuration it can be either a single string or an array of
+/// strings, allowing the old and the new secret to be accepted at the same
+/// time while rotating them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Secrets(Vec<String>);
+
+impl Secrets {
+    /// Check if one of the secrets satisfies the provided check.
+    pub fn any<F: Fn(&str) -> bool>(&self, check: F) -> bool {
+        self.0.iter().any(|secret| check(secret))
+    }
+
+    /// Check if the provided string is one of the secrets, taking the same
+    /// time regardless of how much of it matches.
+    pub fn contains(&self, input: &str) -> bool {
+        self.any(|secret| {
+            constant_time::verify_slices_are_equal(
+                secret.as_bytes(), input.as_bytes(),
+            ).is_ok()
+        })
+    }
+}
+
+impl<'a> From<&'a str> for Secrets {
+    fn from(secret: &'a str) -> Self {
+        Secrets(vec![secret.to_string()])
+    }
+}
+
+struct SecretsVisitor;
+
+impl<'de> Visitor<'de> for SecretsVisitor {
+    type Value = Secrets;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a secret or a non-empty list of secrets")
+    }
+
+    fn visit_str<E: DeError>(self, s: &str) -> StdResult<Secrets, E> {
+        // An empty secret would be matched by empty tokens
+        if s.is_empty() {
+            return Err(E::custom("the secret is empty"));
+        }
+
+        Ok(Secrets(vec![s.to_string()]))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(
+        self, mut seq: A,
+    ) -> StdResult<Secrets, A::Error> {
+        let mut secrets = Vec::new();
+        while let Some(secret) = seq.next_element::<String>()? {
+            if secret.is_empty() {
+                return Err(A::Error::custom("the secret is empty"));
+            }
+            secrets.push(secret);
+        }
+
+        if secrets.is_empty() {
+            return Err(A::Error::custom("the list of secrets is empty"));
+        }
+
+        Ok(Secrets(secrets))
+    }
+}
+
+impl<'de> Deserialize<'de> for Secrets {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> StdResult<Secrets, D::Error> {
+        deserializer.deserialize_any(SecretsVisitor)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use super::Secrets;
+
+
+    #[test]
+    fn test_secrets_deserialize() {
+        let single: Secrets = serde_json::from_str(r#""a""#).unwrap();
+        assert_eq!(single, Secrets(vec!["a".into()]));
+
+        let multiple: Secrets = serde_json::from_str(r#"["a", "b"]"#).unwrap();
+        assert_eq!(multiple, Secrets(vec!["a".into(), "b".into()]));
+
+        for wrong in &[
+            "[]", "12345", "true", "{}", "[12345]", r#"["a", 1]"#, r#""""#,
+            r#"["a", ""]"#,
+        ] {
+            assert!(
+                serde_json::from_str::<Secrets>(wrong).is_err(),
+                wrong.to_string()
+            );
+        }
+    }
+
+
+    #[test]
+    fn test_secrets_contains() {
+        let secrets: Secrets = serde_json::from_str(r#"["a", "b"]"#).unwrap();
+
+        assert!(secrets.contains("a"));
+        assert!(secrets.contains("b"));
+        assert!(!secrets.contains("c"));
+        assert!(!secrets.contains("ab"));
+        assert!(!secrets.contains(""));
+        assert!(secrets.any(|s| s == "b"));
+        assert!(!secrets.any(|s| s.is_empty()));
+    }
+}
@@ -0,0 +1,77 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use ring::{digest, hmac};
+
+use common::config::SigningConfig;
+use utils;
+
+
+/// Sign outgoing deliveries, so receivers can verify they come from this
+/// Fisher instance.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signer {
+    secret: String,
+    header: String,
+}
+
+impl Signer {
+    /// Create a new signer, if signing is enabled in the configuration.
+    pub fn from_config(config: &SigningConfig) -> Option<Self> {
+        config.secret.as_ref().map(|secret| Signer {
+            secret: secret.clone(),
+            header: config.header.clone(),
+        })
+    }
+
+    /// Return the header containing the signature of the body.
+    pub fn header(&self, body: &[u8]) -> (String, String) {
+        let key = hmac::SigningKey::new(
+            &digest::SHA256, self.secret.as_bytes(),
+        );
+        let signature = hmac::sign(&key, body);
+
+        (
+            self.header.clone(),
+            format!("sha256={}", utils::to_hex(signature.as_ref())),
+        )
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use common::config::SigningConfig;
+
+    use super::Signer;
+
+
+    #[test]
+    fn test_signer() {
+        // Signing is disabled without a secret
+        assert!(Signer::from_config(&SigningConfig::default()).is_none());
+
+        let signer = Signer::from_config(&SigningConfig {
+            secret: Some("secret".into()),
+            header: "X-Signature".into(),
+        }).unwrap();
+
+        assert_eq!(signer.header(b"hello"), (
+            "X-Signature".to_string(),
+            "sha256=88aab3ede8d3adf94d26ab90d3bafd4a2083070c3bcce9c014ee04a\
+             443847c0b".to_string(),
+        ));
+    }
+}
@@ -58,14 +58,16 @@ impl TempDirCreator {
 
     fn create(&mut self) -> Result<path::PathBuf> {
         // The OS's base temp directory
-        let base = env::temp_dir();
+        self.create_in(&env::temp_dir())
+    }
 
+    fn create_in(&mut self, base: &path::Path) -> Result<path::PathBuf> {
         // Create a randomized temp directory
         loop {
             // Generate the random suffix
             let suffix: String = self.rng.gen_ascii_chars().take(10).collect();
 
-            let mut path = base.clone();
+            let mut path = base.to_path_buf();
             path.push(format!("{}-{}", self.prefix, suffix));
 
             // Be sure to set the 0700 permissions on the new directory
@@ -100,17 +102,44 @@ pub fn create_temp_dir() -> Result<path::PathBuf> {
 }
 
 
+pub fn create_temp_dir_in<P: AsRef<path::Path>>(
+    base: P,
+) -> Result<path::PathBuf> {
+    let mut creator = CREATOR.lock().unwrap();
+    creator.create_in(base.as_ref())
+}
+
+
+/// Ensure temporary directories can be created in the base directory,
+/// creating it if it doesn't exist yet.
+pub fn check_temp_base<P: AsRef<path::Path>>(base: P) -> Result<()> {
+    let base = base.as_ref();
+
+    let result = fs::create_dir_all(base)
+        .map_err(Error::from)
+        .and_then(|_| create_temp_dir_in(base))
+        .and_then(|dir| fs::remove_dir(dir).map_err(Error::from));
+    result.map_err(|mut error| {
+        error.set_location(
+            ErrorLocation::File(base.to_string_lossy().into_owned(), None),
+        );
+        error
+    })
+}
+
+
 #[cfg(test)]
 mod tests {
     use std::os::unix::fs::PermissionsExt;
     use std::env;
     use std::path;
-    use std::fs;
+    use std::fs::{self, File};
 
     use rand;
     use rand::Rng;
 
-    use super::TempDirCreator;
+    use common::prelude::*;
+    use super::{check_temp_base, create_temp_dir, TempDirCreator};
 
 
     #[test]
@@ -159,6 +188,31 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_check_temp_base() {
+        let dir = create_temp_dir().unwrap();
+
+        // Missing directories are created, and nothing is left in them
+        let base = dir.join("nested").join("base");
+        check_temp_base(&base).unwrap();
+        assert!(base.is_dir());
+        assert_eq!(fs::read_dir(&base).unwrap().count(), 0);
+
+        // Directories can't be created inside files
+        File::create(dir.join("file")).unwrap();
+        let error = check_temp_base(dir.join("file").join("base")).unwrap_err();
+        assert_eq!(
+            error.location(),
+            &ErrorLocation::File(
+                dir.join("file").join("base").to_string_lossy().into_owned(),
+                None,
+            ),
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+
     fn generate_prefix() -> String {
         // Use this thread's random number generator
         let mut rng = rand::thread_rng();
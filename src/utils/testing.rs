@@ -18,17 +18,21 @@ use std::net::{IpAddr, Ipv4Addr};
 use std::path::PathBuf;
 use std::sync::{mpsc, Arc};
 use std::fs;
+use std::time::Duration;
 
 use hyper::client as hyper;
 use hyper::method::Method;
 
 use common::prelude::*;
 use common::state::State;
-use common::structs::HealthDetails;
-use common::config::{HttpConfig, RateLimitConfig};
+use common::structs::{HealthDetails, QueuedJob};
+use common::config::{Endpoint, HttpConfig, ListenerConfig, RateLimitConfig};
+use common::config::{CorsConfig, SiemConfig, SigningConfig, TlsConfig};
+use common::config::QueueOverflow;
 
+use metrics::Metrics;
 use scripts::{Blueprint as HooksBlueprint, Repository as Hooks};
-use scripts::{Job, JobOutput};
+use scripts::{Job, JobOutput, ResourceUsage};
 use web::{WebApp, WebRequest};
 use utils;
 
@@ -60,10 +64,15 @@ macro_rules! assert_err {
 
 pub fn dummy_web_request() -> WebRequest {
     WebRequest {
+        id: "dummy-request".into(),
+        method: "POST".into(),
+        url: "/hook/example.sh".into(),
         headers: HashMap::new(),
         params: HashMap::new(),
         source: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
         body: String::new(),
+        binary_body: None,
+        spooled: None,
     }
 }
 
@@ -76,9 +85,20 @@ pub fn dummy_job_output() -> JobOutput {
         success: true,
         exit_code: Some(0),
         signal: None,
+        timed_out: false,
+        interrupted: false,
+        termination: None,
+        stdout_log: None,
+        stderr_log: None,
+        artifacts: Vec::new(),
+        artifact_urls: Vec::new(),
+        dead_letter: None,
+        resources: ResourceUsage::default(),
+        duration: Duration::from_secs(0),
 
         script_name: "test".into(),
         request_ip: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+        request_id: Some("dummy-request".into()),
 
         trigger_status_hooks: true,
     }
@@ -145,6 +165,34 @@ pub fn sample_hooks() -> PathBuf {
         r#"cat "prepared" > "${b}/prepared""#
     );
 
+    create_hook!(
+        tempdir,
+        "sync.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher: {"sync": true, "timeout": "2s"}"#,
+        r#"## Fisher-Testing: {}"#,
+        r#"echo "Hello sync""#
+    );
+
+    create_hook!(
+        tempdir,
+        "routed.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher-Testing: {}"#,
+        r#"## Fisher-Route: /deploy/frontend"#,
+        r#"## Fisher-Route: /frontend"#,
+        r#"echo "Hello route""#
+    );
+
+    create_hook!(
+        tempdir,
+        "ping.sh",
+        r#"#!/bin/bash"#,
+        r#"## Fisher: {"methods": ["GET", "POST"]}"#,
+        r#"## Fisher-Testing: {}"#,
+        r#"echo "Hello ping""#
+    );
+
     create_hook!(
         tempdir,
         "long.sh",
@@ -197,8 +245,12 @@ pub fn sample_hooks() -> PathBuf {
 
 pub enum ProcessorApiCall {
     Queue(Job, isize),
+    QueueDelayed(Job, isize, Duration),
     HealthDetails,
+    QueuedJobs,
+    DropOldest,
     Cleanup,
+    SetThreadsCount(u16),
     Lock,
     Unlock,
 }
@@ -214,6 +266,17 @@ impl ProcessorApiTrait<Hooks> for FakeProcessorApi {
         Ok(())
     }
 
+    fn queue_delayed(
+        &self,
+        job: Job,
+        priority: isize,
+        delay: Duration,
+    ) -> Result<()> {
+        self.sender
+            .send(ProcessorApiCall::QueueDelayed(job, priority, delay))?;
+        Ok(())
+    }
+
     fn health_details(&self) -> Result<HealthDetails> {
         self.sender.send(ProcessorApiCall::HealthDetails)?;
         Ok(HealthDetails {
@@ -223,11 +286,30 @@ impl ProcessorApiTrait<Hooks> for FakeProcessorApi {
         })
     }
 
+    fn queued_jobs(&self) -> Result<Vec<QueuedJob>> {
+        self.sender.send(ProcessorApiCall::QueuedJobs)?;
+        Ok(vec![QueuedJob {
+            hook: "example.sh".into(),
+            priority: 0,
+            delayed: false,
+        }])
+    }
+
+    fn drop_oldest(&self) -> Result<bool> {
+        self.sender.send(ProcessorApiCall::DropOldest)?;
+        Ok(true)
+    }
+
     fn cleanup(&self) -> Result<()> {
         self.sender.send(ProcessorApiCall::Cleanup)?;
         Ok(())
     }
 
+    fn set_threads_count(&self, count: u16) -> Result<()> {
+        self.sender.send(ProcessorApiCall::SetThreadsCount(count))?;
+        Ok(())
+    }
+
     fn lock(&self) -> Result<()> {
         self.sender.send(ProcessorApiCall::Lock)?;
         Ok(())
@@ -244,40 +326,80 @@ pub struct WebAppInstance {
     inst: WebApp<FakeProcessorApi>,
 
     url: String,
+    listener_url: String,
     client: hyper::Client,
 
     processor_api_call: mpsc::Receiver<ProcessorApiCall>,
 }
 
 impl WebAppInstance {
-    pub fn new(hooks: Arc<Hooks>, health: bool, behind_proxies: u8) -> Self {
+    pub fn new<F: FnOnce(&mut HttpConfig)>(
+        hooks: Arc<Hooks>,
+        health: bool,
+        behind_proxies: u8,
+        customize: F,
+    ) -> Self {
         let (chan_send, chan_recv) = mpsc::channel();
         let fake_processor = FakeProcessorApi { sender: chan_send };
 
+        let mut config = HttpConfig {
+            behind_proxies,
+            trusted_proxies: Vec::new(),
+            bind: "127.0.0.1:0".parse().unwrap(),
+            rate_limit: RateLimitConfig {
+                allowed: ::std::u64::MAX,
+                interval: ::std::u64::MAX.into(),
+            },
+            request_limit: None,
+            health_endpoint: health,
+            metrics_endpoint: health,
+            admin_tokens: Some("admin-token".into()),
+            monitoring_tokens: None,
+            worker_tokens: None,
+            tls: TlsConfig::default(),
+            cors: CorsConfig::default(),
+            unix_socket: None,
+            listeners: vec![ListenerConfig {
+                bind: "127.0.0.1:0".parse().unwrap(),
+                endpoints: vec![Endpoint::Health],
+            }],
+            drain_timeout: 0.into(),
+            max_body_size: ::std::u64::MAX.into(),
+            access_log: None,
+            access_log_path: None,
+            spool_threshold: ::std::u64::MAX.into(),
+            replay_cache: 1000,
+            max_connections: 64,
+            read_timeout: 30.into(),
+            max_queued_jobs: None,
+            queue_overflow: QueueOverflow::Reject,
+            keep_alive: true,
+            base_path: "/".into(),
+        };
+        customize(&mut config);
+
         // Start the web server
         // Create a new instance of WebApp
         let inst = WebApp::new(
             hooks,
-            &HttpConfig {
-                behind_proxies,
-                bind: "127.0.0.1:0".parse().unwrap(),
-                rate_limit: RateLimitConfig {
-                    allowed: ::std::u64::MAX,
-                    interval: ::std::u64::MAX.into(),
-                },
-                health_endpoint: health,
-            },
+            &config,
+            &SiemConfig::default(),
+            &SigningConfig::default(),
             fake_processor,
+            Arc::new(Metrics::new()),
+            Arc::new(|| Ok(())),
         ).unwrap();
 
         // Create the HTTP client
         let url = format!("http://{}", inst.addr());
+        let listener_url = format!("http://{}", inst.addrs()[1]);
         let client = hyper::Client::new();
 
         WebAppInstance {
             inst: inst,
 
             url: url,
+            listener_url: listener_url,
             client: client,
             processor_api_call: chan_recv,
         }
@@ -292,6 +414,21 @@ impl WebAppInstance {
         self.client.request(method, &format!("{}{}", self.url, url))
     }
 
+    /// Make a request to the additional listener, which only serves the
+    /// health endpoint.
+    pub fn listener_request(
+        &mut self,
+        method: Method,
+        url: &str,
+    ) -> hyper::RequestBuilder {
+        let url = format!("{}{}", self.listener_url, url);
+        self.client.request(method, &url)
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
     pub fn processor_input(&self) -> Option<ProcessorApiCall> {
         if let Ok(result) = self.processor_api_call.try_recv() {
             Some(result)
@@ -300,6 +437,14 @@ impl WebAppInstance {
         }
     }
 
+    /// Wait for the next call to the processor, at most for the timeout.
+    pub fn wait_processor_input(
+        &self,
+        timeout: Duration,
+    ) -> Option<ProcessorApiCall> {
+        self.processor_api_call.recv_timeout(timeout).ok()
+    }
+
     pub fn lock(&self) {
         self.inst.lock();
     }
@@ -350,6 +495,18 @@ impl TestingEnv {
         health: bool,
         behind_proxies: u8,
     ) -> WebAppInstance {
-        WebAppInstance::new(self.hooks.clone(), health, behind_proxies)
+        self.start_web_with(health, behind_proxies, |_| {})
+    }
+
+    /// Start the web app, customizing its configuration.
+    pub fn start_web_with<F: FnOnce(&mut HttpConfig)>(
+        &self,
+        health: bool,
+        behind_proxies: u8,
+        customize: F,
+    ) -> WebAppInstance {
+        WebAppInstance::new(
+            self.hooks.clone(), health, behind_proxies, customize,
+        )
     }
 }
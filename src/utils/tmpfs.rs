@@ -0,0 +1,116 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Memory-backed filesystems for the temporary directories of the jobs.
+//!
+//! The content of a tmpfs lives in memory, so it's fast to access and it's
+//! gone after a reboot, even if Fisher didn't have the chance to clean up.
+//! Mounting one requires the `CAP_SYS_ADMIN` capability.
+
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use libc;
+
+use common::prelude::*;
+
+
+/// The filesystem type of tmpfs, as returned by `statfs`.
+const TMPFS_MAGIC: i64 = 0x0102_1994;
+
+
+/// Mount a tmpfs with the maximum size, in bytes, on the directory. If a
+/// tmpfs is already mounted there, for example by a previous run of Fisher,
+/// it's only resized.
+pub fn mount_tmpfs(path: &Path, size: u64) -> Result<()> {
+    let target = CString::new(path.as_os_str().as_bytes()).map_err(|_| {
+        Error::from(ErrorKind::InvalidInput(
+            format!("invalid path: {}", path.display()),
+        ))
+    })?;
+    // Like /tmp, everyone can create files in it
+    let options = CString::new(format!("size={},mode=1777", size)).unwrap();
+
+    let mut flags = libc::MS_NOSUID | libc::MS_NODEV;
+    if is_tmpfs_mount(path, &target)? {
+        flags |= libc::MS_REMOUNT;
+    }
+
+    let result = unsafe {
+        libc::mount(
+            b"tmpfs\0".as_ptr() as *const libc::c_char,
+            target.as_ptr(),
+            b"tmpfs\0".as_ptr() as *const libc::c_char,
+            flags,
+            options.as_ptr() as *const libc::c_void,
+        )
+    };
+    if result != 0 {
+        let mut error = Error::from(io::Error::last_os_error());
+        error.set_location(
+            ErrorLocation::File(path.to_string_lossy().into_owned(), None),
+        );
+        return Err(error);
+    }
+
+    Ok(())
+}
+
+
+/// Check if a tmpfs is mounted on the directory.
+fn is_tmpfs_mount(path: &Path, target: &CString) -> Result<bool> {
+    // Mount points are on a different device than their parent
+    let parent = match path.parent() {
+        Some(parent) => parent,
+        None => return Ok(false),
+    };
+    if fs::metadata(path)?.dev() == fs::metadata(parent)?.dev() {
+        return Ok(false);
+    }
+
+    let mut stat: libc::statfs = unsafe { ::std::mem::zeroed() };
+    if unsafe { libc::statfs(target.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(stat.f_type as i64 == TMPFS_MAGIC)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+
+    use utils;
+
+    use super::is_tmpfs_mount;
+
+
+    #[test]
+    fn test_is_tmpfs_mount() {
+        let dir = utils::create_temp_dir().unwrap();
+        let target = CString::new(dir.as_os_str().as_bytes()).unwrap();
+
+        // A normal directory is not a mount point
+        assert!(!is_tmpfs_mount(&dir, &target).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,256 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json;
+
+use common::prelude::*;
+use common::config::AccessLogFormat;
+use requests::RequestType;
+
+
+/// Where the entries of the access log are written, either a file or the
+/// standard output.
+#[derive(Debug)]
+pub struct AccessLog {
+    format: AccessLogFormat,
+    file: Option<Mutex<File>>,
+}
+
+impl AccessLog {
+    /// Open the access log, appending to the file if a path is provided.
+    pub fn new(format: AccessLogFormat, path: Option<&str>) -> Result<Self> {
+        let file = match path {
+            Some(path) => Some(Mutex::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            None => None,
+        };
+
+        Ok(AccessLog { format, file })
+    }
+
+    /// Write the entry as a single line, without failing the request if it
+    /// can't be written.
+    pub fn write(&self, entry: &AccessLogEntry) {
+        let line = format!("{}\n", entry.format(self.format));
+        let result = match self.file {
+            Some(ref file) => match file.lock() {
+                Ok(mut file) => file.write_all(line.as_bytes()),
+                Err(..) => return,
+            },
+            None => io::stdout().write_all(line.as_bytes()),
+        };
+
+        if let Err(error) = result {
+            Error::from(error).pretty_print();
+        }
+    }
+}
+
+
+/// The details of a request, logged after the response is sent.
+#[derive(Debug, Clone)]
+pub struct AccessLogEntry {
+    pub request_id: String,
+    pub timestamp: SystemTime,
+    pub source: IpAddr,
+    pub method: String,
+    pub path: String,
+    pub hook: Option<String>,
+    pub provider: Option<String>,
+    pub result: Option<RequestType>,
+    pub status: u16,
+    pub latency: Duration,
+}
+
+impl AccessLogEntry {
+    /// Start a new entry. The query string is stripped from the URL, since
+    /// it might contain secrets.
+    pub fn new(
+        request_id: String,
+        source: IpAddr,
+        method: &str,
+        url: &str,
+    ) -> Self {
+        AccessLogEntry {
+            request_id,
+            timestamp: SystemTime::now(),
+            source,
+            method: method.to_string(),
+            path: url.splitn(2, '?').next().unwrap().to_string(),
+            hook: None,
+            provider: None,
+            result: None,
+            status: 0,
+            latency: Duration::from_secs(0),
+        }
+    }
+
+    fn timestamp_millis(&self) -> u64 {
+        match self.timestamp.duration_since(UNIX_EPOCH) {
+            Ok(duration) => millis(duration),
+            Err(..) => 0,
+        }
+    }
+
+    fn result_name(&self) -> Option<&'static str> {
+        self.result.map(|result| match result {
+            RequestType::ExecuteHook => "execute",
+            RequestType::Ping => "ping",
+            RequestType::Handshake => "handshake",
+            RequestType::Invalid => "invalid",
+        })
+    }
+
+    fn plain(&self) -> String {
+        format!(
+            "{} {} \"{} {}\" {} {}ms hook={} provider={} result={} id={}",
+            self.timestamp_millis() / 1000,
+            self.source,
+            self.method,
+            self.path,
+            self.status,
+            millis(self.latency),
+            self.hook.as_ref().map(|s| s.as_str()).unwrap_or("-"),
+            self.provider.as_ref().map(|s| s.as_str()).unwrap_or("-"),
+            self.result_name().unwrap_or("-"),
+            self.request_id,
+        )
+    }
+
+    fn json(&self) -> String {
+        serde_json::to_string(&json!({
+            "timestamp": self.timestamp_millis() / 1000,
+            "source": self.source.to_string(),
+            "method": self.method,
+            "path": self.path,
+            "hook": self.hook,
+            "provider": self.provider,
+            "result": self.result_name(),
+            "status": self.status,
+            "latency_ms": millis(self.latency),
+            "request_id": self.request_id,
+        })).unwrap()
+    }
+
+    /// Render the entry in the chosen format.
+    pub fn format(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Plain => self.plain(),
+            AccessLogFormat::Json => self.json(),
+        }
+    }
+}
+
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_nanos()) / 1_000_000
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use serde_json;
+
+    use std::fs::{self, File};
+    use std::io::Read;
+
+    use common::config::AccessLogFormat;
+    use requests::RequestType;
+    use utils;
+
+    use super::{AccessLog, AccessLogEntry};
+
+
+    #[test]
+    fn test_access_log_entry() {
+        let mut entry = AccessLogEntry::new(
+            "abcd".into(), "127.0.0.1".parse().unwrap(), "POST",
+            "/hook/a.sh?secret=abc",
+        );
+        entry.timestamp = UNIX_EPOCH + Duration::from_secs(1500000000);
+        entry.status = 403;
+        entry.latency = Duration::from_millis(12);
+
+        // The query string is never logged
+        assert_eq!(entry.path, "/hook/a.sh");
+
+        assert_eq!(
+            entry.format(AccessLogFormat::Plain),
+            "1500000000 127.0.0.1 \"POST /hook/a.sh\" 403 12ms hook=- \
+             provider=- result=- id=abcd"
+        );
+
+        entry.hook = Some("a.sh".into());
+        entry.provider = Some("Standalone".into());
+        entry.result = Some(RequestType::Invalid);
+        assert_eq!(
+            entry.format(AccessLogFormat::Plain),
+            "1500000000 127.0.0.1 \"POST /hook/a.sh\" 403 12ms hook=a.sh \
+             provider=Standalone result=invalid id=abcd"
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&entry.format(AccessLogFormat::Json))
+                .unwrap();
+        assert_eq!(json, json!({
+            "timestamp": 1500000000,
+            "source": "127.0.0.1",
+            "method": "POST",
+            "path": "/hook/a.sh",
+            "hook": "a.sh",
+            "provider": "Standalone",
+            "result": "invalid",
+            "status": 403,
+            "latency_ms": 12,
+            "request_id": "abcd",
+        }));
+    }
+
+
+    #[test]
+    fn test_access_log_file() {
+        let dir = utils::create_temp_dir().unwrap();
+        let path = dir.join("access.log");
+        File::create(&path).unwrap();
+
+        let entry = AccessLogEntry::new(
+            "abcd".into(), "127.0.0.1".parse().unwrap(), "GET", "/health",
+        );
+
+        // The entries are appended to the file, one per line
+        for _ in 0..2 {
+            let log = AccessLog::new(
+                AccessLogFormat::Plain, Some(path.to_str().unwrap()),
+            ).unwrap();
+            log.write(&entry);
+        }
+
+        let mut content = String::new();
+        File::open(&path).unwrap().read_to_string(&mut content).unwrap();
+        let line = format!("{}\n", entry.format(AccessLogFormat::Plain));
+        assert_eq!(content, line.repeat(2));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::env;
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::Mutex;
+
+use nix::unistd::getpid;
+
+use common::prelude::*;
+
+
+/// The first file descriptor passed by systemd.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+
+lazy_static! {
+    // The sockets are taken from the environment only once, so they're still
+    // available when the HTTP server is restarted
+    static ref LISTENERS: Mutex<Option<Vec<TcpListener>>> = Mutex::new(None);
+}
+
+
+/// Return the listening sockets passed by systemd with socket activation.
+pub fn listeners() -> Result<Vec<TcpListener>> {
+    let mut cache = LISTENERS.lock()?;
+    if cache.is_none() {
+        *cache = Some(take_listeners(
+            env::var("LISTEN_PID").ok().as_ref().map(|s| s.as_str()),
+            env::var("LISTEN_FDS").ok().as_ref().map(|s| s.as_str()),
+        )?);
+
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+    }
+
+    let mut result = Vec::new();
+    for listener in cache.as_ref().unwrap() {
+        result.push(listener.try_clone()?);
+    }
+    Ok(result)
+}
+
+
+fn take_listeners(
+    pid: Option<&str>,
+    fds: Option<&str>,
+) -> Result<Vec<TcpListener>> {
+    let count = match (pid, fds) {
+        (Some(pid), Some(fds)) => {
+            // The sockets are meant for another process
+            if pid != getpid().to_string() {
+                return Ok(Vec::new());
+            }
+
+            fds.parse::<RawFd>().map_err(|_| {
+                ErrorKind::InvalidInput(format!(
+                    "invalid LISTEN_FDS provided by systemd: {}", fds,
+                ))
+            })?
+        }
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut listeners = Vec::new();
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count {
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+        // Only TCP sockets are supported
+        if listener.local_addr().is_err() {
+            return Err(ErrorKind::InvalidInput(format!(
+                "the socket passed by systemd as fd {} is not a TCP socket",
+                fd,
+            )).into());
+        }
+
+        listeners.push(listener);
+    }
+
+    Ok(listeners)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use nix::unistd::getpid;
+
+    use super::take_listeners;
+
+
+    #[test]
+    fn test_take_listeners() {
+        let pid = getpid().to_string();
+
+        // Nothing is returned without socket activation
+        assert!(take_listeners(None, None).unwrap().is_empty());
+        assert!(take_listeners(Some(&pid), None).unwrap().is_empty());
+
+        // Sockets meant for other processes are ignored
+        assert!(take_listeners(Some("1"), Some("1")).unwrap().is_empty());
+
+        assert!(take_listeners(Some(&pid), Some("0")).unwrap().is_empty());
+        assert!(take_listeners(Some(&pid), Some("a")).is_err());
+    }
+}
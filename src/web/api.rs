@@ -13,27 +13,64 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::cmp;
+use std::collections::HashMap;
 use std::net::IpAddr;
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use serde_json;
 
 use common::prelude::*;
-use common::config::RateLimitConfig;
+use common::config::{HttpConfig, QueueOverflow};
 
+use events::JobEventKind;
+use metrics::Metrics;
 use requests::{Request, RequestType};
-use scripts::{Repository, Job};
-use web::rate_limits::RateLimiter;
+use scripts::{Repository, Job, JobOutcome, RateLimitResult, Script};
+use scripts::{DispatchedJob, RemoteOutput};
+use siem::SiemSink;
+use utils::Secrets;
+use web::access_log::AccessLogEntry;
+use web::cors::Cors;
+use web::deliveries::SeenDeliveries;
+use web::rate_limits::{RateLimiter, TokenBuckets};
 use web::responses::Response;
 
 
+/// How long senders are asked to wait when the queue is full, in seconds.
+const QUEUE_FULL_RETRY_AFTER: u64 = 60;
+
+/// How often blocked webhooks check if the queue has room, in milliseconds.
+const QUEUE_FULL_POLL_INTERVAL: u64 = 100;
+
+
+/// Reload the scripts from the disk.
+pub type ReloadScripts = Arc<Fn() -> Result<()> + Send + Sync>;
+
+
 #[derive(Clone)]
 pub struct WebApi<A: ProcessorApiTrait<Repository>> {
     processor: Arc<Mutex<A>>,
     hooks: Arc<Repository>,
     locked: Arc<AtomicBool>,
     limiter: Arc<Mutex<RateLimiter<IpAddr>>>,
+    request_limiter: Option<Arc<Mutex<TokenBuckets<IpAddr>>>>,
+    deliveries: Arc<Mutex<SeenDeliveries>>,
+    siem: Option<Arc<SiemSink>>,
+    metrics: Option<Arc<Metrics>>,
+    admin_tokens: Option<Secrets>,
+    monitoring_tokens: Option<Secrets>,
+    worker_tokens: Option<Secrets>,
+    reload: ReloadScripts,
 
     health_enabled: bool,
+    route_prefix: String,
+    max_queued_jobs: Option<usize>,
+    queue_overflow: QueueOverflow,
+    cors: Option<Cors>,
 }
 
 impl<A: ProcessorApiTrait<Repository>> WebApi<A> {
@@ -41,22 +78,190 @@ impl<A: ProcessorApiTrait<Repository>> WebApi<A> {
         processor: A,
         hooks: Arc<Repository>,
         locked: Arc<AtomicBool>,
-        rate_limit_config: &RateLimitConfig,
-        health_enabled: bool,
+        config: &HttpConfig,
+        siem: Option<SiemSink>,
+        metrics: Arc<Metrics>,
+        reload: ReloadScripts,
     ) -> Self {
         let limiter = Arc::new(Mutex::new(RateLimiter::new(
-            rate_limit_config.allowed,
-            rate_limit_config.interval.as_u64(),
+            config.rate_limit.allowed,
+            config.rate_limit.interval.as_u64(),
         )));
+        let request_limiter = config.request_limit.as_ref().map(|config| {
+            Arc::new(Mutex::new(TokenBuckets::new(
+                config.rate.allowed,
+                config.rate.interval.as_u64(),
+                config.burst(),
+            )))
+        });
 
         WebApi {
             processor: Arc::new(Mutex::new(processor)),
-            hooks, locked, limiter, health_enabled,
+            request_limiter,
+            deliveries: Arc::new(Mutex::new(
+                SeenDeliveries::new(config.replay_cache),
+            )),
+            siem: siem.map(Arc::new),
+            metrics: if config.metrics_endpoint {
+                Some(metrics)
+            } else {
+                None
+            },
+            admin_tokens: config.admin_tokens.clone(),
+            monitoring_tokens: config.monitoring_tokens.clone(),
+            worker_tokens: config.worker_tokens.clone(),
+            health_enabled: config.health_endpoint,
+            route_prefix: config.route_prefix().to_string(),
+            max_queued_jobs: config.max_queued_jobs,
+            queue_overflow: config.queue_overflow,
+            cors: Cors::new(&config.cors),
+            hooks, locked, limiter, reload,
+        }
+    }
+
+    /// Update the metrics of the hook, if they're exported.
+    fn record(&self, update: fn(&Metrics, &str), hook: &str) {
+        if let Some(ref metrics) = self.metrics {
+            update(metrics, hook);
+        }
+    }
+
+    /// Check if the queue reached the configured limit.
+    fn queue_full(&self) -> bool {
+        match self.max_queued_jobs {
+            Some(max) => {
+                let processor = self.processor.lock().unwrap();
+                processor.health_details().unwrap().queued_jobs >= max
+            }
+            None => false,
+        }
+    }
+
+    /// Forget a delivery which was rejected, so it's accepted when the
+    /// service sends it again.
+    fn forget_delivery(&self, hook: &str, delivery: &Option<(String, String)>) {
+        if let Some((ref provider, ref id)) = *delivery {
+            self.deliveries.lock().unwrap().forget(hook, provider, id);
         }
     }
 
-    pub fn process_hook(&self, req: &Request, args: Vec<String>) -> Response {
-        let hook_name = &args[0];
+    /// Make room in the queue for a new job according to the overflow
+    /// policy, returning `false` if the job should be rejected.
+    fn make_room(&self) -> bool {
+        if !self.queue_full() {
+            return true;
+        }
+
+        match self.queue_overflow {
+            QueueOverflow::Reject => false,
+            // The job is rejected anyway if there is nothing to drop
+            QueueOverflow::DropOldest => {
+                self.processor.lock().unwrap().drop_oldest().unwrap()
+            }
+            QueueOverflow::Block => {
+                while self.queue_full() {
+                    thread::sleep(
+                        Duration::from_millis(QUEUE_FULL_POLL_INTERVAL),
+                    );
+                }
+                true
+            }
+        }
+    }
+
+    /// Check if the request can use the admin endpoints, returning the
+    /// response to send back if it can't.
+    fn admin_denied(&self, req: &Request) -> Option<Response> {
+        match self.admin_tokens {
+            Some(ref tokens) => unauthorized(tokens, req),
+            None => Some(Response::Forbidden),
+        }
+    }
+
+    /// Check if the request can use the monitoring endpoints, returning the
+    /// response to send back if it can't.
+    fn monitoring_denied(&self, req: &Request) -> Option<Response> {
+        match self.monitoring_tokens {
+            Some(ref tokens) => unauthorized(tokens, req),
+            None => None,
+        }
+    }
+
+    /// Check if the request can send jobs to this worker, returning the
+    /// response to send back if it can't.
+    fn worker_denied(&self, req: &Request) -> Option<Response> {
+        match self.worker_tokens {
+            Some(ref tokens) => unauthorized(tokens, req),
+            None => Some(Response::Forbidden),
+        }
+    }
+
+    /// Check if the client exceeded the limit applied to every request.
+    fn request_limited(&self, req: &Request) -> Option<Response> {
+        if let Some(ref limiter) = self.request_limiter {
+            if let Ok(r) = req.web() {
+                let limited = limiter.lock().unwrap().take(r.source);
+                return limited.map(Response::TooManyRequests);
+            }
+        }
+        None
+    }
+
+    /// Let web pages read the response, if their origin is allowed.
+    fn allow_cross_origin(
+        &self,
+        req: &Request,
+        response: Response,
+    ) -> Response {
+        match (self.cors.as_ref(), req.web()) {
+            (Some(cors), Ok(web)) => cors.apply(web, response),
+            _ => response,
+        }
+    }
+
+    pub fn process_hook(
+        &self,
+        req: &Request,
+        args: Vec<String>,
+        entry: &mut AccessLogEntry,
+    ) -> Response {
+        let response = self.handle_hook(req, entry, |hooks| {
+            // Hooks with custom routes are reachable only through them
+            hooks.get_by_name(&args[0]).and_then(|hook| {
+                if hook.routes().is_empty() {
+                    Some(hook)
+                } else {
+                    None
+                }
+            })
+        });
+        self.allow_cross_origin(req, response)
+    }
+
+    pub fn process_route(
+        &self,
+        req: &Request,
+        args: Vec<String>,
+        entry: &mut AccessLogEntry,
+    ) -> Response {
+        let response = self.handle_hook(
+            req, entry, |hooks| hooks.get_by_route(&args[0]),
+        );
+        self.allow_cross_origin(req, response)
+    }
+
+    fn handle_hook<F>(
+        &self,
+        req: &Request,
+        entry: &mut AccessLogEntry,
+        find: F,
+    ) -> Response
+    where
+        F: FnOnce(&Repository) -> Option<Arc<Script>>,
+    {
+        if let Some(response) = self.request_limited(req) {
+            return response;
+        }
 
         // Don't process hooks if the web api is locked
         if self.locked.load(Ordering::Relaxed) {
@@ -73,36 +278,245 @@ impl<A: ProcessorApiTrait<Repository>> WebApi<A> {
 
         // Check if the hook exists
         let hook;
-        if let Some(found) = self.hooks.get_by_name(hook_name) {
+        if let Some(found) = find(&*self.hooks) {
             hook = found;
         } else {
             return Response::NotFound;
         }
+        let hook_name = &hook.name().to_string();
+        entry.hook = Some(hook_name.clone());
+
+        // Reject the methods the hook doesn't expect, like the GET requests
+        // sent by crawlers
+        if let Ok(web) = req.web() {
+            let methods = hook.methods()
+                .iter()
+                .map(|method| method.name().to_string())
+                .collect::<Vec<_>>();
+
+            // Browsers ask if they can call the hook before doing so
+            if web.method == "OPTIONS" {
+                if let Some(ref cors) = self.cors {
+                    if let Some(response) = cors.preflight(web, &methods) {
+                        return response;
+                    }
+                }
+            }
+
+            if !hook.allows_method(&web.method) {
+                return Response::MethodNotAllowed(methods);
+            }
+        }
+
+        self.record(Metrics::request_received, hook_name);
+
+        // Spooled bodies are loaded only while the request is validated, the
+        // queued job keeps them on disk
+        let loaded = match req.with_body() {
+            Ok(loaded) => loaded,
+            Err(error) => return Response::BadRequest(error),
+        };
+
+        // Malformed XML bodies never reach the providers
+        if let Ok(web) = loaded.web() {
+            if let Err(error) = web.xml() {
+                return Response::BadRequest(error);
+            }
+        }
 
         // Validate the hook
-        let (request_type, provider) = hook.validate(req);
+        let (request_type, provider, provider_env) = hook.validate(&loaded);
+        entry.result = Some(request_type);
+        entry.provider = provider.as_ref().map(|p| p.name().to_string());
+
+        // The provider might want to send a specific response back
+        let response = match request_type {
+            RequestType::Invalid => None,
+            _ => provider
+                .as_ref()
+                .and_then(|p| p.response(&loaded, request_type)),
+        };
+        let response = response.map(Response::Provider).unwrap_or(Response::Ok);
 
         // Change behavior based on the request type
         match request_type {
-            // Don't do anything if it's only a ping
-            RequestType::Ping => Response::Ok,
+            // Don't do anything if it's only a ping, but the upstream is
+            // still alive
+            RequestType::Ping => {
+                self.hooks.record_delivery(hook_name);
+                self.record(Metrics::request_accepted, hook_name);
+                response
+            },
+
+            // Handshakes are answered by the provider
+            RequestType::Handshake => {
+                self.record(Metrics::request_accepted, hook_name);
+                response
+            },
 
             // Queue a job if the hook should be executed
             RequestType::ExecuteHook => {
-                let job = Job::new(hook.clone(), provider, req.clone());
-                self.processor
-                    .lock()
-                    .unwrap()
-                    .queue(job, hook.priority())
-                    .unwrap();
-
-                Response::Ok
+                // Drop redeliveries of requests already accepted before
+                // anything else, answering them as usual so the service stops
+                // retrying
+                let delivery = provider.as_ref().and_then(|provider| {
+                    provider.delivery_id(&loaded)
+                        .map(|id| (provider.name().to_string(), id))
+                });
+                if let Some((ref provider_name, ref id)) = delivery {
+                    let mut deliveries = self.deliveries.lock().unwrap();
+                    if deliveries.check(hook_name, provider_name, id) {
+                        return response;
+                    }
+                }
+
+                // The hook's rate limit decides when the job can be run
+                let delay = match hook.rate_limit() {
+                    RateLimitResult::Allowed => None,
+                    RateLimitResult::Delayed(delay) => Some(delay),
+                    RateLimitResult::Rejected(retry_after) => {
+                        self.forget_delivery(hook_name, &delivery);
+                        self.record(Metrics::request_rejected, hook_name);
+                        return Response::TooManyRequests(retry_after);
+                    }
+                };
+
+                // Delayed hooks wait even if the rate limit doesn't
+                let delay = match (delay, hook.delay()) {
+                    (Some(limit), Some(hook)) => Some(cmp::max(limit, hook)),
+                    (limit, hook) => limit.or(hook),
+                };
+
+                // Let the sender retry later instead of growing the queue
+                // without bounds
+                if !self.make_room() {
+                    self.forget_delivery(hook_name, &delivery);
+                    self.record(Metrics::request_rejected, hook_name);
+                    return Response::TooManyRequests(
+                        Duration::from_secs(QUEUE_FULL_RETRY_AFTER),
+                    );
+                }
+
+                self.hooks.record_delivery(hook_name);
+                self.record(Metrics::request_accepted, hook_name);
+
+                // Trusted providers can make the job jump ahead of the queue
+                let priority = provider
+                    .as_ref()
+                    .and_then(|provider| provider.priority(&loaded))
+                    .unwrap_or_else(|| hook.priority());
+
+                // Coalesced jobs are grouped by a variable the provider
+                // exposes to the script
+                let coalesce_key = hook.coalesce().map(|coalesce| {
+                    let env = match (&provider_env, &provider) {
+                        (&Some(ref env), _) => env.clone(),
+                        (&None, &Some(ref provider)) => provider.env(&loaded),
+                        (&None, &None) => HashMap::new(),
+                    };
+                    coalesce.key(&env)
+                });
+
+                // Don't keep spooled bodies in memory while waiting for
+                // synchronous hooks
+                drop(loaded);
+
+                let mut job = Job::new(hook.clone(), provider, req.clone())
+                    .with_provider_env(provider_env);
+                let job_id = job.id().to_string();
+
+                // Wait for newer jobs superseding this one before running it
+                let delay = match (hook.coalesce(), coalesce_key) {
+                    (Some(coalesce), Some(key)) => {
+                        coalesce.push(key, &job_id);
+                        match delay {
+                            Some(delay) if delay > coalesce.window() => {
+                                Some(delay)
+                            }
+                            _ => Some(coalesce.window()),
+                        }
+                    }
+                    _ => delay,
+                };
+
+                // Clients of synchronous hooks wait for the output of the job
+                let mut output = None;
+                if hook.is_sync() {
+                    let (output_send, output_recv) = mpsc::channel();
+                    job = job.reply_to(output_send);
+                    output = Some(output_recv);
+                }
+
+                // Persist the job before queueing it, so it's not lost if
+                // Fisher stops before running it
+                let journal = self.hooks.journal();
+                if let Err(error) = journal.queued(&job, priority) {
+                    error.pretty_print();
+                }
+
+                // Publish the event first, as the job could start right away
+                self.hooks.events().publish(job.event(JobEventKind::Queued));
+
+                {
+                    let processor = self.processor.lock().unwrap();
+                    let result = if let Some(delay) = delay {
+                        processor.queue_delayed(job, priority, delay)
+                    } else {
+                        processor.queue(job, priority)
+                    };
+                    result.unwrap();
+                }
+
+                let response = if let Some(output) = output {
+                    // Synchronous hooks always have a timeout
+                    let timeout = hook.timeout().unwrap();
+
+                    match output.recv_timeout(timeout) {
+                        Ok(output) => Response::ScriptOutput {
+                            success: output.success,
+                            stdout: output.stdout,
+                        },
+                        Err(mpsc::RecvTimeoutError::Timeout) => {
+                            Response::GatewayTimeout
+                        }
+                        // The job failed before producing any output
+                        Err(mpsc::RecvTimeoutError::Disconnected) => {
+                            Response::ScriptOutput {
+                                success: false,
+                                stdout: String::new(),
+                            }
+                        }
+                    }
+                } else {
+                    match response {
+                        Response::Ok => Response::JobQueued(job_id.clone()),
+                        other => other,
+                    }
+                };
+
+                // The status of the job can be polled with its ID
+                Response::WithHeaders(
+                    Box::new(response), vec![format!("X-Job-Id: {}", job_id)],
+                )
             },
 
             RequestType::Invalid => {
-                // Increment the limits for the user
+                self.record(Metrics::request_rejected, hook_name);
+
                 if let Ok(r) = req.web() {
-                    self.limiter.lock().unwrap().increment(r.source);
+                    // Increment the limits for the user
+                    let mut limiter = self.limiter.lock().unwrap();
+                    limiter.increment(r.source);
+
+                    // Report the failure to the SIEM, if enabled
+                    if let Some(ref siem) = self.siem {
+                        siem.validation_failed(r.source, hook_name);
+
+                        // The user wasn't limited before this request
+                        if limiter.is_limited(&r.source).is_some() {
+                            siem.source_banned(r.source, hook_name);
+                        }
+                    }
                 }
 
                 Response::Forbidden
@@ -110,13 +524,344 @@ impl<A: ProcessorApiTrait<Repository>> WebApi<A> {
         }
     }
 
-    pub fn get_health(&self, _req: &Request, _args: Vec<String>) -> Response {
+    pub fn job_status(
+        &self,
+        req: &Request,
+        args: Vec<String>,
+        _entry: &mut AccessLogEntry,
+    ) -> Response {
+        if let Some(response) = self.request_limited(req) {
+            return response;
+        }
+
+        match self.hooks.events().status(&args[0]) {
+            Some(status) => Response::Json(json!(status)),
+            None => Response::NotFound,
+        }
+    }
+
+    pub fn get_health(
+        &self,
+        req: &Request,
+        _args: Vec<String>,
+        _entry: &mut AccessLogEntry,
+    ) -> Response {
+        if let Some(response) = self.request_limited(req) {
+            return response;
+        }
+
+        if let Some(response) = self.monitoring_denied(req) {
+            return response;
+        }
+
         if self.health_enabled {
             Response::HealthStatus(
                 self.processor.lock().unwrap().health_details().unwrap(),
+                self.hooks.health(),
             )
         } else {
             Response::Forbidden
         }
     }
+
+    pub fn get_metrics(
+        &self,
+        req: &Request,
+        _args: Vec<String>,
+        _entry: &mut AccessLogEntry,
+    ) -> Response {
+        if let Some(response) = self.request_limited(req) {
+            return response;
+        }
+
+        // Hook names are never shown to unauthenticated clients
+        if let Some(response) = self.monitoring_denied(req) {
+            return response;
+        }
+
+        if let Some(ref metrics) = self.metrics {
+            let health =
+                self.processor.lock().unwrap().health_details().unwrap();
+            Response::PlainText(metrics.render(&health))
+        } else {
+            Response::Forbidden
+        }
+    }
+
+    pub fn admin_hooks(
+        &self,
+        req: &Request,
+        _args: Vec<String>,
+        _entry: &mut AccessLogEntry,
+    ) -> Response {
+        if let Some(response) = self.admin_denied(req) {
+            return response;
+        }
+
+        let hooks = self.hooks.iter().map(|hook| json!({
+            "name": hook.name(),
+            "providers": hook.providers()
+                .iter()
+                .map(|provider| provider.name())
+                .collect::<Vec<_>>(),
+            "routes": hook.routes(),
+            "urls": self.hook_urls(&hook),
+            "priority": hook.priority(),
+            "parallel": hook.can_be_parallel(),
+            "concurrency": hook.max_concurrency(),
+        })).collect::<Vec<_>>();
+
+        Response::Json(json!(hooks))
+    }
+
+    /// Return the paths the hook is reachable from.
+    fn hook_urls(&self, hook: &Script) -> Vec<String> {
+        if hook.routes().is_empty() {
+            vec![format!("{}/hook/{}", self.route_prefix, hook.name())]
+        } else {
+            hook.routes()
+                .iter()
+                .map(|route| format!("{}/{}", self.route_prefix, route))
+                .collect()
+        }
+    }
+
+    pub fn admin_queue(
+        &self,
+        req: &Request,
+        _args: Vec<String>,
+        _entry: &mut AccessLogEntry,
+    ) -> Response {
+        if let Some(response) = self.admin_denied(req) {
+            return response;
+        }
+
+        let queued = self.processor.lock().unwrap().queued_jobs().unwrap();
+        Response::Json(json!(queued))
+    }
+
+    pub fn admin_jobs(
+        &self,
+        req: &Request,
+        _args: Vec<String>,
+        _entry: &mut AccessLogEntry,
+    ) -> Response {
+        if let Some(response) = self.admin_denied(req) {
+            return response;
+        }
+
+        let params = match req.web() {
+            Ok(web) => &web.params,
+            Err(error) => return Response::BadRequest(error),
+        };
+
+        let outcome = params.get("outcome").map(|o| o.parse::<JobOutcome>());
+        let outcome = match outcome {
+            Some(Ok(outcome)) => Some(outcome),
+            Some(Err(error)) => return Response::BadRequest(error),
+            None => None,
+        };
+        let limit = match params.get("limit").map(|l| l.parse::<usize>()) {
+            Some(Ok(limit)) if limit > 0 => Some(limit),
+            Some(_) => {
+                return Response::BadRequest(ErrorKind::InvalidInput(
+                    format!("invalid limit: {}", params["limit"]),
+                ).into());
+            }
+            None => None,
+        };
+        let hook = params.get("hook").map(|hook| hook.as_str());
+
+        Response::Json(json!(self.hooks.recent_jobs(hook, outcome, limit)))
+    }
+
+    pub fn admin_events(
+        &self,
+        req: &Request,
+        _args: Vec<String>,
+        _entry: &mut AccessLogEntry,
+    ) -> Response {
+        if let Some(response) = self.admin_denied(req) {
+            return response;
+        }
+
+        Response::EventStream(self.hooks.events().subscribe())
+    }
+
+    pub fn admin_threads(
+        &self,
+        req: &Request,
+        args: Vec<String>,
+        _entry: &mut AccessLogEntry,
+    ) -> Response {
+        if let Some(response) = self.admin_denied(req) {
+            return response;
+        }
+
+        let count = match args[0].parse::<u16>() {
+            Ok(count) if count > 0 => count,
+            _ => {
+                return Response::BadRequest(ErrorKind::InvalidInput(
+                    format!("invalid threads count: {}", args[0]),
+                ).into());
+            }
+        };
+        self.processor.lock().unwrap().set_threads_count(count).unwrap();
+
+        Response::Ok
+    }
+
+    pub fn admin_reload(
+        &self,
+        req: &Request,
+        _args: Vec<String>,
+        _entry: &mut AccessLogEntry,
+    ) -> Response {
+        if let Some(response) = self.admin_denied(req) {
+            return response;
+        }
+
+        if let Err(error) = (self.reload)() {
+            error.pretty_print();
+            return Response::BadRequest(error);
+        }
+        self.processor.lock().unwrap().cleanup().unwrap();
+
+        Response::Ok
+    }
+
+    pub fn admin_requeue(
+        &self,
+        req: &Request,
+        args: Vec<String>,
+        _entry: &mut AccessLogEntry,
+    ) -> Response {
+        if let Some(response) = self.admin_denied(req) {
+            return response;
+        }
+
+        let dead_letters = self.hooks.dead_letters();
+        let letter = match dead_letters.get(&args[0]) {
+            Ok(Some(letter)) => letter,
+            Ok(None) => return Response::NotFound,
+            Err(error) => return Response::BadRequest(error),
+        };
+
+        // The hook might have been removed since the job failed
+        let (job, priority) = match self.hooks.restore_job(letter.entry) {
+            Some(restored) => restored,
+            None => return Response::NotFound,
+        };
+        let job_id = job.id().to_string();
+
+        // The file is removed first, since the job could fail again and be
+        // saved with the same ID before it's queued
+        if let Err(error) = dead_letters.remove(&job_id) {
+            return Response::BadRequest(error);
+        }
+
+        if let Err(error) = self.hooks.journal().queued(&job, priority) {
+            error.pretty_print();
+        }
+        self.hooks.events().publish(job.event(JobEventKind::Queued));
+        self.processor.lock().unwrap().queue(job, priority).unwrap();
+
+        Response::JobQueued(job_id)
+    }
+
+    pub fn worker_run(
+        &self,
+        req: &Request,
+        _args: Vec<String>,
+        entry: &mut AccessLogEntry,
+    ) -> Response {
+        if let Some(response) = self.worker_denied(req) {
+            return response;
+        }
+
+        // The dispatcher tries another worker if this one is locked
+        if self.locked.load(Ordering::Relaxed) {
+            return Response::Unavailable;
+        }
+
+        let loaded = match req.with_body() {
+            Ok(loaded) => loaded,
+            Err(error) => return Response::BadRequest(error),
+        };
+        let dispatched = loaded
+            .web()
+            .and_then(|web| {
+                Ok(serde_json::from_str::<DispatchedJob>(&web.body)?)
+            });
+        let dispatched = match dispatched {
+            Ok(dispatched) => dispatched,
+            Err(error) => return Response::BadRequest(error),
+        };
+        entry.hook = Some(dispatched.entry.hook.clone());
+
+        // The hook might not be available on this worker
+        let (job, priority) = match self.hooks.restore_job(dispatched.entry) {
+            Some(restored) => restored,
+            None => return Response::NotFound,
+        };
+
+        // The instance which dispatched the job keeps it in its journal, and
+        // takes care of retrying it
+        let (output_send, output_recv) = mpsc::channel();
+        let job = job.dispatched(dispatched.attempt).reply_to(output_send);
+        self.hooks.events().publish(job.event(JobEventKind::Queued));
+        self.processor.lock().unwrap().queue(job, priority).unwrap();
+
+        // The instance which sent the job stops waiting after its timeout,
+        // so there's no point in waiting more
+        let output = match dispatched.timeout {
+            Some(timeout) => {
+                output_recv.recv_timeout(Duration::from_secs(timeout))
+            }
+            None => output_recv.recv().map_err(|_| {
+                mpsc::RecvTimeoutError::Disconnected
+            }),
+        };
+        let output = match output {
+            Ok(output) => RemoteOutput::from(&output),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                return Response::GatewayTimeout;
+            }
+            // The job failed before producing any output
+            Err(mpsc::RecvTimeoutError::Disconnected) => RemoteOutput {
+                stdout: String::new(),
+                stderr: String::new(),
+                success: false,
+                exit_code: None,
+                signal: None,
+                timed_out: false,
+            },
+        };
+        Response::Json(json!(output))
+    }
+}
+
+
+/// Check if the request contains one of the allowed bearer tokens, returning
+/// the response to send back if it doesn't.
+fn unauthorized(tokens: &Secrets, req: &Request) -> Option<Response> {
+    let header = req.web().ok().and_then(|r| {
+        r.headers
+            .iter()
+            .find(|&(name, _)| name.eq_ignore_ascii_case("Authorization"))
+            .map(|(_, value)| value)
+    });
+
+    let token = header.and_then(|header| {
+        let mut parts = header.splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some("Bearer"), Some(token)) => Some(token.trim()),
+            _ => None,
+        }
+    });
+
+    match token {
+        Some(token) if tokens.contains(token) => None,
+        _ => Some(Response::Unauthorized),
+    }
 }
@@ -15,63 +15,159 @@
 
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::net::SocketAddr;
+use std::net::{SocketAddr, TcpListener};
+use std::time::Duration;
 
 use tiny_http::Method;
 
 use common::prelude::*;
-use common::config::HttpConfig;
+use common::config::{Endpoint, HttpConfig, SiemConfig, SigningConfig};
 
+use metrics::Metrics;
 use scripts::Repository;
+use siem::SiemSink;
+use utils::Signer;
+use web::access_log::AccessLog;
+use web::activation;
 use web::http::HttpServer;
-use web::api::WebApi;
+use web::api::{ReloadScripts, WebApi};
+use web::tls::TlsCertificate;
+use web::unix;
+
+
+/// All the endpoints, available on the main address.
+static ALL_ENDPOINTS: &[Endpoint] = &[
+    Endpoint::Hooks, Endpoint::Health, Endpoint::Metrics, Endpoint::Admin,
+    Endpoint::Worker,
+];
 
 
 pub struct WebApp<A: ProcessorApiTrait<Repository> + 'static> {
-    server: HttpServer<WebApi<A>>,
-    addr: SocketAddr,
+    servers: Vec<HttpServer<WebApi<A>>>,
+    addrs: Vec<SocketAddr>,
     locked: Arc<AtomicBool>,
+    tls: Option<TlsCertificate>,
 }
 
 impl<A: ProcessorApiTrait<Repository>> WebApp<A> {
     pub fn new(
         hooks: Arc<Repository>,
         config: &HttpConfig,
+        siem_config: &SiemConfig,
+        signing_config: &SigningConfig,
         processor: A,
+        metrics: Arc<Metrics>,
+        reload: ReloadScripts,
     ) -> Result<Self> {
+        if config.behind_proxies != 0 && !config.trusted_proxies.is_empty() {
+            return Err(ErrorKind::InvalidInput(
+                "http.behind-proxies and http.trusted-proxies can't be used \
+                 together".into(),
+            ).into());
+        }
+
+        if config.max_connections == 0 {
+            return Err(ErrorKind::InvalidInput(
+                "http.max-connections must be at least 1".into(),
+            ).into());
+        }
+
+        // The base path must be matchable by the HTTP server
+        let valid_base_path = config.base_path.starts_with('/')
+            && config.base_path.chars().all(|c| match c {
+                'a'...'z' | 'A'...'Z' | '0'...'9' | '.' | '/' | '_' | '-' => {
+                    true
+                }
+                _ => false,
+            });
+        if !valid_base_path {
+            return Err(ErrorKind::InvalidInput(format!(
+                "invalid http.base-path: {}", config.base_path,
+            )).into());
+        }
+
         let locked = Arc::new(AtomicBool::new(false));
 
         // Create the web api
-        let api = WebApi::new(
-            processor, hooks, locked.clone(), &config.rate_limit,
-            config.health_endpoint,
-        );
+        let siem = SiemSink::from_config(
+            siem_config, Signer::from_config(signing_config),
+        )?;
+        let api = Arc::new(WebApi::new(
+            processor, hooks, locked.clone(), config, siem, metrics, reload,
+        ));
 
-        // Create the HTTP server
-        let mut server = HttpServer::new(api, config.behind_proxies);
-        server.add_route(Method::Get, "/health", Box::new(WebApi::get_health));
-        server.add_route(
-            Method::Get,
-            "/hook/?",
-            Box::new(WebApi::process_hook),
-        );
-        server.add_route(
-            Method::Post,
-            "/hook/?",
-            Box::new(WebApi::process_hook),
-        );
+        let tls = TlsCertificate::load(&config.tls)?;
 
-        let socket = server.listen(config.bind)?;
+        // Every server writes to the same access log
+        let access_log = match config.access_log {
+            Some(format) => Some(Arc::new(AccessLog::new(
+                format, config.access_log_path.as_ref().map(|p| p.as_str()),
+            )?)),
+            None => None,
+        };
 
-        Ok(WebApp {
-            server: server,
-            addr: socket,
+        // The main address serves every endpoint, while the additional ones
+        // serve only the configured endpoints. The sockets passed by systemd
+        // are served directly in place of the main address.
+        let mut listeners = Vec::new();
+        let activated = activation::listeners()?;
+        if activated.is_empty() {
+            listeners.push((TcpListener::bind(config.bind)?, ALL_ENDPOINTS));
+        }
+        for listener in activated {
+            listeners.push((listener, ALL_ENDPOINTS));
+        }
+        for listener in &config.listeners {
+            listeners.push((
+                TcpListener::bind(listener.bind)?, &listener.endpoints[..],
+            ));
+        }
+
+        let mut app = WebApp {
+            servers: Vec::new(),
+            addrs: Vec::new(),
             locked: locked,
-        })
+            tls: None,
+        };
+
+        // If one of the listeners fails to start, dropping the app stops
+        // the ones already started
+        for (listener, endpoints) in listeners {
+            let mut server =
+                create_server(&api, config, endpoints, &access_log);
+            server.set_trusted_proxies(config.trusted_proxies.clone());
+
+            app.addrs.push(listener.local_addr()?);
+            server.listen_on(listener, tls.as_ref())?;
+            app.servers.push(server);
+        }
+
+        // The clients connected to the Unix socket have no IP address, so
+        // they're never trusted as proxies
+        if let Some(ref unix_config) = config.unix_socket {
+            let mut server =
+                create_server(&api, config, ALL_ENDPOINTS, &access_log);
+            server.listen_on(unix::bind(unix_config)?, tls.as_ref())?;
+            app.servers.push(server);
+        }
+
+        app.tls = tls;
+        Ok(app)
+    }
+
+    /// Check if the TLS certificate on disk is different from the one the
+    /// server was started with.
+    pub fn tls_changed(&self, config: &HttpConfig) -> Result<bool> {
+        Ok(TlsCertificate::load(&config.tls)? != self.tls)
     }
 
     pub fn addr(&self) -> &SocketAddr {
-        &self.addr
+        &self.addrs[0]
+    }
+
+    /// The addresses the app listens on, starting with the main one.
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
     }
 
     pub fn lock(&self) {
@@ -83,7 +179,144 @@ impl<A: ProcessorApiTrait<Repository>> WebApp<A> {
     }
 
     pub fn stop(mut self) {
-        self.server.stop();
+        self.stop_servers();
+    }
+
+    fn stop_servers(&mut self) {
+        for server in &mut self.servers {
+            server.stop();
+        }
+    }
+}
+
+impl<A: ProcessorApiTrait<Repository> + 'static> Drop for WebApp<A> {
+    fn drop(&mut self) {
+        self.stop_servers();
+    }
+}
+
+
+fn create_server<A: ProcessorApiTrait<Repository>>(
+    api: &Arc<WebApi<A>>,
+    config: &HttpConfig,
+    endpoints: &[Endpoint],
+    access_log: &Option<Arc<AccessLog>>,
+) -> HttpServer<WebApi<A>> {
+    let mut server = HttpServer::shared(api.clone(), config.behind_proxies);
+    server.set_route_prefix(config.route_prefix());
+    add_routes(&mut server, endpoints);
+    server.set_drain_timeout(
+        Duration::from_secs(config.drain_timeout.as_u64()),
+    );
+    server.set_max_body_size(config.max_body_size.as_u64());
+    server.set_spool_threshold(config.spool_threshold.as_u64());
+    server.set_max_connections(config.max_connections);
+    server.set_read_timeout(
+        Duration::from_secs(config.read_timeout.as_u64()),
+    );
+    server.set_keep_alive(config.keep_alive);
+    server.set_access_log(access_log.clone());
+    server
+}
+
+
+fn add_routes<A: ProcessorApiTrait<Repository>>(
+    server: &mut HttpServer<WebApi<A>>,
+    endpoints: &[Endpoint],
+) {
+    for endpoint in endpoints {
+        match *endpoint {
+            Endpoint::Hooks => {
+                server.add_route(
+                    Method::Get,
+                    "/hook/?",
+                    Box::new(WebApi::process_hook),
+                );
+                server.add_route(
+                    Method::Post,
+                    "/hook/?",
+                    Box::new(WebApi::process_hook),
+                );
+                server.add_route(
+                    Method::Options,
+                    "/hook/?",
+                    Box::new(WebApi::process_hook),
+                );
+                server.add_route(
+                    Method::Get,
+                    "/jobs/?",
+                    Box::new(WebApi::job_status),
+                );
+            }
+            Endpoint::Health => {
+                server.add_route(
+                    Method::Get,
+                    "/health",
+                    Box::new(WebApi::get_health),
+                );
+            }
+            Endpoint::Metrics => {
+                server.add_route(
+                    Method::Get,
+                    "/metrics",
+                    Box::new(WebApi::get_metrics),
+                );
+            }
+            Endpoint::Admin => {
+                server.add_route(
+                    Method::Get,
+                    "/admin/hooks",
+                    Box::new(WebApi::admin_hooks),
+                );
+                server.add_route(
+                    Method::Get,
+                    "/admin/queue",
+                    Box::new(WebApi::admin_queue),
+                );
+                server.add_route(
+                    Method::Get,
+                    "/admin/jobs",
+                    Box::new(WebApi::admin_jobs),
+                );
+                server.add_route(
+                    Method::Get,
+                    "/admin/events",
+                    Box::new(WebApi::admin_events),
+                );
+                server.add_route(
+                    Method::Post,
+                    "/admin/threads/?",
+                    Box::new(WebApi::admin_threads),
+                );
+                server.add_route(
+                    Method::Post,
+                    "/admin/reload",
+                    Box::new(WebApi::admin_reload),
+                );
+                server.add_route(
+                    Method::Post,
+                    "/admin/requeue/?",
+                    Box::new(WebApi::admin_requeue),
+                );
+            }
+            Endpoint::Worker => {
+                server.add_route(
+                    Method::Post,
+                    "/worker/run",
+                    Box::new(WebApi::worker_run),
+                );
+            }
+        }
+    }
+
+    // The custom routes of the hooks match every path, so they must be
+    // checked after all the other endpoints
+    if endpoints.contains(&Endpoint::Hooks) {
+        server.add_route(Method::Get, "/?", Box::new(WebApi::process_route));
+        server.add_route(Method::Post, "/?", Box::new(WebApi::process_route));
+        server.add_route(
+            Method::Options, "/?", Box::new(WebApi::process_route),
+        );
     }
 }
 
@@ -91,17 +324,53 @@ impl<A: ProcessorApiTrait<Repository>> WebApp<A> {
 #[cfg(test)]
 mod tests {
     use std::io::Read;
+    use std::thread;
+    use std::time::Duration;
 
     use serde_json;
+    use hyper;
     use hyper::status::StatusCode;
     use hyper::method::Method;
     use hyper::header::Headers;
 
     use common::prelude::*;
+    use common::config::{QueueOverflow, WorkersConfig};
 
+    use scripts::{DispatchedJob, Dispatcher, JobContext, JournalEntry};
+    use scripts::StoredRequest;
     use utils::testing::*;
 
 
+    #[test]
+    fn test_listeners() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        // The additional listener only serves the health endpoint
+        let res = inst.listener_request(Method::Get, "/health")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(inst.processor_input().is_some());
+
+        let res = inst.listener_request(Method::Post, "/hook/example.sh")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::NotFound);
+        assert!(inst.processor_input().is_none());
+
+        // The main address serves everything
+        let res = inst.request(Method::Post, "/hook/example.sh?secret=testing")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(inst.processor_input().is_some());
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+
     #[test]
     fn test_startup() {
         let testing_env = TestingEnv::new();
@@ -121,21 +390,21 @@ mod tests {
         let mut inst = testing_env.start_web(true, 0);
 
         // It shouldn't be possible to call a non-existing hook
-        let res = inst.request(Method::Get, "/hook/invalid.sh")
+        let res = inst.request(Method::Post, "/hook/invalid.sh")
             .send()
             .unwrap();
         assert_eq!(res.status, StatusCode::NotFound);
         assert!(inst.processor_input().is_none());
 
         // Call the example hook without authorization
-        let res = inst.request(Method::Get, "/hook/example.sh?secret=invalid")
+        let res = inst.request(Method::Post, "/hook/example.sh?secret=invalid")
             .send()
             .unwrap();
         assert_eq!(res.status, StatusCode::Forbidden);
         assert!(inst.processor_input().is_none());
 
         // Call the example hook with authorization
-        let res = inst.request(Method::Get, "/hook/example.sh?secret=testing")
+        let res = inst.request(Method::Post, "/hook/example.sh?secret=testing")
             .send()
             .unwrap();
         assert_eq!(res.status, StatusCode::Ok);
@@ -144,15 +413,26 @@ mod tests {
         let input = inst.processor_input();
 
         // Assert the right job is queued
-        if let ProcessorApiCall::Queue(job, _) = input.unwrap() {
+        if let ProcessorApiCall::Queue(job, priority) = input.unwrap() {
             assert_eq!(job.script_name(), "example.sh");
+            assert_eq!(priority, 0);
         } else {
             panic!("Wrong processor input received");
         }
 
+        // The provider can override the priority of the hook
+        let res = inst.request(Method::Post, "/hook/example.sh?priority=10")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        match inst.processor_input() {
+            Some(ProcessorApiCall::Queue(_, 10)) => {}
+            _ => panic!("Wrong processor input received"),
+        }
+
         // Call the example hook simulating a Ping
         let res =
-            inst.request(Method::Get, "/hook/example.sh?request_type=ping")
+            inst.request(Method::Post, "/hook/example.sh?request_type=ping")
                 .send()
                 .unwrap();
         assert_eq!(res.status, StatusCode::Ok);
@@ -160,9 +440,31 @@ mod tests {
         // Even if the last request succeded, there shouldn't be any job
         assert!(inst.processor_input().is_none());
 
+        // Call the example hook simulating an handshake
+        let mut res = inst.request(
+            Method::Post,
+            "/hook/example.sh?request_type=handshake&challenge=abcde",
+        ).send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "abcde");
+        assert!(inst.processor_input().is_none());
+
+        // Call the example hook with a custom response
+        let mut res = inst.request(
+            Method::Post,
+            "/hook/example.sh?response=custom",
+        ).send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "custom");
+        assert!(inst.processor_input().is_some());
+
         // Try to call an internal hook (in this case with the Status provider)
         let res = inst.request(
-            Method::Get,
+            Method::Post,
             concat!(
                 "/hook/status-example.sh",
                 "?event=job_completed",
@@ -178,7 +480,7 @@ mod tests {
         assert!(inst.processor_input().is_none());
 
         // Try to call an hook in a sub directory
-        let res = inst.request(Method::Get, "/hook/sub/hook.sh")
+        let res = inst.request(Method::Post, "/hook/sub/hook.sh")
             .send()
             .unwrap();
         assert_eq!(res.status, StatusCode::Ok);
@@ -189,7 +491,7 @@ mod tests {
 
         // Even if this requets is valid, it should not be processed -- the
         // instance is locked
-        let res = inst.request(Method::Get, "/hook/example.sh?secret=testing")
+        let res = inst.request(Method::Post, "/hook/example.sh?secret=testing")
             .send()
             .unwrap();
         assert_eq!(res.status, StatusCode::ServiceUnavailable);
@@ -199,7 +501,7 @@ mod tests {
         inst.unlock();
 
         // Call the example hook with authorization
-        let res = inst.request(Method::Get, "/hook/example.sh?secret=testing")
+        let res = inst.request(Method::Post, "/hook/example.sh?secret=testing")
             .send()
             .unwrap();
         assert_eq!(res.status, StatusCode::Ok);
@@ -211,6 +513,190 @@ mod tests {
         testing_env.cleanup();
     }
 
+    #[test]
+    fn test_hook_methods() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        // Hooks accept only POST requests by default
+        let res = inst.request(Method::Get, "/hook/example.sh")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::MethodNotAllowed);
+        assert_eq!(&res.headers.get_raw("Allow").unwrap()[0], b"POST");
+        assert!(inst.processor_input().is_none());
+
+        // Other methods can be allowed by the hooks
+        for method in &[Method::Get, Method::Post] {
+            let res = inst.request(method.clone(), "/hook/ping.sh")
+                .send()
+                .unwrap();
+            assert_eq!(res.status, StatusCode::Ok);
+            assert!(inst.processor_input().is_some());
+        }
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_cors() {
+        let origin = |origin: &str| {
+            let mut headers = Headers::new();
+            headers.set_raw("Origin", vec![origin.as_bytes().to_vec()]);
+            headers
+        };
+        let allow_origin = |res: &hyper::client::Response| {
+            res.headers
+                .get_raw("Access-Control-Allow-Origin")
+                .map(|value| String::from_utf8(value[0].clone()).unwrap())
+        };
+
+        // Preflight requests are rejected if CORS is disabled
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+        let res = inst.request(Method::Options, "/hook/example.sh")
+            .headers(origin("https://example.com"))
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::MethodNotAllowed);
+        assert_eq!(allow_origin(&res), None);
+        inst.stop();
+
+        let mut inst = testing_env.start_web_with(true, 0, |config| {
+            config.cors.allowed_origins = vec!["https://example.com".into()];
+        });
+
+        // Browsers are allowed to use the methods of the hook
+        for &(url, methods) in &[
+            ("/hook/example.sh", "POST"),
+            ("/hook/ping.sh", "GET, POST"),
+            ("/frontend", "POST"),
+        ] {
+            let res = inst.request(Method::Options, url)
+                .headers(origin("https://example.com"))
+                .send()
+                .unwrap();
+            assert_eq!(res.status, StatusCode::Ok);
+            assert_eq!(allow_origin(&res), Some("https://example.com".into()));
+            let allowed = res.headers
+                .get_raw("Access-Control-Allow-Methods")
+                .unwrap();
+            assert_eq!(&allowed[0], methods.as_bytes());
+            assert!(inst.processor_input().is_none());
+        }
+
+        // The responses can be read by the allowed origins only
+        let res = inst.request(Method::Post, "/hook/example.sh")
+            .headers(origin("https://example.com"))
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert_eq!(allow_origin(&res), Some("https://example.com".into()));
+        assert!(inst.processor_input().is_some());
+
+        let res = inst.request(Method::Options, "/hook/example.sh")
+            .headers(origin("https://evil.com"))
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::MethodNotAllowed);
+        assert_eq!(allow_origin(&res), None);
+
+        // The other endpoints don't allow cross-origin requests
+        let res = inst.request(Method::Get, "/health")
+            .headers(origin("https://example.com"))
+            .send()
+            .unwrap();
+        assert_eq!(allow_origin(&res), None);
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_custom_routes() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        // Every route of the hook can be used
+        for url in &["/deploy/frontend", "/frontend/", "/frontend?env=a"] {
+            let res = inst.request(Method::Post, url).send().unwrap();
+            assert_eq!(res.status, StatusCode::Ok);
+
+            if let Some(ProcessorApiCall::Queue(job, _)) =
+                inst.processor_input()
+            {
+                assert_eq!(job.script_name(), "routed.sh");
+            } else {
+                panic!("Wrong processor input received");
+            }
+        }
+
+        // The hook isn't reachable from its name anymore
+        let res = inst.request(Method::Post, "/hook/routed.sh")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::NotFound);
+
+        // Unknown routes are not found, while the other endpoints still work
+        let res = inst.request(Method::Post, "/deploy").send().unwrap();
+        assert_eq!(res.status, StatusCode::NotFound);
+        let res = inst.request(Method::Get, "/health").send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_base_path() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(true, 0, |config| {
+            config.base_path = "/fisher/".into();
+        });
+
+        // Every endpoint is served under the base path
+        for url in &["/fisher/hook/example.sh", "/fisher/frontend"] {
+            let res = inst.request(Method::Post, url).send().unwrap();
+            assert_eq!(res.status, StatusCode::Ok);
+            assert!(inst.processor_input().is_some());
+        }
+        let res = inst.request(Method::Get, "/fisher/health").send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        // The paths outside of the base path are not found
+        for url in &["/hook/example.sh", "/frontend", "/health"] {
+            let res = inst.request(Method::Get, url).send().unwrap();
+            assert_eq!(res.status, StatusCode::NotFound);
+        }
+
+        // The URLs listed by the admin API include the base path
+        let mut headers = Headers::new();
+        headers.set_raw("Authorization", vec![b"Bearer admin-token".to_vec()]);
+        let mut res = inst.request(Method::Get, "/fisher/admin/hooks")
+            .headers(headers)
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        let data = serde_json::from_str::<serde_json::Value>(&content).unwrap();
+        let routed = data["result"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|hook| hook["name"].as_str() == Some("routed.sh"))
+            .unwrap()
+            .clone();
+        assert_eq!(
+            routed["urls"],
+            json!(["/fisher/deploy/frontend", "/fisher/frontend"])
+        );
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
     #[test]
     fn test_health_disabled() {
         // Create the instance with disabled health status
@@ -256,6 +742,522 @@ mod tests {
             3 as u64
         );
 
+        // The details about the loaded hooks are also included
+        assert!(result.get("hooks_loaded").unwrap().as_u64().unwrap() > 0);
+        assert!(result.get("last_reload").unwrap().as_u64().unwrap() > 0);
+        assert!(result.get("last_failure").unwrap().is_null());
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_metrics() {
+        let testing_env = TestingEnv::new();
+
+        // The metrics aren't exported if they're disabled
+        let mut inst = testing_env.start_web(false, 0);
+        let res = inst.request(Method::Get, "/metrics").send().unwrap();
+        assert_eq!(res.status, StatusCode::Forbidden);
+        inst.stop();
+
+        let mut inst = testing_env.start_web(true, 0);
+
+        // Send a valid and an invalid request to the same hook
+        for secret in &["testing", "invalid"] {
+            let url = format!("/hook/example.sh?secret={}", secret);
+            inst.request(Method::Post, &url).send().unwrap();
+        }
+
+        let mut res = inst.request(Method::Get, "/metrics").send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        let lines = content.lines().collect::<Vec<_>>();
+        for expected in &[
+            "fisher_jobs_queued 1",
+            "fisher_requests_received_total{hook=\"example.sh\"} 2",
+            "fisher_requests_accepted_total{hook=\"example.sh\"} 1",
+            "fisher_requests_rejected_total{hook=\"example.sh\"} 1",
+        ] {
+            assert!(lines.contains(expected), "missing line: {}", expected);
+        }
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_admin() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        let auth = |token: &str| {
+            let mut headers = Headers::new();
+            headers.set_raw(
+                "Authorization",
+                vec![format!("Bearer {}", token).into_bytes()],
+            );
+            headers
+        };
+
+        // Requests without the right token are rejected
+        let res = inst.request(Method::Get, "/admin/hooks").send().unwrap();
+        assert_eq!(res.status, StatusCode::Unauthorized);
+        let res = inst.request(Method::Get, "/admin/hooks")
+            .headers(auth("invalid"))
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Unauthorized);
+
+        // List the hooks
+        let mut res = inst.request(Method::Get, "/admin/hooks")
+            .headers(auth("admin-token"))
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        let data = serde_json::from_str::<serde_json::Value>(&content).unwrap();
+        let example = data["result"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|hook| hook["name"] == "example.sh")
+            .unwrap()
+            .clone();
+        assert_eq!(example["providers"], json!(["Testing"]));
+        assert_eq!(example["urls"], json!(["/hook/example.sh"]));
+
+        // Inspect the queue
+        let mut res = inst.request(Method::Get, "/admin/queue")
+            .headers(auth("admin-token"))
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        let data = serde_json::from_str::<serde_json::Value>(&content).unwrap();
+        assert_eq!(data["result"][0]["hook"], "example.sh");
+        match inst.processor_input() {
+            Some(ProcessorApiCall::QueuedJobs) => {}
+            _ => panic!("the queue wasn't requested"),
+        }
+
+        // Filter the results of the jobs
+        let mut res = inst.request(
+            Method::Get, "/admin/jobs?hook=example.sh&outcome=failed&limit=5",
+        ).headers(auth("admin-token")).send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        let data = serde_json::from_str::<serde_json::Value>(&content).unwrap();
+        assert_eq!(data["result"], json!([]));
+        for query in &["outcome=maybe", "limit=0", "limit=many"] {
+            let url = format!("/admin/jobs?{}", query);
+            let res = inst.request(Method::Get, &url)
+                .headers(auth("admin-token"))
+                .send()
+                .unwrap();
+            assert_eq!(res.status, StatusCode::BadRequest);
+        }
+
+        // Reload the hooks
+        let res = inst.request(Method::Post, "/admin/reload")
+            .headers(auth("admin-token"))
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        match inst.processor_input() {
+            Some(ProcessorApiCall::Cleanup) => {}
+            _ => panic!("the processor wasn't cleaned up"),
+        }
+
+        // Resize the threads pool
+        let res = inst.request(Method::Post, "/admin/threads/4")
+            .headers(auth("admin-token"))
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        match inst.processor_input() {
+            Some(ProcessorApiCall::SetThreadsCount(4)) => {}
+            _ => panic!("the threads count wasn't changed"),
+        }
+        for count in &["0", "-1", "many"] {
+            let url = format!("/admin/threads/{}", count);
+            let res = inst.request(Method::Post, &url)
+                .headers(auth("admin-token"))
+                .send()
+                .unwrap();
+            assert_eq!(res.status, StatusCode::BadRequest);
+        }
+        assert!(inst.processor_input().is_none());
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+
+    #[test]
+    fn test_admin_events() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        // The events are available only to the admins
+        let res = inst.request(Method::Get, "/admin/events").send().unwrap();
+        assert_eq!(res.status, StatusCode::Unauthorized);
+
+        let mut headers = Headers::new();
+        headers.set_raw("Authorization", vec![b"Bearer admin-token".to_vec()]);
+        let mut events = inst.request(Method::Get, "/admin/events")
+            .headers(headers)
+            .send()
+            .unwrap();
+        assert_eq!(events.status, StatusCode::Ok);
+        assert_eq!(
+            &events.headers.get_raw("Content-Type").unwrap()[0],
+            b"text/event-stream"
+        );
+
+        // Queueing a job sends an event to the subscribers
+        let res = inst.request(Method::Post, "/hook/example.sh")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        let job = match inst.processor_input() {
+            Some(ProcessorApiCall::Queue(job, _)) => job,
+            _ => panic!("Wrong processor input received"),
+        };
+
+        let mut event = Vec::new();
+        let mut byte = [0];
+        while !event.ends_with(b"\n\n") {
+            events.read_exact(&mut byte).unwrap();
+            event.push(byte[0]);
+        }
+        let event = String::from_utf8(event).unwrap();
+        let mut lines = event.lines();
+        assert_eq!(lines.next(), Some("event: queued"));
+
+        let data = lines.next().unwrap().trim_left_matches("data: ");
+        let data = serde_json::from_str::<serde_json::Value>(data).unwrap();
+        assert_eq!(data["hook"].as_str(), Some("example.sh"));
+        assert_eq!(data["job"].as_str(), Some(job.id()));
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_job_status() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        // The ID of the job is returned when it's queued
+        let mut res = inst.request(Method::Post, "/hook/example.sh")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        let header = String::from_utf8(
+            res.headers.get_raw("X-Job-Id").unwrap()[0].clone(),
+        ).unwrap();
+
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        let data = serde_json::from_str::<serde_json::Value>(&content).unwrap();
+        let id = data["job_id"].as_str().unwrap().to_string();
+        assert_eq!(id, header);
+
+        match inst.processor_input() {
+            Some(ProcessorApiCall::Queue(job, _)) => assert_eq!(job.id(), id),
+            _ => panic!("Wrong processor input received"),
+        }
+
+        // The status of the job can be polled
+        let url = format!("/jobs/{}", id);
+        let mut res = inst.request(Method::Get, &url).send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        let mut content = String::new();
+        res.read_to_string(&mut content).unwrap();
+        let data = serde_json::from_str::<serde_json::Value>(&content).unwrap();
+        assert_eq!(data["result"]["job"].as_str(), Some(id.as_str()));
+        assert_eq!(data["result"]["hook"].as_str(), Some("example.sh"));
+        assert_eq!(data["result"]["state"].as_str(), Some("queued"));
+        assert!(data["result"]["queued_at"].is_u64());
+        assert!(data["result"]["started_at"].is_null());
+
+        // Unknown jobs are not found
+        let res = inst.request(Method::Get, "/jobs/unknown").send().unwrap();
+        assert_eq!(res.status, StatusCode::NotFound);
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_monitoring_tokens() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(true, 0, |config| {
+            config.monitoring_tokens = Some("monitoring-token".into());
+        });
+
+        // Unauthenticated clients don't get any data
+        for url in &["/health", "/metrics"] {
+            let mut res = inst.request(Method::Get, url).send().unwrap();
+            assert_eq!(res.status, StatusCode::Unauthorized);
+
+            let mut content = String::new();
+            res.read_to_string(&mut content).unwrap();
+            assert!(!content.contains("example.sh"));
+        }
+        assert!(inst.processor_input().is_none());
+
+        // The header name is case insensitive
+        for header in &["Authorization", "authorization"] {
+            let mut headers = Headers::new();
+            headers.set_raw(*header, vec![b"Bearer monitoring-token".to_vec()]);
+
+            for url in &["/health", "/metrics"] {
+                let res = inst.request(Method::Get, url)
+                    .headers(headers.clone())
+                    .send()
+                    .unwrap();
+                assert_eq!(res.status, StatusCode::Ok);
+            }
+        }
+
+        // The monitoring tokens can't be used for the admin API
+        let mut headers = Headers::new();
+        headers.set_raw(
+            "Authorization", vec![b"Bearer monitoring-token".to_vec()],
+        );
+        let res = inst.request(Method::Get, "/admin/hooks")
+            .headers(headers)
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Unauthorized);
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+
+    #[test]
+    fn test_worker_run() {
+        let dispatched = |hook: &str| DispatchedJob {
+            entry: JournalEntry {
+                job_id: "job-id".into(),
+                hook: hook.into(),
+                provider: None,
+                priority: 0,
+                received_at: Some(1000),
+                request: StoredRequest::new(&dummy_web_request()).unwrap(),
+            },
+            attempt: 1,
+            timeout: None,
+        };
+        let job = |hook: &str| {
+            serde_json::to_string(&dispatched(hook)).unwrap()
+        };
+        let mut headers = Headers::new();
+        headers.set_raw("Authorization", vec![b"Bearer worker-token".to_vec()]);
+
+        // Instances without worker tokens don't accept jobs
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+        let res = inst.request(Method::Post, "/worker/run")
+            .headers(headers.clone())
+            .body(&job("example.sh"))
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Forbidden);
+        inst.stop();
+
+        let mut inst = testing_env.start_web_with(true, 0, |config| {
+            config.worker_tokens = Some("worker-token".into());
+        });
+
+        let res = inst.request(Method::Post, "/worker/run")
+            .body(&job("example.sh"))
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Unauthorized);
+
+        for &(body, status) in &[
+            ("not json", StatusCode::BadRequest),
+            (&job("missing.sh") as &str, StatusCode::NotFound),
+        ] {
+            let res = inst.request(Method::Post, "/worker/run")
+                .headers(headers.clone())
+                .body(body)
+                .send()
+                .unwrap();
+            assert_eq!(res.status, status);
+        }
+        assert!(inst.processor_input().is_none());
+
+        // Dispatch the job in the background, like the processor does
+        let dispatcher = Dispatcher::from_config(&WorkersConfig {
+            urls: vec![inst.url().to_string()],
+            token: Some("worker-token".into()),
+        }).unwrap().unwrap();
+        let job = dispatched("example.sh");
+        let request = thread::spawn(move || dispatcher.run(&job, None));
+
+        let job = match inst.wait_processor_input(Duration::from_secs(5)) {
+            Some(ProcessorApiCall::Queue(job, _)) => job,
+            _ => panic!("The job wasn't queued"),
+        };
+        assert!(!job.trigger_status_hooks());
+        job.execute(&JobContext::default()).unwrap();
+
+        let output = request.join().unwrap().unwrap();
+        assert!(output.success);
+        assert_eq!(output.stdout, "Hello world\n");
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+
+    #[test]
+    fn test_sync_hook() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        // Nothing runs the job, so the request times out
+        let res = inst.request(Method::Post, "/hook/sync.sh?secret=testing")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::GatewayTimeout);
+        assert!(inst.processor_input().is_some());
+
+        // Run the job in the background, like the processor does
+        let url = format!("{}/hook/sync.sh?secret=testing", inst.url());
+        let request = thread::spawn(move || {
+            let mut res = hyper::Client::new().post(&url).send().unwrap();
+            let mut content = String::new();
+            res.read_to_string(&mut content).unwrap();
+            (res.status, content)
+        });
+
+        let job = match inst.wait_processor_input(Duration::from_secs(5)) {
+            Some(ProcessorApiCall::Queue(job, _)) => job,
+            _ => panic!("The job wasn't queued"),
+        };
+        job.execute(&JobContext::default()).unwrap();
+
+        let (status, content) = request.join().unwrap();
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(content, "Hello sync\n");
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+
+    #[test]
+    fn test_replay_protection() {
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web(true, 0);
+
+        // The first delivery is executed
+        let url = "/hook/example.sh?delivery=1";
+        let res = inst.request(Method::Post, url).send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(inst.processor_input().is_some());
+
+        // Redeliveries are answered but not executed
+        let res = inst.request(Method::Post, url).send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(inst.processor_input().is_none());
+
+        // The same identifier can be used by other hooks
+        let url = "/hook/sub/hook.sh?delivery=1";
+        let res = inst.request(Method::Post, url).send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert!(inst.processor_input().is_some());
+
+        // Deliveries without an identifier are always executed
+        for _ in 0..2 {
+            let res = inst.request(Method::Post, "/hook/example.sh")
+                .send()
+                .unwrap();
+            assert_eq!(res.status, StatusCode::Ok);
+            assert!(inst.processor_input().is_some());
+        }
+
+        inst.stop();
+        testing_env.cleanup();
+    }
+
+    #[test]
+    fn test_max_queued_jobs() {
+        // The fake processor always has one job in the queue
+        let testing_env = TestingEnv::new();
+        let mut inst = testing_env.start_web_with(true, 0, |config| {
+            config.max_queued_jobs = Some(1);
+        });
+
+        // Webhooks are rejected while the queue is full
+        let res = inst.request(Method::Post, "/hook/example.sh")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::TooManyRequests);
+        assert_eq!(&res.headers.get_raw("Retry-After").unwrap()[0], b"60");
+        match inst.processor_input() {
+            Some(ProcessorApiCall::HealthDetails) => {}
+            _ => panic!("Wrong processor input received"),
+        }
+        assert!(inst.processor_input().is_none());
+
+        inst.stop();
+
+        // Webhooks are queued if there is still space in the queue
+        let mut inst = testing_env.start_web_with(true, 0, |config| {
+            config.max_queued_jobs = Some(2);
+        });
+
+        let res = inst.request(Method::Post, "/hook/example.sh")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        match inst.processor_input() {
+            Some(ProcessorApiCall::HealthDetails) => {}
+            _ => panic!("Wrong processor input received"),
+        }
+        match inst.processor_input() {
+            Some(ProcessorApiCall::Queue(..)) => {}
+            _ => panic!("Wrong processor input received"),
+        }
+
+        inst.stop();
+
+        // The oldest job can be dropped to make room for new webhooks
+        let mut inst = testing_env.start_web_with(true, 0, |config| {
+            config.max_queued_jobs = Some(1);
+            config.queue_overflow = QueueOverflow::DropOldest;
+        });
+
+        let res = inst.request(Method::Post, "/hook/example.sh")
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        match inst.processor_input() {
+            Some(ProcessorApiCall::HealthDetails) => {}
+            _ => panic!("Wrong processor input received"),
+        }
+        match inst.processor_input() {
+            Some(ProcessorApiCall::DropOldest) => {}
+            _ => panic!("Wrong processor input received"),
+        }
+        match inst.processor_input() {
+            Some(ProcessorApiCall::Queue(..)) => {}
+            _ => panic!("Wrong processor input received"),
+        }
+
         inst.stop();
         testing_env.cleanup();
     }
@@ -267,7 +1269,7 @@ mod tests {
         let mut inst = testing_env.start_web(true, 1);
 
         // Call the example hook without a proxy
-        let res = inst.request(Method::Get, "/hook/example.sh?ip=127.1.1.1")
+        let res = inst.request(Method::Post, "/hook/example.sh?ip=127.1.1.1")
             .send()
             .unwrap();
         assert_eq!(res.status, StatusCode::BadRequest);
@@ -278,7 +1280,7 @@ mod tests {
         headers.set_raw("X-Forwarded-For", vec![b"127.1.1.1".to_vec()]);
 
         // Make an example request
-        let res = inst.request(Method::Get, "/hook/example.sh?ip=127.1.1.1")
+        let res = inst.request(Method::Post, "/hook/example.sh?ip=127.1.1.1")
             .headers(headers)
             .send()
             .unwrap();
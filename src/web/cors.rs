@@ -0,0 +1,187 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Cross-origin requests to the hooks, sent by web pages.
+
+use common::config::CorsConfig;
+use web::requests::WebRequest;
+use web::responses::Response;
+
+
+/// The origins allowed to call the hooks from a browser.
+#[derive(Debug, Clone)]
+pub struct Cors {
+    allowed_origins: Vec<String>,
+    max_age: u64,
+}
+
+impl Cors {
+    /// Create the policy from the configuration, if any origin is allowed.
+    pub fn new(config: &CorsConfig) -> Option<Self> {
+        if config.allowed_origins.is_empty() {
+            return None;
+        }
+
+        Some(Cors {
+            allowed_origins: config
+                .allowed_origins
+                .iter()
+                .map(|origin| origin.trim_right_matches('/').to_string())
+                .collect(),
+            max_age: config.max_age.as_u64(),
+        })
+    }
+
+    /// Return the value of the `Access-Control-Allow-Origin` header for the
+    /// request, if its origin is allowed.
+    fn allowed_origin(&self, req: &WebRequest) -> Option<String> {
+        let origin = match req.header("Origin") {
+            Some(origin) => origin,
+            None => return None,
+        };
+
+        if self.allowed_origins.iter().any(|allowed| allowed == "*") {
+            Some("*".into())
+        } else if self.allowed_origins
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+        {
+            Some(origin.into())
+        } else {
+            None
+        }
+    }
+
+    /// Allow the browser to read the response, if the origin is allowed.
+    pub fn apply(&self, req: &WebRequest, response: Response) -> Response {
+        match self.allowed_origin(req) {
+            Some(origin) => {
+                let mut headers = vec![
+                    format!("Access-Control-Allow-Origin: {}", origin),
+                ];
+                if origin != "*" {
+                    headers.push("Vary: Origin".into());
+                }
+                Response::WithHeaders(Box::new(response), headers)
+            }
+            None => response,
+        }
+    }
+
+    /// Answer a preflight request, if the origin is allowed.
+    pub fn preflight(
+        &self,
+        req: &WebRequest,
+        methods: &[String],
+    ) -> Option<Response> {
+        if self.allowed_origin(req).is_none() {
+            return None;
+        }
+
+        let mut headers = vec![
+            format!("Access-Control-Allow-Methods: {}", methods.join(", ")),
+            format!("Access-Control-Max-Age: {}", self.max_age),
+        ];
+        let requested = req.header("Access-Control-Request-Headers");
+        if let Some(requested) = requested {
+            headers.push(
+                format!("Access-Control-Allow-Headers: {}", requested),
+            );
+        }
+
+        Some(Response::WithHeaders(Box::new(Response::Ok), headers))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use common::config::CorsConfig;
+    use utils::testing::*;
+    use web::responses::Response;
+
+    use super::Cors;
+
+
+    fn cors(origins: &[&str]) -> Option<Cors> {
+        Cors::new(&CorsConfig {
+            allowed_origins: origins.iter().map(|o| o.to_string()).collect(),
+            max_age: 600.into(),
+        })
+    }
+
+
+    #[test]
+    fn test_apply() {
+        // CORS is disabled without allowed origins
+        assert!(cors(&[]).is_none());
+
+        let cors = cors(&["https://example.com/"]).unwrap();
+        let mut req = dummy_web_request();
+
+        // Requests without an allowed origin are left alone
+        let response = cors.apply(&req, Response::Ok);
+        assert!(response.headers().is_none());
+        req.headers.insert("Origin".into(), "https://evil.com".into());
+        let response = cors.apply(&req, Response::Ok);
+        assert!(response.headers().is_none());
+
+        req.headers.insert("origin".into(), "https://example.com".into());
+        req.headers.remove("Origin");
+        let response = cors.apply(&req, Response::NotFound);
+        assert_eq!(response.status(), 404);
+        assert_eq!(response.headers(), Some(vec![
+            "Access-Control-Allow-Origin: https://example.com".into(),
+            "Vary: Origin".into(),
+        ]));
+    }
+
+
+    #[test]
+    fn test_preflight() {
+        let mut req = dummy_web_request();
+        req.method = "OPTIONS".into();
+        req.headers.insert("Origin".into(), "https://example.com".into());
+
+        let wildcard = cors(&["*"]).unwrap();
+        let response = wildcard.apply(
+            &req, wildcard.preflight(&req, &["POST".into()]).unwrap(),
+        );
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers(), Some(vec![
+            "Access-Control-Allow-Methods: POST".into(),
+            "Access-Control-Max-Age: 600".into(),
+            "Access-Control-Allow-Origin: *".into(),
+        ]));
+
+        // The requested headers are allowed
+        req.headers.insert(
+            "Access-Control-Request-Headers".into(), "Content-Type".into(),
+        );
+        let methods: Vec<String> = vec!["GET".into(), "POST".into()];
+        assert_eq!(
+            wildcard.preflight(&req, &methods).unwrap().headers(),
+            Some(vec![
+                "Access-Control-Allow-Methods: GET, POST".into(),
+                "Access-Control-Max-Age: 600".into(),
+                "Access-Control-Allow-Headers: Content-Type".into(),
+            ])
+        );
+
+        // Other origins can't send preflight requests
+        let other = cors(&["https://other.com"]).unwrap();
+        assert!(other.preflight(&req, &methods).is_none());
+    }
+}
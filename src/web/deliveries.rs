@@ -0,0 +1,132 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Detection of redelivered webhooks.
+//!
+//! Most services deliver a webhook again if they don't get a response in
+//! time, even if the first delivery was received. The identifiers of the
+//! recent deliveries are kept in a bounded LRU, so the redeliveries can be
+//! dropped instead of running the hook twice.
+
+use std::collections::{HashSet, VecDeque};
+
+
+/// The identifier of a delivery, scoped to the hook and the provider.
+type DeliveryKey = (String, String, String);
+
+
+#[derive(Debug)]
+pub struct SeenDeliveries {
+    capacity: usize,
+    keys: HashSet<DeliveryKey>,
+    // Least recently seen first
+    order: VecDeque<DeliveryKey>,
+}
+
+impl SeenDeliveries {
+    pub fn new(capacity: usize) -> Self {
+        SeenDeliveries {
+            capacity,
+            keys: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Record the delivery, returning true if it was already seen recently.
+    pub fn check(&mut self, hook: &str, provider: &str, id: &str) -> bool {
+        if self.capacity == 0 {
+            return false;
+        }
+
+        let key = (hook.to_string(), provider.to_string(), id.to_string());
+        if self.keys.contains(&key) {
+            // Mark the delivery as the most recently seen one
+            if let Some(pos) = self.order.iter().position(|k| *k == key) {
+                let key = self.order.remove(pos).unwrap();
+                self.order.push_back(key);
+            }
+            return true;
+        }
+
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+        self.keys.insert(key.clone());
+        self.order.push_back(key);
+
+        false
+    }
+
+    /// Forget the delivery, so it's not considered a redelivery anymore.
+    pub fn forget(&mut self, hook: &str, provider: &str, id: &str) {
+        let key = (hook.to_string(), provider.to_string(), id.to_string());
+        if self.keys.remove(&key) {
+            self.order.retain(|k| *k != key);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::SeenDeliveries;
+
+
+    #[test]
+    fn test_seen_deliveries() {
+        let mut seen = SeenDeliveries::new(2);
+
+        assert!(!seen.check("a.sh", "GitHub", "1"));
+        assert!(seen.check("a.sh", "GitHub", "1"));
+
+        // Identifiers are scoped to the hook and the provider
+        assert!(!seen.check("b.sh", "GitHub", "1"));
+        assert!(!seen.check("a.sh", "GitLab", "1"));
+
+        // The least recently seen delivery is forgotten
+        assert!(!seen.check("a.sh", "GitHub", "1"));
+        assert!(seen.check("a.sh", "GitHub", "1"));
+        assert!(!seen.check("a.sh", "GitHub", "2"));
+        assert!(seen.check("a.sh", "GitHub", "1"));
+        assert!(!seen.check("a.sh", "GitHub", "3"));
+        assert!(!seen.check("a.sh", "GitHub", "2"));
+    }
+
+
+    #[test]
+    fn test_seen_deliveries_forget() {
+        let mut seen = SeenDeliveries::new(2);
+
+        assert!(!seen.check("a.sh", "GitHub", "1"));
+        seen.forget("a.sh", "GitHub", "1");
+        assert!(!seen.check("a.sh", "GitHub", "1"));
+        assert!(seen.check("a.sh", "GitHub", "1"));
+
+        // Forgetting unknown deliveries does nothing
+        seen.forget("a.sh", "GitHub", "2");
+        assert!(seen.check("a.sh", "GitHub", "1"));
+    }
+
+
+    #[test]
+    fn test_seen_deliveries_disabled() {
+        let mut seen = SeenDeliveries::new(0);
+
+        assert!(!seen.check("a.sh", "GitHub", "1"));
+        assert!(!seen.check("a.sh", "GitHub", "1"));
+    }
+}
@@ -13,23 +13,43 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::net::{Shutdown, SocketAddr, TcpStream};
-use std::io::Write;
-use std::sync::{Arc, Mutex};
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream,
+};
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
 use std::sync::mpsc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread;
+use std::time::{Duration, Instant};
 
+use libc;
 use regex::{self, Regex};
 use tiny_http::{self, Method};
 
 use common::prelude::*;
+use events::Subscription;
 use requests::Request;
+use utils::{self, Cidr};
+use web::access_log::{AccessLog, AccessLogEntry};
+use web::requests::{self, WebRequest};
 use web::responses::Response;
 use web::proxies::ProxySupport;
+use web::tls::TlsCertificate;
 
 
-pub type RequestHandler<App> = Box<fn(&App, &Request, Vec<String>) -> Response>;
+/// How often the server and the event streams check if they should stop.
+const STOP_CHECK_INTERVAL: u64 = 500;
+
+/// How often a comment is sent to idle event streams, to notice the clients
+/// which went away.
+const EVENTS_KEEP_ALIVE: u64 = 15;
+
+
+pub type RequestHandler<App> =
+    Box<fn(&App, &Request, Vec<String>, &mut AccessLogEntry) -> Response>;
 
 
 struct Route {
@@ -108,176 +128,521 @@ impl<App: Send + Sync + 'static> Handler<App> {
         self.route.matches(method, url)
     }
 
-    fn call(&self, app: &App, req: &Request, args: Vec<String>) -> Response {
-        (self.handler)(app, req, args)
+    fn call(
+        &self,
+        app: &App,
+        req: &Request,
+        args: Vec<String>,
+        entry: &mut AccessLogEntry,
+    ) -> Response {
+        (self.handler)(app, req, args, entry)
     }
 }
 
 
-pub struct HttpServer<App: Send + Sync + 'static> {
+/// Everything needed to handle a request, shared between the threads
+/// handling them.
+struct Worker<App: Send + Sync + 'static> {
     app: Arc<App>,
-    handlers: Arc<Mutex<Vec<Handler<App>>>>,
+    handlers: Arc<Vec<Handler<App>>>,
     proxy_support: Arc<ProxySupport>,
+    max_body_size: u64,
+    spool_threshold: u64,
+    read_timeout: Duration,
+    keep_alive: bool,
+    access_log: Option<Arc<AccessLog>>,
+    server_header: String,
+    should_stop: Arc<AtomicBool>,
+}
+
+impl<App: Send + Sync + 'static> Worker<App> {
+    /// Handle the request and send the response back. If the server is busy
+    /// the request is rejected without reading it.
+    fn handle(&self, mut request: tiny_http::Request, busy: bool) {
+        let start = Instant::now();
+        let request_id = utils::request_id();
+        let mut entry = AccessLogEntry::new(
+            request_id.clone(),
+            requests::source_ip(&request),
+            &request.method().to_string(),
+            request.url(),
+        );
+
+        let response = if busy {
+            Response::Unavailable
+        } else {
+            self.response(&mut request, &request_id, &mut entry)
+        };
+
+        if let Response::EventStream(ref events) = response {
+            self.stream(request, &request_id, events);
+        } else {
+            self.respond(request, &request_id, &response);
+        }
+
+        if let Some(ref access_log) = self.access_log {
+            entry.status = response.status();
+            entry.latency = start.elapsed();
+            access_log.write(&entry);
+        }
+    }
+
+    fn respond(
+        &self,
+        request: tiny_http::Request,
+        request_id: &str,
+        response: &Response,
+    ) {
+        macro_rules! header {
+            ($value:expr) => {
+                $value.parse::<tiny_http::Header>().unwrap()
+            };
+        }
+
+        let mut tiny_response =
+            tiny_http::Response::from_data(
+                response.body().into_bytes(),
+            ).with_status_code(response.status());
+
+        // Add custom headers from the response
+        if let Some(headers) = response.headers() {
+            for header in &headers {
+                tiny_response.add_header(header!(header));
+            }
+        }
+
+        tiny_response.add_header(header!(self.server_header.as_str()));
+        tiny_response.add_header(header!(
+            format!("X-Request-Id: {}", request_id)
+        ));
+        tiny_response.add_header(header!(
+            format!("Content-Type: {}", response.content_type())
+        ));
+        // Connections are not reused while the server is stopping
+        if !self.keep_alive || self.should_stop.load(Ordering::Relaxed) {
+            tiny_response.add_header(header!("Connection: close"));
+        }
+
+        let _ = request.respond(tiny_response);
+    }
+
+    /// Send the events to the client as they happen, until the client goes
+    /// away or the server is stopped. The response is written directly on
+    /// the connection, as tiny_http would buffer it.
+    fn stream(
+        &self,
+        request: tiny_http::Request,
+        request_id: &str,
+        events: &Subscription,
+    ) {
+        let mut writer = request.into_writer();
+
+        let head = format!(
+            "HTTP/1.1 200 OK\r\n{}\r\nX-Request-Id: {}\r\n\
+             Content-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+             Connection: close\r\n\r\n",
+            self.server_header, request_id,
+        );
+        if send(&mut writer, &head).is_err() {
+            return;
+        }
+
+        let timeout = Duration::from_millis(STOP_CHECK_INTERVAL);
+        let keep_alive = Duration::from_secs(EVENTS_KEEP_ALIVE);
+        let mut last_write = Instant::now();
+        while !self.should_stop.load(Ordering::Relaxed) {
+            let data = match events.next(timeout) {
+                Ok(Some(event)) => event.to_sse(),
+                Ok(None) if last_write.elapsed() >= keep_alive => {
+                    ":\n\n".into()
+                }
+                Ok(None) => continue,
+                Err(()) => break,
+            };
+
+            if send(&mut writer, &data).is_err() {
+                break;
+            }
+            last_write = Instant::now();
+        }
+    }
+
+    fn response(
+        &self,
+        request: &mut tiny_http::Request,
+        request_id: &str,
+        entry: &mut AccessLogEntry,
+    ) -> Response {
+        // Convert the request to a Fisher request, without buffering bodies
+        // bigger than the limit
+        let web_request = WebRequest::read(
+            request, self.max_body_size, self.spool_threshold,
+            self.read_timeout, request_id.to_string(),
+        );
+
+        let mut req = match web_request {
+            Ok(req) => Request::Web(req),
+            Err(e) => {
+                return match *e.kind() {
+                    ErrorKind::BodyTooLarge(..) => Response::PayloadTooLarge,
+                    ErrorKind::BodyTimeout(..) => Response::RequestTimeout,
+                    _ => Response::BadRequest(e),
+                };
+            }
+        };
+
+        if let Err(e) = self.proxy_support.fix_request(&mut req) {
+            Response::BadRequest(e)
+        } else {
+            let method = request.method();
+            let url = request.url();
+
+            if let Ok(web) = req.web() {
+                entry.source = web.source;
+            }
+
+            for handler in self.handlers.iter() {
+                if let Some(args) = handler.matches(method, url) {
+                    return handler.call(&self.app, &req, args, entry);
+                }
+            }
+
+            Response::NotFound
+        }
+    }
+}
+
 
+/// Write the data to the client right away.
+fn send<W: Write>(writer: &mut W, data: &str) -> io::Result<()> {
+    writer.write_all(data.as_bytes())?;
+    writer.flush()
+}
+
+
+/// Set the receive timeout of the listening socket. The connections it
+/// accepts start with the same timeout.
+fn set_receive_timeout(
+    listener: &TcpListener,
+    timeout: Duration,
+) -> io::Result<()> {
+    let fd = listener.as_raw_fd();
+    let value = libc::timeval {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_usec: timeout.subsec_nanos() as libc::suseconds_t / 1000,
+    };
+
+    let result = unsafe {
+        libc::setsockopt(
+            fd, libc::SOL_SOCKET, libc::SO_RCVTIMEO,
+            &value as *const libc::timeval as *const libc::c_void,
+            mem::size_of::<libc::timeval>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+
+/// Connect to the socket regularly until the server is stopped. Waiting for
+/// connections is bounded by the receive timeout too, and tiny_http stops
+/// accepting them as soon as waiting fails, so it must never wait that long.
+/// tiny_http itself wakes its accept thread up the same way when stopping.
+fn keep_accepting(
+    addr: SocketAddr,
+    interval: Duration,
     should_stop: Arc<AtomicBool>,
+) {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1))
+        }
+        ip => ip,
+    };
+    let addr = SocketAddr::new(ip, addr.port());
+
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        if should_stop.load(Ordering::Relaxed) {
+            break;
+        }
 
-    listening_to: Option<SocketAddr>,
+        match TcpStream::connect(addr) {
+            Ok(conn) => {
+                let _ = conn.shutdown(Shutdown::Both);
+            }
+            // The socket was closed
+            Err(..) => break,
+        }
+    });
+}
+
+
+pub struct HttpServer<App: Send + Sync + 'static> {
+    app: Arc<App>,
+    handlers: Arc<Vec<Handler<App>>>,
+    proxy_support: Arc<ProxySupport>,
+
+    should_stop: Arc<AtomicBool>,
+    drain_timeout: Duration,
+    max_body_size: u64,
+    spool_threshold: u64,
+    max_connections: usize,
+    read_timeout: Duration,
+    keep_alive: bool,
+    access_log: Option<Arc<AccessLog>>,
+    route_prefix: String,
+
+    server: Option<Arc<tiny_http::Server>>,
     stop_wait: Option<mpsc::Receiver<()>>,
 }
 
 impl<App: Send + Sync + 'static> HttpServer<App> {
     pub fn new(app: App, proxies_count: u8) -> Self {
+        HttpServer::shared(Arc::new(app), proxies_count)
+    }
+
+    /// Create a new server, sharing the app with other servers.
+    pub fn shared(app: Arc<App>, proxies_count: u8) -> Self {
         HttpServer {
-            app: Arc::new(app),
-            handlers: Arc::new(Mutex::new(Vec::new())),
+            app: app,
+            handlers: Arc::new(Vec::new()),
             proxy_support: Arc::new(ProxySupport::new(proxies_count)),
 
             should_stop: Arc::new(AtomicBool::new(false)),
-
-            listening_to: None,
+            drain_timeout: Duration::from_secs(0),
+            max_body_size: ::std::u64::MAX,
+            spool_threshold: ::std::u64::MAX,
+            max_connections: ::std::usize::MAX,
+            read_timeout: Duration::from_secs(30),
+            keep_alive: true,
+            access_log: None,
+            route_prefix: String::new(),
+
+            server: None,
             stop_wait: None,
         }
     }
 
+    /// Set how long the server waits for the pending requests to be handled
+    /// when it's stopped.
+    pub fn set_drain_timeout(&mut self, timeout: Duration) {
+        self.drain_timeout = timeout;
+    }
+
+    /// Trust the X-Forwarded-For headers added by proxies in these ranges.
+    pub fn set_trusted_proxies(&mut self, proxies: Vec<Cidr>) {
+        Arc::make_mut(&mut self.proxy_support).set_trusted(proxies);
+    }
+
+    /// Set the maximum size of the body of the requests, in bytes.
+    pub fn set_max_body_size(&mut self, size: u64) {
+        self.max_body_size = size;
+    }
+
+    /// Set the size above which request bodies are stored on disk instead of
+    /// being kept in memory.
+    pub fn set_spool_threshold(&mut self, size: u64) {
+        self.spool_threshold = size;
+    }
+
+    /// Set how many requests can be handled at the same time. Requests over
+    /// the limit are rejected.
+    pub fn set_max_connections(&mut self, max: usize) {
+        self.max_connections = max;
+    }
+
+    /// Set how long clients have to send the body of the requests.
+    pub fn set_read_timeout(&mut self, timeout: Duration) {
+        self.read_timeout = timeout;
+    }
+
+    /// Ask the clients to close the connection after each request, instead
+    /// of keeping it open for the next ones.
+    pub fn set_keep_alive(&mut self, keep_alive: bool) {
+        self.keep_alive = keep_alive;
+    }
+
+    /// Log every request to the access log, which can be shared with other
+    /// servers.
+    pub fn set_access_log(&mut self, access_log: Option<Arc<AccessLog>>) {
+        self.access_log = access_log;
+    }
+
+    /// Serve the routes added after this call under the prefix, which must
+    /// not end with a slash.
+    pub fn set_route_prefix(&mut self, prefix: &str) {
+        self.route_prefix = prefix.to_string();
+    }
+
     pub fn add_route(
         &mut self,
         method: Method,
         url: &str,
         handler: RequestHandler<App>,
     ) {
-        let route = Route::new(method, url);
-        self.handlers
-            .try_lock()
-            .unwrap()
+        let url = format!("{}{}", self.route_prefix, url);
+        let route = Route::new(method, &url);
+        Arc::get_mut(&mut self.handlers)
+            .expect("routes can't be added to a running server")
             .push(Handler::new(handler, route));
     }
 
-    pub fn listen(&mut self, bind: SocketAddr) -> Result<SocketAddr> {
-        macro_rules! header {
-            ($value:expr) => {
-                $value.parse::<tiny_http::Header>().unwrap()
-            };
-        }
+    pub fn listen(
+        &mut self,
+        bind: SocketAddr,
+        tls: Option<&TlsCertificate>,
+    ) -> Result<SocketAddr> {
+        let listener = TcpListener::bind(bind)?;
+        let addr = listener.local_addr()?;
+
+        self.listen_on(listener, tls)?;
+        Ok(addr)
+    }
 
-        // This will move to the thread, and the server will be stopped when
-        // the thread exits
-        let server = tiny_http::Server::http(bind)?;
+    /// Serve the requests received by a socket which is already listening,
+    /// like the ones passed by systemd.
+    pub fn listen_on<L: Into<tiny_http::Listener>>(
+        &mut self,
+        listener: L,
+        tls: Option<&TlsCertificate>,
+    ) -> Result<()> {
+        // TCP connections inherit the receive timeout of the listening
+        // socket, so clients stalling in the middle of a request can't keep
+        // the worker reading from them blocked
+        let listener = listener.into();
+        if let tiny_http::Listener::Tcp(ref listener) = listener {
+            if self.read_timeout > Duration::from_secs(0) {
+                set_receive_timeout(listener, self.read_timeout)?;
+                keep_accepting(
+                    listener.local_addr()?, self.read_timeout / 2,
+                    self.should_stop.clone(),
+                );
+            }
+        }
 
-        // Store the server address into the struct
-        self.listening_to = Some(server.server_addr());
+        // The server is shared with the thread, and it stops listening when
+        // both of them dropped it
+        let server = Arc::new(match tls {
+            Some(tls) => tls.server(listener)?,
+            None => tiny_http::Server::from_listener(listener, None)?,
+        });
+        self.server = Some(server.clone());
 
         let (stop_send, stop_recv) = mpsc::channel();
         self.stop_wait = Some(stop_recv);
 
-        let app = self.app.clone();
-        let handlers_arc = self.handlers.clone();
-        let proxy_support = self.proxy_support.clone();
+        let worker = Arc::new(Worker {
+            app: self.app.clone(),
+            handlers: self.handlers.clone(),
+            proxy_support: self.proxy_support.clone(),
+            max_body_size: self.max_body_size,
+            spool_threshold: self.spool_threshold,
+            read_timeout: self.read_timeout,
+            keep_alive: self.keep_alive,
+            access_log: self.access_log.clone(),
+            server_header: format!(
+                "Server: Fisher/{}", env!("CARGO_PKG_VERSION"),
+            ),
+            should_stop: self.should_stop.clone(),
+        });
+
         let should_stop = self.should_stop.clone();
+        let drain_timeout = self.drain_timeout;
+        let max_connections = self.max_connections;
         thread::spawn(move || {
-            // Get a reference to the handlers
-            let handlers = &*handlers_arc.lock().unwrap();
-
-            // Prepare some headers which will be sent everytime
-            let server_header = header!(
-                format!("Server: Fisher/{}", env!("CARGO_PKG_VERSION"))
-            );
-            let content_type = header!("Content-Type: application/json");
-
-            let ignored_method =
-                Method::NonStandard("X_FISHER_IGNORE_THIS".parse().unwrap());
-
-            for mut request in server.incoming_requests() {
-                // Don't accept any request anymore
-                if should_stop.load(Ordering::Relaxed) {
-                    break;
+            let in_flight = Arc::new(AtomicUsize::new(0));
+
+            let timeout = Duration::from_millis(STOP_CHECK_INTERVAL);
+            let mut drain_until = None;
+            loop {
+                let request = server.recv_timeout(timeout);
+
+                // When the server is stopped, the requests in flight are
+                // waited for until the drain timeout expires
+                if should_stop.load(Ordering::Relaxed) && drain_until.is_none()
+                {
+                    drain_until = Some(Instant::now() + drain_timeout);
                 }
 
-                // Convert the request to a Fisher request
-                let mut req = Request::Web((&mut request).into());
-
-                let response = (|| {
-                    if *request.method() == ignored_method {
-                        // This request comes with the non-standard method used
-                        // to shut the server down -- no client should be using
-                        // it
-                        Response::Forbidden
-                    } else if let Err(e) = proxy_support.fix_request(&mut req) {
-                        Response::BadRequest(e)
-                    } else {
-                        let method = request.method();
-                        let url = request.url();
-
-                        for handler in handlers {
-                            if let Some(args) = handler.matches(method, url) {
-                                return handler.call(&app, &req, args);
-                            }
-                        }
-
-                        Response::NotFound
-                    }
-                })();
-
-                let mut tiny_response =
-                    tiny_http::Response::from_data(
-                        response.json().into_bytes(),
-                    ).with_status_code(response.status());
-
-                // Add custom headers from the response
-                if let Some(headers) = response.headers() {
-                    for header in &headers {
-                        tiny_response.add_header(header!(header));
-                    }
+                let request = match request {
+                    Ok(Some(request)) => request,
+                    // No requests are pending anymore
+                    Ok(None) if drain_until.is_some() => break,
+                    Ok(None) => continue,
+                    Err(..) => break,
+                };
+
+                // Requests over the limit are rejected right away, so slow
+                // clients can't make the other ones wait, and so are the ones
+                // received after the server was stopped
+                if drain_until.is_some()
+                    || in_flight.load(Ordering::SeqCst) >= max_connections
+                {
+                    worker.handle(request, true);
+                    continue;
                 }
 
-                tiny_response.add_header(server_header.clone());
-                tiny_response.add_header(content_type.clone());
+                in_flight.fetch_add(1, Ordering::SeqCst);
+                let worker = worker.clone();
+                let in_flight = in_flight.clone();
+                thread::spawn(move || {
+                    worker.handle(request, false);
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
 
-                let _ = request.respond(tiny_response);
+            // Stop listening, since this is the last reference to the server,
+            // and wait for the requests still being handled
+            drop(server);
+            let deadline = drain_until.unwrap_or_else(Instant::now);
+            while in_flight.load(Ordering::SeqCst) > 0
+                && Instant::now() < deadline
+            {
+                thread::sleep(Duration::from_millis(10));
             }
 
             stop_send.send(()).unwrap();
         });
 
-        Ok(self.listening_to.unwrap())
+        Ok(())
     }
 
     pub fn stop(&mut self) -> bool {
-        if self.stop_wait.is_some() {
-            // Tell the server to stop
-            self.should_stop.store(true, Ordering::Relaxed);
-
-            // Send an HTTP request to force stopping the server
-            match TcpStream::connect(self.listening_to.unwrap()) {
-                Ok(mut conn) => {
-                    (writeln!(conn, "X_FISHER_IGNORE_THIS / HTTP/1.0\r\n\r\n"))
-                        .unwrap();
-                    conn.shutdown(Shutdown::Both).unwrap();
-                }
-                Err(..) => {
-                    return false;
-                }
-            }
-
-            if let Some(ref stop_wait) = self.stop_wait {
-                // Wait for the http server to stop
-                stop_wait.recv().unwrap();
-            } else {
-                unreachable!();
-            }
-
-            self.stop_wait = None;
-            self.listening_to = None;
-
-            true
-        } else {
-            false
+        let server = match self.server.take() {
+            Some(server) => server,
+            None => return false,
+        };
+
+        // Tell the server to stop, waking it up if it's waiting for requests.
+        // The thread holds the last reference to the server, and closes the
+        // socket as soon as it stops receiving requests
+        self.should_stop.store(true, Ordering::Relaxed);
+        server.unblock();
+        drop(server);
+
+        if let Some(stop_wait) = self.stop_wait.take() {
+            // Wait for the http server to stop
+            stop_wait.recv().unwrap();
         }
+        true
     }
 }
 
 
 #[cfg(test)]
 mod tests {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::thread;
     use std::time::Duration;
 
     use tiny_http::Method;
@@ -285,7 +650,9 @@ mod tests {
     use hyper::status::StatusCode;
 
     use requests::Request;
+    use web::access_log::AccessLogEntry;
     use web::responses::Response;
+    use utils;
     use utils::testing::*;
     use super::{Handler, HttpServer, Route};
 
@@ -296,6 +663,7 @@ mod tests {
         data: &DummyData,
         _req: &Request,
         args: Vec<String>,
+        _entry: &mut AccessLogEntry,
     ) -> Response {
         if data.0 == args {
             Response::Ok
@@ -304,6 +672,16 @@ mod tests {
         }
     }
 
+    fn slow_handler_fn(
+        _data: &DummyData,
+        _req: &Request,
+        _args: Vec<String>,
+        _entry: &mut AccessLogEntry,
+    ) -> Response {
+        thread::sleep(Duration::from_millis(200));
+        Response::Ok
+    }
+
     fn dummy_handler() -> Handler<DummyData> {
         let route = Route::new(Method::Get, "/?");
         Handler::new(Box::new(dummy_handler_fn), route)
@@ -365,12 +743,16 @@ mod tests {
             handler.matches(&Method::Get, "/test"),
             Some(vec!["test".into()])
         );
+        let mut entry = AccessLogEntry::new(
+            "abcd".into(), "127.0.0.1".parse().unwrap(), "GET", "/test",
+        );
         assert_eq!(
             handler
                 .call(
                     &DummyData(vec!["test".into()]),
                     &dummy_web_request().into(),
-                    vec!["test".into()]
+                    vec!["test".into()],
+                    &mut entry,
                 )
                 .status(),
             200
@@ -391,7 +773,8 @@ mod tests {
         server.add_route(Method::Get, "/?", Box::new(dummy_handler_fn));
 
         // Start the server
-        let addr = server.listen("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr =
+            server.listen("127.0.0.1:0".parse().unwrap(), None).unwrap();
 
         let url = format!("http://{}", addr);
         let mut client = hyper::Client::new();
@@ -407,6 +790,10 @@ mod tests {
                 .unwrap();
         assert_eq!(res.status, StatusCode::Ok);
 
+        // Every response contains the ID of the request
+        let id = &res.headers.get_raw("X-Request-Id").unwrap()[0];
+        assert_eq!(id.len(), 32);
+
         // Stop the server
         server.stop();
 
@@ -415,4 +802,184 @@ mod tests {
                 .is_err()
         );
     }
+
+
+    #[test]
+    fn test_route_prefix() {
+        let mut server = HttpServer::new(DummyData(vec!["test".into()]), 0);
+        server.set_route_prefix("/fisher");
+        server.add_route(Method::Get, "/?", Box::new(dummy_handler_fn));
+
+        let addr =
+            server.listen("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let client = hyper::Client::new();
+
+        // Only the requests under the prefix are routed
+        let res = client.get(&format!("http://{}/fisher/test", addr))
+            .send()
+            .unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        for url in &["/test", "/fishertest", "/fisher"] {
+            let res = client.get(&format!("http://{}{}", addr, url))
+                .send()
+                .unwrap();
+            assert_eq!(res.status, StatusCode::NotFound);
+        }
+
+        server.stop();
+    }
+
+
+    #[test]
+    fn test_drain_on_stop() {
+        let mut server = HttpServer::new(DummyData(vec![]), 0);
+        server.add_route(Method::Get, "/slow", Box::new(slow_handler_fn));
+        server.set_drain_timeout(Duration::from_secs(5));
+
+        let addr =
+            server.listen("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let url = format!("http://{}/slow", addr);
+
+        // Start some requests, which will be queued behind the first one
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            let url = url.clone();
+            clients.push(thread::spawn(move || {
+                let mut client = hyper::Client::new();
+                client.set_read_timeout(Some(Duration::new(5, 0)));
+                client.get(&url).send().map(|res| res.status)
+            }));
+        }
+        thread::sleep(Duration::from_millis(100));
+
+        // Every request in flight when stopping the server is answered
+        let stop = thread::spawn(move || server.stop());
+        thread::sleep(Duration::from_millis(50));
+
+        // New requests are not handled while draining
+        match hyper::Client::new().get(&url).send() {
+            Ok(res) => assert_eq!(res.status, StatusCode::ServiceUnavailable),
+            Err(..) => {}
+        }
+
+        for client in clients {
+            assert_eq!(client.join().unwrap().unwrap(), StatusCode::Ok);
+        }
+        assert!(stop.join().unwrap());
+    }
+
+
+    #[test]
+    fn test_max_connections() {
+        let mut server = HttpServer::new(DummyData(vec![]), 0);
+        server.add_route(Method::Get, "/slow", Box::new(slow_handler_fn));
+        server.set_max_connections(1);
+
+        let addr =
+            server.listen("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let url = format!("http://{}/slow", addr);
+
+        // Keep the only available slot busy
+        let slow_url = url.clone();
+        let slow = thread::spawn(move || {
+            hyper::Client::new().get(&slow_url).send().map(|res| res.status)
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        // Requests over the limit are rejected right away
+        let res = hyper::Client::new().get(&url).send().unwrap();
+        assert_eq!(res.status, StatusCode::ServiceUnavailable);
+        assert_eq!(slow.join().unwrap().unwrap(), StatusCode::Ok);
+
+        // The slot is available again after the first request ended
+        let res = hyper::Client::new().get(&url).send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        server.stop();
+    }
+
+
+    #[test]
+    fn test_unix_socket() {
+        let dir = utils::create_temp_dir().unwrap();
+        let path = dir.join("fisher.sock");
+
+        let mut server = HttpServer::new(DummyData(vec!["test".into()]), 0);
+        server.add_route(Method::Get, "/?", Box::new(dummy_handler_fn));
+        server.listen_on(UnixListener::bind(&path).unwrap(), None).unwrap();
+
+        let mut conn = UnixStream::connect(&path).unwrap();
+        write!(conn, "GET /test HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 200 "));
+
+        // The socket is removed when the server is stopped
+        server.stop();
+        assert!(!path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+
+    #[test]
+    fn test_read_timeout() {
+        let mut server = HttpServer::new(DummyData(vec!["test".into()]), 0);
+        server.add_route(Method::Post, "/?", Box::new(dummy_handler_fn));
+        server.set_read_timeout(Duration::from_secs(1));
+
+        let addr =
+            server.listen("127.0.0.1:0".parse().unwrap(), None).unwrap();
+
+        // The client stops sending the body halfway
+        let mut conn = TcpStream::connect(addr).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(
+            conn, "POST /test HTTP/1.0\r\nContent-Length: 2048\r\n\r\nhello",
+        ).unwrap();
+
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 408 "), "{:?}", response);
+
+        // The server keeps accepting connections after being idle for longer
+        // than the timeout
+        thread::sleep(Duration::from_millis(1500));
+
+        // Small bodies are read by tiny_http before the request reaches
+        // Fisher, and it closes the connection when they stall
+        let mut conn = TcpStream::connect(addr).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(
+            conn, "POST /test HTTP/1.0\r\nContent-Length: 10\r\n\r\nhello",
+        ).unwrap();
+
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "");
+
+        server.stop();
+    }
+
+
+    #[test]
+    fn test_max_body_size() {
+        let mut server = HttpServer::new(DummyData(vec!["test".into()]), 0);
+        server.add_route(Method::Post, "/?", Box::new(dummy_handler_fn));
+        server.set_max_body_size(10);
+
+        let addr =
+            server.listen("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let url = format!("http://{}/test", addr);
+        let client = hyper::Client::new();
+
+        let res = client.post(&url).body("0123456789").send().unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+
+        let res = client.post(&url).body("0123456789a").send().unwrap();
+        assert_eq!(res.status, StatusCode::PayloadTooLarge);
+
+        server.stop();
+    }
 }
@@ -13,12 +13,20 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+mod access_log;
+mod activation;
+mod cors;
+mod deliveries;
 mod http;
+mod multipart;
 mod app;
 mod rate_limits;
 mod requests;
 mod responses;
 mod proxies;
+mod tls;
+mod unix;
+mod xml;
 
 // Parts of the webapp
 mod api;
@@ -26,3 +34,5 @@ mod api;
 pub use self::http::HttpServer;
 pub use self::app::WebApp;
 pub use self::requests::WebRequest;
+pub use self::xml::Element as XmlElement;
+pub use self::tls::TlsCertificate;
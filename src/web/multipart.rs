@@ -0,0 +1,235 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Parser for `multipart/form-data` request bodies.
+
+use common::prelude::*;
+
+
+/// A part of a multipart body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Part {
+    pub name: Option<String>,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+}
+
+
+/// Return the boundary of the body, if the content type is
+/// `multipart/form-data`.
+pub fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';').map(|s| s.trim());
+
+    let mime = params.next().unwrap_or("");
+    if !mime.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+
+    params
+        .filter_map(|param| param_value(param, "boundary"))
+        .find(|boundary| !boundary.is_empty())
+}
+
+
+/// Split the body in its parts.
+pub fn parse(boundary: &str, body: &[u8]) -> Result<Vec<Part>> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let separator = format!("\r\n--{}", boundary).into_bytes();
+
+    // Anything before the first delimiter is a preamble, and it's ignored
+    let mut pos = match find(body, &delimiter, 0) {
+        Some(pos) => pos + delimiter.len(),
+        None => return Err(invalid("missing the first boundary")),
+    };
+
+    let mut parts = Vec::new();
+    loop {
+        // The last delimiter is followed by two dashes
+        if body[pos..].starts_with(b"--") {
+            return Ok(parts);
+        }
+        if !body[pos..].starts_with(b"\r\n") {
+            return Err(invalid("malformed boundary"));
+        }
+        pos += 2;
+
+        let end = match find(body, &separator, pos) {
+            Some(end) => end,
+            None => return Err(invalid("missing the last boundary")),
+        };
+        parts.push(parse_part(&body[pos..end])?);
+        pos = end + separator.len();
+    }
+}
+
+
+fn parse_part(raw: &[u8]) -> Result<Part> {
+    let (headers, data) = match find(raw, b"\r\n\r\n", 0) {
+        Some(pos) => (&raw[..pos], &raw[pos + 4..]),
+        // Parts without headers start with an empty line
+        None if raw.starts_with(b"\r\n") => (&raw[..0], &raw[2..]),
+        None => return Err(invalid("missing the headers of a part")),
+    };
+
+    let mut part = Part {
+        name: None,
+        filename: None,
+        content_type: None,
+        data: data.to_vec(),
+    };
+
+    let headers = String::from_utf8_lossy(headers);
+    // Parts without headers have an empty header block
+    for line in headers.split("\r\n").filter(|line| !line.is_empty()) {
+        let mut split = line.splitn(2, ':');
+        let (name, value) = match (split.next(), split.next()) {
+            (Some(name), Some(value)) => (name.trim(), value.trim()),
+            _ => return Err(invalid("malformed header in a part")),
+        };
+
+        if name.eq_ignore_ascii_case("Content-Disposition") {
+            for param in value.split(';').skip(1).map(|s| s.trim()) {
+                if let Some(value) = param_value(param, "name") {
+                    part.name = Some(value);
+                } else if let Some(value) = param_value(param, "filename") {
+                    part.filename = Some(value);
+                }
+            }
+        } else if name.eq_ignore_ascii_case("Content-Type") {
+            part.content_type = Some(value.to_string());
+        }
+    }
+
+    Ok(part)
+}
+
+
+/// Return the value of a `key=value` parameter, removing the quotes.
+fn param_value(param: &str, key: &str) -> Option<String> {
+    let mut split = param.splitn(2, '=');
+    match (split.next(), split.next()) {
+        (Some(name), Some(value)) if name.trim().eq_ignore_ascii_case(key) => {
+            let value = value.trim();
+            if value.len() >= 2 && value.starts_with('"')
+                && value.ends_with('"')
+            {
+                Some(value[1..value.len() - 1].to_string())
+            } else {
+                Some(value.to_string())
+            }
+        }
+        _ => None,
+    }
+}
+
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    if from > haystack.len() {
+        return None;
+    }
+
+    haystack[from..]
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|pos| pos + from)
+}
+
+
+fn invalid(message: &str) -> Error {
+    ErrorKind::InvalidInput(format!("invalid multipart body: {}", message))
+        .into()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use common::prelude::*;
+
+    use super::{boundary, parse, Part};
+
+
+    #[test]
+    fn test_boundary() {
+        assert_eq!(
+            boundary("multipart/form-data; boundary=abc"),
+            Some("abc".into())
+        );
+        assert_eq!(
+            boundary("Multipart/Form-Data; charset=utf-8; boundary=\"a b\""),
+            Some("a b".into())
+        );
+        assert_eq!(boundary("multipart/form-data"), None);
+        assert_eq!(boundary("multipart/form-data; boundary="), None);
+        assert_eq!(boundary("application/json; boundary=abc"), None);
+    }
+
+
+    #[test]
+    fn test_parse() {
+        let body = concat!(
+            "preamble\r\n",
+            "--abc\r\n",
+            "Content-Disposition: form-data; name=\"field\"\r\n",
+            "\r\n",
+            "value\r\n",
+            "--abc\r\n",
+            "Content-Disposition: form-data; name=\"file\"; ",
+            "filename=\"a.txt\"\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "line 1\r\nline 2\r\n",
+            "--abc--\r\n",
+        );
+
+        assert_eq!(parse("abc", body.as_bytes()).unwrap(), vec![
+            Part {
+                name: Some("field".into()),
+                filename: None,
+                content_type: None,
+                data: b"value".to_vec(),
+            },
+            Part {
+                name: Some("file".into()),
+                filename: Some("a.txt".into()),
+                content_type: Some("text/plain".into()),
+                data: b"line 1\r\nline 2".to_vec(),
+            },
+        ]);
+
+        // Binary data is kept as it is
+        let mut body = b"--abc\r\n\r\n".to_vec();
+        body.extend_from_slice(&[0xff, 0x00, 0xfe]);
+        body.extend_from_slice(b"\r\n--abc--");
+        assert_eq!(parse("abc", &body).unwrap()[0].data, vec![0xff, 0, 0xfe]);
+    }
+
+
+    #[test]
+    fn test_parse_invalid() {
+        for body in &[
+            "",
+            "--abc",
+            "--abc\r\nContent-Type: text/plain\r\n\r\nvalue",
+            "--abcdef\r\n\r\nvalue\r\n--abcdef--",
+            "--abc\r\nno headers\r\n--abc--",
+        ] {
+            assert_err!(
+                parse("abc", body.as_bytes()),
+                ErrorKind::InvalidInput(..)
+            );
+        }
+    }
+}
@@ -18,22 +18,41 @@ use std::net::IpAddr;
 use requests::Request;
 use common::prelude::*;
 use utils;
+use web::requests::WebRequest;
 
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct ProxySupport {
     behind: u8,
+    trusted: Vec<utils::Cidr>,
 }
 
 impl ProxySupport {
     pub fn new(behind: u8) -> Self {
-        ProxySupport { behind: behind }
+        ProxySupport {
+            behind: behind,
+            trusted: Vec::new(),
+        }
+    }
+
+    /// Trust the headers added by the proxies in these ranges, instead of
+    /// relying on a fixed number of proxies.
+    pub fn set_trusted(&mut self, trusted: Vec<utils::Cidr>) {
+        self.trusted = trusted;
+    }
+
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted.iter().any(|cidr| cidr.contains(ip))
     }
 
     pub fn source_ip(&self, req: &Request) -> Result<IpAddr> {
         let req = req.web()?;
         let original = req.source;
 
+        if !self.trusted.is_empty() {
+            return self.trusted_source_ip(req);
+        }
+
         // Return the original IP if the proxy support is disabled
         if self.behind == 0 {
             return Ok(original);
@@ -60,6 +79,32 @@ impl ProxySupport {
         }
     }
 
+    fn trusted_source_ip(&self, req: &WebRequest) -> Result<IpAddr> {
+        // Headers sent by untrusted clients can't be trusted
+        if !self.is_trusted(&req.source) {
+            return Ok(req.source);
+        }
+
+        let forwarded_ips = utils::parse_forwarded_for(&req.headers)?;
+        if forwarded_ips.is_empty() {
+            if let Some(real_ip) = req.headers.get("X-Real-IP") {
+                return Ok(real_ip.trim().parse()?);
+            }
+            return Ok(req.source);
+        }
+
+        // The client is the first address not belonging to a trusted proxy,
+        // starting from the closest one
+        for ip in forwarded_ips.iter().rev() {
+            if !self.is_trusted(ip) {
+                return Ok(*ip);
+            }
+        }
+
+        // Every address is trusted, so the request started from a proxy
+        Ok(forwarded_ips[0])
+    }
+
     pub fn fix_request(&self, req: &mut Request) -> Result<()> {
         let fixed_ip = self.source_ip(req)?;
 
@@ -158,6 +203,40 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_trusted_proxies() {
+        let mut p = ProxySupport::new(0);
+        p.set_trusted(vec![
+            "127.1.0.0/16".parse().unwrap(),
+            "10.0.0.1".parse().unwrap(),
+        ]);
+
+        let ip = |req: &Request| p.source_ip(req).unwrap().to_string();
+
+        // Without headers the address of the proxy is used
+        assert_eq!(ip(&req!()), "127.1.1.1");
+
+        // Trusted proxies are skipped, starting from the closest one
+        assert_eq!(ip(&req!("127.2.2.2")), "127.2.2.2");
+        assert_eq!(ip(&req!("127.3.3.3, 127.2.2.2, 10.0.0.1")), "127.2.2.2");
+        assert_eq!(ip(&req!("127.1.2.2, 10.0.0.1")), "127.1.2.2");
+
+        // The X-Real-IP header is used if X-Forwarded-For is missing
+        let mut real_ip = req!();
+        if let Request::Web(ref mut inner) = real_ip {
+            inner.headers.insert("X-Real-IP".into(), "127.4.4.4".into());
+        }
+        assert_eq!(ip(&real_ip), "127.4.4.4");
+
+        // Requests not coming from a trusted proxy are never changed
+        let mut untrusted = req!("127.2.2.2");
+        if let Request::Web(ref mut inner) = untrusted {
+            inner.source = "127.5.5.5".parse().unwrap();
+        }
+        assert_eq!(ip(&untrusted), "127.5.5.5");
+    }
+
+
     #[test]
     fn test_fix_request() {
         let proxy = ProxySupport::new(1);
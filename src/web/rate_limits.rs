@@ -27,8 +27,14 @@
 //! Then, to check if the user is rate limited the code simply subtracts the
 //! current time to the limiting time, and if the delta is greater than
 //! `interval` the user is rejected.
+//!
+//! The limit applied to every request uses a token bucket instead, since it
+//! needs to allow short bursts of requests. Buckets are refilled lazily when
+//! they're used, and they're kept in the order they were last used: the ones
+//! unused for long enough to be full are discarded, and so is the least
+//! recently used one when too many are stored.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::hash::Hash;
 use std::time::{Instant, Duration};
 
@@ -104,12 +110,136 @@ impl<Id: Hash + Eq + PartialEq> RateLimiter<Id> {
 }
 
 
+/// The maximum number of stored buckets.
+const MAX_BUCKETS: usize = 4096;
+
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    /// The position of the bucket in the order they were used.
+    seq: u64,
+}
+
+
+#[derive(Debug)]
+pub struct TokenBuckets<Id: Hash + Eq + Clone> {
+    data: HashMap<Id, Bucket>,
+    /// The IDs of the buckets, starting from the least recently used one.
+    order: BTreeMap<u64, Id>,
+    next_seq: u64,
+    per_second: f64,
+    burst: f64,
+    /// How long an empty bucket takes to be full again.
+    refill_time: Duration,
+}
+
+impl<Id: Hash + Eq + Clone> TokenBuckets<Id> {
+    pub fn new(allowed: u64, interval: u64, burst: u64) -> Self {
+        let per_second = allowed as f64 / interval as f64;
+        let refill_millis = (burst as f64 / per_second * 1000.0).ceil();
+        TokenBuckets {
+            data: HashMap::new(),
+            order: BTreeMap::new(),
+            next_seq: 0,
+            per_second,
+            burst: burst as f64,
+            refill_time: Duration::from_millis(refill_millis as u64),
+        }
+    }
+
+    /// Take a token from the bucket, returning how long the user has to wait
+    /// if the bucket is empty.
+    pub fn take(&mut self, id: Id) -> Option<Duration> {
+        let now = Instant::now();
+        self.discard_full(now);
+
+        let bucket = match self.data.remove(&id) {
+            Some(bucket) => {
+                self.order.remove(&bucket.seq);
+                bucket
+            }
+            None => {
+                // Forgetting a bucket which is still in use gives its user
+                // a new burst, but keeps the memory bounded
+                if self.data.len() >= MAX_BUCKETS {
+                    self.discard_oldest();
+                }
+                Bucket {
+                    tokens: self.burst,
+                    updated_at: now,
+                    seq: 0,
+                }
+            }
+        };
+
+        // Refill the bucket with the tokens accumulated since the last time
+        let elapsed = duration_secs(now.duration_since(bucket.updated_at));
+        let mut tokens =
+            (bucket.tokens + elapsed * self.per_second).min(self.burst);
+
+        let wait = if tokens >= 1.0 {
+            tokens -= 1.0;
+            None
+        } else {
+            let wait = (1.0 - tokens) / self.per_second;
+            Some(Duration::from_millis((wait * 1000.0).ceil() as u64))
+        };
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.order.insert(seq, id.clone());
+        self.data.insert(id, Bucket {
+            tokens,
+            updated_at: now,
+            seq,
+        });
+
+        wait
+    }
+
+    /// Discard the buckets unused for long enough to be full, since they
+    /// behave like new ones. They're the least recently used ones, so only
+    /// the start of the order is checked.
+    fn discard_full(&mut self, now: Instant) {
+        loop {
+            let full = match self.order.values().next() {
+                Some(id) => self.data.get(id).map(|bucket| {
+                    now.duration_since(bucket.updated_at) >= self.refill_time
+                }).unwrap_or(true),
+                None => false,
+            };
+            if !full {
+                break;
+            }
+            self.discard_oldest();
+        }
+    }
+
+    fn discard_oldest(&mut self) {
+        let seq = match self.order.keys().next() {
+            Some(&seq) => seq,
+            None => return,
+        };
+        if let Some(id) = self.order.remove(&seq) {
+            self.data.remove(&id);
+        }
+    }
+}
+
+
+fn duration_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+
 #[cfg(test)]
 mod tests {
     use std::thread;
     use std::time::Duration;
 
-    use super::RateLimiter;
+    use super::{RateLimiter, TokenBuckets, MAX_BUCKETS};
 
 
     #[test]
@@ -129,6 +259,64 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_token_buckets() {
+        // Allow one request a minute, with bursts of three requests
+        let mut buckets = TokenBuckets::<u8>::new(1, 60, 3);
+
+        for _ in 0..3 {
+            assert!(buckets.take(1).is_none());
+        }
+
+        // The bucket is now empty, and a token is added every minute
+        let wait = buckets.take(1).unwrap();
+        assert!(wait > Duration::from_secs(59));
+        assert!(wait <= Duration::from_secs(60));
+
+        // Other users have their own bucket
+        assert!(buckets.take(2).is_none());
+    }
+
+
+    #[test]
+    fn test_token_buckets_refill() {
+        // Allow twenty requests a second, without bursts
+        let mut buckets = TokenBuckets::<u8>::new(20, 1, 1);
+
+        assert!(buckets.take(1).is_none());
+        assert!(buckets.take(1).is_some());
+
+        thread::sleep(Duration::from_millis(100));
+        assert!(buckets.take(1).is_none());
+    }
+
+
+    #[test]
+    fn test_token_buckets_eviction() {
+        // Allow twenty requests a second, with bursts of two requests
+        let mut buckets = TokenBuckets::<usize>::new(20, 1, 2);
+
+        // Full buckets are discarded
+        assert!(buckets.take(1).is_none());
+        thread::sleep(Duration::from_millis(150));
+        assert!(buckets.take(2).is_none());
+        assert!(!buckets.data.contains_key(&1));
+        assert_eq!(buckets.data.len(), buckets.order.len());
+
+        // The least recently used bucket is discarded to make room, even if
+        // it's not full yet
+        let mut buckets = TokenBuckets::<usize>::new(1, 60, 2);
+        buckets.take(2);
+        for id in 3..(MAX_BUCKETS + 3) {
+            buckets.take(id);
+        }
+        assert_eq!(buckets.data.len(), MAX_BUCKETS);
+        assert_eq!(buckets.order.len(), MAX_BUCKETS);
+        assert!(!buckets.data.contains_key(&2));
+        assert!(buckets.data.contains_key(&3));
+    }
+
+
     #[test]
     #[ignore]
     fn test_rate_limiter_slow() {
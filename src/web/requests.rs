@@ -13,26 +13,180 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::net::IpAddr;
+use std::cmp;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::collections::HashMap;
 
+use flate2::read::{GzDecoder, ZlibDecoder};
 use tiny_http;
 use url::form_urlencoded;
 
+use common::prelude::*;
+use utils;
+use web::multipart;
+use web::xml;
+
+
+/// A request body which was too big to be kept in memory, stored in its own
+/// temporary directory. The directory is removed when the body is dropped.
+#[derive(Debug)]
+pub struct SpooledBody {
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl SpooledBody {
+    fn create() -> Result<(SpooledBody, File)> {
+        let dir = utils::create_temp_dir()?;
+        let path = dir.join("request_body");
+        let file = File::create(&path)?;
+
+        Ok((SpooledBody { dir, path }, file))
+    }
+
+    /// Return the path of the file containing the body.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn read(&self) -> Result<Vec<u8>> {
+        let mut content = Vec::new();
+        File::open(&self.path)?.read_to_end(&mut content)?;
+        Ok(content)
+    }
+}
+
+impl Drop for SpooledBody {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
 
 #[derive(Debug, Clone)]
 pub struct WebRequest {
+    /// The unique identifier assigned to the request when it arrived.
+    pub id: String,
+    /// The HTTP method of the request.
+    pub method: String,
+    /// The path of the request, with its query string.
+    pub url: String,
     pub source: IpAddr,
     pub headers: HashMap<String, String>,
     pub params: HashMap<String, String>,
     pub body: String,
+    /// The original body, only present if it isn't valid UTF-8.
+    pub binary_body: Option<Vec<u8>>,
+    /// The body stored on disk, if it was too big to be kept in memory. In
+    /// that case `body` is empty until the body is loaded.
+    pub spooled: Option<Arc<SpooledBody>>,
 }
 
+impl WebRequest {
+    /// Return the body exactly as it was received.
+    pub fn body_bytes(&self) -> &[u8] {
+        if let Some(ref binary) = self.binary_body {
+            binary
+        } else {
+            self.body.as_bytes()
+        }
+    }
+
+    /// Return the encoding of the body, either `utf-8` or `binary`.
+    pub fn body_encoding(&self) -> &'static str {
+        if self.binary_body.is_some() {
+            "binary"
+        } else {
+            "utf-8"
+        }
+    }
+
+    /// Return the value of the `Content-Type` header, if present.
+    /// Return the value of a header, ignoring the case of its name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|&(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn content_type(&self) -> Option<&str> {
+        self.header("Content-Type")
+    }
+
+    /// Return the decoded fields of the body, if it's an
+    /// `application/x-www-form-urlencoded` one.
+    pub fn form(&self) -> Option<HashMap<String, String>> {
+        let mime = self.content_type()
+            .and_then(|value| value.split(';').next())
+            .map(|mime| mime.trim());
+
+        match mime {
+            Some(mime)
+                if mime.eq_ignore_ascii_case(
+                    "application/x-www-form-urlencoded",
+                ) =>
+            {
+                Some(params_from_query(&self.body))
+            }
+            _ => None,
+        }
+    }
+
+    /// Return the parsed body, if it's an XML one. Bodies which are not well
+    /// formed are rejected.
+    pub fn xml(&self) -> Result<Option<xml::Element>> {
+        match self.content_type() {
+            Some(content_type) if xml::is_xml(content_type) => {
+                Ok(Some(xml::parse(self.body_bytes())?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Return the parts of the body, if it's a `multipart/form-data` one.
+    pub fn multipart(&self) -> Result<Option<Vec<multipart::Part>>> {
+        match self.content_type().and_then(multipart::boundary) {
+            Some(boundary) => {
+                Ok(Some(multipart::parse(&boundary, self.body_bytes())?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Return a copy of the request with the spooled body loaded in memory,
+    /// or None if the body was never spooled.
+    pub fn load_spooled(&self) -> Result<Option<WebRequest>> {
+        if let Some(ref spooled) = self.spooled {
+            let (body, binary_body) = decode_body(spooled.read()?);
 
-impl<'a> From<&'a mut tiny_http::Request> for WebRequest {
-    fn from(origin: &'a mut tiny_http::Request) -> WebRequest {
+            let mut loaded = self.clone();
+            loaded.body = body;
+            loaded.binary_body = binary_body;
+            Ok(Some(loaded))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Convert a request received by the HTTP server, refusing bodies bigger
+    /// than `max_body_size` bytes before buffering them. Bodies bigger than
+    /// `spool_threshold` bytes are streamed to disk instead of being kept in
+    /// memory, and bodies not received within `read_timeout` are refused.
+    pub fn read(
+        origin: &mut tiny_http::Request,
+        max_body_size: u64,
+        spool_threshold: u64,
+        read_timeout: Duration,
+        id: String,
+    ) -> Result<WebRequest> {
         // Get the source IP
-        let source = origin.remote_addr().ip();
+        let source = source_ip(origin);
 
         // Get the headers
         let mut headers = HashMap::new();
@@ -43,9 +197,42 @@ impl<'a> From<&'a mut tiny_http::Request> for WebRequest {
             );
         }
 
-        // Get the body
-        let mut body = String::new();
-        origin.as_reader().read_to_string(&mut body).unwrap();
+        // Reject the request early if the client declared a big body
+        if let Some(length) = origin.body_length() {
+            if length as u64 > max_body_size {
+                return Err(ErrorKind::BodyTooLarge(max_body_size).into());
+            }
+        }
+
+        // Compressed bodies are decompressed before being read, so the
+        // size limits apply to the decompressed body
+        let encoding = headers
+            .iter()
+            .find(|&(name, _)| name.eq_ignore_ascii_case("Content-Encoding"))
+            .map(|(name, value)| (name.clone(), value.clone()));
+        let (body, binary_body, spooled) = {
+            let mut reader =
+                DeadlineReader::new(origin.as_reader(), read_timeout);
+            let result = match encoding {
+                Some((name, value)) => {
+                    let mut reader = decompress(&mut reader, &value)?;
+                    headers.remove(&name);
+                    read_body(&mut *reader, max_body_size, spool_threshold)
+                }
+                None => {
+                    read_body(&mut reader, max_body_size, spool_threshold)
+                }
+            };
+
+            match result {
+                Err(..) if reader.expired() => {
+                    return Err(
+                        ErrorKind::BodyTimeout(read_timeout.as_secs()).into(),
+                    );
+                }
+                other => other?,
+            }
+        };
 
         // Get the querystring
         let url = origin.url();
@@ -56,11 +243,138 @@ impl<'a> From<&'a mut tiny_http::Request> for WebRequest {
             HashMap::new()
         };
 
-        WebRequest {
+        Ok(WebRequest {
+            id: id,
+            method: origin.method().to_string(),
+            url: url.to_string(),
             source: source,
             headers: headers,
             params: params,
             body: body,
+            binary_body: binary_body,
+            spooled: spooled,
+        })
+    }
+}
+
+
+/// Return the address the request comes from. Connections without an IP
+/// address are local, so they're seen as coming from 127.0.0.1.
+pub fn source_ip(origin: &tiny_http::Request) -> IpAddr {
+    match origin.remote_addr() {
+        Some(addr) => addr.ip(),
+        None => IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+    }
+}
+
+
+/// Read the body of a request, spooling it to disk if it's too big.
+fn read_body<R: Read + ?Sized>(
+    reader: &mut R,
+    max_body_size: u64,
+    spool_threshold: u64,
+) -> Result<(String, Option<Vec<u8>>, Option<Arc<SpooledBody>>)> {
+    // One more byte than allowed is read to detect chunked bodies which
+    // are too big
+    let mut reader = reader.take(max_body_size.saturating_add(1));
+    let in_memory = cmp::min(spool_threshold, max_body_size);
+
+    let mut raw = Vec::new();
+    (&mut reader)
+        .take(in_memory.saturating_add(1))
+        .read_to_end(&mut raw)?;
+
+    // Stream the rest of big bodies to disk, without buffering them
+    let mut spooled = None;
+    let mut size = raw.len() as u64;
+    if size > in_memory && in_memory < max_body_size {
+        let (body, mut file) = SpooledBody::create()?;
+        file.write_all(&raw)?;
+        size += io::copy(&mut reader, &mut file)?;
+
+        raw = Vec::new();
+        spooled = Some(Arc::new(body));
+    }
+    if size > max_body_size {
+        return Err(ErrorKind::BodyTooLarge(max_body_size).into());
+    }
+
+    let (body, binary_body) = decode_body(raw);
+    Ok((body, binary_body, spooled))
+}
+
+
+/// Reader failing when a deadline passes. Each read is also bounded by the
+/// receive timeout of the connection (see `HttpServer::listen_on`), so
+/// clients which stop sending the body can't keep the read blocked.
+struct DeadlineReader<R: Read> {
+    inner: R,
+    deadline: Instant,
+    timed_out: bool,
+}
+
+impl<R: Read> DeadlineReader<R> {
+    fn new(inner: R, timeout: Duration) -> Self {
+        DeadlineReader {
+            inner,
+            deadline: Instant::now() + timeout,
+            timed_out: false,
+        }
+    }
+
+    fn expired(&self) -> bool {
+        self.timed_out || Instant::now() >= self.deadline
+    }
+}
+
+impl<R: Read> Read for DeadlineReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if Instant::now() >= self.deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the body wasn't received in time",
+            ));
+        }
+
+        let result = self.inner.read(buf);
+        if let Err(ref err) = result {
+            // The receive timeout of the socket expired
+            match err.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+                    self.timed_out = true;
+                }
+                _ => {}
+            }
+        }
+        result
+    }
+}
+
+
+/// Wrap the reader to decompress bodies with the `Content-Encoding`.
+fn decompress<'a, R: Read + 'a>(
+    reader: R,
+    encoding: &str,
+) -> Result<Box<Read + 'a>> {
+    match encoding.trim().to_lowercase().as_str() {
+        "identity" => Ok(Box::new(reader)),
+        "gzip" | "x-gzip" => Ok(Box::new(GzDecoder::new(reader)?)),
+        "deflate" => Ok(Box::new(ZlibDecoder::new(reader))),
+        _ => Err(ErrorKind::InvalidInput(
+            format!("unsupported content encoding: {}", encoding),
+        ).into()),
+    }
+}
+
+
+/// Decode the body, keeping the original bytes if it's not UTF-8: providers
+/// only see the lossy version, but hooks get it verbatim.
+fn decode_body(raw: Vec<u8>) -> (String, Option<Vec<u8>>) {
+    match String::from_utf8(raw) {
+        Ok(body) => (body, None),
+        Err(error) => {
+            let raw = error.into_bytes();
+            (String::from_utf8_lossy(&raw).into_owned(), Some(raw))
         }
     }
 }
@@ -73,3 +387,159 @@ pub fn params_from_query(query: &str) -> HashMap<String, String> {
     }
     hashmap
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::thread;
+    use std::time::Duration;
+
+    use common::prelude::*;
+
+    use super::{decompress, read_body, DeadlineReader};
+
+
+    const HELLO_GZIP: &[u8] = b"\
+        \x1f\x8b\x08\x00\x00\x00\x00\x00\x02\x03\xcb\x48\
+        \xcd\xc9\xc9\x57\x28\xcf\x2f\xca\x49\x01\x00\x85\
+        \x11\x4a\x0d\x0b\x00\x00\x00";
+    const HELLO_DEFLATE: &[u8] = b"\
+        \x78\x9c\xcb\x48\xcd\xc9\xc9\x57\x28\xcf\x2f\xca\
+        \x49\x01\x00\x1a\x0b\x04\x5d";
+    /// A thousand zero bytes, compressed with gzip.
+    const ZEROS_GZIP: &[u8] = b"\
+        \x1f\x8b\x08\x00\x00\x00\x00\x00\x02\x03\x63\x60\
+        \x18\x05\xa3\x60\x14\x0c\x77\x00\x00\x80\x17\x0b\
+        \x06\xe8\x03\x00\x00";
+
+
+    #[test]
+    fn test_read_body() {
+        // Small bodies are kept in memory
+        let (body, binary, spooled) =
+            read_body(&mut &b"hello"[..], 10, 5).unwrap();
+        assert_eq!(body, "hello");
+        assert!(binary.is_none());
+        assert!(spooled.is_none());
+
+        // Bigger ones are streamed to disk
+        let (body, _, spooled) =
+            read_body(&mut &b"hello world"[..], 20, 5).unwrap();
+        assert_eq!(body, "");
+        let spooled = spooled.unwrap();
+        let path = spooled.path().to_path_buf();
+        let mut content = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"hello world");
+
+        // The spooled body is removed when it's not needed anymore
+        drop(spooled);
+        assert!(!path.exists());
+
+        // The size limit is enforced both in memory and on disk
+        assert_err!(
+            read_body(&mut &b"hello world"[..], 10, 10),
+            ErrorKind::BodyTooLarge(10)
+        );
+        assert_err!(
+            read_body(&mut &b"hello world"[..], 10, 5),
+            ErrorKind::BodyTooLarge(10)
+        );
+    }
+
+
+    #[test]
+    fn test_decompress() {
+        for &(encoding, body) in &[
+            ("gzip", HELLO_GZIP),
+            ("x-gzip", HELLO_GZIP),
+            ("Deflate", HELLO_DEFLATE),
+            ("identity", &b"hello world"[..]),
+        ] {
+            let mut reader = decompress(body, encoding).unwrap();
+            let (body, _, _) = read_body(&mut *reader, 20, 20).unwrap();
+            assert_eq!(body, "hello world");
+        }
+
+        // The size limit applies to the decompressed body
+        let mut reader = decompress(ZEROS_GZIP, "gzip").unwrap();
+        assert_err!(
+            read_body(&mut *reader, 100, 100),
+            ErrorKind::BodyTooLarge(100)
+        );
+
+        // Corrupted bodies and unknown encodings are rejected
+        let corrupted = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x02\x03\xff\xff";
+        let mut reader = decompress(&corrupted[..], "gzip").unwrap();
+        assert!(read_body(&mut *reader, 20, 20).is_err());
+        assert_err!(
+            decompress(&b"hello"[..], "br"),
+            ErrorKind::InvalidInput(..)
+        );
+    }
+
+
+    #[test]
+    fn test_deadline_reader() {
+        let mut reader =
+            DeadlineReader::new(&b"hello"[..], Duration::from_millis(50));
+        let mut buf = [0; 2];
+        assert_eq!(reader.read(&mut buf).unwrap(), 2);
+        assert!(!reader.expired());
+
+        // Reads after the deadline fail
+        thread::sleep(Duration::from_millis(60));
+        assert!(reader.expired());
+        assert!(reader.read(&mut buf).is_err());
+
+        // Reads timed out by the socket expire the reader right away
+        struct Stalled;
+        impl Read for Stalled {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+        }
+        let mut reader = DeadlineReader::new(Stalled, Duration::from_secs(60));
+        assert!(reader.read(&mut buf).is_err());
+        assert!(reader.expired());
+    }
+
+
+    #[test]
+    fn test_form() {
+        let mut req = ::utils::testing::dummy_web_request();
+        req.body = "a=1&b=hello+world&c=%26%3D".into();
+        assert!(req.form().is_none());
+
+        req.headers.insert(
+            "Content-Type".into(),
+            "application/x-www-form-urlencoded; charset=utf-8".into(),
+        );
+        let form = req.form().unwrap();
+        assert_eq!(form.len(), 3);
+        assert_eq!(form["a"], "1");
+        assert_eq!(form["b"], "hello world");
+        assert_eq!(form["c"], "&=");
+
+        req.headers.insert("Content-Type".into(), "text/plain".into());
+        assert!(req.form().is_none());
+    }
+
+
+    #[test]
+    fn test_load_spooled() {
+        let (_, _, spooled) =
+            read_body(&mut &b"\xff\xfe body"[..], 20, 2).unwrap();
+
+        let mut req = ::utils::testing::dummy_web_request();
+        assert!(req.load_spooled().unwrap().is_none());
+
+        req.spooled = spooled;
+        let loaded = req.load_spooled().unwrap().unwrap();
+        assert_eq!(loaded.body_bytes(), b"\xff\xfe body");
+        assert_eq!(loaded.body_encoding(), "binary");
+        assert!(loaded.spooled.is_some());
+    }
+}
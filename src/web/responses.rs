@@ -18,37 +18,75 @@ use std::time::Duration;
 use serde_json;
 
 use common::prelude::*;
-use common::structs::HealthDetails;
+use common::structs::{HealthDetails, HooksHealth};
+use events::Subscription;
+use providers::ProviderResponse;
 
 
 #[derive(Debug)]
 pub enum Response {
     NotFound,
+    Unauthorized,
     Forbidden,
     BadRequest(Error),
     TooManyRequests(Duration),
+    PayloadTooLarge,
+    RequestTimeout,
+    /// The allowed methods are sent back to the client.
+    MethodNotAllowed(Vec<String>),
     Unavailable,
+    GatewayTimeout,
     Ok,
-    HealthStatus(HealthDetails),
+    /// The ID of the job which was queued.
+    JobQueued(String),
+    PlainText(String),
+    Provider(ProviderResponse),
+    ScriptOutput { success: bool, stdout: String },
+    HealthStatus(HealthDetails, HooksHealth),
+    Json(serde_json::Value),
+    /// Another response, with some additional headers.
+    WithHeaders(Box<Response>, Vec<String>),
+    /// The events are streamed to the client as they happen.
+    EventStream(Subscription),
 }
 
 impl Response {
     pub fn status(&self) -> u16 {
         match *self {
             Response::NotFound => 404,
+            Response::Unauthorized => 401,
             Response::Forbidden => 403,
             Response::BadRequest(..) => 400,
             Response::TooManyRequests(..) => 429,
+            Response::PayloadTooLarge => 413,
+            Response::RequestTimeout => 408,
+            Response::MethodNotAllowed(..) => 405,
             Response::Unavailable => 503,
+            Response::GatewayTimeout => 504,
+            Response::ScriptOutput { success: false, .. } => 500,
+            Response::Provider(ref response) => response.status,
+            Response::WithHeaders(ref inner, _) => inner.status(),
             _ => 200,
         }
     }
 
     pub fn json(&self) -> String {
         serde_json::to_string(&match *self {
-            Response::HealthStatus(ref details) => json!({
+            Response::WithHeaders(ref inner, _) => return inner.json(),
+            Response::HealthStatus(ref details, ref hooks) => json!({
                 "status": "ok",
-                "result": details,
+                "result": {
+                    "queued_jobs": details.queued_jobs,
+                    "busy_threads": details.busy_threads,
+                    "max_threads": details.max_threads,
+                    "hooks_loaded": hooks.hooks_loaded,
+                    "last_reload": hooks.last_reload,
+                    "last_failure": hooks.last_failure,
+                },
+            }),
+            Response::Json(ref result) => json!({
+                "status": "ok",
+                "result": result,
             }),
             Response::BadRequest(ref error) => json!({
                 "status": "bad_request",
@@ -58,19 +96,56 @@ impl Response {
                 "status": "too_many_requests",
                 "retry_after": until.as_secs(),
             }),
+            Response::JobQueued(ref id) => json!({
+                "status": "ok",
+                "job_id": id,
+            }),
             _ => json!({
                 "status": match *self {
                     Response::NotFound => "not_found",
+                    Response::Unauthorized => "unauthorized",
                     Response::Forbidden => "forbidden",
                     Response::BadRequest(..) => "bad_request",
                     Response::TooManyRequests(..) => "too_many_requests",
+                    Response::PayloadTooLarge => "payload_too_large",
+                    Response::RequestTimeout => "request_timeout",
+                    Response::MethodNotAllowed(..) => "method_not_allowed",
                     Response::Unavailable => "unavailable",
-                    Response::Ok | Response::HealthStatus(..) => "ok",
+                    Response::GatewayTimeout => "gateway_timeout",
+                    Response::ScriptOutput { success: false, .. } => "failed",
+                    Response::Ok | Response::JobQueued(..) |
+                    Response::PlainText(..) |
+                    Response::ScriptOutput { .. } |
+                    Response::Provider(..) | Response::HealthStatus(..) |
+                    Response::Json(..) | Response::EventStream(..) => "ok",
+                    Response::WithHeaders(..) => unreachable!(),
                 },
             }),
         }).unwrap()
     }
 
+    pub fn body(&self) -> String {
+        match *self {
+            Response::PlainText(ref text) => text.clone(),
+            Response::Provider(ref response) => response.body.clone(),
+            Response::ScriptOutput { ref stdout, .. } => stdout.clone(),
+            Response::WithHeaders(ref inner, _) => inner.body(),
+            _ => self.json(),
+        }
+    }
+
+    pub fn content_type(&self) -> &str {
+        match *self {
+            Response::PlainText(..) | Response::ScriptOutput { .. } => {
+                "text/plain"
+            }
+            Response::Provider(ref response) => &response.content_type,
+            Response::WithHeaders(ref inner, _) => inner.content_type(),
+            Response::EventStream(..) => "text/event-stream",
+            _ => "application/json",
+        }
+    }
+
     pub fn headers(&self) -> Option<Vec<String>> {
         match *self {
             Response::TooManyRequests(ref duration) => {
@@ -78,6 +153,21 @@ impl Response {
                     format!("Retry-After: {}", duration.as_secs()),
                 ])
             },
+            Response::Unauthorized => {
+                Some(vec!["WWW-Authenticate: Bearer".into()])
+            },
+            Response::MethodNotAllowed(ref methods) => {
+                Some(vec![format!("Allow: {}", methods.join(", "))])
+            },
+            Response::PlainText(..) | Response::Provider(..) |
+            Response::ScriptOutput { .. } => {
+                Some(vec!["X-Content-Type-Options: nosniff".into()])
+            },
+            Response::WithHeaders(ref inner, ref extra) => {
+                let mut headers = inner.headers().unwrap_or_else(Vec::new);
+                headers.extend(extra.iter().cloned());
+                Some(headers)
+            },
             _ => None,
         }
     }
@@ -91,7 +181,8 @@ mod tests {
     use serde_json;
 
     use common::prelude::*;
-    use common::structs::HealthDetails;
+    use common::structs::{HealthDetails, HooksHealth};
+    use providers::ProviderResponse;
 
     use super::Response;
 
@@ -120,6 +211,19 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_unauthorized() {
+        let response = Response::Unauthorized;
+        assert_eq!(response.status(), 401);
+        assert_eq!(response.headers(), Some(vec![
+            "WWW-Authenticate: Bearer".into(),
+        ]));
+
+        let json = j(response.json());
+        assert_eq!(json["status"].as_str().unwrap(), "unauthorized");
+    }
+
+
     #[test]
     fn test_forbidden() {
         let response = Response::Forbidden;
@@ -165,6 +269,43 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_payload_too_large() {
+        let response = Response::PayloadTooLarge;
+        assert_eq!(response.status(), 413);
+        assert!(response.headers().is_none());
+
+        let json = j(response.json());
+        assert_eq!(json["status"].as_str().unwrap(), "payload_too_large");
+    }
+
+
+    #[test]
+    fn test_request_timeout() {
+        let response = Response::RequestTimeout;
+        assert_eq!(response.status(), 408);
+        assert!(response.headers().is_none());
+
+        let json = j(response.json());
+        assert_eq!(json["status"].as_str().unwrap(), "request_timeout");
+    }
+
+
+    #[test]
+    fn test_method_not_allowed() {
+        let response = Response::MethodNotAllowed(vec![
+            "GET".into(), "POST".into(),
+        ]);
+        assert_eq!(response.status(), 405);
+        assert_eq!(response.headers(), Some(vec![
+            "Allow: GET, POST".into(),
+        ]));
+
+        let json = j(response.json());
+        assert_eq!(json["status"].as_str().unwrap(), "method_not_allowed");
+    }
+
+
     #[test]
     fn test_too_many_requests() {
         let response = Response::TooManyRequests(Duration::from_secs(10));
@@ -219,12 +360,90 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_plain_text() {
+        let response = Response::PlainText("hello".into());
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body(), "hello");
+        assert_eq!(response.content_type(), "text/plain");
+        assert_eq!(
+            response.headers(),
+            Some(vec!["X-Content-Type-Options: nosniff".to_string()])
+        );
+    }
+
+
+    #[test]
+    fn test_gateway_timeout() {
+        let response = Response::GatewayTimeout;
+        assert_eq!(response.status(), 504);
+        assert!(response.headers().is_none());
+
+        let json = j(response.json());
+        assert_eq!(json["status"].as_str().unwrap(), "gateway_timeout");
+    }
+
+
+    #[test]
+    fn test_script_output() {
+        let response = Response::ScriptOutput {
+            success: true,
+            stdout: "done\n".into(),
+        };
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body(), "done\n");
+        assert_eq!(response.content_type(), "text/plain");
+
+        let response = Response::ScriptOutput {
+            success: false,
+            stdout: "oops\n".into(),
+        };
+        assert_eq!(response.status(), 500);
+        assert_eq!(response.body(), "oops\n");
+        assert_eq!(
+            response.headers(),
+            Some(vec!["X-Content-Type-Options: nosniff".to_string()])
+        );
+    }
+
+
+    #[test]
+    fn test_provider() {
+        let custom = ProviderResponse::json(&json!({"type": 1}));
+        let response = Response::Provider(custom.with_status(202));
+        assert_eq!(response.status(), 202);
+        assert_eq!(response.body(), r#"{"type":1}"#);
+        assert_eq!(response.content_type(), "application/json");
+        assert_eq!(
+            response.headers(),
+            Some(vec!["X-Content-Type-Options: nosniff".to_string()])
+        );
+    }
+
+
+    #[test]
+    fn test_json() {
+        let response = Response::Json(json!(["a", "b"]));
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().is_none());
+
+        assert_eq!(j(response.json()), json!({
+            "status": "ok",
+            "result": ["a", "b"],
+        }));
+    }
+
+
     #[test]
     fn test_health_status() {
         let response = Response::HealthStatus(HealthDetails {
             queued_jobs: 1,
             busy_threads: 2,
             max_threads: 3,
+        }, HooksHealth {
+            hooks_loaded: 4,
+            last_reload: 1500000000,
+            last_failure: None,
         });
 
         // The result must be an object
@@ -243,8 +462,7 @@ mod tests {
         // It must have an object called "result"
         let result = obj.get("result").unwrap().as_object().unwrap();
 
-        // The result must contain "queued_jobs", "busy_threads" and
-        // "max_threads"
+        // The result must contain both the processor and the hooks details
         assert_eq!(
             result.get("queued_jobs").unwrap().as_u64().unwrap(),
             1 as u64
@@ -256,6 +474,53 @@ mod tests {
         assert_eq!(
             result.get("max_threads").unwrap().as_u64().unwrap(),
             3 as u64
-        )
+        );
+        assert_eq!(
+            result.get("hooks_loaded").unwrap().as_u64().unwrap(),
+            4 as u64
+        );
+        assert_eq!(
+            result.get("last_reload").unwrap().as_u64().unwrap(),
+            1500000000 as u64
+        );
+        assert!(result.get("last_failure").unwrap().is_null());
+    }
+
+
+    #[test]
+    fn test_with_headers() {
+        let response = Response::WithHeaders(
+            Box::new(Response::TooManyRequests(Duration::from_secs(10))),
+            vec!["Vary: Origin".into()],
+        );
+
+        // Everything except the headers comes from the inner response
+        assert_eq!(response.status(), 429);
+        assert_eq!(response.content_type(), "application/json");
+        assert_eq!(j(response.json()), json!({
+            "status": "too_many_requests",
+            "retry_after": 10,
+        }));
+        assert_eq!(response.headers(), Some(vec![
+            "Retry-After: 10".into(),
+            "Vary: Origin".into(),
+        ]));
+
+        let response = Response::WithHeaders(
+            Box::new(Response::Ok), vec!["Vary: Origin".into()],
+        );
+        assert_eq!(response.headers(), Some(vec!["Vary: Origin".into()]));
+    }
+
+
+    #[test]
+    fn test_job_queued() {
+        let response = Response::JobQueued("abcd".into());
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().is_none());
+        assert_eq!(j(response.json()), json!({
+            "status": "ok",
+            "job_id": "abcd",
+        }));
     }
 }
@@ -0,0 +1,139 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs::File;
+use std::io::Read;
+
+use tiny_http;
+
+use common::prelude::*;
+use common::config::TlsConfig;
+
+
+/// The certificate and private key used by the HTTP server.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TlsCertificate {
+    certificate: Vec<u8>,
+    private_key: Vec<u8>,
+}
+
+impl TlsCertificate {
+    /// Load the certificate from the disk, if TLS is enabled.
+    pub fn load(config: &TlsConfig) -> Result<Option<Self>> {
+        match (&config.certificate, &config.private_key) {
+            (&Some(ref certificate), &Some(ref private_key)) => {
+                Ok(Some(TlsCertificate {
+                    certificate: read_file(certificate)?,
+                    private_key: read_file(private_key)?,
+                }))
+            }
+            (&None, &None) => Ok(None),
+            _ => Err(ErrorKind::TlsError(
+                "both the certificate and the private key are needed".into(),
+            ).into()),
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn server<L: Into<tiny_http::Listener>>(
+        &self,
+        listener: L,
+    ) -> Result<tiny_http::Server> {
+        let config = tiny_http::SslConfig {
+            certificate: self.certificate.clone(),
+            private_key: self.private_key.clone(),
+        };
+
+        tiny_http::Server::from_listener(listener, Some(config))
+            .map_err(|e| ErrorKind::TlsError(e.to_string()).into())
+    }
+
+    #[cfg(not(feature = "tls"))]
+    pub fn server<L: Into<tiny_http::Listener>>(
+        &self,
+        _listener: L,
+    ) -> Result<tiny_http::Server> {
+        Err(ErrorKind::TlsError(
+            "Fisher was built without the tls feature".into(),
+        ).into())
+    }
+}
+
+
+fn read_file(path: &str) -> Result<Vec<u8>> {
+    let mut content = Vec::new();
+    File::open(path)?.read_to_end(&mut content)?;
+
+    if content.is_empty() {
+        return Err(ErrorKind::TlsError(format!("{} is empty", path)).into());
+    }
+
+    Ok(content)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use common::config::TlsConfig;
+    use utils;
+
+    use super::TlsCertificate;
+
+
+    #[test]
+    fn test_load() {
+        let dir = utils::create_temp_dir().unwrap();
+        let cert = dir.join("cert.pem");
+        let key = dir.join("key.pem");
+        let empty = dir.join("empty.pem");
+        write!(File::create(&cert).unwrap(), "cert").unwrap();
+        write!(File::create(&key).unwrap(), "key").unwrap();
+        File::create(&empty).unwrap();
+
+        let path = |p: &::std::path::PathBuf| Some(p.to_str().unwrap().into());
+
+        // TLS is disabled by default
+        let config = TlsConfig::default();
+        assert_eq!(TlsCertificate::load(&config).unwrap(), None);
+
+        let config = TlsConfig {
+            certificate: path(&cert),
+            private_key: path(&key),
+        };
+        assert_eq!(
+            TlsCertificate::load(&config).unwrap(),
+            Some(TlsCertificate {
+                certificate: b"cert".to_vec(),
+                private_key: b"key".to_vec(),
+            })
+        );
+
+        // Both the certificate and the key must be present and not empty
+        for (certificate, private_key) in vec![
+            (path(&cert), None),
+            (None, path(&key)),
+            (path(&cert), path(&empty)),
+            (path(&cert), path(&dir.join("missing.pem"))),
+        ] {
+            let config = TlsConfig { certificate, private_key };
+            assert!(TlsCertificate::load(&config).is_err());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+
+use common::prelude::*;
+use common::config::UnixSocketConfig;
+
+
+/// Create the Unix socket the HTTP server listens on. The socket file is
+/// removed when the server is stopped.
+pub fn bind(config: &UnixSocketConfig) -> Result<UnixListener> {
+    // Remove the socket left behind by a previous instance, but don't touch
+    // anything else
+    if let Ok(metadata) = fs::symlink_metadata(&config.path) {
+        if !metadata.file_type().is_socket() {
+            return Err(ErrorKind::InvalidInput(format!(
+                "{} exists and it's not a socket", config.path,
+            )).into());
+        }
+        fs::remove_file(&config.path)?;
+    }
+
+    let listener = UnixListener::bind(&config.path)?;
+    if let Some(mode) = config.mode()? {
+        fs::set_permissions(&config.path, fs::Permissions::from_mode(mode))?;
+    }
+
+    Ok(listener)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+
+    use common::config::UnixSocketConfig;
+    use utils;
+
+    use super::bind;
+
+
+    #[test]
+    fn test_bind() {
+        let dir = utils::create_temp_dir().unwrap();
+        let path = dir.join("fisher.sock");
+
+        let config = UnixSocketConfig {
+            path: path.to_str().unwrap().into(),
+            mode: Some("600".into()),
+        };
+        let listener = bind(&config).unwrap();
+
+        // Check the permissions of the socket
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        UnixStream::connect(&path).unwrap();
+
+        // The socket left behind by a previous instance is replaced
+        drop(listener);
+        let _listener = bind(&config).unwrap();
+        UnixStream::connect(&path).unwrap();
+
+        // Regular files are never replaced
+        fs::remove_file(&path).unwrap();
+        fs::File::create(&path).unwrap();
+        assert!(bind(&config).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
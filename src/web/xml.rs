@@ -0,0 +1,257 @@
+// Copyright (C) 2017 Pietro Albini
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Parser for XML request bodies.
+//!
+//! The whole document is kept in memory as a tree of elements, and values
+//! can be extracted from it with a small subset of XPath. External entities
+//! are never resolved.
+
+use xml::reader::{EventReader, XmlEvent};
+
+use common::prelude::*;
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Element(Element),
+    Text(String),
+}
+
+
+/// An element of an XML document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Node>,
+}
+
+impl Element {
+    /// The name of the element, without the namespace prefix.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the value of an attribute of the element.
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|&&(ref attr, _)| attr == name)
+            .map(|&(_, ref value)| value.as_str())
+    }
+
+    /// Return the child elements, in document order.
+    pub fn children<'a>(&'a self) -> Box<Iterator<Item = &'a Element> + 'a> {
+        Box::new(self.children.iter().filter_map(|node| match *node {
+            Node::Element(ref element) => Some(element),
+            Node::Text(..) => None,
+        }))
+    }
+
+    /// Return all the text inside the element, including the one of its
+    /// descendants.
+    pub fn text(&self) -> String {
+        let mut result = String::new();
+        for node in &self.children {
+            match *node {
+                Node::Element(ref element) => {
+                    result.push_str(&element.text());
+                }
+                Node::Text(ref text) => result.push_str(text),
+            }
+        }
+        result
+    }
+
+    /// Return the first value matching the path, if any.
+    ///
+    /// Paths are a small subset of XPath: an absolute path of element names
+    /// (or `*` to match any element), which can end with `@attribute` to get
+    /// an attribute or with `text()` to get only the text directly inside
+    /// the element. For example `/build/resource/@id` or `/event/name`.
+    pub fn select(&self, path: &str) -> Option<String> {
+        if !path.starts_with('/') {
+            return None;
+        }
+
+        let steps = path[1..].split('/').collect::<Vec<_>>();
+        select_steps(self, &steps)
+    }
+}
+
+
+fn select_steps(element: &Element, steps: &[&str]) -> Option<String> {
+    let (step, rest) = match steps.split_first() {
+        Some(split) => split,
+        None => return None,
+    };
+    if *step != "*" && *step != element.name {
+        return None;
+    }
+
+    if rest.is_empty() {
+        return Some(element.text());
+    }
+
+    if rest.len() == 1 && rest[0].starts_with('@') {
+        element.attribute(&rest[0][1..]).map(|value| value.to_string())
+    } else if rest.len() == 1 && rest[0] == "text()" {
+        let mut result = String::new();
+        for node in &element.children {
+            if let Node::Text(ref text) = *node {
+                result.push_str(text);
+            }
+        }
+        Some(result)
+    } else {
+        element
+            .children()
+            .filter_map(|child| select_steps(child, rest))
+            .next()
+    }
+}
+
+
+/// Check if the content type is the one of an XML body.
+pub fn is_xml(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+
+    mime.eq_ignore_ascii_case("application/xml")
+        || mime.eq_ignore_ascii_case("text/xml")
+        || mime.to_lowercase().ends_with("+xml")
+}
+
+
+/// Parse the body, returning its root element. Bodies which are not well
+/// formed are rejected.
+pub fn parse(body: &[u8]) -> Result<Element> {
+    let mut stack: Vec<Element> = Vec::new();
+    let mut root = None;
+
+    for event in EventReader::new(body) {
+        let event = event.map_err(|error| invalid(&error.to_string()))?;
+
+        match event {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                if root.is_some() {
+                    return Err(invalid("multiple root elements"));
+                }
+
+                stack.push(Element {
+                    name: name.local_name,
+                    attributes: attributes
+                        .into_iter()
+                        .map(|attr| (attr.name.local_name, attr.value))
+                        .collect(),
+                    children: Vec::new(),
+                });
+            }
+            XmlEvent::EndElement { .. } => {
+                let element = stack.pop().unwrap();
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(
+                        Node::Element(element),
+                    ),
+                    None => root = Some(element),
+                }
+            }
+            XmlEvent::Characters(text) | XmlEvent::CData(text) => {
+                if let Some(parent) = stack.last_mut() {
+                    parent.children.push(Node::Text(text));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    root.ok_or_else(|| invalid("missing the root element"))
+}
+
+
+fn invalid(message: &str) -> Error {
+    ErrorKind::InvalidInput(format!("invalid XML body: {}", message)).into()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use common::prelude::*;
+
+    use super::{is_xml, parse};
+
+
+    const BUILD: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+        <build id="42" xmlns:tfs="http://example.com">
+            <status>succeeded</status>
+            <tfs:resource kind="repo">
+                <name>fisher</name>
+                <url><![CDATA[https://example.com/?a=1&b=2]]></url>
+            </tfs:resource>
+            <message>Built <b>master</b> branch</message>
+        </build>
+    "#;
+
+
+    #[test]
+    fn test_is_xml() {
+        assert!(is_xml("application/xml"));
+        assert!(is_xml("Text/XML; charset=utf-8"));
+        assert!(is_xml("application/atom+xml"));
+        assert!(!is_xml("application/json"));
+        assert!(!is_xml("application/xml-dtd"));
+    }
+
+
+    #[test]
+    fn test_select() {
+        let root = parse(BUILD.as_bytes()).unwrap();
+        assert_eq!(root.name(), "build");
+
+        for &(path, expected) in &[
+            ("/build/@id", Some("42")),
+            ("/build/status", Some("succeeded")),
+            ("/build/resource/@kind", Some("repo")),
+            ("/build/*/name", Some("fisher")),
+            ("/build/resource/url", Some("https://example.com/?a=1&b=2")),
+            ("/build/message", Some("Built master branch")),
+            ("/build/message/text()", Some("Built  branch")),
+            ("/build/missing", None),
+            ("/build/status/@missing", None),
+            ("/other/status", None),
+            ("build/status", None),
+        ] {
+            assert_eq!(
+                root.select(path),
+                expected.map(|value| value.to_string()),
+                "{}", path
+            );
+        }
+    }
+
+
+    #[test]
+    fn test_parse_invalid() {
+        for body in &[
+            "",
+            "not xml",
+            "<a><b></a>",
+            "<a></a><b></b>",
+            "<a>",
+        ] {
+            assert_err!(parse(body.as_bytes()), ErrorKind::InvalidInput(..));
+        }
+    }
+}